@@ -1,34 +1,214 @@
-use kitsune_core::{Downloader, DownloadSession, ChannelObserver};
+use kitsune_core::{dash, delta, hls, metalink, torrent};
+use kitsune_core::{BlockList, ByteRange, DownloadLock, DownloadObserver, Downloader, DownloadSession, ChannelObserver, DigestSource, ExpectedDigest, LockError, Mirror, PieceHashes, PreviewHandle, PreviewServer, HttpConfig, Schedule, Scheduler, HttpStrategy, IpPreference, NetworkConfig, SessionStore, SourceAddress, TorrentConfig, TrackSelection, VariantSelection};
 mod native_messaging;
 mod ui;
 
 use clap::Parser;
 use log::info;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// URL of the file to download
-    #[arg(required_unless_present = "native_mode")]
+    /// URL of the file to download (http, https, ftp, ftps, ftpes, sftp or
+    /// s3://bucket/key), an HLS `.m3u8` playlist, a DASH `.mpd` manifest, a
+    /// `.meta4`/`.metalink` path or URL, a magnet link or `.torrent` path
+    /// or URL, or a `.zsync` control file path or URL
+    #[arg(required_unless_present_any = ["native_mode", "list_sessions", "gc_sessions", "daemon", "global_schedule", "clear_global_schedule"])]
     url: Option<String>,
 
-    /// Output file path (optional, defaults to filename from URL)
+    /// Output file path (optional, defaults to filename from URL); the
+    /// target directory for Metalink, DASH and torrent downloads. `-`
+    /// writes the file to stdout
     #[arg(short = 'O', long)]
     output: Option<PathBuf>,
 
+    /// With `-O -`, how many MiB of parts that arrived ahead of the output
+    /// may be held in memory
+    #[arg(long, value_name = "MIB", default_value_t = 64)]
+    pipe_buffer: u64,
+
     /// User Agent to use for requests
     #[arg(long, default_value = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")]
     user_agent: String,
 
+    /// How HTTP parts share connections: h1 opens one connection per part,
+    /// h2 multiplexes them over a few pooled HTTP/2 connections, h3 uses
+    /// QUIC (experimental, only in builds with HTTP/3)
+    #[arg(long = "http", value_name = "STRATEGY", default_value = "h1")]
+    http_strategy: HttpStrategy,
+
+    /// Strategy for one host, e.g. cdn.example.com=h1 or *.example.com=h2;
+    /// repeat for several hosts
+    #[arg(long = "http-host", value_name = "HOST=STRATEGY")]
+    http_hosts: Vec<String>,
+
+    /// Network interface to connect from, e.g. eth0; repeat (also with
+    /// --bind-address) to bond several uplinks
+    #[arg(long = "interface", value_name = "NAME")]
+    interfaces: Vec<String>,
+
+    /// Local address to connect from; repeat to bond several uplinks, with
+    /// each new connection taking the least busy one
+    #[arg(long = "bind-address", value_name = "IP")]
+    bind_addresses: Vec<std::net::IpAddr>,
+
+    /// Address families to use: auto, prefer-4, prefer-6, 4 or 6
+    #[arg(long, value_name = "PREFERENCE", default_value = "auto")]
+    ip_version: IpPreference,
+
+    /// DNS server to use instead of the system resolver, as ip or ip:port;
+    /// repeat for fallbacks
+    #[arg(long = "dns-server", value_name = "ADDR")]
+    dns_servers: Vec<String>,
+
+    /// Fixed address for a host name, skipping DNS; repeat for several hosts
+    #[arg(long = "resolve", value_name = "HOST=IP[,IP]")]
+    resolve: Vec<String>,
+
+    /// Private key for sftp:// URLs, tried after the SSH agent and before
+    /// the default keys in ~/.ssh
+    #[arg(long, value_name = "FILE")]
+    ssh_key: Option<PathBuf>,
+
     /// Number of concurrent connections
     #[arg(short, long, default_value_t = 8)]
     connections: u8,
 
+    /// HLS variant or DASH video track to download: best, worst,
+    /// <height>p (e.g. 720p) or <kbps>k (e.g. 3000k)
+    #[arg(long, default_value = "best")]
+    variant: VariantSelection,
+
+    /// Preferred DASH audio language, e.g. en
+    #[arg(long)]
+    audio_lang: Option<String>,
+
+    /// Torrent files to download, as comma-separated indices from the
+    /// listing printed at start (default: all)
+    #[arg(long, value_delimiter = ',', value_name = "INDICES")]
+    select_files: Option<Vec<usize>>,
+
+    /// Keep seeding a finished torrent until this many times its size has
+    /// been uploaded; 0 stops right away
+    #[arg(long, default_value_t = 0.0)]
+    seed_ratio: f64,
+
+    /// Port to accept BitTorrent peers on
+    #[arg(long, default_value_t = 6881)]
+    torrent_port: u16,
+
+    /// Do not look up torrent peers in the DHT
+    #[arg(long)]
+    no_dht: bool,
+
     /// Run as a Native Messaging Host
     #[arg(long)]
     native_mode: bool,
+
+    /// Expected digest of the finished file, e.g. sha256:<hex>
+    /// (sha256, sha512, sha1, md5, blake3)
+    #[arg(long)]
+    checksum: Option<ExpectedDigest>,
+
+    /// JSON manifest of per-piece hashes; corrupted pieces are re-downloaded
+    /// ({"algorithm": "sha256", "piece_length": N, "hashes": [...]})
+    #[arg(long)]
+    piece_hashes: Option<PathBuf>,
+
+    /// List the entries of a remote ZIP archive, read with range requests,
+    /// and exit
+    #[arg(long)]
+    zip_list: bool,
+
+    /// Download and decompress only this entry (its path inside the
+    /// archive) of a remote ZIP archive
+    #[arg(long, value_name = "NAME")]
+    zip_entry: Option<String>,
+
+    /// Download only these bytes of the file: X-Y (inclusive), X- to the
+    /// end, or -N for the last N bytes
+    #[arg(long, value_name = "RANGE")]
+    range: Option<ByteRange>,
+
+    /// With --range, write the bytes at their offset in the remote file
+    /// instead of at the start of the output
+    #[arg(long, requires = "range")]
+    keep_offset: bool,
+
+    /// Fetch the file front to back in small parts, so its beginning can
+    /// be used before the download finishes
+    #[arg(long)]
+    sequential: bool,
+
+    /// Serve the file over HTTP on this address (e.g. 127.0.0.1:8200)
+    /// while it downloads, so a media player can open it right away;
+    /// implies --sequential
+    #[arg(long, value_name = "ADDR")]
+    preview: Option<SocketAddr>,
+
+    /// When this download may run and how fast, in local time; repeat to
+    /// combine rules. `01:00-07:00` runs only then, `pause weekdays
+    /// 09:00-17:00` stops it during office hours, `limit 2M sat,sun` caps
+    /// the speed. Saved with the session; not applied with --preview
+    #[arg(long = "schedule", value_name = "RULE")]
+    schedule: Vec<String>,
+
+    /// Replace the global schedule, which applies to every download, with
+    /// these rules (same form as --schedule); repeat to combine rules
+    #[arg(long = "global-schedule", value_name = "RULE", conflicts_with = "clear_global_schedule")]
+    global_schedule: Vec<String>,
+
+    /// Remove all global schedule rules
+    #[arg(long)]
+    clear_global_schedule: bool,
+
+    /// Keep running, resuming every unfinished download in the session
+    /// store that was given a --schedule, and picking up new ones as they
+    /// appear. Downloads without one are left for the user to resume
+    #[arg(long, conflicts_with = "url")]
+    daemon: bool,
+
+    /// Older local copy of the file: blocks it still has are copied and
+    /// only the rest is downloaded. Needs a `.zsync` control file as the
+    /// URL, or --piece-hashes. With a control file, defaults to the -O file
+    #[arg(long, value_name = "FILE")]
+    delta_from: Option<PathBuf>,
+
+    /// Another URL serving the same file; repeat to list several. Parts are
+    /// spread across all of them, favouring the fastest
+    #[arg(long = "mirror", value_name = "URL")]
+    mirrors: Vec<String>,
+
+    /// Ignore mirrors the server advertises with `Link: rel=duplicate`
+    /// headers (RFC 6249)
+    #[arg(long)]
+    no_mirror_discovery: bool,
+
+    /// Look for checksum files (e.g. <file>.sha256, SHA256SUMS) next to the
+    /// download when the server advertises no digest
+    #[arg(long)]
+    probe_checksums: bool,
+
+    /// When resuming, re-fetch the last 64 KiB of each part and roll back
+    /// parts whose data on disk does not match
+    #[arg(long)]
+    verify_resume: bool,
+
+    /// Follow the progress of a download already running in another process
+    /// instead of failing
+    #[arg(long)]
+    attach: bool,
+
+    /// List unfinished downloads in the session store and exit
+    #[arg(long)]
+    list_sessions: bool,
+
+    /// Remove completed and orphaned sessions from the session store and exit
+    #[arg(long)]
+    gc_sessions: bool,
 }
 
 #[tokio::main]
@@ -47,59 +227,377 @@ async fn main() -> anyhow::Result<()> {
         return native_messaging::run().await;
     }
 
+    if args.list_sessions || args.gc_sessions {
+        return manage_sessions(args.list_sessions, args.gc_sessions).await;
+    }
+
+    let schedule_path = Schedule::default_path();
+    if !args.global_schedule.is_empty() || args.clear_global_schedule {
+        let global = Schedule::parse(&args.global_schedule)?;
+        global.save(&schedule_path).await?;
+        for rule in &global.rules {
+            eprintln!("Global schedule: {}", rule);
+        }
+        if global.is_empty() {
+            eprintln!("Global schedule cleared");
+        }
+        if args.url.is_none() && !args.daemon {
+            return Ok(());
+        }
+    }
+    let scheduler = Arc::new(Scheduler::new(Schedule::load(&schedule_path).await?));
+    let schedule = match args.schedule.as_slice() {
+        [] => None,
+        rules => Some(Schedule::parse(rules)?),
+    };
+
+    let mut http_config = HttpConfig { default: args.http_strategy, ..HttpConfig::default() };
+    for rule in &args.http_hosts {
+        http_config.add_rule(rule)?;
+    }
+
+    let mut network = NetworkConfig {
+        sources: args.bind_addresses.iter().copied().map(SourceAddress::Ip).collect(),
+        ip_preference: args.ip_version,
+        ..NetworkConfig::default()
+    };
+    network.sources.extend(args.interfaces.iter().cloned().map(SourceAddress::Interface));
+    for server in &args.dns_servers {
+        network.add_dns_server(server)?;
+    }
+    for rule in &args.resolve {
+        network.add_host(rule)?;
+    }
+
+    let mut downloader = Downloader::new(&args.user_agent)?
+        .with_http_config(http_config)?
+        .with_network_config(&network)?
+        .with_checksum_probing(args.probe_checksums)
+        .with_mirror_discovery(!args.no_mirror_discovery)
+        .with_resume_check(if args.verify_resume { 64 * 1024 } else { 0 });
+    if let Some(key) = args.ssh_key.clone() {
+        downloader = downloader.with_ssh_identity(key);
+    }
+    if args.daemon {
+        return run_daemon(&downloader, scheduler, &schedule_path).await;
+    }
+
     let url = args.url.expect("URL is required");
 
     info!("Starting download for: {}", url);
     info!("Connections: {}", args.connections);
 
-    let downloader = Downloader::new(&args.user_agent)?;
+    if args.output.as_deref() == Some(Path::new("-")) {
+        let special = torrent::is_torrent_source(&url)
+            || dash::is_dash_source(&url)
+            || hls::is_hls_source(&url)
+            || metalink::is_metalink_source(&url)
+            || delta::is_zsync_source(&url);
+        if special || args.zip_entry.is_some() || args.range.is_some() || args.delta_from.is_some() || args.preview.is_some() {
+            anyhow::bail!("-O - only streams plain single-file downloads");
+        }
+        if !args.schedule.is_empty() {
+            anyhow::bail!("--schedule cannot be used with -O -");
+        }
+        return pipe_to_stdout(&downloader, &url, args.connections, args.pipe_buffer * 1024 * 1024, args.checksum).await;
+    }
+
+    let store = SessionStore::open_default()?;
+    let run = RunOptions { attach: args.attach, schedule, scheduler };
+
+    if torrent::is_torrent_source(&url) {
+        downloader = downloader.with_torrent_config(TorrentConfig {
+            listen_port: args.torrent_port,
+            dht: !args.no_dht,
+            ..TorrentConfig::default()
+        });
+        let output_dir = args.output.unwrap_or_else(kitsune_core::utils::fs::get_downloads_dir);
+        let session = downloader.init_torrent(&url, &output_dir, args.select_files, args.seed_ratio).await?;
+        let id = SessionStore::id_for_output(&session.output_path);
+        let (session_id, session) = match store.find_by_output(&id, &session.output_path).await? {
+            Some(found) => found,
+            None => (id, session),
+        };
+        match session.torrent.as_ref().map(|job| job.parsed_info()).transpose()?.flatten() {
+            Some(info) => {
+                for (i, file) in info.files.iter().enumerate() {
+                    println!("{:>4}  {:>12}  {}", i, file.length, file.path.join("/"));
+                }
+            }
+            None => println!("Fetching torrent metadata from peers"),
+        }
+        return download(&downloader, &store, session_id, session, &run, None).await;
+    }
+
+    if dash::is_dash_source(&url) {
+        // Every selected track is its own download and file
+        let output_dir = args.output.unwrap_or_else(kitsune_core::utils::fs::get_downloads_dir);
+        let selection = TrackSelection {
+            video: Some(args.variant),
+            audio: Some(VariantSelection::Best),
+            audio_language: args.audio_lang,
+        };
+        for session in downloader.init_dash(&url, &output_dir, args.connections, &selection).await? {
+            let id = SessionStore::id_for_output(&session.output_path);
+            let (session_id, session) = match store.find_by_output(&id, &session.output_path).await? {
+                Some(found) => found,
+                None => (id, session),
+            };
+            download(&downloader, &store, session_id, session, &run, None).await?;
+        }
+        return Ok(());
+    }
+
+    if metalink::is_metalink_source(&url) {
+        let output_dir = args.output.unwrap_or_else(kitsune_core::utils::fs::get_downloads_dir);
+        for file in downloader.fetch_metalink(&url).await? {
+            let path = output_dir.join(&file.name);
+            let id = SessionStore::id_for_output(&path);
+            let (session_id, session) = match store.find_by_output(&id, &path).await? {
+                Some(found) => found,
+                None => (id, downloader.init_from_metalink(&file, &output_dir, args.connections).await?),
+            };
+            println!("{} ({} mirrors)", file.name.display(), session.mirrors.len());
+            download(&downloader, &store, session_id, session, &run, None).await?;
+        }
+        return Ok(());
+    }
+
+    if args.zip_list {
+        for entry in downloader.list_zip(&url).await? {
+            println!("{:>14}  {:>14}  {}", entry.size, entry.compressed_size, entry.name);
+        }
+        return Ok(());
+    }
+
+    if let Some(name) = &args.zip_entry {
+        let entries = downloader.list_zip(&url).await?;
+        let entry = entries
+            .iter()
+            .find(|e| e.name == *name)
+            .ok_or_else(|| anyhow::anyhow!("{} has no entry {}; list them with --zip-list", url, name))?;
+        let path = args.output.clone().unwrap_or_else(|| kitsune_core::utils::fs::get_downloads_dir().join(entry.file_name()));
+        let id = SessionStore::id_for_output(&path);
+        let (session_id, session) = match store.find_by_output(&id, &path).await? {
+            Some(found) => found,
+            None => (id, downloader.init_zip_entry(&url, entry, Some(path), args.connections).await?),
+        };
+        println!("{}: {} bytes, {} compressed", entry.name, entry.size, entry.compressed_size);
+        return download(&downloader, &store, session_id, session, &run, None).await;
+    }
+
+    let zsync = delta::is_zsync_source(&url);
+    if zsync || args.delta_from.is_some() {
+        let seed = args
+            .delta_from
+            .or_else(|| args.output.clone())
+            .ok_or_else(|| anyhow::anyhow!("a .zsync control file needs --delta-from or an existing -O file"))?;
+        let path = args.output.clone().unwrap_or_else(|| seed.clone());
+        let id = SessionStore::id_for_output(&path);
+        let (session_id, session) = match store.find_by_output(&id, &path).await? {
+            Some(found) => found,
+            None => {
+                let (target, blocks) = if zsync {
+                    (None, BlockList::Zsync(downloader.fetch_zsync(&url).await?))
+                } else {
+                    let manifest = args
+                        .piece_hashes
+                        .as_ref()
+                        .ok_or_else(|| anyhow::anyhow!("--delta-from needs a .zsync control file as the URL, or --piece-hashes"))?;
+                    (Some(url.as_str()), BlockList::Pieces(PieceHashes::load_manifest(manifest).await?))
+                };
+                let session = downloader.init_delta(target, blocks, &seed, Some(path), args.connections).await?;
+                (id, session)
+            }
+        };
+        if let (Some(job), Some(total)) = (&session.delta, session.total_size) {
+            println!("Reusing {} of {} bytes from {:?}", job.reused_bytes, total, job.seed);
+        }
+        return download(&downloader, &store, session_id, session, &run, None).await;
+    }
+
+    if args.range.is_some() && args.piece_hashes.is_some() {
+        anyhow::bail!("--piece-hashes describe the whole file and cannot be combined with --range");
+    }
+    let args_plan = Plan { connections: args.connections, variant: args.variant, range: args.range, compact: !args.keep_offset };
     let mut session;
-    let session_file;
+    let session_id;
 
     if let Some(path) = args.output {
-        session_file = PathBuf::from(format!("{}.kitsune", path.to_string_lossy()));
-        if session_file.exists() {
-            info!("Resuming download from session file: {:?}", session_file);
-            session = DownloadSession::load(&session_file).await?;
+        let id = SessionStore::id_for_output(&path);
+        if let Some((found_id, existing)) = store.find_by_output(&id, &path).await? {
+            info!("Resuming download from session store: {}", found_id);
+            session = existing;
+            session_id = found_id;
         } else {
-            session = downloader.init_download(&url, Some(path), args.connections).await?;
+            session = init_session(&downloader, &url, Some(path), &args_plan).await?;
+            session_id = id;
         }
     } else {
         // No explicit path, resolve via init_download
-        session = downloader.init_download(&url, None, args.connections).await?;
-        session_file = PathBuf::from(format!("{}.kitsune", session.output_path.to_string_lossy()));
-        
-        if session_file.exists() {
-             info!("Resuming download from session file (resolved): {:?}", session_file);
+        session = init_session(&downloader, &url, None, &args_plan).await?;
+        let id = SessionStore::id_for_output(&session.output_path);
+
+        if let Some((found_id, existing)) = store.find_by_output(&id, &session.output_path).await? {
+             info!("Resuming download from session store (resolved): {}", found_id);
              // TODO: Verify URL matches?
-             session = DownloadSession::load(&session_file).await?;
+             session = existing;
+             session_id = found_id;
+        } else {
+            session_id = id;
+        }
+    }
+
+    for (i, url) in args.mirrors.iter().enumerate() {
+        if *url != session.url && !session.mirrors.iter().any(|m| m.url == *url) {
+            session.mirrors.push(Mirror { url: url.clone(), priority: i as u32 + 1, location: None });
         }
     }
 
-    println!("File size: {} bytes", session.total_size.unwrap_or(0));
+    if let Some(manifest) = &args.piece_hashes
+        && session.piece_hashes.is_none()
+    {
+        session.piece_hashes = Some(PieceHashes::load_manifest(manifest).await?);
+    }
+
+    if let Some(digest) = args.checksum {
+        session.set_expected_digest(digest, DigestSource::User);
+    } else if let Some(digest) = &session.expected_digest {
+        println!("Will verify against {}", digest);
+    }
+
+    if args.sequential || args.preview.is_some() {
+        Downloader::plan_sequential(&mut session);
+    }
+    let preview = match args.preview {
+        Some(addr) => {
+            let server = PreviewServer::bind(addr, PreviewHandle::new(&session)?).await?;
+            println!("Preview at {}", server.url());
+            Some(server)
+        }
+        None => None,
+    };
+
+    download(&downloader, &store, session_id, session, &run, preview).await
+}
+
+/// How to plan a new session, from the command line.
+struct Plan {
+    connections: u8,
+    variant: VariantSelection,
+    range: Option<ByteRange>,
+    /// With `range`: start the output at the first requested byte.
+    compact: bool,
+}
+
+/// Probes `url` and plans a new session, following HLS playlists down to
+/// their segments, or only a byte range of it.
+async fn init_session(
+    downloader: &Downloader,
+    url: &str,
+    output: Option<PathBuf>,
+    plan: &Plan,
+) -> anyhow::Result<DownloadSession> {
+    let connections = plan.connections;
+    if let Some(range) = plan.range {
+        return downloader.init_range(url, range, plan.compact, output, connections).await;
+    }
+    if hls::is_hls_source(url) {
+        let session = downloader.init_hls(url, output, connections, plan.variant).await?;
+        if let Some(job) = &session.stream {
+            println!("HLS stream: {} segments", job.segments.len());
+        }
+        return Ok(session);
+    }
+    downloader.init_download(url, output, connections).await
+}
+
+/// How to run downloads, from the command line.
+struct RunOptions {
+    /// Follow a download busy in another process instead of failing.
+    attach: bool,
+    /// Rules from --schedule, replacing those saved with the session.
+    schedule: Option<Schedule>,
+    scheduler: Arc<Scheduler>,
+}
+
+/// Runs `session` to completion with a progress bar, holding its lock.
+async fn download(
+    downloader: &Downloader,
+    store: &SessionStore,
+    session_id: String,
+    mut session: DownloadSession,
+    run: &RunOptions,
+    preview: Option<PreviewServer>,
+) -> anyhow::Result<()> {
+    let session_file = store.session_path(&session_id);
+
+    let _lock = match store.lock(&session_id, &session.output_path) {
+        Ok(lock) => lock,
+        Err(e @ (LockError::Busy { .. } | LockError::BusyUnknown { .. })) if run.attach => {
+            println!("{}; attaching as observer", e);
+            match attach(store, &session_id, &session.output_path, session.target_size().unwrap_or(0)).await? {
+                Some((lock, saved)) => {
+                    println!("The other process stopped; taking over the download");
+                    session = saved;
+                    lock
+                }
+                None => return Ok(()),
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    println!("File size: {} bytes", session.remote_size().unwrap_or(0));
+    if let Some(range) = &session.range {
+        println!("Range: bytes {}-{} ({} bytes)", range.start, range.end, range.byte_count());
+    }
     println!("Saving to: {:?}", session.output_path);
+    if run.schedule.is_some() {
+        session.schedule = run.schedule.clone();
+    }
+    for rule in run.scheduler.global().rules.iter().chain(session.schedule.iter().flat_map(|s| &s.rules)) {
+        println!("Schedule: {}", rule);
+    }
 
     let multi_progress = indicatif::MultiProgress::new();
     let main_style = indicatif::ProgressStyle::with_template(&format!(
         "{{spinner:.green}} [{{elapsed_precise}}] [{{wide_bar:.cyan/blue}}] {{bytes}}/{{total_bytes}} ({{bytes_per_sec}}, {{eta}}) [Conn: {}]",
-        args.connections
+        session.connections
     )).unwrap()
     .progress_chars("#>-");
 
-    let main_pb = multi_progress.add(indicatif::ProgressBar::new(session.total_size.unwrap_or(0)));
+    let main_pb = multi_progress.add(indicatif::ProgressBar::new(session.target_size().unwrap_or(0)));
     main_pb.set_style(main_style);
-    main_pb.set_position(session.parts.iter().map(|p| p.current_byte - p.start_byte).sum());
+    main_pb.set_position(session.completed_bytes());
+    let size_known = session.total_size.is_some();
 
     let (tx, mut rx) = tokio::sync::mpsc::channel(100);
     let observer = Arc::new(ChannelObserver::new(tx));
 
     // Spawn downloader in a separate task
     let session_file_clone = session_file.clone();
+    let downloader = downloader.clone();
+    let preview_handle = preview.as_ref().map(|server| server.handle().clone());
+    let scheduler = run.scheduler.clone();
     let download_handle = tokio::spawn(async move {
-        downloader.run(&mut session, Some(observer), Some(session_file_clone), None).await
+        match preview_handle {
+            Some(handle) => downloader.run_with_preview(&mut session, Some(observer), Some(session_file_clone), None, &handle).await?,
+            None => downloader.run_scheduled(&mut session, Some(observer), Some(session_file_clone), None, scheduler).await?,
+        }
+        anyhow::Ok(session)
     });
 
     while let Some((_worker_id, bytes, active_workers)) = rx.recv().await {
+        // A magnet link's size is only known once its metadata arrived and
+        // the session was checkpointed
+        if !size_known
+            && main_pb.length() == Some(0)
+            && let Ok(Some(saved)) = store.load(&session_id).await
+            && let Some(total) = saved.total_size
+        {
+            main_pb.set_length(total);
+        }
         main_pb.inc(bytes);
         // Only update style if active workers changed? Or just update regularly. 
         // Setting style is cheap?
@@ -110,13 +608,196 @@ async fn main() -> anyhow::Result<()> {
         .progress_chars("#>-"));
     }
 
-    download_handle.await??;
+    let session = download_handle.await??;
     main_pb.finish_with_message("Download completed");
+
+    if let Some(verification) = &session.verification {
+        println!("Checksum verified: {} (from {})", verification.digest, verification.source);
+    }
+    if let Some(job) = &session.delta {
+        println!("Delta update saved {} bytes of download", job.reused_bytes);
+    }
     
     // Optional: remove session file on completion
-    if session_file.exists() {
-        let _ = tokio::fs::remove_file(session_file).await;
+    DownloadSession::remove(&session_file).await;
+
+    // A player may still be reading
+    if let Some(server) = preview {
+        println!("Still serving {}; press Ctrl-C to stop", server.url());
+        tokio::signal::ctrl_c().await?;
     }
 
     Ok(())
 }
+
+/// Progress of `-O -` on stderr; stdout carries the data.
+struct PipeProgress(indicatif::ProgressBar);
+
+impl DownloadObserver for PipeProgress {
+    fn on_progress(&self, _worker_id: u32, bytes_downloaded: u64, _active_workers: usize) {
+        self.0.inc(bytes_downloaded);
+    }
+}
+
+async fn pipe_to_stdout(
+    downloader: &Downloader,
+    url: &str,
+    connections: u8,
+    buffer_limit: u64,
+    checksum: Option<ExpectedDigest>,
+) -> anyhow::Result<()> {
+    let pb = indicatif::ProgressBar::with_draw_target(None, indicatif::ProgressDrawTarget::stderr());
+    pb.set_style(indicatif::ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] {bytes} ({bytes_per_sec}) [stdout]").unwrap());
+    let observer = Arc::new(PipeProgress(pb.clone()));
+
+    let mut stdout = tokio::io::stdout();
+    downloader.download_to_writer(url, &mut stdout, connections, buffer_limit, checksum, Some(observer)).await?;
+    pb.finish_and_clear();
+    Ok(())
+}
+
+/// Runs every unfinished download in the session store that has its own
+/// schedule, checking for new sessions and global schedule changes once a
+/// minute. Downloads without one may have been stopped by the user, so
+/// they are left alone, as are sessions locked by another process; ones
+/// that fail are not retried until the daemon restarts.
+async fn run_daemon(downloader: &Downloader, scheduler: Arc<Scheduler>, schedule_path: &Path) -> anyhow::Result<()> {
+    let store = Arc::new(SessionStore::open_default()?);
+    let mut running = tokio::task::JoinSet::new();
+    let mut busy = std::collections::HashSet::new();
+    let mut failed = std::collections::HashSet::new();
+    println!("Running scheduled downloads; press Ctrl-C to stop");
+
+    loop {
+        match Schedule::load(schedule_path).await {
+            Ok(global) => scheduler.set_global(global),
+            Err(e) => log::warn!("Could not reload the global schedule: {}", e),
+        }
+        while let Some(finished) = running.try_join_next() {
+            let (id, result): (String, anyhow::Result<()>) = finished?;
+            busy.remove(&id);
+            if let Err(e) = result {
+                println!("{}: failed: {}", id, e);
+                failed.insert(id);
+            }
+        }
+
+        for (id, mut session) in store.list_unfinished().await? {
+            if session.schedule.is_none() || busy.contains(&id) || failed.contains(&id) {
+                continue;
+            }
+            let lock = match store.lock(&id, &session.output_path) {
+                Ok(lock) => lock,
+                Err(LockError::Busy { .. } | LockError::BusyUnknown { .. }) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            println!("{}: {}", id, session.output_path.display());
+            busy.insert(id.clone());
+            let (downloader, store, scheduler) = (downloader.clone(), store.clone(), scheduler.clone());
+            running.spawn(async move {
+                let _lock = lock;
+                let session_file = store.session_path(&id);
+                let result = downloader.run_scheduled(&mut session, None, Some(session_file.clone()), None, scheduler).await;
+                if result.is_ok() {
+                    println!("{}: completed", id);
+                    DownloadSession::remove(&session_file).await;
+                }
+                (id, result)
+            });
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(60)) => {}
+            _ = tokio::signal::ctrl_c() => {
+                // Sessions were checkpointed as they ran
+                running.shutdown().await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn manage_sessions(list: bool, gc: bool) -> anyhow::Result<()> {
+    let store = SessionStore::open_default()?;
+
+    if gc {
+        for id in store.gc().await? {
+            println!("Removed stale session {}", id);
+        }
+    }
+
+    if list {
+        for (id, session) in store.list_unfinished().await? {
+            let done = session.completed_bytes();
+            println!(
+                "{}  {:?}  {}/{} bytes  {}",
+                id,
+                session.state,
+                done,
+                session.target_size().unwrap_or(0),
+                session.output_path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Mirrors the progress of a download owned by another process by polling
+/// its session checkpoints until it completes or disappears.
+///
+/// If the other process goes away before the download finished, its lock
+/// is taken and returned with the last checkpoint so the caller can carry
+/// on.
+async fn attach(
+    store: &SessionStore,
+    session_id: &str,
+    output_path: &Path,
+    total_size: u64,
+) -> anyhow::Result<Option<(DownloadLock, DownloadSession)>> {
+    let pb = indicatif::ProgressBar::new(total_size);
+    pb.set_style(indicatif::ProgressStyle::with_template(
+        "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta}) [Observer]"
+    ).unwrap()
+    .progress_chars("#>-"));
+
+    loop {
+        let Some(session) = store.load(session_id).await? else {
+            pb.finish_with_message("Download finished in the other process");
+            return Ok(None);
+        };
+        if let Some(total) = session.target_size() {
+            pb.set_length(total);
+        }
+        pb.set_position(session.completed_bytes());
+
+        match session.state {
+            kitsune_core::session::DownloadState::Completed => {
+                pb.finish_with_message("Download completed");
+                return Ok(None);
+            }
+            kitsune_core::session::DownloadState::Error(e) => {
+                pb.abandon();
+                anyhow::bail!("download failed in the other process: {}", e);
+            }
+            _ => {}
+        }
+
+        // The owner exited without finishing, e.g. after Ctrl-C
+        match store.lock(session_id, output_path) {
+            Ok(lock) => {
+                // It may have finished and cleaned up just before exiting
+                let Some(session) = store.load(session_id).await? else {
+                    pb.finish_with_message("Download finished in the other process");
+                    return Ok(None);
+                };
+                pb.abandon();
+                return Ok(Some((lock, session)));
+            }
+            Err(LockError::Busy { .. } | LockError::BusyUnknown { .. }) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
@@ -0,0 +1,43 @@
+//! Downloads a URL once per HTTP strategy and compares throughput.
+//!
+//!     cargo run --release --example http_strategies -- <url> [connections] [runs]
+//!
+//! Per-connection throttling only shows against real CDNs, so this talks to
+//! whatever server the URL names. Build with `--features http3` and
+//! `RUSTFLAGS="--cfg reqwest_unstable"` to include HTTP/3.
+
+use kitsune_core::{Downloader, HttpConfig, HttpStrategy};
+use std::time::Instant;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let url = args.next().ok_or_else(|| anyhow::anyhow!("usage: http_strategies <url> [connections] [runs]"))?;
+    let connections: u8 = args.next().map(|s| s.parse()).transpose()?.unwrap_or(8);
+    let runs: u32 = args.next().map(|s| s.parse()).transpose()?.unwrap_or(3);
+
+    let strategies = [HttpStrategy::PerConnection, HttpStrategy::Multiplexed, HttpStrategy::Http3];
+    println!("{:<4} {:>4} {:>14} {:>10} {:>12}", "http", "run", "bytes", "seconds", "MiB/s");
+    for strategy in strategies.into_iter().filter(|s| s.is_available()) {
+        let config = HttpConfig { default: strategy, ..HttpConfig::default() };
+        let downloader = Downloader::new("Kitsune-DM/1.0")?.with_http_config(config)?;
+        for run in 1..=runs {
+            let output = std::env::temp_dir().join(format!("kitsune-bench-{}-{}", std::process::id(), strategy));
+            let started = Instant::now();
+            let mut session = downloader.init_download(&url, Some(output.clone()), connections).await?;
+            downloader.run(&mut session, None, None, None).await?;
+            let seconds = started.elapsed().as_secs_f64();
+            let bytes = tokio::fs::metadata(&output).await?.len();
+            let _ = tokio::fs::remove_file(&output).await;
+            println!(
+                "{:<4} {:>4} {:>14} {:>10.2} {:>12.2}",
+                strategy,
+                run,
+                bytes,
+                seconds,
+                bytes as f64 / seconds / (1024.0 * 1024.0)
+            );
+        }
+    }
+    Ok(())
+}
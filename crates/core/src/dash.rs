@@ -0,0 +1,547 @@
+//! MPEG-DASH (`.mpd`) manifests: parsing and track selection.
+//!
+//! Each selected representation becomes its own session and output file;
+//! audio and video are not muxed. Only the first period of a manifest is
+//! used, and live (`type="dynamic"`) manifests are downloaded as they
+//! stand.
+
+use super::downloader::Downloader;
+use super::hls::VariantSelection;
+use super::segments::{Segment, SegmentJob};
+use super::session::{DownloadSession, DownloadState};
+use anyhow::{Context, Result};
+use reqwest::Url;
+use roxmltree::Node;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// True if `url` points at a DASH manifest.
+pub fn is_dash_source(url: &str) -> bool {
+    url.split(['?', '#']).next().unwrap_or(url).to_ascii_lowercase().ends_with(".mpd")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackKind {
+    Video,
+    Audio,
+    Text,
+    Other,
+}
+
+impl fmt::Display for TrackKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TrackKind::Video => "video",
+            TrackKind::Audio => "audio",
+            TrackKind::Text => "text",
+            TrackKind::Other => "other",
+        })
+    }
+}
+
+/// One `Representation` with its segments resolved to absolute URLs.
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub id: String,
+    pub kind: TrackKind,
+    pub bandwidth: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub codecs: Option<String>,
+    pub mime_type: Option<String>,
+    pub language: Option<String>,
+    pub segments: Vec<Segment>,
+}
+
+impl Track {
+    /// File extension for the joined track.
+    pub fn extension(&self) -> &'static str {
+        match (self.kind, self.mime_type.as_deref()) {
+            (_, Some(m)) if m.ends_with("/webm") => "webm",
+            (_, Some("text/vtt")) => "vtt",
+            (_, Some("application/ttml+xml")) => "ttml",
+            (TrackKind::Audio, _) => "m4a",
+            _ => "mp4",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DashManifest {
+    pub tracks: Vec<Track>,
+}
+
+/// Which tracks of a manifest to download. `None` skips that kind.
+#[derive(Debug, Clone)]
+pub struct TrackSelection {
+    pub video: Option<VariantSelection>,
+    pub audio: Option<VariantSelection>,
+    /// Preferred audio language (e.g. `en`); falls back to any language.
+    pub audio_language: Option<String>,
+}
+
+impl Default for TrackSelection {
+    fn default() -> Self {
+        Self { video: Some(VariantSelection::Best), audio: Some(VariantSelection::Best), audio_language: None }
+    }
+}
+
+impl DashManifest {
+    /// Picks at most one video and one audio track.
+    pub fn select(&self, selection: &TrackSelection) -> Vec<&Track> {
+        let of_kind = |kind| self.tracks.iter().filter(|t| t.kind == kind).collect::<Vec<_>>();
+        let mut chosen = Vec::new();
+
+        if let Some(video) = selection.video {
+            let tracks = of_kind(TrackKind::Video);
+            chosen.extend(video.pick(&tracks, |t| t.bandwidth, |t| t.height).copied());
+        }
+        if let Some(audio) = selection.audio {
+            let mut tracks = of_kind(TrackKind::Audio);
+            if let Some(lang) = &selection.audio_language {
+                let lang = lang.to_ascii_lowercase();
+                let speaks = |t: &&Track| t.language.as_deref().is_some_and(|l| l.to_ascii_lowercase().starts_with(&lang));
+                if tracks.iter().any(speaks) {
+                    tracks.retain(speaks);
+                }
+            }
+            chosen.extend(audio.pick(&tracks, |t| t.bandwidth, |_| None).copied());
+        }
+        chosen
+    }
+}
+
+/// Parses an MPD fetched from `base_url`.
+pub fn parse(xml: &str, base_url: &str) -> Result<DashManifest> {
+    let doc = roxmltree::Document::parse(xml).context("invalid MPD XML")?;
+    let mpd = doc.root_element();
+    if mpd.tag_name().name() != "MPD" {
+        anyhow::bail!("not a DASH manifest: root element is <{}>", mpd.tag_name().name());
+    }
+    if mpd.attribute("type") == Some("dynamic") {
+        log::warn!("Live DASH manifest; downloading the segments it lists now");
+    }
+
+    let mut periods = children(mpd, "Period");
+    let period = periods.next().context("MPD has no Period")?;
+    if periods.next().is_some() {
+        log::warn!("MPD has several periods; only the first is downloaded");
+    }
+    let period_seconds = period
+        .attribute("duration")
+        .or_else(|| mpd.attribute("mediaPresentationDuration"))
+        .and_then(parse_duration);
+
+    let base = Url::parse(base_url).with_context(|| format!("invalid manifest URL {}", base_url))?;
+    let base = join_base_url(&join_base_url(&base, mpd)?, period)?;
+
+    let mut tracks = Vec::new();
+    for set in children(period, "AdaptationSet") {
+        let set_base = join_base_url(&base, set)?;
+        for rep in children(set, "Representation") {
+            let id = rep.attribute("id").unwrap_or_default().to_string();
+            let mime_type = inherited(rep, set, "mimeType");
+            let codecs = inherited(rep, set, "codecs");
+            let kind = track_kind(set.attribute("contentType"), mime_type.as_deref(), codecs.as_deref());
+            let bandwidth = rep.attribute("bandwidth").and_then(|b| b.parse().ok()).unwrap_or(0);
+            let rep_base = join_base_url(&set_base, rep)?;
+
+            let segments = representation_segments(&[period, set, rep], &rep_base, &id, bandwidth, period_seconds)
+                .with_context(|| format!("representation {:?}", id))?;
+
+            tracks.push(Track {
+                id,
+                kind,
+                bandwidth,
+                width: inherited(rep, set, "width").and_then(|w| w.parse().ok()),
+                height: inherited(rep, set, "height").and_then(|h| h.parse().ok()),
+                codecs,
+                mime_type,
+                language: set.attribute("lang").map(str::to_string),
+                segments,
+            });
+        }
+    }
+
+    if tracks.is_empty() {
+        anyhow::bail!("MPD has no representations");
+    }
+    Ok(DashManifest { tracks })
+}
+
+fn children<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'a str) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children().filter(move |n| n.has_tag_name(name))
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn inherited(rep: Node, set: Node, attr: &str) -> Option<String> {
+    rep.attribute(attr).or_else(|| set.attribute(attr)).map(str::to_string)
+}
+
+fn join_base_url(base: &Url, node: Node) -> Result<Url> {
+    match child(node, "BaseURL").and_then(|n| n.text()) {
+        Some(url) => base.join(url.trim()).with_context(|| format!("invalid BaseURL {:?}", url)),
+        None => Ok(base.clone()),
+    }
+}
+
+fn track_kind(content_type: Option<&str>, mime_type: Option<&str>, codecs: Option<&str>) -> TrackKind {
+    let hint = content_type.or(mime_type).unwrap_or_default();
+    if hint.starts_with("video") {
+        TrackKind::Video
+    } else if hint.starts_with("audio") {
+        TrackKind::Audio
+    } else if hint.starts_with("text") || codecs.is_some_and(|c| c.starts_with("wvtt") || c.starts_with("stpp")) {
+        TrackKind::Text
+    } else {
+        TrackKind::Other
+    }
+}
+
+/// Segment addressing collected from `SegmentTemplate` elements, inner
+/// elements overriding outer ones.
+#[derive(Default)]
+struct Template<'a, 'input> {
+    attrs: HashMap<&'a str, &'a str>,
+    timeline: Option<Node<'a, 'input>>,
+}
+
+fn representation_segments(
+    levels: &[Node],
+    base: &Url,
+    rep_id: &str,
+    bandwidth: u64,
+    period_seconds: Option<f64>,
+) -> Result<Vec<Segment>> {
+    let resolve = |uri: &str| -> Result<String> {
+        Ok(base.join(uri.trim()).with_context(|| format!("invalid segment URL {:?}", uri))?.to_string())
+    };
+    let segment = |uri: String, byte_range: Option<(u64, u64)>, init: bool| Segment { uri, byte_range, key: None, init, done: false };
+
+    // SegmentList (innermost wins)
+    if let Some(list) = levels.iter().rev().find_map(|n| child(*n, "SegmentList")) {
+        let mut segments = Vec::new();
+        if let Some(init) = child(list, "Initialization") {
+            let uri = init.attribute("sourceURL").map_or(Ok(base.to_string()), resolve)?;
+            segments.push(segment(uri, init.attribute("range").map(parse_range).transpose()?, true));
+        }
+        for url in children(list, "SegmentURL") {
+            let uri = url.attribute("media").map_or(Ok(base.to_string()), resolve)?;
+            segments.push(segment(uri, url.attribute("mediaRange").map(parse_range).transpose()?, false));
+        }
+        return Ok(segments);
+    }
+
+    let mut template = Template::default();
+    for node in levels {
+        if let Some(t) = child(*node, "SegmentTemplate") {
+            for attr in t.attributes() {
+                template.attrs.insert(attr.name(), attr.value());
+            }
+            if let Some(timeline) = child(t, "SegmentTimeline") {
+                template.timeline = Some(timeline);
+            }
+        }
+    }
+
+    if template.attrs.is_empty() {
+        // SegmentBase or a bare BaseURL: the whole file is one resource
+        return Ok(vec![segment(base.to_string(), None, false)]);
+    }
+
+    let number_of = |name: &str, default: u64| template.attrs.get(name).and_then(|v| v.parse().ok()).unwrap_or(default);
+    let start_number = number_of("startNumber", 1);
+    let timescale = number_of("timescale", 1).max(1);
+    let media = template.attrs.get("media").context("SegmentTemplate without media")?;
+
+    let mut segments = Vec::new();
+    if let Some(init) = template.attrs.get("initialization") {
+        segments.push(segment(resolve(&expand_template(init, rep_id, bandwidth, 0, 0))?, None, true));
+    }
+
+    // (number, time) of every media segment
+    let mut numbered = Vec::new();
+    if let Some(timeline) = template.timeline {
+        let end_time = period_seconds.map(|s| (s * timescale as f64) as u64);
+        let mut time = 0u64;
+        let mut number = start_number;
+        for s in children(timeline, "S") {
+            let d: u64 = s.attribute("d").and_then(|d| d.parse().ok()).context("S without d")?;
+            if let Some(t) = s.attribute("t").and_then(|t| t.parse().ok()) {
+                time = t;
+            }
+            let repeat: i64 = s.attribute("r").and_then(|r| r.parse().ok()).unwrap_or(0);
+            let count = if repeat < 0 {
+                // Repeat until the end of the period
+                end_time.map_or(1, |end| end.saturating_sub(time).div_ceil(d.max(1)))
+            } else {
+                repeat as u64 + 1
+            };
+            for _ in 0..count {
+                numbered.push((number, time));
+                number += 1;
+                time += d;
+            }
+        }
+    } else {
+        let duration = number_of("duration", 0);
+        let seconds = period_seconds.context("SegmentTemplate@duration needs a period or presentation duration")?;
+        if duration == 0 {
+            anyhow::bail!("SegmentTemplate has neither duration nor SegmentTimeline");
+        }
+        let count = (seconds * timescale as f64 / duration as f64).ceil() as u64;
+        numbered.extend((0..count).map(|i| (start_number + i, i * duration)));
+    }
+
+    for (number, time) in numbered {
+        segments.push(segment(resolve(&expand_template(media, rep_id, bandwidth, number, time))?, None, false));
+    }
+    Ok(segments)
+}
+
+/// Substitutes `$RepresentationID$`, `$Bandwidth$`, `$Number$` and `$Time$`
+/// (each optionally with a `%0Nd` width) and `$$`.
+fn expand_template(template: &str, rep_id: &str, bandwidth: u64, number: u64, time: u64) -> String {
+    let mut out = String::new();
+    let mut parts = template.split('$');
+    out.push_str(parts.next().unwrap_or_default());
+    let mut in_identifier = true;
+    for part in parts {
+        if !in_identifier {
+            out.push_str(part);
+            in_identifier = true;
+            continue;
+        }
+        in_identifier = false;
+        let (name, width) = match part.split_once('%') {
+            Some((name, format)) => (name, format.trim_start_matches('0').trim_end_matches('d').parse().unwrap_or(0)),
+            None => (part, 0),
+        };
+        let value = match name {
+            "" => "$".to_string(),
+            "RepresentationID" => rep_id.to_string(),
+            "Bandwidth" => format!("{:0width$}", bandwidth, width = width),
+            "Number" => format!("{:0width$}", number, width = width),
+            "Time" => format!("{:0width$}", time, width = width),
+            other => format!("${}$", other),
+        };
+        out.push_str(&value);
+    }
+    out
+}
+
+/// `first-last` byte range to `(offset, length)`.
+fn parse_range(range: &str) -> Result<(u64, u64)> {
+    let (first, last) = range.split_once('-').context("invalid byte range")?;
+    let first: u64 = first.trim().parse().context("invalid byte range")?;
+    let last: u64 = last.trim().parse().context("invalid byte range")?;
+    if last < first {
+        anyhow::bail!("inverted byte range {}", range);
+    }
+    let end = last.checked_add(1).with_context(|| format!("byte range {} past the end of any file", range))?;
+    Ok((first, end - first))
+}
+
+/// ISO 8601 durations as used by MPDs, e.g. `PT1H2M3.5S`, in seconds.
+fn parse_duration(value: &str) -> Option<f64> {
+    let rest = value.trim().strip_prefix('P')?;
+    let (date, time) = rest.split_once('T').unwrap_or((rest, ""));
+    let mut seconds = 0.0;
+    let mut take = |s: &str, units: &[(char, f64)]| -> Option<()> {
+        let mut number = String::new();
+        for c in s.chars() {
+            if c.is_ascii_digit() || c == '.' {
+                number.push(c);
+            } else {
+                let scale = units.iter().find(|(u, _)| *u == c)?.1;
+                seconds += number.parse::<f64>().ok()? * scale;
+                number.clear();
+            }
+        }
+        Some(())
+    };
+    take(date, &[('D', 86_400.0)])?;
+    take(time, &[('H', 3_600.0), ('M', 60.0), ('S', 1.0)])?;
+    Some(seconds)
+}
+
+impl Downloader {
+    /// Fetches a manifest and creates one session per selected track, each
+    /// writing `<name>.<kind>.<ext>` in `output_dir`.
+    pub async fn init_dash(
+        &self,
+        url: &str,
+        output_dir: &Path,
+        connections: u8,
+        selection: &TrackSelection,
+    ) -> Result<Vec<DownloadSession>> {
+        let response = self.client().get(url).send().await?.error_for_status()?;
+        let manifest_url = response.url().to_string();
+        let manifest = parse(&response.text().await?, &manifest_url)?;
+
+        let tracks = manifest.select(selection);
+        if tracks.is_empty() {
+            anyhow::bail!("no track in {} matches the selection", url);
+        }
+
+        let stem = url
+            .split(['?', '#'])
+            .next()
+            .and_then(|u| u.rsplit('/').next())
+            .and_then(|name| name.rsplit_once('.').map(|(stem, _)| stem.to_string()))
+            .filter(|stem| !stem.is_empty())
+            .unwrap_or_else(|| "stream".to_string());
+
+        let mut sessions = Vec::new();
+        for track in tracks {
+            log::info!(
+                "Selected DASH {} track {} ({} bps, {} segments)",
+                track.kind, track.id, track.bandwidth, track.segments.len()
+            );
+            let output = output_dir.join(format!("{}.{}.{}", stem, track.kind, track.extension()));
+            let mut session = DownloadSession::new(url.to_string(), output, connections.max(1));
+            session.stream = Some(SegmentJob { manifest_url: manifest_url.clone(), segments: track.segments.clone() });
+            session.state = DownloadState::Downloading;
+            sessions.push(session);
+        }
+        Ok(sessions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MPD: &str = r#"<?xml version="1.0"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT9.5S">
+  <BaseURL>media/</BaseURL>
+  <Period>
+    <AdaptationSet contentType="video" mimeType="video/mp4">
+      <SegmentTemplate timescale="1000" duration="4000" startNumber="1"
+          initialization="$RepresentationID$/init.mp4" media="$RepresentationID$/seg-$Number%03d$.m4s"/>
+      <Representation id="v480" bandwidth="900000" width="854" height="480"/>
+      <Representation id="v1080" bandwidth="4500000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" lang="de">
+      <Representation id="a-de" bandwidth="128000">
+        <SegmentTemplate timescale="48000" initialization="a/init.mp4" media="a/$Time$.m4s">
+          <SegmentTimeline><S t="0" d="96000" r="2"/><S d="48000"/></SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" lang="en">
+      <Representation id="a-en" bandwidth="96000">
+        <BaseURL>https://cdn.example/en.m4a</BaseURL>
+        <SegmentList>
+          <Initialization range="0-799"/>
+          <SegmentURL mediaRange="800-1799"/>
+          <SegmentURL mediaRange="1800-2499"/>
+        </SegmentList>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet mimeType="text/vtt">
+      <Representation id="subs" bandwidth="100"><BaseURL>subs.vtt</BaseURL><SegmentBase/></Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    #[test]
+    fn parses_templates_lists_and_base() {
+        let manifest = parse(MPD, "https://example.com/show/manifest.mpd").unwrap();
+        let track = |id: &str| manifest.tracks.iter().find(|t| t.id == id).unwrap();
+
+        let video = track("v1080");
+        assert_eq!(video.kind, TrackKind::Video);
+        let uris: Vec<_> = video.segments.iter().map(|s| s.uri.as_str()).collect();
+        assert_eq!(uris, vec![
+            "https://example.com/show/media/v1080/init.mp4",
+            "https://example.com/show/media/v1080/seg-001.m4s",
+            "https://example.com/show/media/v1080/seg-002.m4s",
+            "https://example.com/show/media/v1080/seg-003.m4s",
+        ]);
+
+        let times: Vec<_> = track("a-de").segments.iter().map(|s| s.uri.rsplit('/').next().unwrap().to_string()).collect();
+        assert_eq!(times, vec!["init.mp4", "0.m4s", "96000.m4s", "192000.m4s", "288000.m4s"]);
+
+        let en = track("a-en");
+        assert!(en.segments[0].init);
+        assert_eq!(en.segments[2].byte_range, Some((1800, 700)));
+        assert_eq!(en.segments[2].uri, "https://cdn.example/en.m4a");
+
+        let subs = track("subs");
+        assert_eq!((subs.kind, subs.extension()), (TrackKind::Text, "vtt"));
+        assert_eq!(subs.segments.len(), 1);
+    }
+
+    #[test]
+    fn selects_video_and_preferred_audio() {
+        let manifest = parse(MPD, "https://example.com/show/manifest.mpd").unwrap();
+        let ids = |selection: TrackSelection| -> Vec<String> {
+            manifest.select(&selection).iter().map(|t| t.id.clone()).collect()
+        };
+
+        assert_eq!(ids(TrackSelection::default()), vec!["v1080", "a-de"]);
+        assert_eq!(
+            ids(TrackSelection {
+                video: Some(VariantSelection::MaxHeight(720)),
+                audio: Some(VariantSelection::Best),
+                audio_language: Some("en".into()),
+            }),
+            vec!["v480", "a-en"]
+        );
+        assert_eq!(ids(TrackSelection { video: None, ..Default::default() }), vec!["a-de"]);
+        assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(parse_range("100-199").unwrap(), (100, 100));
+        assert_eq!(parse_range("0-18446744073709551614").unwrap(), (0, u64::MAX));
+        for bad in ["200-100", "0-18446744073709551615", "1-18446744073709551615", "-5"] {
+            assert!(parse_range(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn downloads_each_track_to_its_own_file() {
+        use crate::test_support;
+
+        let mpd = r#"<MPD type="static" mediaPresentationDuration="PT6S"><Period>
+  <AdaptationSet contentType="video" mimeType="video/mp4">
+    <SegmentTemplate duration="2" initialization="v-init.mp4" media="v-$Number$.m4s"/>
+    <Representation id="v" bandwidth="1000"/>
+  </AdaptationSet>
+  <AdaptationSet contentType="audio" mimeType="audio/mp4">
+    <Representation id="a" bandwidth="100"><BaseURL>audio.m4a</BaseURL><SegmentBase/></Representation>
+  </AdaptationSet>
+</Period></MPD>"#;
+        let chunk = |seed: usize| test_support::payload(5_000 + seed);
+        let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+        files.insert("/movie.mpd".into(), mpd.as_bytes().to_vec());
+        files.insert("/v-init.mp4".into(), chunk(0));
+        for n in 1..=3 {
+            files.insert(format!("/v-{}.m4s", n), chunk(n));
+        }
+        files.insert("/audio.m4a".into(), chunk(9));
+        let server = test_support::serve(files, HashMap::new(), None).await;
+
+        let dir = test_support::temp_path("dash").parent().unwrap().to_path_buf();
+        let downloader = Downloader::new("test").unwrap();
+        let sessions = downloader
+            .init_dash(&server.url("/movie.mpd"), &dir, 2, &TrackSelection::default())
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 2);
+
+        for mut session in sessions {
+            downloader.run(&mut session, None, None, None).await.unwrap();
+        }
+        let video: Vec<u8> = (0..=3).flat_map(chunk).collect();
+        assert_eq!(std::fs::read(dir.join("movie.video.mp4")).unwrap(), video);
+        assert_eq!(std::fs::read(dir.join("movie.audio.m4a")).unwrap(), chunk(9));
+    }
+}
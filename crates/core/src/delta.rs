@@ -0,0 +1,755 @@
+//! Delta updates: rebuild a new version of a file from an old local copy,
+//! downloading only the blocks that changed.
+//!
+//! The blocks of the new file are described either by a zsync control file
+//! (rolling checksum plus truncated MD4 per block, so blocks are found at
+//! any offset of the old file) or by a [`PieceHashes`] list, whose strong
+//! hashes are only compared at block-aligned offsets. Matching blocks are
+//! copied into the output and the gaps become ordinary parts for the
+//! ranged workers.
+
+use super::downloader::Downloader;
+use super::integrity::{DigestSource, ExpectedDigest, HashAlgorithm, Hasher};
+use super::pieces::PieceHashes;
+use super::session::{DownloadPart, DownloadSession, DownloadState, Mirror};
+use anyhow::{Context, Result};
+use md4::{Digest, Md4};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Changed ranges are merged across the smallest unchanged gaps until at
+/// most this many remain, leaving part ids free for work stealing.
+const MAX_DELTA_PARTS: usize = 64;
+
+/// How much of the old file is read at a time while matching.
+const READ_CHUNK: usize = 1024 * 1024;
+
+/// Control files list a few bytes per block; anything this large is not one.
+const MAX_CONTROL_SIZE: usize = 64 * 1024 * 1024;
+
+/// True if `source` names a zsync control file.
+pub fn is_zsync_source(source: &str) -> bool {
+    source.split(['?', '#']).next().unwrap_or(source).to_ascii_lowercase().ends_with(".zsync")
+}
+
+/// Where the local copy came from and how much of the new file it supplied.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DeltaJob {
+    /// The old file blocks were copied from.
+    pub seed: PathBuf,
+    /// Bytes of the new file copied from `seed` instead of downloaded.
+    pub reused_bytes: u64,
+    /// `seed` is a renamed copy of the file being updated in place and is
+    /// deleted once the download is verified.
+    #[serde(default)]
+    pub remove_seed: bool,
+}
+
+/// Block checksums of the new version of a file.
+#[derive(Debug, Clone)]
+pub enum BlockList {
+    Zsync(ControlFile),
+    Pieces(PieceHashes),
+}
+
+/// A parsed `.zsync` control file. Only uncompressed targets (`URL:`) are
+/// supported; `Z-URL:` gzip reconstruction is not.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlFile {
+    /// Path or URL the control file was read from.
+    pub source: String,
+    pub filename: Option<String>,
+    pub block_size: usize,
+    pub length: u64,
+    /// Target URLs, resolved against `source` when it is a URL.
+    pub urls: Vec<String>,
+    /// Lowercase hex SHA-1 of the whole target.
+    pub sha1: Option<String>,
+    /// Consecutive blocks that must match before a match is accepted.
+    seq_matches: usize,
+    /// Trailing bytes of the rolling checksum stored per block.
+    rsum_bytes: usize,
+    /// Leading bytes of the MD4 stored per block.
+    checksum_bytes: usize,
+    blocks: Vec<BlockSums>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct BlockSums {
+    rsum: u32,
+    checksum: Vec<u8>,
+}
+
+impl ControlFile {
+    pub fn parse(data: &[u8], source: &str) -> Result<Self> {
+        let header_end = data
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .context("zsync control file has no header terminator")?;
+        let header = std::str::from_utf8(&data[..header_end]).context("zsync header is not UTF-8")?;
+        let sums = &data[header_end + 2..];
+
+        let mut fields: HashMap<String, Vec<String>> = HashMap::new();
+        for line in header.lines() {
+            let (key, value) = line.split_once(':').with_context(|| format!("malformed zsync header line {:?}", line))?;
+            fields.entry(key.trim().to_ascii_lowercase()).or_default().push(value.trim().to_string());
+        }
+        let field = |name: &str| fields.get(name).and_then(|v| v.first()).map(String::as_str);
+        let number = |name: &str| -> Result<u64> {
+            field(name)
+                .with_context(|| format!("zsync control file has no {} line", name))?
+                .parse()
+                .with_context(|| format!("invalid zsync {} line", name))
+        };
+
+        if field("zsync").is_none() {
+            anyhow::bail!("not a zsync control file");
+        }
+        let block_size = number("blocksize")? as usize;
+        if block_size == 0 {
+            anyhow::bail!("zsync block size must be non-zero");
+        }
+        let length = number("length")?;
+
+        let (seq_matches, rsum_bytes, checksum_bytes) = match field("hash-lengths") {
+            Some(value) => {
+                let lengths = value
+                    .split(',')
+                    .map(|n| n.trim().parse::<usize>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .context("invalid zsync Hash-Lengths line")?;
+                let [seq, rsum, checksum] = lengths[..] else {
+                    anyhow::bail!("zsync Hash-Lengths needs three values");
+                };
+                (seq, rsum, checksum)
+            }
+            None => (1, 4, 16),
+        };
+        if !(1..=2).contains(&seq_matches) || !(1..=4).contains(&rsum_bytes) || !(3..=16).contains(&checksum_bytes) {
+            anyhow::bail!("unsupported zsync Hash-Lengths {},{},{}", seq_matches, rsum_bytes, checksum_bytes);
+        }
+
+        let base = Url::parse(source).ok();
+        let urls = fields
+            .get("url")
+            .into_iter()
+            .flatten()
+            .map(|url| match &base {
+                Some(base) => base.join(url).map(String::from).with_context(|| format!("invalid zsync URL {}", url)),
+                None => Ok(url.clone()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if urls.is_empty() && fields.contains_key("z-url") {
+            anyhow::bail!("zsync control file only offers a compressed (Z-URL) target, which is not supported");
+        }
+
+        let sha1 = field("sha-1")
+            .map(|hex| ExpectedDigest::new(HashAlgorithm::Sha1, hex).map(|d| d.value))
+            .transpose()?;
+
+        let count = length.div_ceil(block_size as u64) as usize;
+        let entry = rsum_bytes + checksum_bytes;
+        if sums.len() != count * entry {
+            anyhow::bail!(
+                "zsync control file has {} bytes of block sums, expected {} for {} blocks",
+                sums.len(), count * entry, count
+            );
+        }
+        let blocks = sums
+            .chunks(entry)
+            .map(|chunk| BlockSums {
+                rsum: chunk[..rsum_bytes].iter().fold(0u32, |acc, &b| acc << 8 | b as u32),
+                checksum: chunk[rsum_bytes..].to_vec(),
+            })
+            .collect();
+
+        Ok(Self {
+            source: source.to_string(),
+            filename: field("filename").map(str::to_string),
+            block_size,
+            length,
+            urls,
+            sha1,
+            seq_matches,
+            rsum_bytes,
+            checksum_bytes,
+            blocks,
+        })
+    }
+
+    fn rsum_mask(&self) -> u32 {
+        (u64::MAX >> (64 - 8 * self.rsum_bytes)) as u32
+    }
+
+    /// Whether block `index` of the target has the checksums of `data`.
+    fn block_matches(&self, index: usize, data: &[u8]) -> bool {
+        let block = &self.blocks[index];
+        Rsum::of(data).value() & self.rsum_mask() == block.rsum && md4(data)[..self.checksum_bytes] == block.checksum[..]
+    }
+}
+
+/// zsync's rolling checksum: `a` sums the bytes, `b` weights them by their
+/// distance from the end of the block.
+#[derive(Debug, Clone, Copy)]
+struct Rsum {
+    a: u16,
+    b: u16,
+}
+
+impl Rsum {
+    fn of(block: &[u8]) -> Self {
+        let len = block.len();
+        block.iter().enumerate().fold(Self { a: 0, b: 0 }, |r, (i, &c)| Self {
+            a: r.a.wrapping_add(c as u16),
+            b: r.b.wrapping_add(((len - i) as u16).wrapping_mul(c as u16)),
+        })
+    }
+
+    /// Slides a window of `len` bytes one byte forward.
+    fn roll(self, old: u8, new: u8, len: usize) -> Self {
+        let a = self.a.wrapping_add(new as u16).wrapping_sub(old as u16);
+        let b = self.b.wrapping_add(a).wrapping_sub((len as u16).wrapping_mul(old as u16));
+        Self { a, b }
+    }
+
+    /// As stored in control files, `a` in the high half.
+    fn value(self) -> u32 {
+        (self.a as u32) << 16 | self.b as u32
+    }
+}
+
+fn md4(data: &[u8]) -> [u8; 16] {
+    Md4::digest(data).into()
+}
+
+/// Sequential reader that keeps a sliding window of the file in memory.
+struct Window {
+    file: File,
+    buf: Vec<u8>,
+    /// File offset of `buf[0]`.
+    start: u64,
+    eof: bool,
+}
+
+impl Window {
+    fn new(file: File) -> Self {
+        Self { file, buf: Vec::new(), start: 0, eof: false }
+    }
+
+    /// Up to `len` bytes at `pos`; shorter only at the end of the file.
+    /// `pos` must not go backwards.
+    fn at(&mut self, pos: u64, len: usize) -> io::Result<&[u8]> {
+        let offset = (pos - self.start) as usize;
+        if offset + len > self.buf.len() && !self.eof {
+            self.buf.drain(..offset.min(self.buf.len()));
+            self.start = pos;
+            while self.buf.len() < len.max(READ_CHUNK) && !self.eof {
+                let filled = self.buf.len();
+                self.buf.resize(filled + READ_CHUNK, 0);
+                let n = self.file.read(&mut self.buf[filled..])?;
+                self.buf.truncate(filled + n);
+                self.eof = n == 0;
+            }
+        }
+        let offset = ((pos - self.start) as usize).min(self.buf.len());
+        let end = (offset + len).min(self.buf.len());
+        Ok(&self.buf[offset..end])
+    }
+}
+
+/// Finds target blocks anywhere in `seed`. Returns the seed offset of each
+/// block found; the partial last block is never matched.
+fn match_zsync(control: &ControlFile, seed: &Path) -> io::Result<Vec<Option<u64>>> {
+    let bs = control.block_size;
+    let full_blocks = (control.length / bs as u64) as usize;
+    let mask = control.rsum_mask();
+    let mut matches = vec![None; control.blocks.len()];
+
+    let mut table: HashMap<u32, Vec<usize>> = HashMap::new();
+    // Cheap first filter on the low 16 bits before the hash table lookup
+    let mut seen = vec![false; 1 << 16];
+    for (i, block) in control.blocks[..full_blocks].iter().enumerate() {
+        table.entry(block.rsum).or_default().push(i);
+        seen[block.rsum as usize & 0xffff] = true;
+    }
+
+    let mut window = Window::new(File::open(seed)?);
+    let mut pos = 0u64;
+    let mut rsum = None;
+    loop {
+        let data = window.at(pos, 2 * bs)?;
+        if data.len() < bs {
+            break;
+        }
+        let r = *rsum.get_or_insert_with(|| Rsum::of(&data[..bs]));
+        let key = r.value() & mask;
+
+        let mut hit = false;
+        if seen[key as usize & 0xffff]
+            && let Some(candidates) = table.get(&key)
+        {
+            let checksum = md4(&data[..bs]);
+            for &i in candidates {
+                if checksum[..control.checksum_bytes] != control.blocks[i].checksum[..] {
+                    continue;
+                }
+                // Short checksums are only trusted for runs of blocks:
+                // the previous block must have matched just before, or
+                // the next one must match just after
+                let continues_run = i > 0 && pos >= bs as u64 && matches[i - 1] == Some(pos - bs as u64);
+                if control.seq_matches > 1
+                    && i + 1 < full_blocks
+                    && !continues_run
+                    && !data.get(bs..2 * bs).is_some_and(|next| control.block_matches(i + 1, next))
+                {
+                    continue;
+                }
+                hit = true;
+                matches[i].get_or_insert(pos);
+            }
+        }
+
+        if hit {
+            pos += bs as u64;
+            rsum = None;
+        } else {
+            rsum = data.get(bs).map(|&new| r.roll(data[0], new, bs));
+            pos += 1;
+        }
+    }
+    Ok(matches)
+}
+
+/// Finds pieces at piece-aligned offsets of `seed`, wherever they were in
+/// the old file's piece order.
+fn match_pieces(pieces: &PieceHashes, total_size: u64, seed: &Path) -> io::Result<Vec<Option<u64>>> {
+    let mut wanted: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, hash) in pieces.hashes.iter().enumerate() {
+        wanted.entry(hash.as_str()).or_default().push(i);
+    }
+
+    let mut matches = vec![None; pieces.len()];
+    let mut file = File::open(seed)?;
+    let mut buf = vec![0u8; pieces.piece_length as usize];
+    let mut offset = 0u64;
+    loop {
+        let n = read_full(&mut file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        let mut hasher = Hasher::new(pieces.algorithm);
+        hasher.update(&buf[..n]);
+        for &i in wanted.get(hasher.finalize().as_str()).into_iter().flatten() {
+            let (start, end) = pieces.piece_range(i, total_size);
+            if end - start + 1 == n as u64 {
+                matches[i].get_or_insert(offset);
+            }
+        }
+        offset += n as u64;
+        if n < buf.len() {
+            break;
+        }
+    }
+    Ok(matches)
+}
+
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// `len` bytes at `src` in the seed go to `dst` in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockCopy {
+    src: u64,
+    dst: u64,
+    len: u64,
+}
+
+#[derive(Debug, PartialEq)]
+struct Plan {
+    copies: Vec<BlockCopy>,
+    /// Inclusive byte ranges to download.
+    changed: Vec<(u64, u64)>,
+    reused: u64,
+}
+
+/// Turns per-block matches into copies and at most `max_ranges` changed
+/// ranges. Blocks inside merged gaps are unmatched in `matches`.
+fn plan(matches: &mut [Option<u64>], block_size: u64, total_size: u64, max_ranges: usize) -> Plan {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for i in (0..matches.len()).filter(|&i| matches[i].is_none()) {
+        match runs.last_mut() {
+            Some((_, last)) if *last + 1 == i => *last = i,
+            _ => runs.push((i, i)),
+        }
+    }
+
+    if runs.len() > max_ranges {
+        // Merging across a gap never changes the other gaps, so merge the
+        // smallest ones in a single pass
+        let mut gaps: Vec<(usize, usize)> = runs.windows(2).enumerate().map(|(i, w)| (w[1].0 - w[0].1, i)).collect();
+        gaps.sort_unstable();
+        let mut merge_next = vec![false; runs.len()];
+        for &(_, i) in &gaps[..runs.len() - max_ranges] {
+            merge_next[i] = true;
+        }
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(max_ranges);
+        for (i, &run) in runs.iter().enumerate() {
+            match merged.last_mut() {
+                Some(last) if merge_next[i - 1] => last.1 = run.1,
+                _ => merged.push(run),
+            }
+        }
+        for &(first, last) in &merged {
+            matches[first..=last].fill(None);
+        }
+        runs = merged;
+    }
+
+    let changed = runs
+        .iter()
+        .map(|&(first, last)| (first as u64 * block_size, ((last as u64 + 1) * block_size).min(total_size) - 1))
+        .collect();
+
+    let mut copies: Vec<BlockCopy> = Vec::new();
+    for (i, src) in matches.iter().enumerate() {
+        let Some(src) = *src else { continue };
+        let dst = i as u64 * block_size;
+        let len = block_size.min(total_size - dst);
+        match copies.last_mut() {
+            Some(last) if last.dst + last.len == dst && last.src + last.len == src => last.len += len,
+            _ => copies.push(BlockCopy { src, dst, len }),
+        }
+    }
+    let reused = copies.iter().map(|c| c.len).sum();
+    Plan { copies, changed, reused }
+}
+
+/// Writes the planned copies into `output`, sized to `total_size`.
+fn copy_blocks(seed: &Path, output: &Path, total_size: u64, copies: &[BlockCopy]) -> io::Result<()> {
+    let mut src = File::open(seed)?;
+    let mut dst = OpenOptions::new().write(true).create(true).truncate(false).open(output)?;
+    dst.set_len(total_size)?;
+    for copy in copies {
+        src.seek(SeekFrom::Start(copy.src))?;
+        dst.seek(SeekFrom::Start(copy.dst))?;
+        let copied = io::copy(&mut (&mut src).take(copy.len), &mut dst)?;
+        if copied != copy.len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "local file shrank while copying blocks"));
+        }
+    }
+    dst.sync_all()
+}
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+async fn same_file(a: &Path, b: &Path) -> bool {
+    match (tokio::fs::canonicalize(a).await, tokio::fs::canonicalize(b).await) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+impl Downloader {
+    /// Reads a zsync control file from a local path or a URL.
+    pub async fn fetch_zsync(&self, source: &str) -> Result<ControlFile> {
+        let data = match crate::transport::scheme(source) {
+            Some(_) => {
+                let stream = self
+                    .transport_for(source)?
+                    .read_range(source, 0, crate::worker::OPEN_END)
+                    .await
+                    .with_context(|| format!("cannot fetch {}", source))?;
+                crate::transport::collect(stream, MAX_CONTROL_SIZE).await?
+            }
+            None => tokio::fs::read(source).await.with_context(|| format!("cannot read {}", source))?,
+        };
+        ControlFile::parse(&data, source)
+    }
+
+    /// Creates a session that updates `seed`, an older copy of the file,
+    /// to the version `blocks` describes.
+    ///
+    /// Blocks found in `seed` are copied into the output right away and
+    /// only the remaining ranges are left as parts. `url` defaults to the
+    /// control file's first `URL:` line and `output_path` to `seed`
+    /// itself, which is then renamed to `<seed>.zs-old` while the new
+    /// version is assembled.
+    pub async fn init_delta(
+        &self,
+        url: Option<&str>,
+        blocks: BlockList,
+        seed: &Path,
+        output_path: Option<PathBuf>,
+        connections: u8,
+    ) -> Result<DownloadSession> {
+        let (url, mirrors) = match (url, &blocks) {
+            (Some(url), _) => (url.to_string(), Vec::new()),
+            (None, BlockList::Zsync(control)) => {
+                let (first, rest) = control.urls.split_first().context("zsync control file names no URL")?;
+                (first.clone(), rest.to_vec())
+            }
+            (None, BlockList::Pieces(_)) => anyhow::bail!("a block list needs the URL of the new file"),
+        };
+
+        let meta = self.get_remote_metadata(&url).await?;
+        if !meta.accept_ranges {
+            anyhow::bail!("{} does not serve byte ranges, which a delta update needs", url);
+        }
+        let total_size = match &blocks {
+            BlockList::Zsync(control) => {
+                if let Some(size) = meta.total_size.filter(|&size| size != control.length) {
+                    anyhow::bail!("{} is {} bytes but the control file describes {}", url, size, control.length);
+                }
+                control.length
+            }
+            BlockList::Pieces(pieces) => {
+                let size = meta.total_size.with_context(|| format!("{} does not report its size", url))?;
+                pieces.check_size(size)?;
+                size
+            }
+        };
+
+        let output_path = output_path.unwrap_or_else(|| seed.to_path_buf());
+        let in_place = same_file(seed, &output_path).await;
+        let old = sibling_path(&output_path, ".zs-old");
+        // A leftover `.zs-old` is the pristine old file of an interrupted update
+        let renamed = in_place && tokio::fs::metadata(&old).await.is_err();
+        if renamed {
+            tokio::fs::rename(seed, &old).await.with_context(|| format!("cannot rename {:?}", seed))?;
+        }
+        let seed = if in_place { old } else { seed.to_path_buf() };
+
+        let (seed_path, output) = (seed.clone(), output_path.clone());
+        let result = tokio::task::spawn_blocking(move || -> Result<(Plan, BlockList)> {
+            let (mut matches, block_size) = match &blocks {
+                BlockList::Zsync(control) => (match_zsync(control, &seed_path)?, control.block_size as u64),
+                BlockList::Pieces(pieces) => (match_pieces(pieces, total_size, &seed_path)?, pieces.piece_length),
+            };
+            let plan = plan(&mut matches, block_size, total_size, MAX_DELTA_PARTS);
+            copy_blocks(&seed_path, &output, total_size, &plan.copies)
+                .with_context(|| format!("cannot copy blocks from {:?}", seed_path))?;
+
+            let mut blocks = blocks;
+            if let BlockList::Pieces(pieces) = &mut blocks {
+                for (i, matched) in matches.iter().enumerate() {
+                    pieces.verified.set(i, matched.is_some());
+                }
+            }
+            Ok((plan, blocks))
+        })
+        .await?;
+        let (plan, blocks) = match result {
+            Ok(planned) => planned,
+            Err(e) => {
+                if renamed {
+                    let _ = tokio::fs::remove_file(&output_path).await;
+                    let _ = tokio::fs::rename(&seed, &output_path).await;
+                }
+                return Err(e);
+            }
+        };
+
+        log::info!(
+            "Reusing {} of {} bytes from {:?}; fetching {} ranges from {}",
+            plan.reused, total_size, seed, plan.changed.len(), url
+        );
+
+        let mut session = DownloadSession::new(url, output_path, connections.max(1));
+        session.total_size = Some(total_size);
+        session.mirrors = mirrors
+            .into_iter()
+            .enumerate()
+            .map(|(i, url)| Mirror { url, priority: i as u32 + 1, location: None })
+            .collect();
+        match blocks {
+            BlockList::Zsync(control) => {
+                if let Some(sha1) = &control.sha1 {
+                    session.set_expected_digest(ExpectedDigest::new(HashAlgorithm::Sha1, sha1)?, DigestSource::Zsync(control.source));
+                }
+            }
+            BlockList::Pieces(pieces) => {
+                if let Some((digest, source)) = meta.digests.into_iter().next() {
+                    session.set_expected_digest(digest, source);
+                }
+                session.piece_hashes = Some(pieces);
+            }
+        }
+        session.parts = plan
+            .changed
+            .iter()
+            .enumerate()
+            .map(|(id, &(start_byte, end_byte))| DownloadPart {
+                id: id as u32,
+                start_byte,
+                end_byte,
+                current_byte: start_byte,
+                completed: false,
+            })
+            .collect();
+        session.delta = Some(DeltaJob { seed, reused_bytes: plan.reused, remove_seed: in_place });
+        session.state = DownloadState::Downloading;
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Non-repeating test data, unlike `test_support::payload`.
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// What `zsyncmake` writes for `data`.
+    fn zsyncmake(data: &[u8], block_size: usize, lengths: (usize, usize, usize), url: &str) -> Vec<u8> {
+        let (seq, rsum_bytes, checksum_bytes) = lengths;
+        let mut hasher = Hasher::new(HashAlgorithm::Sha1);
+        hasher.update(data);
+        let mut out = format!(
+            "zsync: 0.6.2\nFilename: new.img\nBlocksize: {}\nLength: {}\nHash-Lengths: {},{},{}\nURL: {}\nSHA-1: {}\n\n",
+            block_size, data.len(), seq, rsum_bytes, checksum_bytes, url, hasher.finalize()
+        )
+        .into_bytes();
+        for chunk in data.chunks(block_size) {
+            let mut block = chunk.to_vec();
+            block.resize(block_size, 0);
+            out.extend_from_slice(&Rsum::of(&block).value().to_be_bytes()[4 - rsum_bytes..]);
+            out.extend_from_slice(&md4(&block)[..checksum_bytes]);
+        }
+        out
+    }
+
+    #[test]
+    fn rolling_checksum_matches_recomputation() {
+        let data = noise(4096, 1);
+        let mut r = Rsum::of(&data[..1024]);
+        for pos in 1..=3072 {
+            r = r.roll(data[pos - 1], data[pos + 1023], 1024);
+            assert_eq!(r.value(), Rsum::of(&data[pos..pos + 1024]).value(), "offset {}", pos);
+        }
+    }
+
+    #[test]
+    fn plan_merges_smallest_gaps() {
+        // Blocks 1, 3, 4, 8 and 9 changed
+        let mut matches: Vec<Option<u64>> = (0..10).map(|i| Some(i * 100)).collect();
+        for i in [1, 3, 4, 8, 9] {
+            matches[i] = None;
+        }
+        let planned = plan(&mut matches, 100, 950, 2);
+        assert_eq!(planned.changed, vec![(100, 499), (800, 949)]);
+        assert_eq!(matches[2], None);
+        assert_eq!(
+            planned.copies,
+            vec![BlockCopy { src: 0, dst: 0, len: 100 }, BlockCopy { src: 500, dst: 500, len: 300 }]
+        );
+        assert_eq!(planned.reused, 400);
+    }
+
+    #[tokio::test]
+    async fn zsync_update_fetches_only_changed_blocks() {
+        let block_size = 1024;
+        let old = noise(64 * block_size, 7);
+        // Insert bytes near the start, shifting everything after them, and
+        // rewrite one block further on; the tail is a partial block
+        let mut new = old[..5000].to_vec();
+        new.extend_from_slice(&noise(100, 9));
+        new.extend_from_slice(&old[5000..40_000]);
+        new.extend_from_slice(&noise(block_size, 11));
+        new.extend_from_slice(&old[40_000 + block_size..]);
+        new.extend_from_slice(&noise(300, 13));
+
+        let served = Arc::new(AtomicU64::new(0));
+        let counter = served.clone();
+        let tamper: test_support::Tamper = Arc::new(move |_, _, bytes: &mut [u8]| {
+            counter.fetch_add(bytes.len() as u64, Ordering::SeqCst);
+        });
+        let server = test_support::serve([("/new.img".to_string(), new.clone())].into(), HashMap::new(), Some(tamper)).await;
+
+        let local = test_support::temp_path("zsync");
+        std::fs::write(&local, &old).unwrap();
+        let control_path = local.with_file_name("new.img.zsync");
+        std::fs::write(&control_path, zsyncmake(&new, block_size, (2, 2, 4), &server.url("/new.img"))).unwrap();
+
+        let downloader = Downloader::new("test").unwrap();
+        let control = downloader.fetch_zsync(control_path.to_str().unwrap()).await.unwrap();
+        assert_eq!(control.urls, vec![server.url("/new.img")]);
+
+        // Updating in place
+        let mut session = downloader.init_delta(None, BlockList::Zsync(control), &local, None, 2).await.unwrap();
+        let reused = session.delta.as_ref().unwrap().reused_bytes;
+        assert_eq!(reused, 61 * block_size as u64);
+        assert_eq!(session.completed_bytes(), reused);
+
+        downloader.run(&mut session, None, None, None).await.unwrap();
+
+        assert_eq!(std::fs::read(&local).unwrap(), new);
+        assert!(session.verification.unwrap().verified);
+        assert_eq!(served.load(Ordering::SeqCst), new.len() as u64 - reused + 1);
+        assert!(!local.with_file_name("out.bin.zs-old").exists());
+    }
+
+    #[tokio::test]
+    async fn block_list_update_copies_aligned_pieces() {
+        let piece_length = 4096;
+        let old = noise(16 * piece_length, 3);
+        let mut new = old.clone();
+        new[5 * piece_length + 10] ^= 0xff;
+        new[12 * piece_length..13 * piece_length].copy_from_slice(&noise(piece_length, 5));
+        let hashes = new
+            .chunks(piece_length)
+            .map(|chunk| {
+                let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+                hasher.update(chunk);
+                hasher.finalize()
+            })
+            .collect();
+        let pieces = PieceHashes::new(HashAlgorithm::Sha256, piece_length as u64, hashes).unwrap();
+        let server = test_support::serve([("/new.img".to_string(), new.clone())].into(), HashMap::new(), None).await;
+
+        let output = test_support::temp_path("block-list");
+        let seed = output.with_file_name("old.img");
+        std::fs::write(&seed, &old).unwrap();
+
+        let downloader = Downloader::new("test").unwrap();
+        let mut session = downloader
+            .init_delta(Some(&server.url("/new.img")), BlockList::Pieces(pieces), &seed, Some(output.clone()), 4)
+            .await
+            .unwrap();
+        assert_eq!(session.delta.as_ref().unwrap().reused_bytes, 14 * piece_length as u64);
+        assert_eq!(session.parts.len(), 2);
+
+        downloader.run(&mut session, None, None, None).await.unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), new);
+        assert_eq!(std::fs::read(&seed).unwrap(), old);
+        let pieces = session.piece_hashes.unwrap();
+        assert_eq!(pieces.verified.count_ones(), pieces.len());
+    }
+}
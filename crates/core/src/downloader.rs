@@ -1,111 +1,251 @@
-use super::session::{DownloadSession, DownloadState};
+use super::metalink::MetalinkFile;
+use super::network::{Network, NetworkConfig};
+use super::mirrors::MirrorPool;
+use super::session::{DownloadPart, DownloadSession, DownloadState, Mirror};
+use super::torrent::TorrentConfig;
+use super::transport::{FtpTransport, HttpConfig, HttpTransport, S3Config, S3Transport, SftpTransport, Transport, TransportError};
 use super::worker::Worker;
+use crate::preview::PreviewHandle;
+use crate::schedule::RateLimiter;
+use crate::integrity::{DigestSource, ExpectedDigest, HashAlgorithm, IntegrityError, MultipartEtag, Verification};
 use anyhow::Result;
-use reqwest::{Client, header};
-use std::path::PathBuf;
+use reqwest::Client;
+use std::path::{Path, PathBuf};
 use tokio::fs::OpenOptions;
 use tokio::sync::mpsc;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use tokio::task::JoinHandle;
 use tokio::time::timeout;
 use std::time::{Duration, Instant};
 
+/// A piece that fails its hash this many times aborts the download; the
+/// hash list itself is probably wrong.
+const MAX_PIECE_ATTEMPTS: u32 = 3;
+
 pub trait DownloadObserver: Send + Sync {
-    fn on_progress(&self, worker_id: u8, bytes_downloaded: u64, active_workers: usize);
+    fn on_progress(&self, worker_id: u32, bytes_downloaded: u64, active_workers: usize);
+
+    /// Called when the session moves to a new state, e.g. `Verifying`.
+    fn on_state_change(&self, _state: &DownloadState) {}
 }
 
 pub struct ChannelObserver {
-    tx: mpsc::Sender<(u8, u64, usize)>,
+    tx: mpsc::Sender<(u32, u64, usize)>,
 }
 
 impl ChannelObserver {
-    pub fn new(tx: mpsc::Sender<(u8, u64, usize)>) -> Self {
+    pub fn new(tx: mpsc::Sender<(u32, u64, usize)>) -> Self {
         Self { tx }
     }
 }
 
 impl DownloadObserver for ChannelObserver {
-    fn on_progress(&self, worker_id: u8, bytes_downloaded: u64, active_workers: usize) {
+    fn on_progress(&self, worker_id: u32, bytes_downloaded: u64, active_workers: usize) {
         let _ = self.tx.try_send((worker_id, bytes_downloaded, active_workers));
     }
 }
 
+/// What a server tells us about a file before we download it.
+#[derive(Debug, Clone)]
+pub struct RemoteMetadata {
+    pub filename: String,
+    pub total_size: Option<u64>,
+    pub accept_ranges: bool,
+    /// Whole-file digests advertised by the server, strongest first.
+    pub digests: Vec<(ExpectedDigest, DigestSource)>,
+    /// Strong part of the `ETag`, used to tell whether mirrors serve the same bytes.
+    pub etag: Option<String>,
+    /// Mirrors advertised with `Link: rel=duplicate`, unless discovery is off.
+    pub mirrors: Vec<Mirror>,
+    /// Set for S3 objects uploaded in several parts.
+    pub multipart_etag: Option<MultipartEtag>,
+}
+
+/// Bookkeeping for a running worker.
+struct RunningWorker {
+    /// Shared end offset, lowered when another worker steals the tail.
+    end: Arc<AtomicU64>,
+    url: String,
+    started: Instant,
+}
+
+/// A seek this far past a running part's position splits the part instead
+/// of waiting for its worker to get there.
+const SEEK_SPLIT_DISTANCE: u64 = 1024 * 1024;
+
+/// How many windows `verify_resume_offsets` walks back before giving up
+/// on a part and restarting it from scratch.
+const MAX_RESUME_CHECK_WINDOWS: u32 = 16;
+
 #[derive(Clone)]
 pub struct Downloader {
     client: Client,
+    http: Arc<HttpTransport>,
+    ftp: Arc<FtpTransport>,
+    sftp: Arc<SftpTransport>,
+    s3: Arc<S3Transport>,
+    torrent: TorrentConfig,
+    probe_checksum_files: bool,
+    resume_check_window: u64,
+    discover_mirrors: bool,
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 impl Downloader {
     pub fn new(user_agent: &str) -> Result<Self> {
-        let client = Client::builder()
-            .user_agent(user_agent)
-            .timeout(std::time::Duration::from_secs(30))
-            .pool_max_idle_per_host(0)
-            .build()?;
-            
+        let http = HttpTransport::new(user_agent, HttpConfig::default())?;
+        let client = http.client().clone();
+
         Ok(Self {
+            http: Arc::new(http),
+            ftp: Arc::new(FtpTransport::new()?),
+            sftp: Arc::new(SftpTransport::new()),
+            s3: Arc::new(S3Transport::new(client.clone(), S3Config::from_env())),
             client,
+            torrent: TorrentConfig::default(),
+            probe_checksum_files: false,
+            resume_check_window: 0,
+            discover_mirrors: true,
+            rate_limiter: None,
         })
     }
 
-    pub async fn get_remote_metadata(&self, url: &str) -> Result<(String, Option<u64>, bool)> {
-        let response = self.client
-            .get(url)
-            .header(header::RANGE, "bytes=0-0")
-            .send()
-            .await?;
-        let headers = response.headers();
-
-        let total_size = headers
-            .get(header::CONTENT_RANGE)
-            .and_then(|val| val.to_str().ok())
-            .and_then(|val| val.split('/').last())
-            .and_then(|val| val.parse::<u64>().ok())
-            .or_else(|| {
-                headers
-                    .get(header::CONTENT_LENGTH)
-                    .and_then(|val| val.to_str().ok())
-                    .and_then(|val| val.parse::<u64>().ok())
-            });
+    /// Also look for `<file>.sha256`, `SHA256SUMS` and friends next to the
+    /// download when the server advertises no digest in its headers.
+    pub fn with_checksum_probing(mut self, enabled: bool) -> Self {
+        self.probe_checksum_files = enabled;
+        self
+    }
 
-        let accept_ranges = headers.get(header::CONTENT_RANGE).is_some()
-            || headers
-                .get(header::ACCEPT_RANGES)
-                .and_then(|val| val.to_str().ok())
-                .map(|val| val == "bytes")
-                .unwrap_or(false);
-
-        let content_disposition = headers
-            .get(header::CONTENT_DISPOSITION)
-            .and_then(|val| val.to_str().ok());
-
-        let filename_from_header = content_disposition
-            .and_then(|cd| {
-                if let Some(idx) = cd.find("filename=") {
-                    let remaining = &cd[idx + 9..];
-                    let end_idx = remaining.find(';').unwrap_or(remaining.len());
-                    let raw_name = &remaining[..end_idx];
-                    Some(raw_name.trim_matches('"').to_string())
-                } else {
-                    None
-                }
-            });
+    /// Throttles downloaded data through `limiter`: the workers of parts
+    /// and HLS/DASH segments, and blocks received from torrent peers.
+    pub fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
 
-        let filename = filename_from_header.unwrap_or_else(|| {
-            url.split('/')
-                .last()
-                .map(|s| s.split('?').next().unwrap_or(s))
-                .filter(|s| !s.is_empty())
-                .unwrap_or("download.bin")
-                .to_string()
-        });
+    /// Before resuming, re-fetch the last `window` bytes of every part and
+    /// compare them with the file on disk. Parts whose data does not match
+    /// are rolled back. `0` disables the check.
+    pub fn with_resume_check(mut self, window: u64) -> Self {
+        self.resume_check_window = window;
+        self
+    }
+
+    /// Offer `key` for `sftp://` logins, after the SSH agent and before
+    /// the default key files in `~/.ssh`.
+    pub fn with_ssh_identity(mut self, key: PathBuf) -> Self {
+        self.sftp = Arc::new((*self.sftp).clone().with_identity(key));
+        self
+    }
+
+    /// Chooses between a connection per part, HTTP/2 multiplexing and
+    /// HTTP/3, per host. Fails if the config asks for HTTP/3 and this build
+    /// lacks it.
+    pub fn with_http_config(mut self, config: HttpConfig) -> Result<Self> {
+        self.http = Arc::new((*self.http).clone().with_config(config)?);
+        Ok(self)
+    }
+
+    /// Binds connections to the configured local addresses or interfaces,
+    /// bonding them when there are several, and applies its IP preference
+    /// and DNS overrides. Covers HTTP(S), FTP, SFTP and S3.
+    pub fn with_network_config(mut self, config: &NetworkConfig) -> Result<Self> {
+        let network = Network::new(config);
+        self.http = Arc::new((*self.http).clone().with_network(network.clone())?);
+        self.ftp = Arc::new((*self.ftp).clone().with_network(network.clone()));
+        self.sftp = Arc::new((*self.sftp).clone().with_network(network));
+        self.client = self.http.client().clone();
+        self.s3 = Arc::new((*self.s3).clone().with_client(self.client.clone()));
+        Ok(self)
+    }
+
+    /// Replaces the S3 settings read from the environment at construction.
+    pub fn with_s3_config(mut self, config: S3Config) -> Self {
+        self.s3 = Arc::new(S3Transport::new(self.client.clone(), config));
+        self
+    }
+
+    /// Replaces the default BitTorrent port, DHT and peer settings.
+    pub fn with_torrent_config(mut self, config: TorrentConfig) -> Self {
+        self.torrent = config;
+        self
+    }
+
+    pub(crate) fn torrent_config(&self) -> &TorrentConfig {
+        &self.torrent
+    }
+
+    pub(crate) fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub(crate) fn rate_limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.rate_limiter.clone()
+    }
+
+    /// The transport that handles `url`'s scheme.
+    pub fn transport_for(&self, url: &str) -> Result<Arc<dyn Transport>> {
+        match crate::transport::scheme(url).as_deref() {
+            Some("http" | "https") => Ok(self.http.clone()),
+            Some("ftp" | "ftps" | "ftpes") => Ok(self.ftp.clone()),
+            Some("sftp") => Ok(self.sftp.clone()),
+            Some("s3") => Ok(self.s3.clone()),
+            Some(other) => anyhow::bail!("unsupported URL scheme {}://", other),
+            None => anyhow::bail!("not a URL: {}", url),
+        }
+    }
+
+    /// Use mirrors the server advertises with `Link: <...>; rel=duplicate`
+    /// (RFC 6249) as extra sources. On by default.
+    pub fn with_mirror_discovery(mut self, enabled: bool) -> Self {
+        self.discover_mirrors = enabled;
+        self
+    }
+
+    pub async fn get_remote_metadata(&self, url: &str) -> Result<RemoteMetadata> {
+        let mut meta = self.transport_for(url)?.probe(url).await?;
+        if !self.discover_mirrors {
+            meta.mirrors.clear();
+        }
+        if meta.digests.is_empty() && self.probe_checksum_files {
+            meta.digests.extend(self.probe_checksum_files(url, &meta.filename).await);
+        }
+        Ok(meta)
+    }
+
+    /// Looks for checksum files published next to `url`.
+    async fn probe_checksum_files(&self, url: &str, filename: &str) -> Option<(ExpectedDigest, DigestSource)> {
+        let base = url.split(['?', '#']).next().unwrap_or(url);
+        let dir = &base[..base.rfind('/').map(|i| i + 1).unwrap_or(base.len())];
 
-        Ok((filename, total_size, accept_ranges))
+        let candidates = [
+            (format!("{}.sha512", base), HashAlgorithm::Sha512),
+            (format!("{}.sha256", base), HashAlgorithm::Sha256),
+            (format!("{}SHA512SUMS", dir), HashAlgorithm::Sha512),
+            (format!("{}SHA256SUMS", dir), HashAlgorithm::Sha256),
+            (format!("{}.sha1", base), HashAlgorithm::Sha1),
+            (format!("{}.md5", base), HashAlgorithm::Md5),
+        ];
+
+        for (candidate, algorithm) in candidates {
+            let Ok(transport) = self.transport_for(&candidate) else { continue };
+            let Ok(stream) = transport.read_range(&candidate, 0, crate::worker::OPEN_END).await else { continue };
+            // Checksum files are tiny; anything large is an HTML error page or worse
+            let Ok(body) = crate::transport::collect(stream, 1024 * 1024).await else { continue };
+            let body = String::from_utf8_lossy(&body);
+            if let Some(digest) = crate::integrity::parse_checksum_file(&body, algorithm, filename) {
+                log::info!("Found {} checksum for {} in {}", algorithm, filename, candidate);
+                return Some((digest, DigestSource::ChecksumFile(candidate)));
+            }
+        }
+        None
     }
 
     pub async fn init_download(&self, url: &str, output_path: Option<PathBuf>, connections: u8) -> Result<DownloadSession> {
-        let (filename, total_size, accept_ranges) = self.get_remote_metadata(url).await?;
+        let RemoteMetadata { filename, total_size, accept_ranges, digests, mirrors, multipart_etag, .. } = self.get_remote_metadata(url).await?;
         
         let final_path = if let Some(path) = output_path {
             path
@@ -115,29 +255,136 @@ impl Downloader {
 
         let mut session = DownloadSession::new(url.to_string(), final_path, connections);
         session.total_size = total_size;
+        if let Some((digest, source)) = digests.into_iter().next() {
+            log::info!("Server advertises {} ({})", digest, source);
+            session.set_expected_digest(digest, source);
+        }
+        if session.expected_digest.is_none()
+            && let Some(etag) = multipart_etag
+        {
+            log::info!("Object has multipart ETag {} ({} byte parts)", etag, etag.part_size);
+            session.multipart_etag = Some(etag);
+        }
+        if !mirrors.is_empty() {
+            log::info!("Server advertises {} mirrors", mirrors.len());
+            session.mirrors = mirrors;
+        }
+
+        Self::plan_parts(&mut session, accept_ranges);
+        
+        session.state = DownloadState::Downloading;
 
-        if let Some(size) = total_size {
-             if accept_ranges && connections > 1 {
-                let part_size = size / connections as u64;
-                let mut start_byte = 0;
-
-                for i in 0..connections {
-                    let end_byte = if i == connections - 1 {
-                        size - 1
-                    } else {
-                        start_byte + part_size - 1
-                    };
-
-                    session.parts.push(super::session::DownloadPart {
-                        id: i,
-                        start_byte,
-                        end_byte,
-                        current_byte: start_byte,
-                        completed: false,
-                    });
-
-                    start_byte = end_byte + 1;
+        Ok(session)
+    }
+
+    /// Reads a Metalink document from a local path or an http(s) URL.
+    pub async fn fetch_metalink(&self, source: &str) -> Result<Vec<MetalinkFile>> {
+        let xml = if source.starts_with("http://") || source.starts_with("https://") {
+            self.client.get(source).send().await?.error_for_status()?.text().await?
+        } else {
+            tokio::fs::read_to_string(source).await?
+        };
+        crate::metalink::parse(&xml)
+    }
+
+    /// Creates a session for one Metalink file under `output_dir`.
+    ///
+    /// Mirrors are probed in priority order and the first one that answers
+    /// with the advertised size becomes the active URL; the rest stay in
+    /// `session.mirrors` for failover.
+    pub async fn init_from_metalink(&self, file: &MetalinkFile, output_dir: &Path, connections: u8) -> Result<DownloadSession> {
+        let mut probed = None;
+        for mirror in &file.mirrors {
+            match self.get_remote_metadata(&mirror.url).await {
+                Ok(meta) if file.size.is_some() && meta.total_size != file.size => {
+                    log::warn!("Mirror {} reports size {:?}, expected {:?}; skipping", mirror.url, meta.total_size, file.size);
                 }
+                Ok(meta) => {
+                    probed = Some((mirror.url.clone(), meta));
+                    break;
+                }
+                Err(e) => log::warn!("Mirror {} unavailable: {}", mirror.url, e),
+            }
+        }
+        let Some((url, meta)) = probed else {
+            anyhow::bail!("none of the {} mirrors for {:?} is usable", file.mirrors.len(), file.name);
+        };
+
+        let mut session = DownloadSession::new(url, output_dir.join(&file.name), connections);
+        session.total_size = file.size.or(meta.total_size);
+        session.mirrors = file.mirrors.clone();
+        if let Some(digest) = &file.digest {
+            session.set_expected_digest(digest.clone(), DigestSource::Metalink);
+        } else if let Some((digest, source)) = meta.digests.into_iter().next() {
+            session.set_expected_digest(digest, source);
+        }
+        if let (Some(pieces), Some(size)) = (&file.pieces, session.total_size) {
+            match pieces.check_size(size) {
+                Ok(()) => session.piece_hashes = Some(pieces.clone()),
+                Err(e) => log::warn!("Ignoring Metalink piece hashes for {:?}: {}", file.name, e),
+            }
+        }
+
+        if let Some(parent) = session.output_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Self::plan_parts(&mut session, meta.accept_ranges);
+        session.state = DownloadState::Downloading;
+        Ok(session)
+    }
+
+    /// Probes `session.url` and every mirror before bytes from them are mixed.
+    ///
+    /// Mirrors whose size differs from the session, or whose ETag differs
+    /// from the first mirror that answered, are removed from
+    /// `session.mirrors`. Returns the URLs that must not be used in this
+    /// run: unreachable ones, and `session.url` itself if it disagrees.
+    pub async fn check_mirrors(&self, session: &mut DownloadSession) -> Vec<String> {
+        let mut urls = vec![session.url.clone()];
+        urls.extend(session.mirrors.iter().map(|m| m.url.clone()).filter(|u| *u != session.url));
+        let probes = futures::future::join_all(urls.iter().map(|url| self.get_remote_metadata(url))).await;
+
+        let mut reference_etag: Option<String> = None;
+        let mut excluded = Vec::new();
+        for (url, probe) in urls.into_iter().zip(probes) {
+            let meta = match probe {
+                Ok(meta) => meta,
+                Err(e) => {
+                    log::warn!("Mirror {} unavailable: {}", url, e);
+                    excluded.push(url);
+                    continue;
+                }
+            };
+
+            let size_matches = session.remote_size().is_none() || meta.total_size == session.remote_size();
+            let etag_matches = match (&reference_etag, &meta.etag) {
+                (Some(reference), Some(etag)) => reference == etag,
+                _ => true,
+            };
+            if size_matches && etag_matches {
+                if reference_etag.is_none() {
+                    reference_etag = meta.etag;
+                }
+                continue;
+            }
+
+            log::warn!(
+                "Mirror {} serves a different file (size {:?}, ETag {:?}); dropping it",
+                url, meta.total_size, meta.etag
+            );
+            session.mirrors.retain(|m| m.url != url);
+            excluded.push(url);
+        }
+        excluded
+    }
+
+    /// Splits the session into one part per connection, or a single part if
+    /// the server cannot serve ranges or the size is unknown.
+    pub(crate) fn plan_parts(session: &mut DownloadSession, accept_ranges: bool) {
+        session.parts.clear();
+        if let Some(size) = session.total_size {
+             if accept_ranges && session.connections > 1 {
+                Self::split_range(session, 0, size - 1);
             } else {
                 session.parts.push(super::session::DownloadPart {
                     id: 0,
@@ -159,10 +406,34 @@ impl Downloader {
             });
             session.connections = 1;
         }
-        
-        session.state = DownloadState::Downloading;
+    }
 
-        Ok(session)
+    /// Appends one part per connection covering `start..=end`, with no
+    /// more parts than bytes.
+    pub(crate) fn split_range(session: &mut DownloadSession, start: u64, end: u64) {
+        let len = end - start + 1;
+        let connections = (session.connections as u64).clamp(1, len) as u8;
+        let part_size = len / connections as u64;
+        let first_id = session.next_part_id();
+        let mut start_byte = start;
+
+        for i in 0..connections {
+            let end_byte = if i == connections - 1 {
+                end
+            } else {
+                start_byte + part_size - 1
+            };
+
+            session.parts.push(super::session::DownloadPart {
+                id: first_id + i as u32,
+                start_byte,
+                end_byte,
+                current_byte: start_byte,
+                completed: false,
+            });
+
+            start_byte = end_byte + 1;
+        }
     }
 
     pub async fn run(
@@ -172,42 +443,90 @@ impl Downloader {
         session_file: Option<PathBuf>,
         cancel_flag: Option<Arc<AtomicBool>>,
     ) -> Result<()> {
-        // Pre-allocate file only if starting from scratch or if file doesn't exist
-        if !session.output_path.exists() {
-            let file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(false)
-                .open(&session.output_path)
-                .await?;
-            
-            if let Some(size) = session.total_size {
-                file.set_len(size).await?;
-            }
+        if session.stream.is_some() {
+            return crate::segments::run(self, session, observer, session_file, cancel_flag).await;
+        }
+        if session.torrent.is_some() {
+            return crate::torrent::run(self, session, observer, session_file, cancel_flag).await;
+        }
+        self.run_parts(session, observer, session_file, cancel_flag, None).await
+    }
+
+    /// Like [`run`](Self::run), while `preview` serves the file: it sees
+    /// every byte as soon as it is on disk, and its seeks move the parts
+    /// they need to the front of the queue.
+    pub async fn run_with_preview(
+        &self,
+        session: &mut DownloadSession,
+        observer: Option<Arc<dyn DownloadObserver>>,
+        session_file: Option<PathBuf>,
+        cancel_flag: Option<Arc<AtomicBool>>,
+        preview: &PreviewHandle,
+    ) -> Result<()> {
+        let result = self.run_parts(session, observer, session_file, cancel_flag, Some(preview)).await;
+        preview.close(session);
+        result
+    }
+
+    async fn run_parts(
+        &self,
+        session: &mut DownloadSession,
+        observer: Option<Arc<dyn DownloadObserver>>,
+        session_file: Option<PathBuf>,
+        cancel_flag: Option<Arc<AtomicBool>>,
+        preview: Option<&PreviewHandle>,
+    ) -> Result<()> {
+        // Pre-allocate the file if it is new or shorter than the download;
+        // never truncate, existing bytes may belong to a resumed session
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(session.data_path())
+            .await?;
+
+        if let Some(size) = session.total_size
+            && file.metadata().await?.len() < size
+        {
+            file.set_len(size).await?;
+        }
+        drop(file);
+
+        if self.resume_check_window > 0 {
+            self.verify_resume_offsets(session).await?;
         }
         session.state = DownloadState::Downloading;
 
-        let (tx, mut rx) = mpsc::channel::<(u8, u64, u8)>(100);
+        // With several sources, make sure they agree before mixing their bytes
+        let mut excluded = Vec::new();
+        if !session.mirrors.is_empty() {
+            excluded = self.check_mirrors(session).await;
+        }
+        let mut pool = MirrorPool::for_session(session);
+        for url in &excluded {
+            pool.mark_failed(url);
+        }
+        if pool.is_failed(&session.url) {
+            let Some(next) = pool.pick(Instant::now()) else {
+                anyhow::bail!("none of the mirrors for {:?} is usable", session.output_path);
+            };
+            session.url = next.to_string();
+        }
+
+        let (tx, mut rx) = mpsc::channel::<(u32, u64, u8)>(100);
         let mut handles = vec![];
-        let mut worker_controls: HashMap<u8, Arc<AtomicU64>> = HashMap::new();
-
-        // Spawn initial workers
-        for part in &session.parts {
-            if !part.completed {
-                let atomic_end = Arc::new(AtomicU64::new(part.end_byte));
-                worker_controls.insert(part.id, atomic_end.clone());
-
-                let worker = Worker::new(
-                    part.id,
-                    session.url.clone(),
-                    (part.current_byte, part.end_byte),
-                    session.output_path.clone(),
-                    self.client.clone(),
-                    tx.clone(),
-                    Some(atomic_end),
-                );
-                handles.push(tokio::spawn(async move { worker.run().await }));
-            }
+        let mut workers: HashMap<u32, RunningWorker> = HashMap::new();
+
+        // Spawn initial workers; a delta update can plan more parts than
+        // connections, the rest wait for a worker to finish
+        let mut queued: VecDeque<u32> = session.parts.iter().filter(|p| !p.completed).map(|p| p.id).collect();
+        if session.sequential {
+            queued.make_contiguous().sort_by_key(|id| session.parts.iter().find(|p| p.id == *id).map(|p| p.start_byte));
+        }
+        while workers.len() < session.connections.max(1) as usize
+            && let Some(id) = queued.pop_front()
+        {
+            handles.push(self.spawn_worker(session, id, &tx, &mut workers, &mut pool));
         }
 
         // Keep tx alive for work-stealing
@@ -217,19 +536,61 @@ impl Downloader {
         let mut last_save = std::time::Instant::now();
         let mut last_ui_update = Instant::now();
         let mut pending_bytes: u64 = 0;
-        let mut next_worker_id = session.parts.iter().map(|p| p.id).max().unwrap_or(0) + 1;
+
+        // Piece verification results: (piece index, hash matched)
+        let (piece_tx, mut piece_rx) = mpsc::unbounded_channel::<(usize, bool)>();
+        let mut pieces_in_flight: HashSet<usize> = HashSet::new();
+        let mut piece_failures: HashMap<usize, u32> = HashMap::new();
+        if let (Some(pieces), Some(size)) = (&session.piece_hashes, session.total_size) {
+            pieces.check_size(size)?;
+        }
+        Self::schedule_piece_checks(session, &mut pieces_in_flight, &piece_tx);
         
         loop {
             // Check cancellation
-            if let Some(ref flag) = cancel_flag {
-                if flag.load(Ordering::Relaxed) {
-                    return Err(anyhow::anyhow!("cancelled"));
+            if let Some(ref flag) = cancel_flag
+                && flag.load(Ordering::Relaxed)
+            {
+                if let Some(path) = &session_file {
+                    let _ = session.checkpoint(path).await;
                 }
+                return Err(anyhow::anyhow!("cancelled"));
+            }
+
+            // A preview reader is waiting for bytes nobody is fetching yet
+            if let Some(offset) = preview.and_then(PreviewHandle::take_seek) {
+                Self::prioritize(session, offset, &mut queued, &workers);
+            }
+
+            // Apply finished piece checks; failed pieces go back to the workers
+            while let Ok((index, matched)) = piece_rx.try_recv() {
+                pieces_in_flight.remove(&index);
+                let (Some(pieces), Some(size)) = (session.piece_hashes.as_mut(), session.total_size) else {
+                    continue;
+                };
+                if matched {
+                    pieces.verified.set(index, true);
+                    continue;
+                }
+
+                let failures = piece_failures.entry(index).or_insert(0);
+                *failures += 1;
+                if *failures >= MAX_PIECE_ATTEMPTS {
+                    return Err(IntegrityError::PieceMismatch { index, attempts: *failures }.into());
+                }
+
+                let (start, end) = pieces.piece_range(index, size);
+                log::warn!("Piece {} ({}-{}) failed verification, downloading it again", index, start, end);
+                let new_id = session.reopen_range(start, end);
+                handles.push(self.spawn_worker(session, new_id, &worker_tx, &mut workers, &mut pool));
             }
 
             // Check for completion
             let all_done = session.parts.iter().all(|p| p.completed);
-            if all_done {
+            if all_done
+                && Self::schedule_piece_checks(session, &mut pieces_in_flight, &piece_tx) == 0
+                && pieces_in_flight.is_empty()
+            {
                 break;
             }
 
@@ -237,9 +598,31 @@ impl Downloader {
             match timeout(Duration::from_secs(1), rx.recv()).await {
                 Ok(Some((worker_id, bytes, status))) => {
                     if status == 2 {
+                        // Drop the worker's mirror from rotation and restart the part elsewhere
+                        if let Some(worker) = workers.remove(&worker_id) {
+                            pool.finished(&worker.url, worker.started, Instant::now());
+                            if pool.mark_failed(&worker.url) && pool.usable() > 0 {
+                                log::warn!("Mirror {} failed, dropping it from rotation", worker.url);
+                            }
+                        }
+                        if pool.is_failed(&session.url)
+                            && let Some(next) = pool.pick(Instant::now())
+                        {
+                            session.url = next.to_string();
+                        }
+                        if pool.usable() > 0 {
+                            handles.push(self.spawn_worker(session, worker_id, &worker_tx, &mut workers, &mut pool));
+                            continue;
+                        }
                         log::error!("Worker {} reported failure", worker_id);
                         return Err(anyhow::anyhow!("Worker {} reported failure", worker_id));
                     }
+                    if let Some(worker) = workers.get(&worker_id) {
+                        pool.record(&worker.url, bytes);
+                        if status == 1 {
+                            pool.finished(&worker.url, worker.started, Instant::now());
+                        }
+                    }
                     // Update session
                     if let Some(part) = session.parts.iter_mut().find(|p| p.id == worker_id) {
                         if status == 1 {
@@ -255,24 +638,40 @@ impl Downloader {
                         }
                     }
 
+                    if let Some(preview) = preview {
+                        preview.publish(session);
+                    }
+
                     // Accumulate bytes for throttled UI updates
                     pending_bytes += bytes;
 
                     // Forward to UI only if 50ms elapsed or worker completed
-                    if let Some(obs) = &observer {
-                        if status == 1 || last_ui_update.elapsed() >= Duration::from_millis(50) {
-                            let active_count = session.parts.iter().filter(|p| !p.completed).count();
-                            // Use pending_bytes
-                            obs.on_progress(worker_id, pending_bytes, active_count);
-                            pending_bytes = 0;
-                            last_ui_update = Instant::now();
-                        }
+                    if let Some(obs) = &observer
+                        && (status == 1 || last_ui_update.elapsed() >= Duration::from_millis(50))
+                    {
+                        let active_count = session.parts.iter().filter(|p| !p.completed).count();
+                        // Use pending_bytes
+                        obs.on_progress(worker_id, pending_bytes, active_count);
+                        pending_bytes = 0;
+                        last_ui_update = Instant::now();
                     }
                     
+                    if status == 1 {
+                        Self::schedule_piece_checks(session, &mut pieces_in_flight, &piece_tx);
+                    }
+
+                    if status == 1 {
+                        workers.remove(&worker_id);
+                        if let Some(id) = queued.pop_front() {
+                            handles.push(self.spawn_worker(session, id, &worker_tx, &mut workers, &mut pool));
+                            continue;
+                        }
+                    }
+
                     // Work-stealing: if this worker just completed, help the slowest worker
                     if status == 1 {
                         // Find slowest worker (most bytes remaining)
-                        let mut slowest: Option<(u8, u64, u64)> = None; // (id, remaining, current)
+                        let mut slowest: Option<(u32, u64, u64)> = None; // (id, remaining, current)
                         
                         for part in &session.parts {
                             if !part.completed {
@@ -300,39 +699,25 @@ impl Downloader {
                                 
                                 // Update the slow worker's end
                                 part.end_byte = split_point;
-                                if let Some(atomic) = worker_controls.get(&slow_id) {
-                                    atomic.store(split_point, Ordering::Relaxed);
+                                if let Some(worker) = workers.get(&slow_id) {
+                                    worker.end.store(split_point + session.remote_offset, Ordering::Relaxed);
                                 }
                                 
                                 // Create new part for this helper worker
                                 let new_part_start = split_point + 1;
                                 let new_part_end = old_end;
-                                let new_id = next_worker_id;
-                                next_worker_id += 1;
+                                let new_id = session.next_part_id();
                                 
-                                let new_part = super::session::DownloadPart {
+                                session.parts.push(super::session::DownloadPart {
                                     id: new_id,
                                     start_byte: new_part_start,
                                     end_byte: new_part_end,
                                     current_byte: new_part_start,
                                     completed: false,
-                                };
+                                });
                                 
-                                let atomic_end_new = Arc::new(AtomicU64::new(new_part_end));
-                                worker_controls.insert(new_id, atomic_end_new.clone());
-                                
-                                let worker = Worker::new(
-                                    new_id,
-                                    session.url.clone(),
-                                    (new_part_start, new_part_end),
-                                    session.output_path.clone(),
-                                    self.client.clone(),
-                                    worker_tx.clone(), // Reuse the main sender
-                                    Some(atomic_end_new),
-                                );
-                                
-                                handles.push(tokio::spawn(async move { worker.run().await }));
-                                session.parts.push(new_part);
+                                // Reuse the main sender
+                                handles.push(self.spawn_worker(session, new_id, &worker_tx, &mut workers, &mut pool));
                                 
                                 log::info!("Worker {} done, stealing from {} ({}-{})", 
                                     worker_id, slow_id, new_part_start, new_part_end);
@@ -344,26 +729,23 @@ impl Downloader {
                 Err(_) => {}
             }
             
-            // Periodically save
-            if let Some(path) = &session_file {
-                 if last_save.elapsed().as_secs() >= 1 {
-                     let _ = session.save(path).await;
-                     last_save = std::time::Instant::now();
-                 }
+            // Periodically checkpoint (fsync data, then atomically record offsets)
+            if let Some(path) = &session_file
+                && last_save.elapsed().as_secs() >= 1
+            {
+                if let Err(e) = session.checkpoint(path).await {
+                    log::warn!("Failed to checkpoint session: {}", e);
+                }
+                last_save = std::time::Instant::now();
             }
         }
 
         // Flush any remaining accumulated bytes to UI
-        if pending_bytes > 0 {
-            if let Some(obs) = &observer {
-                let active_count = session.parts.iter().filter(|p| !p.completed).count();
-                obs.on_progress(0, pending_bytes, active_count);
-            }
-        }
-
-        session.state = DownloadState::Completed;
-        if let Some(path) = &session_file {
-             let _ = session.save(path).await;
+        if pending_bytes > 0
+            && let Some(obs) = &observer
+        {
+            let active_count = session.parts.iter().filter(|p| !p.completed).count();
+            obs.on_progress(0, pending_bytes, active_count);
         }
 
         drop(rx);
@@ -373,8 +755,276 @@ impl Downloader {
             let _ = handle.await;
         }
 
+        if session.zip_entry.is_some() {
+            Self::set_state(session, &observer, DownloadState::Verifying);
+            if let Err(e) = crate::zip::extract(session).await {
+                log::error!("Extracting {:?} failed: {}", session.output_path, e);
+                Self::set_state(session, &observer, DownloadState::Error(e.to_string()));
+                if let Some(path) = &session_file {
+                    let _ = session.checkpoint(path).await;
+                }
+                return Err(e);
+            }
+        }
+
+        if let Some(expected) = session.expected_digest.clone() {
+            Self::set_state(session, &observer, DownloadState::Verifying);
+            if let Some(path) = &session_file {
+                let _ = session.checkpoint(path).await;
+            }
+
+            let result = expected.verify_file(&session.output_path).await;
+            session.verification = Some(Verification {
+                digest: expected.clone(),
+                source: session.digest_source.clone().unwrap_or(DigestSource::User),
+                verified: result.is_ok(),
+            });
+
+            if let Err(e) = result {
+                log::error!("Integrity check failed for {:?}: {}", session.output_path, e);
+                Self::set_state(session, &observer, DownloadState::Error(e.to_string()));
+                if let Some(path) = &session_file {
+                    let _ = session.checkpoint(path).await;
+                }
+                return Err(e);
+            }
+            log::info!("Verified {:?} against {}", session.output_path, expected);
+        } else if let Some(etag) = session.multipart_etag.clone() {
+            Self::set_state(session, &observer, DownloadState::Verifying);
+            if let Err(e) = etag.verify_file(&session.output_path).await {
+                log::error!("Integrity check failed for {:?}: {}", session.output_path, e);
+                Self::set_state(session, &observer, DownloadState::Error(e.to_string()));
+                if let Some(path) = &session_file {
+                    let _ = session.checkpoint(path).await;
+                }
+                return Err(e);
+            }
+            log::info!("Verified {:?} against multipart ETag {}", session.output_path, etag);
+        }
+
+        if let Some(job) = &session.delta
+            && job.remove_seed
+            && let Err(e) = tokio::fs::remove_file(&job.seed).await
+        {
+            log::warn!("Failed to remove {:?}: {}", job.seed, e);
+        }
+
+        Self::set_state(session, &observer, DownloadState::Completed);
+        if let Some(path) = &session_file
+            && let Err(e) = session.checkpoint(path).await
+        {
+            log::warn!("Failed to checkpoint session: {}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Makes the download continue from `offset`: queued parts from there on
+    /// move to the front, and a running part still far from `offset` is
+    /// split there so the rest is fetched next.
+    fn prioritize(session: &mut DownloadSession, offset: u64, queued: &mut VecDeque<u32>, workers: &HashMap<u32, RunningWorker>) {
+        let Some(index) = session.parts.iter().position(|p| !p.completed && p.start_byte <= offset && offset <= p.end_byte) else {
+            return;
+        };
+        let DownloadPart { id, start_byte, end_byte, current_byte, .. } = session.parts[index];
+        let mut first = start_byte;
+        if let Some(worker) = workers.get(&id) {
+            if offset < current_byte + SEEK_SPLIT_DISTANCE {
+                // Its worker gets there soon enough
+                return;
+            }
+            let new_id = session.next_part_id();
+            session.parts[index].end_byte = offset - 1;
+            worker.end.store(offset - 1 + session.remote_offset, Ordering::Relaxed);
+            session.parts.push(DownloadPart {
+                id: new_id,
+                start_byte: offset,
+                end_byte,
+                current_byte: offset,
+                completed: false,
+            });
+            queued.push_back(new_id);
+            first = offset;
+            log::info!("Seek to {}: part {} now ends at {}, {}-{} queued", offset, id, offset - 1, offset, end_byte);
+        }
+
+        // Queued parts from the seek position on come first, in file order
+        let starts: HashMap<u32, u64> = session.parts.iter().map(|p| (p.id, p.start_byte)).collect();
+        queued.make_contiguous().sort_by_key(|id| {
+            let start = starts[id];
+            (start < first, start)
+        });
+    }
+
+    /// Compares the tail of each part's downloaded data with the server and
+    /// rolls `current_byte` back to the first byte that differs.
+    ///
+    /// Walks back one window at a time until a window matches, so a file
+    /// that was truncated or whose last writes never reached the disk loses
+    /// only the damaged span.
+    pub async fn verify_resume_offsets(&self, session: &mut DownloadSession) -> Result<()> {
+        let window = self.resume_check_window.max(1);
+        let mut file = tokio::fs::File::open(session.data_path()).await?;
+        let remote_offset = session.remote_offset;
+
+        for part in session.parts.iter_mut().filter(|p| p.current_byte > p.start_byte) {
+            let mut end = part.current_byte;
+            let mut rollback_to = None;
+            let mut windows = 0;
+
+            loop {
+                let start = end.saturating_sub(window).max(part.start_byte);
+                let Some(remote) = self.fetch_range(&session.url, start + remote_offset, end - 1 + remote_offset).await? else {
+                    log::warn!("Server ignored range request, skipping resume check");
+                    return Ok(());
+                };
+                // A short read means the file was truncated; that counts as a mismatch
+                let local = read_at(&mut file, start, remote.len()).await?;
+                let Some(offset) = (0..remote.len()).position(|i| local.get(i) != Some(&remote[i])) else {
+                    break;
+                };
+
+                rollback_to = Some(start + offset as u64);
+                if offset > 0 || start == part.start_byte {
+                    break;
+                }
+
+                // The whole window is bad; the damage may extend further back
+                windows += 1;
+                if windows >= MAX_RESUME_CHECK_WINDOWS {
+                    rollback_to = Some(part.start_byte);
+                    break;
+                }
+                end = start;
+            }
+
+            if let Some(offset) = rollback_to {
+                log::warn!(
+                    "Part {} data on disk differs from the server, resuming from {} instead of {}",
+                    part.id, offset, part.current_byte
+                );
+                // Pieces checked before the crash no longer hold those bytes
+                if let Some(pieces) = session.piece_hashes.as_mut() {
+                    pieces.clear_verified(offset, part.current_byte - 1);
+                }
+                part.current_byte = offset;
+                part.completed = false;
+            }
+        }
         Ok(())
     }
+
+    /// Reads `start..=end`. Returns `None` if the server ignores the range.
+    pub(crate) async fn fetch_range(&self, url: &str, start: u64, end: u64) -> Result<Option<Vec<u8>>> {
+        let stream = match self.transport_for(url)?.read_range(url, start, end).await {
+            Ok(stream) => stream,
+            Err(TransportError::RangeIgnored) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let len = (end - start + 1) as usize;
+        Ok(Some(crate::transport::collect(stream, len).await?))
+    }
+
+    /// Starts a worker for the remaining bytes of part `part_id` on the
+    /// mirror the pool picks.
+    fn spawn_worker(
+        &self,
+        session: &DownloadSession,
+        part_id: u32,
+        tx: &mpsc::Sender<(u32, u64, u8)>,
+        workers: &mut HashMap<u32, RunningWorker>,
+        pool: &mut MirrorPool,
+    ) -> JoinHandle<Result<()>> {
+        let part = session
+            .parts
+            .iter()
+            .find(|p| p.id == part_id)
+            .expect("spawn_worker called for unknown part");
+
+        let now = Instant::now();
+        let url = pool.pick(now).unwrap_or(&session.url).to_string();
+        pool.started(&url, now);
+
+        // Workers count in remote offsets and write at the same offset in
+        // the data file, less `remote_offset`
+        let atomic_end = Arc::new(AtomicU64::new(part.end_byte + session.remote_offset));
+        workers.insert(part.id, RunningWorker { end: atomic_end.clone(), url: url.clone(), started: now });
+
+        let transport = match self.transport_for(&url) {
+            Ok(transport) => transport,
+            Err(e) => {
+                // Reported like any other worker failure so the mirror is dropped
+                let (tx, id) = (tx.clone(), part.id);
+                return tokio::spawn(async move {
+                    let _ = tx.send((id, 0, 2)).await;
+                    Err(e)
+                });
+            }
+        };
+        let worker = Worker::new(
+            part.id,
+            url,
+            (part.current_byte + session.remote_offset, part.end_byte + session.remote_offset),
+            session.data_path(),
+            transport,
+            tx.clone(),
+            Some(atomic_end),
+        )
+        .writing_at(part.current_byte)
+        .limited_by(self.rate_limiter.clone());
+        tokio::spawn(async move { worker.run().await })
+    }
+
+    /// Starts hashing every piece that became fully downloaded. Returns the
+    /// number of checks started.
+    fn schedule_piece_checks(
+        session: &DownloadSession,
+        in_flight: &mut HashSet<usize>,
+        results: &mpsc::UnboundedSender<(usize, bool)>,
+    ) -> usize {
+        let (Some(pieces), Some(size)) = (&session.piece_hashes, session.total_size) else {
+            return 0;
+        };
+
+        let mut started = 0;
+        for index in pieces.ready_pieces(session) {
+            if !in_flight.insert(index) {
+                continue;
+            }
+            let (start, end) = pieces.piece_range(index, size);
+            let expected = pieces.hashes[index].clone();
+            let algorithm = pieces.algorithm;
+            let path = session.data_path();
+            let results = results.clone();
+            tokio::spawn(async move {
+                let matched = match crate::integrity::hash_range(&path, algorithm, start, end - start + 1).await {
+                    Ok(actual) => actual == expected,
+                    Err(e) => {
+                        log::warn!("Failed to hash piece {}: {}", index, e);
+                        false
+                    }
+                };
+                let _ = results.send((index, matched));
+            });
+            started += 1;
+        }
+        started
+    }
+
+    pub(crate) fn set_state(session: &mut DownloadSession, observer: &Option<Arc<dyn DownloadObserver>>, state: DownloadState) {
+        if let Some(obs) = observer {
+            obs.on_state_change(&state);
+        }
+        session.state = state;
+    }
+}
+
+async fn read_at(file: &mut tokio::fs::File, offset: u64, len: usize) -> Result<Vec<u8>> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut buf = Vec::with_capacity(len);
+    file.take(len as u64).read_to_end(&mut buf).await?;
+    Ok(buf)
 }
 
 #[cfg(test)]
@@ -383,7 +1033,7 @@ mod tests {
     use std::sync::Mutex;
 
     struct MockObserver {
-        progress: Mutex<Vec<(u8, u64, usize)>>,
+        progress: Mutex<Vec<(u32, u64, usize)>>,
     }
 
     impl MockObserver {
@@ -395,7 +1045,7 @@ mod tests {
     }
 
     impl DownloadObserver for MockObserver {
-        fn on_progress(&self, worker_id: u8, bytes_downloaded: u64, active_workers: usize) {
+        fn on_progress(&self, worker_id: u32, bytes_downloaded: u64, active_workers: usize) {
             self.progress.lock().unwrap().push((worker_id, bytes_downloaded, active_workers));
         }
     }
@@ -413,4 +1063,254 @@ mod tests {
         assert_eq!(progress.len(), 1);
         assert_eq!(progress[0], (1, 1024, 1));
     }
+
+    #[tokio::test]
+    async fn resume_check_rolls_back_damaged_tail() {
+        use crate::integrity::HashAlgorithm;
+        use crate::pieces::PieceHashes;
+        use crate::session::DownloadPart;
+        use crate::test_support;
+
+        let body = test_support::payload(200 * 1024);
+        let server = test_support::serve([("/file.bin".to_string(), body.clone())].into(), HashMap::new(), None).await;
+
+        // Part 0 is intact up to its offset; part 1 claims 150000 bytes but
+        // everything from 120000 on was lost in a crash
+        let output = test_support::temp_path("resume-check");
+        let mut on_disk = body.clone();
+        on_disk[120_000..].fill(0);
+        std::fs::write(&output, &on_disk).unwrap();
+
+        let mut session = DownloadSession::new(server.url("/file.bin"), output, 2);
+        session.total_size = Some(body.len() as u64);
+        session.parts = vec![
+            DownloadPart { id: 0, start_byte: 0, end_byte: 99_999, current_byte: 90_000, completed: false },
+            DownloadPart { id: 1, start_byte: 100_000, end_byte: 204_799, current_byte: 150_000, completed: false },
+        ];
+        let mut pieces = PieceHashes::new(HashAlgorithm::Md5, 10_000, vec!["0".repeat(32); 21]).unwrap();
+        for index in 0..15 {
+            pieces.verified.set(index, true);
+        }
+        session.piece_hashes = Some(pieces);
+
+        let downloader = Downloader::new("test").unwrap().with_resume_check(16 * 1024);
+        downloader.verify_resume_offsets(&mut session).await.unwrap();
+        assert_eq!(session.parts[0].current_byte, 90_000);
+        // Windows [133616, 150000) and [117232, 133616) differ, the second from 120000
+        assert_eq!(session.parts[1].current_byte, 120_000);
+        // Pieces 12-14 held the rolled-back bytes and must be checked again
+        let verified = &session.piece_hashes.as_ref().unwrap().verified;
+        assert_eq!((0..15).filter(|&i| verified.get(i)).collect::<Vec<_>>(), (0..12).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn corrupted_piece_is_downloaded_again() {
+        use crate::integrity::{HashAlgorithm, Hasher};
+        use crate::pieces::PieceHashes;
+        use crate::test_support;
+        use std::sync::atomic::AtomicBool;
+
+        let body = test_support::payload(64 * 1024);
+        let piece_length = 16 * 1024;
+        let hashes = body
+            .chunks(piece_length)
+            .map(|chunk| {
+                let mut hasher = Hasher::new(HashAlgorithm::Md5);
+                hasher.update(chunk);
+                hasher.finalize()
+            })
+            .collect();
+
+        // Flip one byte in the first response that carries offset 20000
+        let corrupted = Arc::new(AtomicBool::new(false));
+        let tamper: test_support::Tamper = Arc::new(move |_, start, bytes: &mut [u8]| {
+            let target = 20_000u64;
+            if bytes.len() > 1
+                && start <= target
+                && target < start + bytes.len() as u64
+                && !corrupted.swap(true, Ordering::SeqCst)
+            {
+                bytes[(target - start) as usize] ^= 0xff;
+            }
+        });
+        let server = test_support::serve(
+            [("/file.bin".to_string(), body.clone())].into(),
+            HashMap::new(),
+            Some(tamper),
+        ).await;
+
+        let output = test_support::temp_path("pieces");
+        let downloader = Downloader::new("test").unwrap();
+        let mut session = downloader.init_download(&server.url("/file.bin"), Some(output.clone()), 2).await.unwrap();
+        session.piece_hashes = Some(PieceHashes::new(HashAlgorithm::Md5, piece_length as u64, hashes).unwrap());
+
+        downloader.run(&mut session, None, None, None).await.unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), body);
+        let pieces = session.piece_hashes.unwrap();
+        assert_eq!(pieces.verified.count_ones(), pieces.len());
+        // Probe + two parts + one piece re-fetch
+        assert_eq!(server.requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn metalink_download_fails_over_between_mirrors() {
+        use crate::test_support;
+
+        let body = test_support::payload(48 * 1024);
+        let server = test_support::serve([("/file.bin".to_string(), body.clone())].into(), HashMap::new(), None).await;
+        let dead = server.url("/gone.bin");
+        let file = MetalinkFile {
+            name: "nested/file.bin".into(),
+            size: Some(body.len() as u64),
+            digest: None,
+            pieces: None,
+            mirrors: vec![
+                Mirror { url: dead.clone(), priority: 1, location: None },
+                Mirror { url: server.url("/file.bin"), priority: 2, location: None },
+            ],
+        };
+
+        let dir = test_support::temp_path("metalink").parent().unwrap().to_path_buf();
+        let downloader = Downloader::new("test").unwrap();
+        let mut session = downloader.init_from_metalink(&file, &dir, 2).await.unwrap();
+        assert_eq!(session.url, server.url("/file.bin"));
+        assert_eq!(session.output_path, dir.join("nested/file.bin"));
+
+        // A dead preferred mirror is skipped when the run starts
+        session.url = dead;
+        downloader.run(&mut session, None, None, None).await.unwrap();
+        assert_eq!(std::fs::read(&session.output_path).unwrap(), body);
+        assert_eq!(session.url, server.url("/file.bin"));
+    }
+
+    #[tokio::test]
+    async fn parts_are_spread_across_consistent_mirrors() {
+        use crate::test_support::{self, FAIL_RANGED};
+
+        let body = test_support::payload(256 * 1024);
+        let etag = |tag: &str| vec![("ETag".to_string(), format!("\"{}\"", tag))];
+        let files = || [("/file.bin".to_string(), body.clone())].into();
+        let a = test_support::serve(files(), [("/file.bin".to_string(), etag("v1"))].into(), None).await;
+        let b = test_support::serve(files(), [("/file.bin".to_string(), etag("v1"))].into(), None).await;
+        // Same size but another revision of the file
+        let stale = test_support::serve(files(), [("/file.bin".to_string(), etag("v0"))].into(), None).await;
+        // Passes the check, then fails every part it is given
+        let mut flaky_headers = etag("v1");
+        flaky_headers.push((FAIL_RANGED.to_string(), "1".to_string()));
+        let flaky = test_support::serve(files(), [("/file.bin".to_string(), flaky_headers)].into(), None).await;
+
+        let output = test_support::temp_path("multi-source");
+        let downloader = Downloader::new("test").unwrap();
+        let mut session = downloader.init_download(&a.url("/file.bin"), Some(output.clone()), 4).await.unwrap();
+        session.mirrors = [&a, &b, &stale, &flaky]
+            .iter()
+            .enumerate()
+            .map(|(i, server)| Mirror { url: server.url("/file.bin"), priority: i as u32 + 1, location: None })
+            .collect();
+
+        downloader.run(&mut session, None, None, None).await.unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), body);
+        let mirrors: Vec<_> = session.mirrors.iter().map(|m| m.url.clone()).collect();
+        assert!(!mirrors.contains(&stale.url("/file.bin")));
+        // Init probe + check probe, then at least one part each
+        assert!(a.requests.load(Ordering::SeqCst) >= 3);
+        assert!(b.requests.load(Ordering::SeqCst) >= 2);
+        assert!(flaky.requests.load(Ordering::SeqCst) >= 2);
+    }
+
+    #[tokio::test]
+    async fn advertised_duplicates_become_mirrors() {
+        use crate::integrity::Hasher;
+        use crate::test_support;
+        use base64::Engine;
+
+        let body = test_support::payload(32 * 1024);
+        let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+        hasher.update(&body);
+        let digest = base64::engine::general_purpose::STANDARD.encode(hex::decode(hasher.finalize()).unwrap());
+
+        let mirror = test_support::serve([("/pub/file.bin".to_string(), body.clone())].into(), HashMap::new(), None).await;
+        let headers = vec![
+            ("Link".to_string(), format!("<{}>; rel=duplicate; pri=1; geo=nl", mirror.url("/pub/file.bin"))),
+            ("Digest".to_string(), format!("SHA-256={}", digest)),
+        ];
+        let origin = test_support::serve(
+            [("/file.bin".to_string(), body.clone())].into(),
+            [("/file.bin".to_string(), headers)].into(),
+            None,
+        ).await;
+
+        let output = test_support::temp_path("rfc6249");
+        let downloader = Downloader::new("test").unwrap();
+        let mut session = downloader.init_download(&origin.url("/file.bin"), Some(output.clone()), 2).await.unwrap();
+        assert_eq!(session.mirrors.len(), 1);
+        assert_eq!(session.mirrors[0].location.as_deref(), Some("nl"));
+        assert_eq!(session.digest_source, Some(DigestSource::Header("Digest".to_string())));
+
+        downloader.run(&mut session, None, None, None).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), body);
+        assert!(session.verification.unwrap().verified);
+        // Probe during the mirror check, then a part
+        assert!(mirror.requests.load(Ordering::SeqCst) >= 2);
+
+        let session = Downloader::new("test").unwrap()
+            .with_mirror_discovery(false)
+            .init_download(&origin.url("/file.bin"), Some(output), 2).await.unwrap();
+        assert!(session.mirrors.is_empty());
+    }
+
+    #[test]
+    fn seek_moves_and_splits_parts() {
+        let mut session = DownloadSession::new("http://x/f".into(), "f".into(), 2);
+        session.total_size = Some(64 * 1024 * 1024);
+        session.connections = 8;
+        Downloader::plan_parts(&mut session, true);
+        // Parts 0 and 1 are running, 2..8 queued
+        let mut queued: VecDeque<u32> = (2..8).collect();
+        let worker = |end: u64| RunningWorker { end: Arc::new(AtomicU64::new(end)), url: String::new(), started: Instant::now() };
+        let workers: HashMap<u32, RunningWorker> = [(0, worker(session.parts[0].end_byte)), (1, worker(session.parts[1].end_byte))].into();
+
+        let part_size = 8 * 1024 * 1024;
+        Downloader::prioritize(&mut session, 5 * part_size + 10, &mut queued, &workers);
+        assert_eq!(Vec::from(queued.clone()), [5, 6, 7, 2, 3, 4]);
+
+        // Close to a running worker: leave it be
+        Downloader::prioritize(&mut session, part_size + 100, &mut queued, &workers);
+        assert_eq!(session.parts.len(), 8);
+
+        // Far ahead of it: split and fetch the rest next
+        let offset = part_size + part_size / 2;
+        Downloader::prioritize(&mut session, offset, &mut queued, &workers);
+        assert_eq!(session.parts[1].end_byte, offset - 1);
+        assert_eq!(workers[&1].end.load(Ordering::Relaxed), offset - 1);
+        assert_eq!(queued.front(), Some(&8));
+        assert_eq!((session.parts[8].start_byte, session.parts[8].end_byte), (offset, 2 * part_size - 1));
+    }
+
+    #[test]
+    fn repeated_seeks_keep_part_ids_unique() {
+        let mut session = DownloadSession::new("http://x/f".into(), "f".into(), 1);
+        session.total_size = Some(1024 * 1024 * 1024);
+        Downloader::plan_parts(&mut session, true);
+        let end = Arc::new(AtomicU64::new(session.parts[0].end_byte));
+        let workers: HashMap<u32, RunningWorker> =
+            [(0, RunningWorker { end: end.clone(), url: String::new(), started: Instant::now() })].into();
+        let mut queued = VecDeque::new();
+
+        // Each seek lands in the running part, far ahead of its worker
+        let mut offset = session.parts[0].end_byte;
+        for _ in 0..300 {
+            offset -= 2 * 1024 * 1024;
+            Downloader::prioritize(&mut session, offset, &mut queued, &workers);
+        }
+        assert_eq!(session.parts.len(), 301);
+        assert_eq!(queued.len(), 300);
+        let ids: HashSet<u32> = session.parts.iter().map(|p| p.id).collect();
+        assert_eq!(ids.len(), 301);
+        assert_eq!(end.load(Ordering::Relaxed), offset - 1);
+        session.parts.sort_by_key(|p| p.start_byte);
+        assert!(session.validate().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,433 @@
+//! HLS (`.m3u8`) streams: playlist parsing and variant selection.
+//!
+//! Media playlists become a [`SegmentJob`]; TS segments are joined into a
+//! `.ts` file, fMP4 segments (with their `EXT-X-MAP` init section) into an
+//! `.mp4`.
+
+use super::downloader::Downloader;
+use super::segments::{Segment, SegmentJob, SegmentKey};
+use super::session::{DownloadSession, DownloadState};
+use anyhow::{Context, Result};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// True if `url` points at an HLS playlist.
+pub fn is_hls_source(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or(url).to_ascii_lowercase();
+    path.ends_with(".m3u8") || path.ends_with(".m3u")
+}
+
+/// A parsed playlist.
+#[derive(Debug, Clone)]
+pub enum Playlist {
+    Master(Vec<Variant>),
+    Media(MediaPlaylist),
+}
+
+/// One `EXT-X-STREAM-INF` entry of a master playlist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub uri: String,
+    pub bandwidth: u64,
+    pub resolution: Option<(u32, u32)>,
+    pub codecs: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaPlaylist {
+    /// Segments in playback order, `EXT-X-MAP` init sections inline.
+    pub segments: Vec<Segment>,
+    /// False for live playlists, which are downloaded as they are now.
+    pub ended: bool,
+}
+
+impl MediaPlaylist {
+    /// fMP4 streams carry an init section; plain TS streams do not.
+    pub fn is_fmp4(&self) -> bool {
+        self.segments.iter().any(|s| s.init)
+    }
+}
+
+
+/// Which variant of a master playlist to download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VariantSelection {
+    #[default]
+    Best,
+    Worst,
+    /// Best variant no taller than this many lines, e.g. `720p`.
+    MaxHeight(u32),
+    /// Best variant within this many bits per second, e.g. `3000k`.
+    MaxBandwidth(u64),
+}
+
+impl VariantSelection {
+    pub fn select<'a>(&self, variants: &'a [Variant]) -> Option<&'a Variant> {
+        self.pick(variants, |v| v.bandwidth, |v| v.resolution.map(|(_, h)| h))
+    }
+
+    /// Applies the selection to any list of renditions, given how to read
+    /// their bandwidth and height.
+    pub fn pick<'a, T>(
+        &self,
+        items: &'a [T],
+        bandwidth: impl Fn(&T) -> u64,
+        height: impl Fn(&T) -> Option<u32>,
+    ) -> Option<&'a T> {
+        let lowest = items.iter().min_by_key(|v| bandwidth(v));
+        let best_where = |keep: &dyn Fn(&T) -> bool| {
+            items.iter().filter(|v| keep(v)).max_by_key(|v| bandwidth(v)).or(lowest)
+        };
+        match *self {
+            VariantSelection::Best => items.iter().max_by_key(|v| bandwidth(v)),
+            VariantSelection::Worst => lowest,
+            VariantSelection::MaxHeight(max) => best_where(&|v| height(v).is_some_and(|h| h <= max)),
+            VariantSelection::MaxBandwidth(max) => best_where(&|v| bandwidth(v) <= max),
+        }
+    }
+}
+
+impl FromStr for VariantSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_ascii_lowercase();
+        let parsed = match s.as_str() {
+            "best" => Some(VariantSelection::Best),
+            "worst" => Some(VariantSelection::Worst),
+            _ if s.ends_with('p') => s[..s.len() - 1].parse().ok().map(VariantSelection::MaxHeight),
+            _ if s.ends_with('k') => s[..s.len() - 1].parse::<u64>().ok().map(|k| VariantSelection::MaxBandwidth(k * 1000)),
+            _ => s.parse().ok().map(VariantSelection::MaxBandwidth),
+        };
+        parsed.ok_or_else(|| anyhow::anyhow!("invalid variant {:?}: expected best, worst, <height>p or <kbps>k", s))
+    }
+}
+
+/// Parses a master or media playlist fetched from `base_url`.
+pub fn parse(text: &str, base_url: &str) -> Result<Playlist> {
+    let base = Url::parse(base_url).with_context(|| format!("invalid playlist URL {}", base_url))?;
+    let resolve = |uri: &str| -> Result<String> {
+        Ok(base.join(uri.trim()).with_context(|| format!("invalid URI {:?} in playlist", uri))?.to_string())
+    };
+
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        anyhow::bail!("not an HLS playlist: missing #EXTM3U");
+    }
+
+    let mut variants = Vec::new();
+    let mut pending_variant: Option<Variant> = None;
+
+    let mut segments = Vec::new();
+    let mut media_sequence: u64 = 0;
+    let mut key: Option<(String, Option<[u8; 16]>)> = None;
+    let mut map: Option<Segment> = None;
+    let mut pending_range: Option<(u64, Option<u64>)> = None;
+    let mut last_range_end: Option<(String, u64)> = None;
+    let mut ended = false;
+
+    for line in lines {
+        if let Some(attrs) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            let attrs = parse_attributes(attrs);
+            pending_variant = Some(Variant {
+                uri: String::new(),
+                bandwidth: attrs.get("BANDWIDTH").and_then(|b| b.parse().ok()).unwrap_or(0),
+                resolution: attrs.get("RESOLUTION").and_then(|r| {
+                    let (w, h) = r.split_once(['x', 'X'])?;
+                    Some((w.parse().ok()?, h.parse().ok()?))
+                }),
+                codecs: attrs.get("CODECS").cloned(),
+            });
+        } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            media_sequence = value.trim().parse().unwrap_or(0);
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-KEY:") {
+            let attrs = parse_attributes(attrs);
+            key = match attrs.get("METHOD").map(String::as_str) {
+                Some("NONE") | None => None,
+                Some("AES-128") => {
+                    let uri = attrs.get("URI").context("EXT-X-KEY without URI")?;
+                    let iv = attrs.get("IV").map(|iv| parse_iv(iv)).transpose()?;
+                    Some((resolve(uri)?, iv))
+                }
+                Some(other) => anyhow::bail!("unsupported HLS encryption method {}", other),
+            };
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-MAP:") {
+            let attrs = parse_attributes(attrs);
+            let uri = resolve(attrs.get("URI").context("EXT-X-MAP without URI")?)?;
+            let byte_range = attrs.get("BYTERANGE").map(|r| parse_byte_range(r)).transpose()?
+                .map(|(len, offset)| (offset.unwrap_or(0), len));
+            map = Some(Segment { uri, byte_range, key: None, init: true, done: false });
+        } else if let Some(value) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+            pending_range = Some(parse_byte_range(value)?);
+        } else if line == "#EXT-X-ENDLIST" {
+            ended = true;
+        } else if line.starts_with('#') {
+            // EXTINF, EXT-X-DISCONTINUITY, comments and unknown tags need no handling
+        } else if let Some(mut variant) = pending_variant.take() {
+            variant.uri = resolve(line)?;
+            variants.push(variant);
+        } else {
+            let uri = resolve(line)?;
+            let byte_range = pending_range
+                .take()
+                .map(|(len, offset)| {
+                    let offset = offset.unwrap_or_else(|| match &last_range_end {
+                        Some((prev, end)) if *prev == uri => *end,
+                        _ => 0,
+                    });
+                    let end = offset.checked_add(len).context("byte range past the end of any file")?;
+                    last_range_end = Some((uri.clone(), end));
+                    Ok::<_, anyhow::Error>((offset, len))
+                })
+                .transpose()?;
+            let sequence = media_sequence + segments.iter().filter(|s: &&Segment| !s.init).count() as u64;
+            let key = key.as_ref().map(|(key_uri, iv)| SegmentKey {
+                uri: key_uri.clone(),
+                iv: iv.unwrap_or_else(|| (sequence as u128).to_be_bytes()),
+            });
+
+            // An init section applies to the segments after it until replaced
+            if let Some(mut init) = map.take() {
+                init.key = key.clone();
+                let repeated = segments.iter().rev().find(|s: &&Segment| s.init)
+                    .is_some_and(|prev| prev.uri == init.uri && prev.byte_range == init.byte_range);
+                if !repeated {
+                    segments.push(init);
+                }
+            }
+            segments.push(Segment { uri, byte_range, key, init: false, done: false });
+        }
+    }
+
+    if !variants.is_empty() {
+        return Ok(Playlist::Master(variants));
+    }
+    if segments.is_empty() {
+        anyhow::bail!("playlist has no segments");
+    }
+    Ok(Playlist::Media(MediaPlaylist { segments, ended }))
+}
+
+/// Splits `KEY=value,KEY="quoted, value"` attribute lists.
+fn parse_attributes(list: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = list.trim();
+    while !rest.is_empty() {
+        let Some((name, after)) = rest.split_once('=') else { break };
+        let (value, after) = if let Some(quoted) = after.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            (&quoted[..end], quoted.get(end + 1..).unwrap_or(""))
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+        attrs.insert(name.trim().to_ascii_uppercase(), value.to_string());
+        rest = after.trim_start_matches(',').trim();
+    }
+    attrs
+}
+
+/// `<length>[@<offset>]`.
+fn parse_byte_range(value: &str) -> Result<(u64, Option<u64>)> {
+    let value = value.trim();
+    let (len, offset) = match value.split_once('@') {
+        Some((len, offset)) => (len, Some(offset.parse::<u64>().context("invalid byte range offset")?)),
+        None => (value, None),
+    };
+    let len: u64 = len.parse().context("invalid byte range length")?;
+    anyhow::ensure!(len > 0, "empty byte range {}", value);
+    if let Some(offset) = offset {
+        offset.checked_add(len).with_context(|| format!("byte range {} past the end of any file", value))?;
+    }
+    Ok((len, offset))
+}
+
+fn parse_iv(value: &str) -> Result<[u8; 16]> {
+    let hex_digits = value.trim().trim_start_matches("0x").trim_start_matches("0X");
+    let bytes = hex::decode(format!("{:0>32}", hex_digits)).context("invalid IV")?;
+    bytes.try_into().map_err(|_| anyhow::anyhow!("IV must be 128 bits"))
+}
+
+
+impl Downloader {
+    /// Resolves `url` to a media playlist and creates a session for it.
+    /// Master playlists are narrowed down to one variant with `selection`.
+    pub async fn init_hls(
+        &self,
+        url: &str,
+        output_path: Option<PathBuf>,
+        connections: u8,
+        selection: VariantSelection,
+    ) -> Result<DownloadSession> {
+        let (mut playlist_url, text) = self.fetch_playlist(url).await?;
+        let mut playlist = parse(&text, &playlist_url)?;
+
+        if let Playlist::Master(variants) = &playlist {
+            let variant = selection.select(variants).context("master playlist has no variants")?;
+            log::info!(
+                "Selected HLS variant {} ({} bps, {:?})",
+                variant.uri, variant.bandwidth, variant.resolution
+            );
+            let (media_url, text) = self.fetch_playlist(&variant.uri).await?;
+            playlist = parse(&text, &media_url)?;
+            playlist_url = media_url;
+        }
+        let Playlist::Media(media) = playlist else {
+            anyhow::bail!("variant playlist {} is itself a master playlist", playlist_url);
+        };
+        if !media.ended {
+            log::warn!("{} is a live playlist; downloading the {} segments listed now", playlist_url, media.segments.len());
+        }
+
+        let output_path = output_path.unwrap_or_else(|| {
+            let stem = url
+                .split(['?', '#'])
+                .next()
+                .and_then(|u| u.rsplit('/').next())
+                .and_then(|name| name.rsplit_once('.').map(|(stem, _)| stem.to_string()))
+                .filter(|stem| !stem.is_empty())
+                .unwrap_or_else(|| "stream".to_string());
+            let ext = if media.is_fmp4() { "mp4" } else { "ts" };
+            crate::utils::fs::get_downloads_dir().join(format!("{}.{}", stem, ext))
+        });
+
+        let mut session = DownloadSession::new(url.to_string(), output_path, connections.max(1));
+        session.stream = Some(SegmentJob { manifest_url: playlist_url, segments: media.segments });
+        session.state = DownloadState::Downloading;
+        Ok(session)
+    }
+
+    async fn fetch_playlist(&self, url: &str) -> Result<(String, String)> {
+        let response = self.client().get(url).send().await?.error_for_status()?;
+        let final_url = response.url().to_string();
+        Ok((final_url, response.text().await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_variants_by_height_and_bandwidth() {
+        let master = "#EXTM3U\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"\n\
+            low/index.m3u8\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=2800000,RESOLUTION=1280x720\n\
+            mid/index.m3u8\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=5000000,RESOLUTION=1920x1080\n\
+            https://cdn.example/high/index.m3u8\n";
+        let Playlist::Master(variants) = parse(master, "https://example.com/video/master.m3u8").unwrap() else {
+            panic!("expected a master playlist");
+        };
+        assert_eq!(variants[0].codecs.as_deref(), Some("avc1.4d401e,mp4a.40.2"));
+
+        let pick = |s: &str| s.parse::<VariantSelection>().unwrap().select(&variants).unwrap().uri.clone();
+        assert_eq!(pick("best"), "https://cdn.example/high/index.m3u8");
+        assert_eq!(pick("worst"), "https://example.com/video/low/index.m3u8");
+        assert_eq!(pick("720p"), "https://example.com/video/mid/index.m3u8");
+        assert_eq!(pick("1000k"), "https://example.com/video/low/index.m3u8");
+        // Nothing fits: fall back to the smallest
+        assert_eq!(pick("100p"), "https://example.com/video/low/index.m3u8");
+    }
+
+    #[test]
+    fn parses_media_playlist_with_keys_ranges_and_map() {
+        let media = "#EXTM3U\n\
+            #EXT-X-TARGETDURATION:4\n\
+            #EXT-X-MEDIA-SEQUENCE:7\n\
+            #EXT-X-MAP:URI=\"init.mp4\"\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"../key.bin\"\n\
+            #EXTINF:4.0,\n\
+            #EXT-X-BYTERANGE:1000@0\n\
+            media.m4s\n\
+            #EXTINF:4.0,\n\
+            #EXT-X-BYTERANGE:500\n\
+            media.m4s\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"key2.bin\",IV=0x1\n\
+            #EXTINF:4.0,\n\
+            last.m4s\n\
+            #EXT-X-ENDLIST\n";
+        let Playlist::Media(playlist) = parse(media, "https://example.com/v/index.m3u8").unwrap() else {
+            panic!("expected a media playlist");
+        };
+        assert!(playlist.ended && playlist.is_fmp4());
+        let segments = &playlist.segments;
+        assert_eq!(segments.len(), 4);
+        assert!(segments[0].init);
+        assert_eq!(segments[1].byte_range, Some((0, 1000)));
+        assert_eq!(segments[2].byte_range, Some((1000, 500)));
+        let key = segments[1].key.as_ref().unwrap();
+        assert_eq!(key.uri, "https://example.com/key.bin");
+        assert_eq!(key.iv, 7u128.to_be_bytes());
+        assert_eq!(segments[2].key.as_ref().unwrap().iv, 8u128.to_be_bytes());
+        assert_eq!(segments[3].key.as_ref().unwrap().iv, 1u128.to_be_bytes());
+    }
+
+    #[test]
+    fn rejects_empty_and_overflowing_byte_ranges() {
+        for range in ["#EXT-X-BYTERANGE:0@0", "#EXT-X-BYTERANGE:0", "#EXT-X-BYTERANGE:1@18446744073709551615"] {
+            let media = format!("#EXTM3U\n#EXTINF:4.0,\n{}\nmedia.m4s\n#EXT-X-ENDLIST\n", range);
+            assert!(parse(&media, "https://example.com/v/index.m3u8").is_err(), "{}", range);
+        }
+        let map = "#EXTM3U\n#EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"0@0\"\n#EXTINF:4.0,\nmedia.m4s\n";
+        assert!(parse(map, "https://example.com/v/index.m3u8").is_err());
+
+        // Each range fits, but the second continues where the first ends
+        let continued = "#EXTM3U\n\
+            #EXTINF:4.0,\n#EXT-X-BYTERANGE:10@18446744073709551600\nmedia.m4s\n\
+            #EXTINF:4.0,\n#EXT-X-BYTERANGE:10\nmedia.m4s\n";
+        assert!(parse(continued, "https://example.com/v/index.m3u8").is_err());
+    }
+
+    #[tokio::test]
+    async fn downloads_decrypts_and_joins_segments() {
+        use crate::segments::segments_dir;
+        use crate::test_support;
+        use aes::cipher::{BlockEncryptMut, KeyIvInit, block_padding::Pkcs7};
+        use std::sync::atomic::Ordering;
+
+        let key = [7u8; 16];
+        let plain: Vec<Vec<u8>> = (0..5).map(|i| test_support::payload(10_000 + i * 1_000)).collect();
+        let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+        let mut playlist = String::from("#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:3\n#EXT-X-KEY:METHOD=AES-128,URI=\"/key\"\n");
+        for (i, data) in plain.iter().enumerate() {
+            let iv = (3 + i as u128).to_be_bytes();
+            let mut buf = data.clone();
+            buf.resize(data.len() + 16 - data.len() % 16, 0);
+            let encrypted = cbc::Encryptor::<aes::Aes128>::new(&key.into(), &iv.into())
+                .encrypt_padded_mut::<Pkcs7>(&mut buf, data.len())
+                .unwrap()
+                .to_vec();
+            files.insert(format!("/seg{}.ts", i), encrypted);
+            playlist.push_str(&format!("#EXTINF:2.0,\nseg{}.ts\n", i));
+        }
+        playlist.push_str("#EXT-X-ENDLIST\n");
+        files.insert("/key".into(), key.to_vec());
+        files.insert("/video.m3u8".into(), playlist.into_bytes());
+        let server = test_support::serve(files, HashMap::new(), None).await;
+
+        let output = test_support::temp_path("hls").with_extension("ts");
+        let downloader = Downloader::new("test").unwrap();
+        let mut session = downloader
+            .init_hls(&server.url("/video.m3u8"), Some(output.clone()), 3, VariantSelection::Best)
+            .await
+            .unwrap();
+
+        // Pretend segment 1 survived an earlier run
+        let dir = segments_dir(&output);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("00001.seg"), &plain[1]).unwrap();
+        session.stream.as_mut().unwrap().segments[1].done = true;
+
+        downloader.run(&mut session, None, None, None).await.unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), plain.concat());
+        assert_eq!(session.state, DownloadState::Completed);
+        assert!(!dir.exists());
+        // Playlist, four segments and the key once
+        assert_eq!(server.requests.load(Ordering::SeqCst), 6);
+    }
+}
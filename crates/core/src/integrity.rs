@@ -0,0 +1,496 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    Sha1,
+    Md5,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Length of the digest in bytes.
+    pub fn digest_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 32,
+            HashAlgorithm::Sha512 => 64,
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Md5 => 16,
+        }
+    }
+
+    /// Preference order when a source offers several digests, strongest first.
+    pub fn strength(self) -> u8 {
+        match self {
+            HashAlgorithm::Sha512 => 5,
+            HashAlgorithm::Blake3 => 4,
+            HashAlgorithm::Sha256 => 3,
+            HashAlgorithm::Sha1 => 2,
+            HashAlgorithm::Md5 => 1,
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Blake3 => "blake3",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = IntegrityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "md5" => Ok(HashAlgorithm::Md5),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            other => Err(IntegrityError::InvalidSpec(format!("unsupported hash algorithm '{}'", other))),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IntegrityError {
+    #[error("{algorithm} mismatch: expected {expected}, got {actual}")]
+    Mismatch {
+        algorithm: HashAlgorithm,
+        expected: String,
+        actual: String,
+    },
+    #[error("multipart ETag mismatch: expected {expected}, got {actual}")]
+    EtagMismatch { expected: String, actual: String },
+    #[error("piece {index} failed verification {attempts} times")]
+    PieceMismatch { index: usize, attempts: u32 },
+    #[error("invalid checksum: {0}")]
+    InvalidSpec(String),
+}
+
+/// A digest the finished file is expected to have, kept as lowercase hex.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExpectedDigest {
+    pub algorithm: HashAlgorithm,
+    pub value: String,
+}
+
+impl ExpectedDigest {
+    pub fn new(algorithm: HashAlgorithm, value: &str) -> Result<Self, IntegrityError> {
+        let value = value.trim().to_ascii_lowercase();
+        let valid_hex = value.len() == algorithm.digest_len() * 2
+            && value.bytes().all(|b| b.is_ascii_hexdigit());
+        if !valid_hex {
+            return Err(IntegrityError::InvalidSpec(format!(
+                "expected {} hex characters for {}",
+                algorithm.digest_len() * 2,
+                algorithm
+            )));
+        }
+        Ok(Self { algorithm, value })
+    }
+
+    pub fn from_bytes(algorithm: HashAlgorithm, bytes: &[u8]) -> Result<Self, IntegrityError> {
+        Self::new(algorithm, &hex::encode(bytes))
+    }
+
+    /// Compares against the digest of `path`.
+    pub async fn verify_file(&self, path: &Path) -> anyhow::Result<()> {
+        let actual = hash_file(path, self.algorithm).await?;
+        if actual != self.value {
+            return Err(IntegrityError::Mismatch {
+                algorithm: self.algorithm,
+                expected: self.value.clone(),
+                actual,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ExpectedDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.value)
+    }
+}
+
+/// Parses `algorithm:hex`, e.g. `sha256:9f86d0...`.
+impl FromStr for ExpectedDigest {
+    type Err = IntegrityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, value) = s
+            .split_once(':')
+            .ok_or_else(|| IntegrityError::InvalidSpec("expected <algorithm>:<hex digest>".into()))?;
+        Self::new(algorithm.parse()?, value)
+    }
+}
+
+/// Where the expected digest of a session came from.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DigestSource {
+    /// Supplied by the user (`--checksum`, GUI field or deep link).
+    User,
+    /// Advertised by the server in the named response header.
+    Header(String),
+    /// Read from a checksum file published next to the download.
+    ChecksumFile(String),
+    /// Taken from a Metalink document.
+    Metalink,
+    /// The `SHA-1` line of a zsync control file at this path or URL.
+    Zsync(String),
+}
+
+impl fmt::Display for DigestSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DigestSource::User => f.write_str("user"),
+            DigestSource::Header(name) => write!(f, "{} header", name),
+            DigestSource::ChecksumFile(url) => write!(f, "{}", url),
+            DigestSource::Metalink => f.write_str("metalink"),
+            DigestSource::Zsync(source) => write!(f, "{}", source),
+        }
+    }
+}
+
+/// Outcome of the post-download integrity check, kept in the session.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Verification {
+    pub digest: ExpectedDigest,
+    pub source: DigestSource,
+    pub verified: bool,
+}
+
+/// ETag S3 assigns to multipart uploads: the MD5 of the concatenated
+/// binary MD5s of every part, then `-<part count>`.
+///
+/// Only checkable when all parts but the last have the same size, which is
+/// what the AWS CLI, SDKs and MinIO client produce.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MultipartEtag {
+    /// Lowercase hex, without the part count.
+    pub value: String,
+    pub parts: u64,
+    pub part_size: u64,
+}
+
+impl MultipartEtag {
+    /// Parses `"<hex>-<parts>"`. Returns `None` for single-part ETags and
+    /// when `part_size` cannot produce `parts` parts of `total_size`.
+    pub fn parse(etag: &str, part_size: u64, total_size: u64) -> Option<Self> {
+        let (value, parts) = etag.trim_matches('"').split_once('-')?;
+        let parts: u64 = parts.parse().ok()?;
+        let value = ExpectedDigest::new(HashAlgorithm::Md5, value).ok()?.value;
+        if part_size == 0 || parts == 0 || total_size.div_ceil(part_size) != parts {
+            return None;
+        }
+        Some(Self { value, parts, part_size })
+    }
+
+    pub async fn verify_file(&self, path: &Path) -> anyhow::Result<()> {
+        let mut combined = Hasher::new(HashAlgorithm::Md5);
+        for part in 0..self.parts {
+            let md5 = hash_range(path, HashAlgorithm::Md5, part * self.part_size, self.part_size).await?;
+            combined.update(&hex::decode(md5)?);
+        }
+        let actual = combined.finalize();
+        if actual != self.value {
+            return Err(IntegrityError::EtagMismatch { expected: self.to_string(), actual: format!("{}-{}", actual, self.parts) }.into());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MultipartEtag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.value, self.parts)
+    }
+}
+
+fn algorithm_from_token(token: &str) -> Option<HashAlgorithm> {
+    match token.trim().to_ascii_lowercase().as_str() {
+        "sha-256" | "sha256" => Some(HashAlgorithm::Sha256),
+        "sha-512" | "sha512" => Some(HashAlgorithm::Sha512),
+        "sha" | "sha-1" | "sha1" => Some(HashAlgorithm::Sha1),
+        "md5" => Some(HashAlgorithm::Md5),
+        "blake3" => Some(HashAlgorithm::Blake3),
+        _ => None,
+    }
+}
+
+fn decode_base64_digest(algorithm: HashAlgorithm, value: &str) -> Option<ExpectedDigest> {
+    let bytes = BASE64.decode(value.trim().trim_matches(':')).ok()?;
+    ExpectedDigest::from_bytes(algorithm, &bytes).ok()
+}
+
+/// Collects whole-file digests advertised in response headers, strongest first.
+///
+/// Understands `Repr-Digest` (RFC 9530), `Digest` (RFC 3230), `Content-MD5`,
+/// `x-goog-hash` and `x-amz-checksum-*`. `partial` must be set for `206`
+/// responses: `Content-MD5` then only covers the returned range and is ignored.
+pub fn advertised_digests(headers: &HeaderMap, partial: bool) -> Vec<(ExpectedDigest, DigestSource)> {
+    let mut found = Vec::new();
+    let values = |name: &str| -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(',').map(|s| s.trim().to_string()).collect::<Vec<_>>())
+            .collect()
+    };
+
+    for (header, list) in [("Repr-Digest", values("repr-digest")), ("Digest", values("digest")), ("x-goog-hash", values("x-goog-hash"))] {
+        for entry in list {
+            let Some((name, value)) = entry.split_once('=') else { continue };
+            if let Some(digest) = algorithm_from_token(name).and_then(|a| decode_base64_digest(a, value)) {
+                found.push((digest, DigestSource::Header(header.to_string())));
+            }
+        }
+    }
+
+    for (header, algorithm) in [
+        ("x-amz-checksum-sha256", HashAlgorithm::Sha256),
+        ("x-amz-checksum-sha1", HashAlgorithm::Sha1),
+    ] {
+        if let Some(digest) = headers
+            .get(header)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| decode_base64_digest(algorithm, v))
+        {
+            found.push((digest, DigestSource::Header(header.to_string())));
+        }
+    }
+
+    if !partial
+        && let Some(digest) = headers
+            .get("content-md5")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| decode_base64_digest(HashAlgorithm::Md5, v))
+    {
+        found.push((digest, DigestSource::Header("Content-MD5".to_string())));
+    }
+
+    found.sort_by_key(|(d, _)| std::cmp::Reverse(d.algorithm.strength()));
+    found.dedup_by(|a, b| a.0 == b.0);
+    found
+}
+
+/// Parses a `sha256sum`-style checksum file.
+///
+/// Accepts a bare digest, `<hex>  <name>` lines (with an optional `*`
+/// binary marker) and BSD-style `SHA256 (<name>) = <hex>` lines. When the
+/// file lists several names only the line for `filename` is used.
+pub fn parse_checksum_file(body: &str, algorithm: HashAlgorithm, filename: &str) -> Option<ExpectedDigest> {
+    let mut single = None;
+    let mut lines = 0;
+    for line in body.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        lines += 1;
+        if let Some((lhs, hex)) = line.split_once(") = ") {
+            let name = lhs.split_once('(').map(|(_, n)| n).unwrap_or("");
+            if name == filename {
+                return ExpectedDigest::new(algorithm, hex).ok();
+            }
+            continue;
+        }
+        // The name is the rest of the line, so it may contain spaces
+        let (hex, name) = match line.split_once(char::is_whitespace) {
+            Some((hex, rest)) => (hex, Some(rest.trim_start())),
+            None => (line, None),
+        };
+        match name.map(|n| n.strip_prefix('*').unwrap_or(n)) {
+            Some(name) if name == filename || name.rsplit('/').next() == Some(filename) => {
+                return ExpectedDigest::new(algorithm, hex).ok();
+            }
+            Some(_) => {}
+            None => single = ExpectedDigest::new(algorithm, hex).ok(),
+        }
+    }
+    if lines == 1 { single } else { None }
+}
+
+/// Incremental hasher over any of the supported algorithms.
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    Sha1(sha1::Sha1),
+    Md5(md5::Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        use sha2::Digest;
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(md5::Md5::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        use sha2::Digest;
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    /// Returns the digest as lowercase hex.
+    pub fn finalize(self) -> String {
+        use sha2::Digest;
+        match self {
+            Hasher::Sha256(h) => hex::encode(h.finalize()),
+            Hasher::Sha512(h) => hex::encode(h.finalize()),
+            Hasher::Sha1(h) => hex::encode(h.finalize()),
+            Hasher::Md5(h) => hex::encode(h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Hashes `len` bytes of `path` starting at `offset` on the blocking pool.
+pub async fn hash_range(path: &Path, algorithm: HashAlgorithm, offset: u64, len: u64) -> anyhow::Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        use std::io::{Seek, SeekFrom};
+        let mut file = std::fs::File::open(&path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = file.take(len);
+        let mut hasher = Hasher::new(algorithm);
+        let mut buf = vec![0u8; 1024 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finalize())
+    })
+    .await?
+}
+
+/// Hashes the whole file at `path`.
+pub async fn hash_file(path: &Path, algorithm: HashAlgorithm) -> anyhow::Result<String> {
+    hash_range(path, algorithm, 0, u64::MAX).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_algorithm_prefixed_digest() {
+        let digest: ExpectedDigest = "SHA256:9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08"
+            .parse()
+            .unwrap();
+        assert_eq!(digest.algorithm, HashAlgorithm::Sha256);
+        assert!(digest.value.starts_with("9f86d0"));
+
+        assert!("sha256:abcd".parse::<ExpectedDigest>().is_err());
+        assert!("crc32:00000000".parse::<ExpectedDigest>().is_err());
+    }
+
+    #[test]
+    fn reads_digests_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("repr-digest", "sha-256=:n4bQgYhMfWWaL+qgxVrQFaO/TxsrC4Is0V1sFbDwCgg=:".parse().unwrap());
+        headers.insert("content-md5", "CY9rzUYh03PK3k6DJie09g==".parse().unwrap());
+        headers.insert("x-goog-hash", "crc32c=n03x6A==, md5=CY9rzUYh03PK3k6DJie09g==".parse().unwrap());
+
+        let digests = advertised_digests(&headers, true);
+        assert_eq!(digests.len(), 2);
+        assert_eq!(digests[0].0.algorithm, HashAlgorithm::Sha256);
+        assert_eq!(digests[0].1, DigestSource::Header("Repr-Digest".into()));
+        assert_eq!(digests[1].1, DigestSource::Header("x-goog-hash".into()));
+        assert!(digests[0].0.value.starts_with("9f86d0"));
+    }
+
+    #[test]
+    fn parses_checksum_file_formats() {
+        let md5 = "098f6bcd4621d373cade4e832627b4f6";
+        let sums = format!("{}  other.iso\n{} *image.iso\n", "0".repeat(32), md5);
+        assert_eq!(parse_checksum_file(&sums, HashAlgorithm::Md5, "image.iso").unwrap().value, md5);
+        assert_eq!(parse_checksum_file(md5, HashAlgorithm::Md5, "image.iso").unwrap().value, md5);
+        let bsd = format!("MD5 (image.iso) = {}", md5);
+        assert_eq!(parse_checksum_file(&bsd, HashAlgorithm::Md5, "image.iso").unwrap().value, md5);
+        assert!(parse_checksum_file(&sums, HashAlgorithm::Md5, "missing.iso").is_none());
+
+        let spaced = format!("{}  my image.iso\n{} *release notes.txt\n", md5, "0".repeat(32));
+        assert_eq!(parse_checksum_file(&spaced, HashAlgorithm::Md5, "my image.iso").unwrap().value, md5);
+        let notes = parse_checksum_file(&spaced, HashAlgorithm::Md5, "release notes.txt").unwrap();
+        assert_eq!(notes.value, "0".repeat(32));
+        assert!(parse_checksum_file(&spaced, HashAlgorithm::Md5, "my").is_none());
+    }
+
+    #[tokio::test]
+    async fn verifies_file_with_each_algorithm() {
+        let path = std::env::temp_dir().join(format!("kitsune-integrity-{}", std::process::id()));
+        std::fs::write(&path, b"test").unwrap();
+
+        let cases = [
+            (HashAlgorithm::Sha256, "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"),
+            (
+                HashAlgorithm::Sha512,
+                "ee26b0dd4af7e749aa1a8ee3c10ae9923f618980772e473f8819a5d4940e0db2\
+                 7ac185f8a0e1d5f84f88bc887fd67b143732c304cc5fa9ad8e6f57f50028a8ff",
+            ),
+            (HashAlgorithm::Sha1, "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3"),
+            (HashAlgorithm::Md5, "098f6bcd4621d373cade4e832627b4f6"),
+            (HashAlgorithm::Blake3, "4878ca0425c739fa427f7eda20fe845f6b2e46ba5fe2a14df5b1e32f50603215"),
+        ];
+        for (algorithm, hex) in cases {
+            ExpectedDigest::new(algorithm, hex).unwrap().verify_file(&path).await.unwrap();
+        }
+
+        let wrong = ExpectedDigest::new(HashAlgorithm::Md5, &"0".repeat(32)).unwrap();
+        let err = wrong.verify_file(&path).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<IntegrityError>(), Some(IntegrityError::Mismatch { .. })));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn verifies_multipart_etag() {
+        use sha2::Digest;
+        let path = std::env::temp_dir().join(format!("kitsune-etag-{}", std::process::id()));
+        std::fs::write(&path, b"testtesttest").unwrap();
+
+        // Parts "test", "test", "test"
+        let part = md5::Md5::digest(b"test");
+        let combined = md5::Md5::digest([part, part, part].concat());
+        let etag = format!("\"{}-3\"", hex::encode(combined));
+
+        let multipart = MultipartEtag::parse(&etag, 4, 12).unwrap();
+        multipart.verify_file(&path).await.unwrap();
+        assert!(MultipartEtag::parse(&etag, 6, 12).is_none());
+        assert!(MultipartEtag::parse("\"098f6bcd4621d373cade4e832627b4f6\"", 4, 12).is_none());
+
+        std::fs::write(&path, b"testtestTEST").unwrap();
+        assert!(multipart.verify_file(&path).await.is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
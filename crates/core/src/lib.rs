@@ -1,8 +1,44 @@
+pub mod dash;
+pub mod delta;
 pub mod downloader;
+pub mod hls;
+pub mod integrity;
+pub mod lock;
+pub mod metalink;
+pub mod mirrors;
+pub mod network;
+pub mod pieces;
+pub mod pipe;
+pub mod preview;
+pub mod range;
+pub mod schedule;
+pub mod segments;
 pub mod session;
+pub mod store;
+pub mod torrent;
+pub mod transport;
 pub mod worker;
 pub mod utils;
+pub mod zip;
 
-pub use downloader::{Downloader, DownloadObserver, ChannelObserver};
-pub use session::DownloadSession;
+#[cfg(test)]
+mod test_support;
+
+pub use dash::TrackSelection;
+pub use delta::{BlockList, ControlFile, DeltaJob};
+pub use downloader::{Downloader, DownloadObserver, ChannelObserver, RemoteMetadata};
+pub use hls::VariantSelection;
+pub use integrity::{DigestSource, ExpectedDigest, HashAlgorithm, IntegrityError, MultipartEtag, Verification};
+pub use lock::{DownloadLock, LockError};
+pub use metalink::MetalinkFile;
+pub use network::{IpPreference, NetworkConfig, SourceAddress};
+pub use pieces::PieceHashes;
+pub use preview::{PreviewHandle, PreviewServer};
+pub use range::{ByteRange, PartialRange};
+pub use schedule::{Decision, RateLimiter, RuleAction, Schedule, ScheduleRule, Scheduler};
+pub use session::{DownloadSession, Mirror, SessionError};
+pub use store::SessionStore;
+pub use torrent::{TorrentConfig, TorrentJob};
+pub use transport::{HttpConfig, HttpStrategy, Transport, TransportError};
 pub use worker::Worker;
+pub use zip::ZipEntry;
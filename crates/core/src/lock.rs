@@ -0,0 +1,92 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockError {
+    #[error("{} is already being downloaded by PID {pid}", path.display())]
+    Busy { path: PathBuf, pid: u32 },
+    #[error("{} is already being downloaded by another process", path.display())]
+    BusyUnknown { path: PathBuf },
+    #[error("failed to lock {}: {source}", path.display())]
+    Io { path: PathBuf, source: std::io::Error },
+}
+
+/// Exclusive advisory lock on a session and its output file.
+///
+/// Lock files live in a directory of their own rather than next to the
+/// session or output: the session file is replaced by rename on every
+/// checkpoint, and the output may sit on a read-only or shared mount. The
+/// OS releases the locks when the owning process exits, so a crash never
+/// leaves a download stuck.
+pub struct DownloadLock {
+    _session: File,
+    _output: File,
+}
+
+impl DownloadLock {
+    pub fn acquire(lock_dir: &Path, session_id: &str, output_path: &Path) -> Result<Self, LockError> {
+        std::fs::create_dir_all(lock_dir).map_err(|source| LockError::Io { path: lock_dir.to_path_buf(), source })?;
+
+        let output_id = crate::store::SessionStore::id_for_output(output_path);
+        let session = lock_file(&lock_dir.join(format!("session-{}.lock", session_id)), output_path)?;
+        let output = lock_file(&lock_dir.join(format!("output-{}.lock", output_id)), output_path)?;
+
+        Ok(Self { _session: session, _output: output })
+    }
+}
+
+fn lock_file(lock_path: &Path, output_path: &Path) -> Result<File, LockError> {
+    let io_err = |source| LockError::Io { path: lock_path.to_path_buf(), source };
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path)
+        .map_err(io_err)?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut contents = String::new();
+            let _ = file.read_to_string(&mut contents);
+            return Err(match contents.trim().parse() {
+                Ok(pid) => LockError::Busy { path: output_path.to_path_buf(), pid },
+                Err(_) => LockError::BusyUnknown { path: output_path.to_path_buf() },
+            });
+        }
+        Err(TryLockError::Error(e)) => return Err(io_err(e)),
+    }
+
+    // Record the owner so a second process can say who holds the download.
+    file.set_len(0).map_err(io_err)?;
+    file.seek(SeekFrom::Start(0)).map_err(io_err)?;
+    write!(file, "{}", std::process::id()).map_err(io_err)?;
+    file.flush().map_err(io_err)?;
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_lock_reports_owner_pid() {
+        let dir = std::env::temp_dir().join(format!("kitsune-lock-{}", std::process::id()));
+        let output = dir.join("file.bin");
+
+        let held = DownloadLock::acquire(&dir, "abc", &output).unwrap();
+        match DownloadLock::acquire(&dir, "other", &output) {
+            Err(LockError::Busy { pid, .. }) => assert_eq!(pid, std::process::id()),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("output lock should be exclusive"),
+        }
+
+        drop(held);
+        assert!(DownloadLock::acquire(&dir, "abc", &output).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
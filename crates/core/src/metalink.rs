@@ -0,0 +1,195 @@
+use super::integrity::{ExpectedDigest, HashAlgorithm};
+use super::mirrors::DEFAULT_PRIORITY;
+use super::pieces::PieceHashes;
+use super::session::Mirror;
+use anyhow::{Context, Result};
+use std::path::{Component, Path, PathBuf};
+
+const NS_V4: &str = "urn:ietf:params:xml:ns:metalink";
+const NS_V3: &str = "http://www.metalinker.org/";
+
+/// One `<file>` entry of a Metalink document.
+#[derive(Debug, Clone)]
+pub struct MetalinkFile {
+    /// Relative path from the document, already stripped of `..` and roots.
+    pub name: PathBuf,
+    pub size: Option<u64>,
+    pub digest: Option<ExpectedDigest>,
+    pub pieces: Option<PieceHashes>,
+    /// Usable mirrors, best priority first.
+    pub mirrors: Vec<Mirror>,
+}
+
+/// True if `source` looks like a Metalink file path or URL.
+pub fn is_metalink_source(source: &str) -> bool {
+    let lower = source.split(['?', '#']).next().unwrap_or(source).to_ascii_lowercase();
+    lower.ends_with(".meta4") || lower.ends_with(".metalink")
+}
+
+/// Parses a Metalink v4 (RFC 5854) or v3 document.
+pub fn parse(xml: &str) -> Result<Vec<MetalinkFile>> {
+    let doc = roxmltree::Document::parse(xml).context("invalid Metalink XML")?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "metalink" {
+        anyhow::bail!("not a Metalink document: root element is <{}>", root.tag_name().name());
+    }
+    let v3 = root.tag_name().namespace() == Some(NS_V3);
+    if !v3 && root.tag_name().namespace() != Some(NS_V4) {
+        log::warn!("Metalink document has unexpected namespace {:?}", root.tag_name().namespace());
+    }
+
+    let mut files = Vec::new();
+    for file in root.descendants().filter(|n| n.has_tag_name("file")) {
+        let Some(name) = file.attribute("name").and_then(sanitize_name) else {
+            log::warn!("Skipping Metalink file with missing or unsafe name {:?}", file.attribute("name"));
+            continue;
+        };
+
+        let size = child_text(file, "size").and_then(|s| s.trim().parse().ok());
+
+        // v3 nests hashes and pieces under <verification>
+        let hash_parent = if v3 { child(file, "verification").unwrap_or(file) } else { file };
+        let digest = hash_parent
+            .children()
+            .filter(|n| n.has_tag_name("hash"))
+            .filter_map(|n| {
+                let algorithm = n.attribute("type").and_then(parse_hash_type)?;
+                ExpectedDigest::new(algorithm, n.text()?).ok()
+            })
+            .max_by_key(|d| d.algorithm.strength());
+
+        let pieces = child(hash_parent, "pieces").and_then(|p| {
+            let algorithm = p.attribute("type").and_then(parse_hash_type)?;
+            let length = p.attribute("length")?.parse().ok()?;
+            let hashes = p
+                .children()
+                .filter(|n| n.has_tag_name("hash"))
+                .filter_map(|n| n.text().map(|t| t.trim().to_string()))
+                .collect();
+            PieceHashes::new(algorithm, length, hashes)
+                .map_err(|e| log::warn!("Ignoring invalid piece hashes for {:?}: {}", name, e))
+                .ok()
+        });
+
+        let url_parent = if v3 { child(file, "resources").unwrap_or(file) } else { file };
+        let mut mirrors: Vec<Mirror> = url_parent
+            .children()
+            .filter(|n| n.has_tag_name("url"))
+            .filter_map(|n| {
+                let url = n.text()?.trim().to_string();
+                if !crate::transport::is_supported(&url) {
+                    return None;
+                }
+                // v4 priority: 1 is best. v3 preference: 100 is best.
+                let priority = if v3 {
+                    n.attribute("preference")
+                        .and_then(|p| p.parse::<u32>().ok())
+                        .map_or(DEFAULT_PRIORITY, |p| 101u32.saturating_sub(p.min(100)))
+                } else {
+                    n.attribute("priority").and_then(|p| p.parse().ok()).unwrap_or(DEFAULT_PRIORITY)
+                };
+                Some(Mirror {
+                    url,
+                    priority,
+                    location: n.attribute("location").map(str::to_string),
+                })
+            })
+            .collect();
+        mirrors.sort_by_key(|m| m.priority);
+
+        if mirrors.is_empty() {
+            log::warn!("Skipping Metalink file {:?}: no supported URLs", name);
+            continue;
+        }
+
+        files.push(MetalinkFile { name, size, digest, pieces, mirrors });
+    }
+
+    if files.is_empty() {
+        anyhow::bail!("Metalink document contains no downloadable files");
+    }
+    Ok(files)
+}
+
+fn child<'a, 'input>(node: roxmltree::Node<'a, 'input>, name: &str) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|n| n.text())
+}
+
+fn parse_hash_type(name: &str) -> Option<HashAlgorithm> {
+    name.parse().ok()
+}
+
+/// Keeps only normal path components so a document cannot write outside
+/// the target directory.
+fn sanitize_name(name: &str) -> Option<PathBuf> {
+    let path: PathBuf = Path::new(name)
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect();
+    if path.as_os_str().is_empty() || Path::new(name).components().any(|c| c == Component::ParentDir) {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V4: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="data/set.tar">
+    <size>40</size>
+    <hash type="md5">098f6bcd4621d373cade4e832627b4f6</hash>
+    <hash type="sha-256">9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08</hash>
+    <pieces length="32" type="sha-1">
+      <hash>a94a8fe5ccb19ba61c4c0873d391e987982fbbd3</hash>
+      <hash>a94a8fe5ccb19ba61c4c0873d391e987982fbbd3</hash>
+    </pieces>
+    <url location="se" priority="2">https://mirror-b.example/set.tar</url>
+    <url location="de" priority="1">https://mirror-a.example/set.tar</url>
+    <url priority="3">rsync://mirror-c.example/set.tar</url>
+  </file>
+  <file name="../escape"><url>https://example.com/x</url></file>
+</metalink>"#;
+
+    #[test]
+    fn parses_v4_document() {
+        let files = parse(V4).unwrap();
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        assert_eq!(file.name, PathBuf::from("data/set.tar"));
+        assert_eq!(file.size, Some(40));
+        assert_eq!(file.digest.as_ref().unwrap().algorithm, HashAlgorithm::Sha256);
+        assert_eq!(file.pieces.as_ref().unwrap().len(), 2);
+        let urls: Vec<_> = file.mirrors.iter().map(|m| m.url.as_str()).collect();
+        assert_eq!(urls, vec!["https://mirror-a.example/set.tar", "https://mirror-b.example/set.tar"]);
+    }
+
+    #[test]
+    fn parses_v3_document() {
+        let xml = r#"<metalink version="3.0" xmlns="http://www.metalinker.org/">
+  <files>
+    <file name="image.iso">
+      <size>1024</size>
+      <verification><hash type="sha1">a94a8fe5ccb19ba61c4c0873d391e987982fbbd3</hash></verification>
+      <resources>
+        <url type="http" preference="10">http://slow.example/image.iso</url>
+        <url type="http" preference="100">http://fast.example/image.iso</url>
+      </resources>
+    </file>
+  </files>
+</metalink>"#;
+        let files = parse(xml).unwrap();
+        assert_eq!(files[0].mirrors[0].url, "http://fast.example/image.iso");
+        assert_eq!(files[0].digest.as_ref().unwrap().algorithm, HashAlgorithm::Sha1);
+    }
+}
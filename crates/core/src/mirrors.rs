@@ -0,0 +1,250 @@
+use super::session::{DownloadSession, Mirror};
+use reqwest::Url;
+use reqwest::header::{HeaderMap, LINK};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// Priority given to mirrors that do not state one.
+pub const DEFAULT_PRIORITY: u32 = 999_999;
+
+/// Runtime view of the sources a download can read from.
+///
+/// Each new worker is sent to the mirror with the fewest connections for
+/// its observed per-connection speed, so fast mirrors end up serving more
+/// parts. Mirrors that fail are taken out of rotation for the rest of the
+/// run.
+#[derive(Debug)]
+pub struct MirrorPool {
+    mirrors: Vec<MirrorStats>,
+}
+
+#[derive(Debug)]
+struct MirrorStats {
+    url: String,
+    bytes: u64,
+    /// Connection time of finished workers.
+    busy: Duration,
+    /// Start times of workers currently reading from this mirror.
+    running: Vec<Instant>,
+    failed: bool,
+}
+
+impl MirrorPool {
+    /// Builds the pool from `session.url` followed by its mirrors, best first.
+    pub fn for_session(session: &DownloadSession) -> Self {
+        let mut urls = vec![session.url.clone()];
+        for mirror in &session.mirrors {
+            if !urls.contains(&mirror.url) {
+                urls.push(mirror.url.clone());
+            }
+        }
+        Self {
+            mirrors: urls
+                .into_iter()
+                .map(|url| MirrorStats { url, bytes: 0, busy: Duration::ZERO, running: Vec::new(), failed: false })
+                .collect(),
+        }
+    }
+
+    /// Mirrors still in rotation.
+    pub fn usable(&self) -> usize {
+        self.mirrors.iter().filter(|m| !m.failed).count()
+    }
+
+    /// Picks the mirror for a new connection, or `None` if all have failed.
+    pub fn pick(&self, now: Instant) -> Option<&str> {
+        let speeds: Vec<Option<f64>> = self.mirrors.iter().map(|m| m.speed(now)).collect();
+        // Unmeasured mirrors are assumed as fast as the best one so they get tried
+        let optimistic = speeds.iter().flatten().copied().fold(1.0, f64::max);
+
+        self.mirrors
+            .iter()
+            .zip(&speeds)
+            .filter(|(m, _)| !m.failed)
+            .min_by(|(a, sa), (b, sb)| {
+                let load_a = (a.running.len() + 1) as f64 / sa.unwrap_or(optimistic);
+                let load_b = (b.running.len() + 1) as f64 / sb.unwrap_or(optimistic);
+                load_a.total_cmp(&load_b)
+            })
+            .map(|(m, _)| m.url.as_str())
+    }
+
+    pub fn started(&mut self, url: &str, at: Instant) {
+        if let Some(m) = self.get_mut(url) {
+            m.running.push(at);
+        }
+    }
+
+    pub fn record(&mut self, url: &str, bytes: u64) {
+        if let Some(m) = self.get_mut(url) {
+            m.bytes += bytes;
+        }
+    }
+
+    /// A worker started at `started` stopped reading from `url`.
+    pub fn finished(&mut self, url: &str, started: Instant, now: Instant) {
+        if let Some(m) = self.get_mut(url)
+            && let Some(pos) = m.running.iter().position(|t| *t == started)
+        {
+            m.running.swap_remove(pos);
+            m.busy += now.saturating_duration_since(started);
+        }
+    }
+
+    /// Takes `url` out of rotation. Returns false if it already was.
+    pub fn mark_failed(&mut self, url: &str) -> bool {
+        match self.get_mut(url) {
+            Some(m) if !m.failed => {
+                m.failed = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_failed(&self, url: &str) -> bool {
+        self.mirrors.iter().any(|m| m.url == url && m.failed)
+    }
+
+    fn get_mut(&mut self, url: &str) -> Option<&mut MirrorStats> {
+        self.mirrors.iter_mut().find(|m| m.url == url)
+    }
+}
+
+impl MirrorStats {
+    /// Bytes per second per connection, once there is enough data to tell.
+    fn speed(&self, now: Instant) -> Option<f64> {
+        let busy = self.busy + self.running.iter().map(|t| now.saturating_duration_since(*t)).sum::<Duration>();
+        if self.bytes == 0 || busy < Duration::from_millis(200) {
+            return None;
+        }
+        Some(self.bytes as f64 / busy.as_secs_f64())
+    }
+}
+
+/// Mirrors advertised with `Link: <url>; rel=duplicate; pri=N; geo=xx`
+/// (Metalink/HTTP, RFC 6249), best first. Relative links are resolved
+/// against `base`.
+pub fn duplicate_links(headers: &HeaderMap, base: &str) -> Vec<Mirror> {
+    let base = Url::parse(base).ok();
+    let mut mirrors: Vec<Mirror> = headers
+        .get_all(LINK)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(split_links)
+        .filter_map(|link| {
+            let (target, params) = link.trim().strip_prefix('<')?.split_once('>')?;
+            let mut is_duplicate = false;
+            let mut priority = DEFAULT_PRIORITY;
+            let mut location = None;
+            for param in params.split(';') {
+                let Some((name, value)) = param.split_once('=') else { continue };
+                let value = value.trim().trim_matches('"');
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "rel" => is_duplicate = value.split_whitespace().any(|r| r.eq_ignore_ascii_case("duplicate")),
+                    "pri" => priority = value.parse().unwrap_or(DEFAULT_PRIORITY),
+                    "geo" => location = Some(value.to_ascii_lowercase()),
+                    _ => {}
+                }
+            }
+            if !is_duplicate {
+                return None;
+            }
+            let url = match &base {
+                Some(base) => base.join(target).ok()?,
+                None => Url::parse(target).ok()?,
+            };
+            matches!(url.scheme(), "http" | "https").then(|| Mirror { url: url.to_string(), priority, location })
+        })
+        .collect();
+    mirrors.sort_by_key(|m| m.priority);
+    // Keeps the best priority a URL was advertised with
+    let mut seen = HashSet::new();
+    mirrors.retain(|m| seen.insert(m.url.clone()));
+    mirrors
+}
+
+/// Splits a `Link` header value on the commas between links, leaving
+/// commas inside `<...>` and quoted strings alone.
+fn split_links(value: &str) -> Vec<&str> {
+    let mut links = Vec::new();
+    let (mut in_uri, mut in_quotes, mut start) = (false, false, 0);
+    for (i, c) in value.char_indices() {
+        match c {
+            '<' if !in_quotes => in_uri = true,
+            '>' if !in_quotes => in_uri = false,
+            '"' if !in_uri => in_quotes = !in_quotes,
+            ',' if !in_uri && !in_quotes => {
+                links.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    links.push(&value[start..]);
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool() -> MirrorPool {
+        let mut session = DownloadSession::new("http://a/f".into(), "/tmp/f".into(), 4);
+        session.mirrors = ["http://a/f", "http://b/f"]
+            .iter()
+            .enumerate()
+            .map(|(i, url)| Mirror { url: url.to_string(), priority: i as u32 + 1, location: None })
+            .collect();
+        MirrorPool::for_session(&session)
+    }
+
+    #[test]
+    fn faster_mirror_gets_more_connections() {
+        let mut pool = pool();
+        let t0 = Instant::now();
+        let now = t0 + Duration::from_secs(1);
+        pool.started("http://a/f", t0);
+        pool.started("http://b/f", t0);
+        pool.record("http://a/f", 300_000);
+        pool.record("http://b/f", 250_000);
+
+        // Per-connection load: a 2/300k, b 2/250k; then a 3/300k
+        assert_eq!(pool.pick(now), Some("http://a/f"));
+        pool.started("http://a/f", now);
+        assert_eq!(pool.pick(now), Some("http://b/f"));
+    }
+
+    #[test]
+    fn failed_mirror_leaves_rotation() {
+        let mut pool = pool();
+        assert!(pool.mark_failed("http://a/f"));
+        assert!(!pool.mark_failed("http://a/f"));
+        assert_eq!(pool.pick(Instant::now()), Some("http://b/f"));
+        pool.mark_failed("http://b/f");
+        assert_eq!(pool.usable(), 0);
+        assert_eq!(pool.pick(Instant::now()), None);
+    }
+
+    #[test]
+    fn parses_rfc6249_duplicate_links() {
+        let mut headers = HeaderMap::new();
+        headers.append(LINK, "<http://mirror.example/a,b.iso>; rel=duplicate; pri=2; geo=de".parse().unwrap());
+        headers.append(
+            LINK,
+            "</pub/a,b.iso>; rel=\"duplicate\"; pri=1, <http://x/a.meta4>; rel=describedby; type=\"application/metalink4+xml\"".parse().unwrap(),
+        );
+        headers.append(LINK, "<ftp://ftp.example/a.iso>; rel=duplicate".parse().unwrap());
+        headers.append(LINK, "<https://other.example/a.iso>; rel=duplicate; pri=5".parse().unwrap());
+        headers.append(LINK, "<http://mirror.example/a,b.iso>; rel=duplicate; pri=9".parse().unwrap());
+
+        let mirrors = duplicate_links(&headers, "https://origin.example/dl/a,b.iso");
+        let urls: Vec<_> = mirrors.iter().map(|m| m.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec!["https://origin.example/pub/a,b.iso", "http://mirror.example/a,b.iso", "https://other.example/a.iso"]
+        );
+        assert_eq!(mirrors[1].priority, 2);
+        assert_eq!(mirrors[1].location.as_deref(), Some("de"));
+    }
+}
@@ -0,0 +1,362 @@
+//! Where outgoing connections come from and how host names resolve.
+//!
+//! A [`NetworkConfig`] can pin connections to a local address or network
+//! interface, or list several of them to bond uplinks: each new connection
+//! then leaves from whichever source has the fewest open, so parts spread
+//! across all links. It also orders or filters IPv4 and IPv6 results and
+//! can replace the system resolver with fixed addresses or other DNS
+//! servers.
+
+use anyhow::{Context, Result};
+use hickory_resolver::TokioResolver;
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, ResolverConfig};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::xfer::Protocol;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A local address or interface to connect from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceAddress {
+    Ip(IpAddr),
+    /// Interface name such as `eth0` or `wwan0`. Interface binding is
+    /// supported on Linux, and on macOS for HTTP only.
+    Interface(String),
+}
+
+impl FromStr for SourceAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            anyhow::bail!("empty source address");
+        }
+        Ok(s.parse().map(SourceAddress::Ip).unwrap_or_else(|_| SourceAddress::Interface(s.to_string())))
+    }
+}
+
+impl fmt::Display for SourceAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceAddress::Ip(ip) => write!(f, "{}", ip),
+            SourceAddress::Interface(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Which address families to connect to, and in what order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpPreference {
+    /// The resolver's order.
+    #[default]
+    Auto,
+    PreferV4,
+    PreferV6,
+    V4Only,
+    V6Only,
+}
+
+impl FromStr for IpPreference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(IpPreference::Auto),
+            "prefer-4" | "prefer-ipv4" => Ok(IpPreference::PreferV4),
+            "prefer-6" | "prefer-ipv6" => Ok(IpPreference::PreferV6),
+            "4" | "ipv4" => Ok(IpPreference::V4Only),
+            "6" | "ipv6" => Ok(IpPreference::V6Only),
+            other => anyhow::bail!("invalid IP preference {:?}: expected auto, prefer-4, prefer-6, 4 or 6", other),
+        }
+    }
+}
+
+/// Source binding, address family and name resolution settings.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConfig {
+    /// Addresses or interfaces to connect from. Empty leaves the choice to
+    /// the OS; more than one bonds them.
+    pub sources: Vec<SourceAddress>,
+    pub ip_preference: IpPreference,
+    /// DNS servers to query instead of the system resolver.
+    pub dns_servers: Vec<SocketAddr>,
+    /// Fixed addresses for host names, used before any DNS lookup.
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl NetworkConfig {
+    /// Adds a `host=address[,address...]` override, like an `/etc/hosts`
+    /// line.
+    pub fn add_host(&mut self, rule: &str) -> Result<()> {
+        let (host, addrs) = rule
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("invalid host override {:?}: expected host=address", rule))?;
+        let addrs = addrs
+            .split(',')
+            .map(|a| a.trim().parse::<IpAddr>().with_context(|| format!("invalid address {:?} for {}", a, host)))
+            .collect::<Result<Vec<_>>>()?;
+        self.hosts.insert(host.trim().to_ascii_lowercase(), addrs);
+        Ok(())
+    }
+
+    /// Adds a DNS server given as `ip` or `ip:port`.
+    pub fn add_dns_server(&mut self, server: &str) -> Result<()> {
+        let addr = server
+            .parse::<SocketAddr>()
+            .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+            .with_context(|| format!("invalid DNS server {:?}", server))?;
+        self.dns_servers.push(addr);
+        Ok(())
+    }
+}
+
+/// Name resolution under a [`NetworkConfig`], optionally limited to the
+/// family of a bound source address. Cheap to clone.
+#[derive(Clone)]
+pub(crate) struct Resolver {
+    preference: IpPreference,
+    hosts: Arc<HashMap<String, Vec<IpAddr>>>,
+    dns: Option<Arc<TokioResolver>>,
+    /// `Some(true)` for IPv4 only, `Some(false)` for IPv6 only.
+    family_v4: Option<bool>,
+}
+
+impl Resolver {
+    fn new(config: &NetworkConfig) -> Self {
+        let dns = (!config.dns_servers.is_empty()).then(|| {
+            let mut servers = ResolverConfig::new();
+            for &addr in &config.dns_servers {
+                servers.add_name_server(NameServerConfig::new(addr, Protocol::Udp));
+                servers.add_name_server(NameServerConfig::new(addr, Protocol::Tcp));
+            }
+            let mut builder = TokioResolver::builder_with_config(servers, TokioConnectionProvider::default());
+            builder.options_mut().ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+            Arc::new(builder.build())
+        });
+        Self { preference: config.ip_preference, hosts: Arc::new(config.hosts.clone()), dns, family_v4: None }
+    }
+
+    /// Same resolver, returning only addresses `source` can reach.
+    fn for_source(&self, source: Option<&SourceAddress>) -> Self {
+        let mut resolver = self.clone();
+        if let Some(SourceAddress::Ip(ip)) = source {
+            resolver.family_v4 = Some(ip.is_ipv4());
+        }
+        resolver
+    }
+
+    pub(crate) async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let mut addrs = if let Ok(ip) = host.parse::<IpAddr>() {
+            vec![ip]
+        } else if let Some(fixed) = self.hosts.get(&host) {
+            fixed.clone()
+        } else if let Some(dns) = &self.dns {
+            dns.lookup_ip(host.as_str()).await.map_err(io::Error::other)?.iter().collect()
+        } else {
+            tokio::net::lookup_host((host.as_str(), 0)).await?.map(|a| a.ip()).collect()
+        };
+
+        addrs.retain(|ip| match (self.family_v4, self.preference) {
+            (Some(v4), _) => ip.is_ipv4() == v4,
+            (None, IpPreference::V4Only) => ip.is_ipv4(),
+            (None, IpPreference::V6Only) => ip.is_ipv6(),
+            _ => true,
+        });
+        match self.preference {
+            IpPreference::PreferV4 => addrs.sort_by_key(|ip| !ip.is_ipv4()),
+            IpPreference::PreferV6 => addrs.sort_by_key(|ip| !ip.is_ipv6()),
+            _ => {}
+        }
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no usable address", host)));
+        }
+        Ok(addrs)
+    }
+}
+
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs = resolver.lookup(name.as_str()).await?;
+            Ok(Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0))) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// One place connections can leave from.
+pub(crate) struct Route {
+    pub source: Option<SourceAddress>,
+    pub resolver: Resolver,
+    active: AtomicUsize,
+}
+
+/// The routes of a [`NetworkConfig`], shared by every transport so that
+/// bonding balances across protocols. Cheap to clone.
+#[derive(Clone)]
+pub(crate) struct Network {
+    routes: Arc<Vec<Route>>,
+}
+
+impl fmt::Debug for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.routes.iter().map(|r| &r.source)).finish()
+    }
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self::new(&NetworkConfig::default())
+    }
+}
+
+impl Network {
+    pub(crate) fn new(config: &NetworkConfig) -> Self {
+        let resolver = Resolver::new(config);
+        let sources: Vec<Option<SourceAddress>> = if config.sources.is_empty() {
+            vec![None]
+        } else {
+            config.sources.iter().cloned().map(Some).collect()
+        };
+        let routes = sources
+            .into_iter()
+            .map(|source| Route { resolver: resolver.for_source(source.as_ref()), source, active: AtomicUsize::new(0) })
+            .collect();
+        Self { routes: Arc::new(routes) }
+    }
+
+    pub(crate) fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// The route with the fewest connections open, held until the lease
+    /// is dropped.
+    pub(crate) fn lease(&self) -> Lease {
+        let index = (0..self.routes.len())
+            .min_by_key(|&i| self.routes[i].active.load(Ordering::Relaxed))
+            .unwrap_or(0);
+        self.routes[index].active.fetch_add(1, Ordering::Relaxed);
+        Lease { network: self.clone(), index }
+    }
+}
+
+/// A connection slot on one route.
+pub(crate) struct Lease {
+    network: Network,
+    pub index: usize,
+}
+
+impl Lease {
+    pub(crate) fn route(&self) -> &Route {
+        &self.network.routes[self.index]
+    }
+
+    /// Resolves `host` and connects to the first address that answers,
+    /// from this lease's source.
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for ip in self.route().resolver.lookup(host).await? {
+            match tokio::time::timeout(CONNECT_TIMEOUT, self.connect_addr(SocketAddr::new(ip, port))).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_error = Some(e),
+                Err(_) => last_error = Some(io::Error::new(io::ErrorKind::TimedOut, format!("timed out connecting to {}", ip))),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host))))
+    }
+
+    /// Connects to `addr` from this lease's source.
+    pub(crate) async fn connect_addr(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        match &self.route().source {
+            Some(SourceAddress::Ip(ip)) => socket.bind(SocketAddr::new(*ip, 0))?,
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            Some(SourceAddress::Interface(name)) => socket.bind_device(Some(name.as_bytes()))?,
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            Some(SourceAddress::Interface(name)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("binding to interface {} is only supported on Linux", name),
+                ));
+            }
+            None => {}
+        }
+        socket.connect(addr).await
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.network.routes[self.index].active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolves_overrides_by_preference() {
+        let mut config = NetworkConfig { ip_preference: IpPreference::PreferV6, ..NetworkConfig::default() };
+        config.add_host("Mirror.Example=10.0.0.1, ::1").unwrap();
+        assert!(config.add_host("mirror.example=nope").is_err());
+        config.add_dns_server("192.0.2.53").unwrap();
+        assert_eq!(config.dns_servers, vec!["192.0.2.53:53".parse().unwrap()]);
+
+        let resolver = Resolver::new(&config);
+        let v6: IpAddr = "::1".parse().unwrap();
+        let v4: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(resolver.lookup("mirror.example").await.unwrap(), vec![v6, v4]);
+        let bound = resolver.for_source(Some(&"192.168.1.2".parse().unwrap()));
+        assert_eq!(bound.lookup("mirror.example").await.unwrap(), vec![v4]);
+        assert!(bound.lookup("[::1]").await.is_err());
+    }
+
+    /// Two loopback addresses stand in for two uplinks.
+    #[tokio::test]
+    async fn bonds_parts_across_sources() {
+        let data = crate::test_support::payload(400_000);
+        let server = crate::test_support::serve([("/f".to_string(), data.clone())].into(), HashMap::new(), None).await;
+        let mut config = NetworkConfig {
+            sources: vec!["127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap()],
+            ..NetworkConfig::default()
+        };
+        config.add_host("files.test=127.0.0.1").unwrap();
+        let downloader = crate::Downloader::new("test").unwrap().with_network_config(&config).unwrap();
+        let output = crate::test_support::temp_path("bonded.bin");
+
+        let url = format!("http://files.test:{}/f", server.addr.port());
+        let mut session = downloader.init_download(&url, Some(output.clone()), 4).await.unwrap();
+        downloader.run(&mut session, None, None, None).await.unwrap();
+        assert_eq!(tokio::fs::read(&output).await.unwrap(), data);
+        let clients = server.clients.lock().unwrap().clone();
+        assert_eq!(clients, ["127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap()].into());
+        let _ = tokio::fs::remove_file(&output).await;
+    }
+
+    #[test]
+    fn leases_the_least_busy_route() {
+        let config = NetworkConfig {
+            sources: vec!["127.0.0.1".parse().unwrap(), "eth1".parse().unwrap()],
+            ..NetworkConfig::default()
+        };
+        assert_eq!(config.sources[1], SourceAddress::Interface("eth1".into()));
+        let network = Network::new(&config);
+        let first = network.lease();
+        let second = network.lease();
+        assert_ne!(first.index, second.index);
+        drop(first);
+        assert_ne!(network.lease().index, second.index);
+    }
+}
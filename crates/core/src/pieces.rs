@@ -0,0 +1,226 @@
+use super::integrity::HashAlgorithm;
+use super::session::DownloadSession;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Fixed-size piece hashes for a file, as found in Metalink `<pieces>` or
+/// a sidecar manifest, plus which pieces have already been checked.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PieceHashes {
+    pub algorithm: HashAlgorithm,
+    pub piece_length: u64,
+    /// Lowercase hex digest per piece, in file order.
+    pub hashes: Vec<String>,
+    #[serde(default)]
+    pub verified: Bitfield,
+}
+
+impl PieceHashes {
+    pub fn new(algorithm: HashAlgorithm, piece_length: u64, hashes: Vec<String>) -> anyhow::Result<Self> {
+        if piece_length == 0 {
+            anyhow::bail!("piece length must be non-zero");
+        }
+        let hashes = hashes
+            .into_iter()
+            .map(|h| super::integrity::ExpectedDigest::new(algorithm, &h).map(|d| d.value))
+            .collect::<Result<Vec<_>, _>>()?;
+        let verified = Bitfield::new(hashes.len());
+        Ok(Self { algorithm, piece_length, hashes, verified })
+    }
+
+    /// Reads a sidecar manifest:
+    /// `{"algorithm": "sha256", "piece_length": 4194304, "hashes": ["..."]}`.
+    pub async fn load_manifest(path: &Path) -> anyhow::Result<Self> {
+        #[derive(Deserialize)]
+        struct Manifest {
+            algorithm: HashAlgorithm,
+            piece_length: u64,
+            hashes: Vec<String>,
+        }
+
+        let json = tokio::fs::read_to_string(path).await?;
+        let manifest: Manifest = serde_json::from_str(&json)
+            .with_context(|| format!("invalid piece manifest {:?}", path))?;
+        Self::new(manifest.algorithm, manifest.piece_length, manifest.hashes)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Inclusive byte range of piece `index` in a file of `total_size` bytes.
+    pub fn piece_range(&self, index: usize, total_size: u64) -> (u64, u64) {
+        let start = index as u64 * self.piece_length;
+        let end = (start + self.piece_length).min(total_size) - 1;
+        (start, end)
+    }
+
+    /// Forgets the checks of every piece overlapping `start..=end`, e.g.
+    /// after those bytes were rolled back to be downloaded again.
+    pub fn clear_verified(&mut self, start: u64, end: u64) {
+        if self.is_empty() {
+            return;
+        }
+        let last = ((end / self.piece_length) as usize).min(self.len() - 1);
+        for index in (start / self.piece_length) as usize..=last {
+            self.verified.set(index, false);
+        }
+    }
+
+    /// Checks that the hash list matches a file of `total_size` bytes.
+    pub fn check_size(&self, total_size: u64) -> anyhow::Result<()> {
+        let expected = total_size.div_ceil(self.piece_length) as usize;
+        if expected != self.hashes.len() {
+            anyhow::bail!(
+                "{} piece hashes do not cover {} bytes at {} bytes per piece",
+                self.hashes.len(), total_size, self.piece_length
+            );
+        }
+        Ok(())
+    }
+
+    /// Unverified pieces whose bytes all lie in completed parts.
+    pub fn ready_pieces(&self, session: &DownloadSession) -> Vec<usize> {
+        let Some(size) = session.total_size else {
+            return Vec::new();
+        };
+
+        let mut completed: Vec<(u64, u64)> = session
+            .parts
+            .iter()
+            .filter(|p| p.completed)
+            .map(|p| (p.start_byte, p.end_byte))
+            .collect();
+        completed.sort_unstable();
+
+        let mut ready = Vec::new();
+        let mut cursor = 0;
+        for index in 0..self.len() {
+            if self.verified.get(index) {
+                continue;
+            }
+            let (start, end) = self.piece_range(index, size);
+            // Completed parts are disjoint and sorted, so walk them once
+            while cursor < completed.len() && completed[cursor].1 < start {
+                cursor += 1;
+            }
+            let mut covered_to = start;
+            let mut i = cursor;
+            while i < completed.len() && completed[i].0 <= covered_to && covered_to <= end {
+                covered_to = completed[i].1 + 1;
+                i += 1;
+            }
+            if covered_to > end {
+                ready.push(index);
+            }
+        }
+        ready
+    }
+}
+
+/// Compact bit set, serialized as a hex string to keep checkpoints small.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self { bits: vec![0; len.div_ceil(8)], len }
+    }
+
+    /// Wraps bits in wire order, as in a peer's bitfield message.
+    pub fn from_bytes(bits: Vec<u8>, len: usize) -> Option<Self> {
+        (bits.len() == len.div_ceil(8)).then_some(Self { bits, len })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        if index >= self.len {
+            return;
+        }
+        if value {
+            self.bits[index / 8] |= 0x80 >> (index % 8);
+        } else {
+            self.bits[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Serialize for Bitfield {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{}:{}", self.len, hex::encode(&self.bits)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Bitfield {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let s = String::deserialize(deserializer)?;
+        let (len, bits) = s.split_once(':').ok_or_else(|| D::Error::custom("expected <len>:<hex>"))?;
+        let len: usize = len.parse().map_err(D::Error::custom)?;
+        let bits = hex::decode(bits).map_err(D::Error::custom)?;
+        if bits.len() != len.div_ceil(8) {
+            return Err(D::Error::custom("bitfield length mismatch"));
+        }
+        Ok(Self { bits, len })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::DownloadPart;
+
+    #[test]
+    fn bitfield_round_trips_through_json() {
+        let mut bits = Bitfield::new(11);
+        bits.set(0, true);
+        bits.set(10, true);
+        let json = serde_json::to_string(&bits).unwrap();
+        assert_eq!(json, "\"11:8020\"");
+        let back: Bitfield = serde_json::from_str(&json).unwrap();
+        assert!(back.get(0) && back.get(10) && !back.get(5));
+        assert_eq!(back.count_ones(), 2);
+    }
+
+    #[test]
+    fn only_pieces_inside_completed_parts_are_ready() {
+        let pieces = PieceHashes::new(HashAlgorithm::Md5, 10, vec!["0".repeat(32); 4]).unwrap();
+        let mut session = DownloadSession::new("http://example.com/f".into(), "/tmp/f".into(), 2);
+        session.total_size = Some(35);
+        session.parts = vec![
+            DownloadPart { id: 0, start_byte: 0, end_byte: 14, current_byte: 15, completed: true },
+            DownloadPart { id: 1, start_byte: 15, end_byte: 24, current_byte: 25, completed: true },
+            DownloadPart { id: 2, start_byte: 25, end_byte: 34, current_byte: 30, completed: false },
+        ];
+        pieces.check_size(35).unwrap();
+        assert_eq!(pieces.ready_pieces(&session), vec![0, 1]);
+        assert_eq!(pieces.piece_range(3, 35), (30, 34));
+    }
+}
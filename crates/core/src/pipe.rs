@@ -0,0 +1,162 @@
+//! Downloads straight into a writer such as stdout, in file order.
+//!
+//! The file is fetched as fixed-size chunks over several connections.
+//! Chunks that arrive ahead of the writer wait in memory, and no chunk is
+//! started that would take the buffered bytes past the limit, so a slow
+//! reader throttles the download instead of growing the buffer. Nothing is
+//! written to disk, so there is no session and no resuming.
+
+use super::downloader::{DownloadObserver, Downloader, RemoteMetadata};
+use super::integrity::{ExpectedDigest, Hasher, IntegrityError};
+use super::transport::TransportError;
+use super::worker::OPEN_END;
+use anyhow::Result;
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Bounds on the chunk size; within them a chunk is the buffer limit
+/// shared by the connections.
+const MIN_CHUNK: u64 = 64 * 1024;
+const MAX_CHUNK: u64 = 8 * 1024 * 1024;
+
+const MAX_ATTEMPTS: u32 = 5;
+
+impl Downloader {
+    /// Writes the file at `url` to `writer` and returns its length.
+    ///
+    /// With range support and a known size, up to `connections` chunks are
+    /// fetched at once and at most `buffer_limit` bytes are held for the
+    /// writer; otherwise the file is streamed over one connection. A
+    /// mismatching `expected` digest is reported after everything was
+    /// written, as the bytes cannot be taken back.
+    pub async fn download_to_writer<W: AsyncWrite + Unpin>(
+        &self,
+        url: &str,
+        writer: &mut W,
+        connections: u8,
+        buffer_limit: u64,
+        expected: Option<ExpectedDigest>,
+        observer: Option<Arc<dyn DownloadObserver>>,
+    ) -> Result<u64> {
+        let RemoteMetadata { total_size, accept_ranges, .. } = self.get_remote_metadata(url).await?;
+        let mut hasher = expected.as_ref().map(|digest| Hasher::new(digest.algorithm));
+        let mut written = 0u64;
+        let mut emit = async |data: &[u8], writer: &mut W| -> Result<()> {
+            writer.write_all(data).await?;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(data);
+            }
+            if let Some(obs) = &observer {
+                obs.on_progress(0, data.len() as u64, 1);
+            }
+            written += data.len() as u64;
+            Ok(())
+        };
+
+        match total_size {
+            Some(size) if accept_ranges && connections > 1 && size > 0 => {
+                let chunk = (buffer_limit / connections as u64).clamp(MIN_CHUNK, MAX_CHUNK);
+                let window = (buffer_limit / chunk).max(1);
+                let chunks = size.div_ceil(chunk);
+                let range_of = |index: u64| (index * chunk, ((index + 1) * chunk).min(size) - 1);
+
+                let mut fetching = FuturesUnordered::new();
+                let mut arrived: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+                let (mut next_start, mut next_write) = (0u64, 0u64);
+                while next_write < chunks {
+                    // Chunks past the writer, fetched or in flight, stay within the window
+                    while fetching.len() < connections as usize && next_start < chunks && next_start - next_write < window {
+                        let (start, end) = range_of(next_start);
+                        let index = next_start;
+                        fetching.push(async move { (index, self.fetch_chunk(url, start, end).await) });
+                        next_start += 1;
+                    }
+                    let Some((index, data)) = fetching.next().await else { break };
+                    arrived.insert(index, data?);
+                    while let Some(data) = arrived.remove(&next_write) {
+                        emit(&data, writer).await?;
+                        next_write += 1;
+                    }
+                }
+            }
+            _ => {
+                let mut body = self.transport_for(url)?.read_range(url, 0, OPEN_END).await?;
+                while let Some(data) = body.next().await {
+                    emit(&data?, writer).await?;
+                }
+            }
+        }
+        writer.flush().await?;
+
+        if let (Some(expected), Some(hasher)) = (expected, hasher) {
+            let actual = hasher.finalize();
+            if actual != expected.value {
+                return Err(IntegrityError::Mismatch { algorithm: expected.algorithm, expected: expected.value, actual }.into());
+            }
+            log::info!("Verified {} against {}", url, expected);
+        }
+        Ok(written)
+    }
+
+    /// Fetches `start..=end` whole, retrying transient failures.
+    async fn fetch_chunk(&self, url: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+        let len = (end - start + 1) as usize;
+        let mut backoff = Duration::from_millis(500);
+        for attempt in 1.. {
+            let result = match self.transport_for(url)?.read_range(url, start, end).await {
+                Ok(body) => crate::transport::collect(body, len).await.map_err(TransportError::Transient),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(data) if data.len() == len => return Ok(data),
+                Ok(data) => log::warn!("Short read of {}-{}: {} of {} bytes", start, end, data.len(), len),
+                Err(TransportError::Transient(e)) if attempt < MAX_ATTEMPTS => log::warn!("Fetching {}-{} failed: {}", start, end, e),
+                Err(e) => return Err(e.into()),
+            }
+            if attempt >= MAX_ATTEMPTS {
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        anyhow::bail!("could not fetch bytes {}-{} of {} after {} attempts", start, end, url, MAX_ATTEMPTS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrity::HashAlgorithm;
+    use crate::test_support;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn writes_in_order_and_verifies() {
+        let body = test_support::payload(3 * 1024 * 1024 + 17);
+        let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+        hasher.update(&body);
+        let digest = ExpectedDigest::new(HashAlgorithm::Sha256, &hasher.finalize()).unwrap();
+
+        let server = test_support::serve([("/f.bin".to_string(), body.clone())].into(), HashMap::new(), None).await;
+        let downloader = Downloader::new("test").unwrap();
+
+        let mut out = Vec::new();
+        let written = downloader
+            .download_to_writer(&server.url("/f.bin"), &mut out, 4, 1024 * 1024, Some(digest), None)
+            .await
+            .unwrap();
+        assert_eq!(written, body.len() as u64);
+        assert_eq!(out, body);
+
+        let wrong = ExpectedDigest::new(HashAlgorithm::Sha256, &"0".repeat(64)).unwrap();
+        let err = downloader
+            .download_to_writer(&server.url("/f.bin"), &mut Vec::new(), 1, 1024 * 1024, Some(wrong), None)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<IntegrityError>().is_some());
+    }
+}
@@ -0,0 +1,393 @@
+//! Sequential downloads, and a local HTTP server that serves a file while
+//! it downloads.
+//!
+//! A sequential session is planned as many small parts fetched in file
+//! order, so the head of the file fills in contiguously. [`PreviewServer`]
+//! answers GET and HEAD, with single byte ranges; a request for bytes not
+//! on disk yet blocks until they arrive and asks the download to fetch them
+//! next, so a media player can start playing and seeking right away.
+
+use super::downloader::Downloader;
+use super::range::ByteRange;
+use super::session::{DownloadPart, DownloadSession};
+use anyhow::Result;
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Parts of a sequential download are this large, so the head of the file
+/// is complete soon after the start...
+const SEQUENTIAL_PART_SIZE: u64 = 4 * 1024 * 1024;
+/// ...unless that would make more than this many, leaving part ids for
+/// work stealing and seeks.
+const MAX_SEQUENTIAL_PARTS: u64 = 64;
+
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+const READ_CHUNK: u64 = 64 * 1024;
+
+impl Downloader {
+    /// Switches `session` to sequential mode. A session that has not
+    /// started is re-planned into small parts; one being resumed keeps its
+    /// parts and only fetches them in file order.
+    pub fn plan_sequential(session: &mut DownloadSession) {
+        session.sequential = true;
+        let fresh = session.parts.iter().all(|p| !p.completed && p.current_byte == p.start_byte);
+        if !fresh || session.total_size.is_none() || session.connections <= 1 || session.delta.is_some() {
+            return;
+        }
+        let (Some(first), Some(last)) = (
+            session.parts.iter().map(|p| p.start_byte).min(),
+            session.parts.iter().map(|p| p.end_byte).max(),
+        ) else {
+            return;
+        };
+
+        let part_size = SEQUENTIAL_PART_SIZE.max((last - first + 1).div_ceil(MAX_SEQUENTIAL_PARTS));
+        session.parts.clear();
+        let (mut start_byte, mut id) = (first, 0);
+        while start_byte <= last {
+            let end_byte = (start_byte + part_size - 1).min(last);
+            session.parts.push(DownloadPart { id, start_byte, end_byte, current_byte: start_byte, completed: false });
+            start_byte = end_byte + 1;
+            id += 1;
+        }
+    }
+}
+
+/// Which bytes of the file are on disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Availability {
+    /// Sorted, merged, inclusive.
+    ranges: Vec<(u64, u64)>,
+    /// The download stopped; whatever is missing now stays missing.
+    closed: bool,
+}
+
+/// Connects a running download with the [`PreviewServer`] serving it.
+#[derive(Clone)]
+pub struct PreviewHandle {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    name: String,
+    size: u64,
+    available: watch::Sender<Availability>,
+    /// Latest offset a reader is waiting for.
+    seek: Mutex<Option<u64>>,
+}
+
+impl PreviewHandle {
+    /// Only downloads whose parts write the output file directly, and
+    /// whose size is known, can be previewed.
+    pub fn new(session: &DownloadSession) -> Result<Self> {
+        if session.stream.is_some() || session.torrent.is_some() || session.zip_entry.is_some() {
+            anyhow::bail!("only plain downloads can be previewed");
+        }
+        let Some(size) = session.total_size else {
+            anyhow::bail!("cannot preview a download of unknown size");
+        };
+        let name = session
+            .output_path
+            .file_name()
+            .map_or_else(|| "download".to_string(), |n| n.to_string_lossy().into_owned());
+        let (available, _) = watch::channel(Availability { ranges: downloaded_ranges(session), closed: false });
+        Ok(Self {
+            inner: Arc::new(Inner { path: session.data_path(), name, size, available, seek: Mutex::new(None) }),
+        })
+    }
+
+    pub(crate) fn publish(&self, session: &DownloadSession) {
+        let ranges = downloaded_ranges(session);
+        self.inner.available.send_if_modified(|available| {
+            let changed = available.ranges != ranges;
+            available.ranges = ranges;
+            changed
+        });
+    }
+
+    /// Publishes the final state; readers stop waiting for missing bytes.
+    pub(crate) fn close(&self, session: &DownloadSession) {
+        let ranges = downloaded_ranges(session);
+        self.inner.available.send_modify(|available| {
+            available.ranges = ranges;
+            available.closed = true;
+        });
+    }
+
+    pub(crate) fn take_seek(&self) -> Option<u64> {
+        self.inner.seek.lock().unwrap().take()
+    }
+
+    /// Waits until byte `pos` is on disk and returns the last byte of the
+    /// run it is in, or `None` if the download stopped without it.
+    async fn wait_for(&self, pos: u64) -> Option<u64> {
+        let mut rx = self.inner.available.subscribe();
+        let mut requested = false;
+        loop {
+            {
+                let available = rx.borrow_and_update();
+                if let Some(&(_, end)) = available.ranges.iter().find(|(start, end)| *start <= pos && pos <= *end) {
+                    return Some(end);
+                }
+                if available.closed {
+                    return None;
+                }
+            }
+            if !requested {
+                *self.inner.seek.lock().unwrap() = Some(pos);
+                requested = true;
+            }
+            rx.changed().await.ok()?;
+        }
+    }
+}
+
+fn downloaded_ranges(session: &DownloadSession) -> Vec<(u64, u64)> {
+    let mut spans: Vec<(u64, u64)> = session
+        .parts
+        .iter()
+        .filter_map(|p| match p.completed {
+            true => Some((p.start_byte, p.end_byte)),
+            false if p.current_byte > p.start_byte => Some((p.start_byte, (p.current_byte - 1).min(p.end_byte))),
+            false => None,
+        })
+        .collect();
+    spans.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(spans.len());
+    for (start, end) in spans {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Serves one download over HTTP on a local address, at any path. Stops
+/// when dropped.
+pub struct PreviewServer {
+    addr: SocketAddr,
+    handle: PreviewHandle,
+    task: JoinHandle<()>,
+}
+
+impl PreviewServer {
+    pub async fn bind(addr: impl ToSocketAddrs, handle: PreviewHandle) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let served = handle.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else { return };
+                let handle = served.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, &handle).await {
+                        log::debug!("Preview connection ended: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self { addr, handle, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// URL to open in a player; it ends in the file name so players can
+    /// guess the format.
+    pub fn url(&self) -> String {
+        format!("http://{}/{}", self.addr, utf8_percent_encode(&self.handle.inner.name, NON_ALPHANUMERIC))
+    }
+
+    /// Pass this to [`Downloader::run_with_preview`].
+    pub fn handle(&self) -> &PreviewHandle {
+        &self.handle
+    }
+}
+
+impl Drop for PreviewServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(mut stream: TcpStream, handle: &PreviewHandle) -> Result<()> {
+    let head = read_head(&mut stream).await?;
+    let mut lines = head.lines();
+    let method = lines.next().and_then(|l| l.split_whitespace().next()).unwrap_or_default().to_string();
+    if method != "GET" && method != "HEAD" {
+        return respond(&mut stream, "405 Method Not Allowed", &[("Allow", "GET, HEAD".to_string())]).await;
+    }
+    let range = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("range").then(|| value.trim().to_string())
+    });
+
+    // Multiple ranges are not supported; ignoring the header is allowed
+    let size = handle.inner.size;
+    let mut headers = vec![
+        ("Content-Type", content_type(&handle.inner.name).to_string()),
+        ("Accept-Ranges", "bytes".to_string()),
+    ];
+    let (status, span) = match range.as_deref().and_then(|r| r.strip_prefix("bytes=")).filter(|r| !r.contains(',')) {
+        Some(spec) => match spec.parse::<ByteRange>().and_then(|r| r.resolve(Some(size))) {
+            Ok((start, end)) => {
+                headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end, size)));
+                ("206 Partial Content", Some((start, end)))
+            }
+            Err(_) => {
+                let headers = [("Content-Range", format!("bytes */{}", size))];
+                return respond(&mut stream, "416 Range Not Satisfiable", &headers).await;
+            }
+        },
+        None => ("200 OK", (size > 0).then(|| (0, size - 1))),
+    };
+    headers.push(("Content-Length", span.map_or(0, |(start, end)| end - start + 1).to_string()));
+    write_head(&mut stream, status, &headers).await?;
+
+    let Some((start, end)) = span.filter(|_| method == "GET") else {
+        return Ok(());
+    };
+    let mut file = tokio::fs::File::open(&handle.inner.path).await?;
+    let mut buf = vec![0; READ_CHUNK as usize];
+    let mut pos = start;
+    while pos <= end {
+        let Some(available_end) = handle.wait_for(pos).await else {
+            anyhow::bail!("download stopped before byte {}", pos);
+        };
+        let n = (available_end.min(end) - pos + 1).min(READ_CHUNK) as usize;
+        file.seek(SeekFrom::Start(pos)).await?;
+        file.read_exact(&mut buf[..n]).await?;
+        stream.write_all(&buf[..n]).await?;
+        pos += n as u64;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_HEAD {
+            anyhow::bail!("request head too large");
+        }
+        match stream.read(&mut chunk).await? {
+            0 => anyhow::bail!("connection closed before the request was complete"),
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+async fn write_head(stream: &mut TcpStream, status: &str, headers: &[(&str, String)]) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, headers: &[(&str, String)]) -> Result<()> {
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Length", "0".to_string()));
+    write_head(stream, status, &headers).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Media types players care about; everything else is a byte stream.
+fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "avi" => "video/x-msvideo",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "flac" => "audio/flac",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "wav" => "audio/wav",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::collections::HashMap;
+
+    #[test]
+    fn plans_small_parts_in_file_order() {
+        let mut session = DownloadSession::new("http://x/f".into(), "f".into(), 8);
+        session.total_size = Some(100 * 1024 * 1024);
+        Downloader::plan_parts(&mut session, true);
+        Downloader::plan_sequential(&mut session);
+        assert!(session.sequential);
+        assert_eq!(session.parts.len(), 25);
+        assert!(session.parts.windows(2).all(|w| w[1].start_byte == w[0].end_byte + 1));
+        assert_eq!(session.parts.last().unwrap().end_byte, 100 * 1024 * 1024 - 1);
+
+        session.total_size = Some(10 * 1024 * 1024 * 1024);
+        Downloader::plan_parts(&mut session, true);
+        Downloader::plan_sequential(&mut session);
+        assert_eq!(session.parts.len(), 64);
+
+        // Resumed sessions keep their layout
+        session.parts[0].current_byte += 1;
+        Downloader::plan_sequential(&mut session);
+        assert_eq!(session.parts.len(), 64);
+        assert_eq!(session.parts[0].current_byte, 1);
+    }
+
+    #[tokio::test]
+    async fn serves_ranges_while_downloading() {
+        let body = test_support::payload(6 * 1024 * 1024 + 123);
+        let server = test_support::serve([("/movie.mp4".to_string(), body.clone())].into(), HashMap::new(), None).await;
+        let downloader = Downloader::new("test").unwrap();
+        let output = test_support::temp_path("preview").with_file_name("movie.mp4");
+        let mut session = downloader.init_download(&server.url("/movie.mp4"), Some(output), 4).await.unwrap();
+        Downloader::plan_sequential(&mut session);
+
+        let handle = PreviewHandle::new(&session).unwrap();
+        let preview = PreviewServer::bind("127.0.0.1:0", handle.clone()).await.unwrap();
+        assert!(preview.url().ends_with("/movie%2Emp4"));
+
+        // Ask before anything is downloaded; the requests wait for the bytes
+        let client = reqwest::Client::new();
+        let tail = client.get(preview.url()).header("Range", "bytes=5000000-5999999").send();
+        let whole = client.get(preview.url()).send();
+        let download = downloader.run_with_preview(&mut session, None, None, None, &handle);
+        let (tail, whole, done) = tokio::join!(tail, whole, download);
+        done.unwrap();
+
+        let tail = tail.unwrap();
+        assert_eq!(tail.status(), 206);
+        assert_eq!(tail.headers()["content-type"], "video/mp4");
+        assert_eq!(tail.headers()["content-range"], format!("bytes 5000000-5999999/{}", body.len()));
+        assert_eq!(tail.bytes().await.unwrap(), &body[5_000_000..6_000_000]);
+        let whole = whole.unwrap();
+        assert_eq!(whole.status(), 200);
+        assert_eq!(whole.bytes().await.unwrap(), body);
+
+        let unsatisfiable = client.get(preview.url()).header("Range", "bytes=99999999-").send().await.unwrap();
+        assert_eq!(unsatisfiable.status(), 416);
+    }
+}
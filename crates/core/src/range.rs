@@ -0,0 +1,190 @@
+//! Partial downloads: fetch only bytes X–Y of a remote file.
+//!
+//! The parts are split inside the requested range. The output either keeps
+//! the bytes at their offset in the remote file, so the file ends at the
+//! last requested byte, or is compacted to start at 0 by shifting every
+//! request by [`remote_offset`](crate::session::DownloadSession::remote_offset).
+//! Whole-file digests, piece hashes and multipart ETags describe bytes we
+//! do not fetch and are not used.
+
+use super::downloader::{Downloader, RemoteMetadata};
+use super::session::{DownloadSession, DownloadState};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A byte range as written on the command line, before the remote size is
+/// known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `X-Y`, or `X-` to the end of the file. Both ends are inclusive.
+    From(u64, Option<u64>),
+    /// `-N`: the last N bytes.
+    Last(u64),
+}
+
+impl ByteRange {
+    /// Resolves to inclusive `(start, end)` offsets in a file of `size`
+    /// bytes; only `X-Y` works when the size is unknown.
+    pub fn resolve(&self, size: Option<u64>) -> Result<(u64, u64)> {
+        let (start, end) = match (*self, size) {
+            (ByteRange::From(start, Some(end)), None) => (start, end),
+            (ByteRange::From(start, end), Some(size)) => {
+                if start >= size {
+                    anyhow::bail!("range starts at {} but the file has {} bytes", start, size);
+                }
+                (start, end.map_or(size - 1, |end| end.min(size - 1)))
+            }
+            (ByteRange::Last(n), Some(size)) => {
+                if n == 0 || size == 0 {
+                    anyhow::bail!("range selects no bytes");
+                }
+                (size.saturating_sub(n), size - 1)
+            }
+            (_, None) => anyhow::bail!("server did not report the file size; give the range as X-Y"),
+        };
+        Ok((start, end))
+    }
+}
+
+impl FromStr for ByteRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("invalid range {:?}: expected X-Y, X- or -N", s);
+        let (start, end) = s.trim().split_once('-').ok_or_else(invalid)?;
+        let parse = |n: &str| n.trim().parse::<u64>().map_err(|_| invalid());
+        match (start.trim().is_empty(), end.trim().is_empty()) {
+            (true, true) => Err(invalid()),
+            (true, false) => Ok(ByteRange::Last(parse(end)?)),
+            (false, true) => Ok(ByteRange::From(parse(start)?, None)),
+            (false, false) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if end < start {
+                    anyhow::bail!("invalid range {:?}: end is before start", s);
+                }
+                Ok(ByteRange::From(start, Some(end)))
+            }
+        }
+    }
+}
+
+/// The slice of the remote file a partial download covers.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PartialRange {
+    /// First and last requested byte, in remote offsets.
+    pub start: u64,
+    pub end: u64,
+    /// Size of the whole remote file, if the server reported it.
+    pub remote_size: Option<u64>,
+    /// The output starts with byte `start` instead of keeping it at offset
+    /// `start`.
+    pub compact: bool,
+}
+
+impl PartialRange {
+    /// Number of requested bytes; never zero.
+    pub fn byte_count(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+impl Downloader {
+    /// Creates a session that downloads only `range` of `url`.
+    ///
+    /// With `compact` the output holds just the range; otherwise the bytes
+    /// land at their remote offsets and the output is `end + 1` bytes long,
+    /// with whatever was there before, or zeros, in front of the range.
+    pub async fn init_range(
+        &self,
+        url: &str,
+        range: ByteRange,
+        compact: bool,
+        output_path: Option<PathBuf>,
+        connections: u8,
+    ) -> Result<DownloadSession> {
+        let RemoteMetadata { filename, total_size, accept_ranges, .. } = self.get_remote_metadata(url).await?;
+        if !accept_ranges {
+            anyhow::bail!("{} does not support range requests", url);
+        }
+        let (start, end) = range.resolve(total_size)?;
+
+        let output_path = output_path.unwrap_or_else(|| crate::utils::fs::get_downloads_dir().join(filename));
+        let mut session = DownloadSession::new(url.to_string(), output_path, connections);
+        let partial = PartialRange { start, end, remote_size: total_size, compact };
+        if compact {
+            session.remote_offset = start;
+            session.total_size = Some(partial.byte_count());
+            Self::split_range(&mut session, 0, end - start);
+        } else {
+            session.total_size = Some(end + 1);
+            Self::split_range(&mut session, start, end);
+        }
+        log::info!("Downloading bytes {}-{} of {} ({} bytes)", start, end, url, partial.byte_count());
+        session.range = Some(partial);
+        session.state = DownloadState::Downloading;
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parses_and_resolves_ranges() {
+        assert_eq!("100-199".parse::<ByteRange>().unwrap(), ByteRange::From(100, Some(199)));
+        assert_eq!("100-".parse::<ByteRange>().unwrap(), ByteRange::From(100, None));
+        assert_eq!("-500".parse::<ByteRange>().unwrap(), ByteRange::Last(500));
+        for bad in ["", "-", "abc", "10", "20-10", "1-2-3"] {
+            assert!(bad.parse::<ByteRange>().is_err(), "{:?} should not parse", bad);
+        }
+
+        assert_eq!(ByteRange::From(100, Some(199)).resolve(None).unwrap(), (100, 199));
+        assert_eq!(ByteRange::From(100, Some(5000)).resolve(Some(1000)).unwrap(), (100, 999));
+        assert_eq!(ByteRange::From(100, None).resolve(Some(1000)).unwrap(), (100, 999));
+        assert_eq!(ByteRange::Last(300).resolve(Some(1000)).unwrap(), (700, 999));
+        assert_eq!(ByteRange::Last(3000).resolve(Some(1000)).unwrap(), (0, 999));
+        assert!(ByteRange::From(1000, None).resolve(Some(1000)).is_err());
+        assert!(ByteRange::Last(10).resolve(None).is_err());
+    }
+
+    #[tokio::test]
+    async fn downloads_only_the_requested_range() {
+        let body = test_support::payload(256 * 1024);
+        let server = test_support::serve([("/f.bin".to_string(), body.clone())].into(), HashMap::new(), None).await;
+        let url = server.url("/f.bin");
+        let downloader = Downloader::new("test").unwrap();
+        let (start, end) = (10_000usize, 150_000usize);
+
+        let output = test_support::temp_path("range-compact");
+        let mut session = downloader
+            .init_range(&url, ByteRange::From(start as u64, Some(end as u64)), true, Some(output.clone()), 4)
+            .await
+            .unwrap();
+        assert_eq!(session.parts.len(), 4);
+        downloader.run(&mut session, None, None, None).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), &body[start..=end]);
+        assert_eq!(session.completed_bytes(), session.target_size().unwrap());
+
+        let output = test_support::temp_path("range-offset");
+        let mut session = downloader
+            .init_range(&url, ByteRange::From(start as u64, Some(end as u64)), false, Some(output.clone()), 4)
+            .await
+            .unwrap();
+        assert!(session.parts.iter().all(|p| p.start_byte >= start as u64 && p.end_byte <= end as u64));
+        downloader.run(&mut session, None, None, None).await.unwrap();
+        let written = std::fs::read(&output).unwrap();
+        assert_eq!(written.len(), end + 1);
+        assert!(written[..start].iter().all(|&b| b == 0));
+        assert_eq!(&written[start..], &body[start..=end]);
+
+        let output = test_support::temp_path("range-tail");
+        let mut session = downloader.init_range(&url, ByteRange::Last(1000), true, Some(output.clone()), 8).await.unwrap();
+        downloader.run(&mut session, None, None, None).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), &body[body.len() - 1000..]);
+    }
+}
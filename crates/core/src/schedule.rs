@@ -0,0 +1,566 @@
+//! Time-based rules for when downloads run and how fast.
+//!
+//! A rule is written like `01:00-07:00`, `pause weekdays 09:00-17:00` or
+//! `limit 2M sat,sun`: an action, the days it applies to and a time of
+//! day window, in local time. Rules come from two places, the global
+//! [`Schedule`] kept by a [`Scheduler`] and the download's own
+//! `session.schedule`, and both must allow a download for it to run.
+//! Within one schedule:
+//!
+//! - with any `only` rules, downloads run only inside one of them;
+//! - an active `pause` rule stops them;
+//! - active `limit` rules cap the speed, the lowest one winning. Global
+//!   limits are shared by every download under the scheduler.
+
+use super::downloader::{DownloadObserver, Downloader};
+use super::session::{DownloadSession, DownloadState};
+use anyhow::Result;
+use chrono::{Datelike, Timelike, Weekday};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// How often rules are re-evaluated while a download runs or waits.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+const ALL_DAYS: u8 = 0b111_1111;
+const WEEKDAYS: u8 = 0b001_1111;
+const WEEKENDS: u8 = 0b110_0000;
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Downloads run only inside this window (or another `only` window).
+    Only,
+    Pause,
+    /// Bytes per second.
+    Limit(u64),
+}
+
+/// One rule of a [`Schedule`]. Stored as its text form, e.g.
+/// `pause weekdays 09:00-17:00`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ScheduleRule {
+    pub action: RuleAction,
+    /// Bit 0 is Monday. A window past midnight belongs to the day it
+    /// starts on.
+    pub days: u8,
+    /// Minutes after midnight. `start >= end` wraps past midnight, so
+    /// `0..1440` is the whole day.
+    pub start: u16,
+    pub end: u16,
+}
+
+impl ScheduleRule {
+    pub fn is_active(&self, day: Weekday, minute: u16) -> bool {
+        let on = |day: Weekday| self.days & (1 << day.num_days_from_monday()) != 0;
+        if self.start < self.end {
+            on(day) && self.start <= minute && minute < self.end
+        } else {
+            (on(day) && minute >= self.start) || (on(day.pred()) && minute < self.end)
+        }
+    }
+}
+
+impl FromStr for ScheduleRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace().peekable();
+        let action = match words.peek().map(|w| w.to_ascii_lowercase()).as_deref() {
+            Some("pause") => {
+                words.next();
+                RuleAction::Pause
+            }
+            Some("limit") => {
+                words.next();
+                let rate = words.next().ok_or_else(|| anyhow::anyhow!("invalid rule {:?}: limit needs a rate, e.g. 2M", s))?;
+                RuleAction::Limit(parse_rate(rate)?)
+            }
+            Some("only") => {
+                words.next();
+                RuleAction::Only
+            }
+            _ => RuleAction::Only,
+        };
+
+        let (mut days, mut window) = (None, None);
+        for word in words {
+            if word.contains(':') && window.is_none() {
+                window = Some(parse_window(word)?);
+            } else if days.is_none() {
+                days = Some(parse_days(word)?);
+            } else {
+                anyhow::bail!("invalid rule {:?}: unexpected {:?}", s, word);
+            }
+        }
+        let (start, end) = window.unwrap_or((0, MINUTES_PER_DAY));
+        Ok(Self { action, days: days.unwrap_or(ALL_DAYS), start, end })
+    }
+}
+
+impl fmt::Display for ScheduleRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.action {
+            RuleAction::Only => write!(f, "only")?,
+            RuleAction::Pause => write!(f, "pause")?,
+            RuleAction::Limit(rate) => write!(f, "limit {}", format_rate(rate))?,
+        }
+        match self.days {
+            ALL_DAYS => {}
+            WEEKDAYS => write!(f, " weekdays")?,
+            WEEKENDS => write!(f, " weekends")?,
+            days => {
+                let names: Vec<_> = (0..7).filter(|i| days & (1 << i) != 0).map(|i| DAY_NAMES[i]).collect();
+                write!(f, " {}", names.join(","))?;
+            }
+        }
+        if (self.start, self.end) != (0, MINUTES_PER_DAY) {
+            let time = |m: u16| format!("{:02}:{:02}", m / 60, m % 60);
+            write!(f, " {}-{}", time(self.start), time(self.end))?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for ScheduleRule {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<ScheduleRule> for String {
+    fn from(rule: ScheduleRule) -> Self {
+        rule.to_string()
+    }
+}
+
+/// `2M`, `500K`, `1.5MB/s` or plain bytes per second; units are binary.
+fn parse_rate(s: &str) -> Result<u64> {
+    let lower = s.to_ascii_lowercase();
+    let trimmed = lower.trim_end_matches("/s").trim_end_matches('b');
+    let (number, unit) = match trimmed.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((i, _)) => trimmed.split_at(i),
+        None => (trimmed, ""),
+    };
+    let multiplier = match unit {
+        "" => 1.0,
+        "k" => 1024.0,
+        "m" => 1024.0 * 1024.0,
+        "g" => 1024.0 * 1024.0 * 1024.0,
+        _ => anyhow::bail!("invalid rate {:?}: expected e.g. 500K or 2M", s),
+    };
+    let number: f64 = number.parse().map_err(|_| anyhow::anyhow!("invalid rate {:?}: expected e.g. 500K or 2M", s))?;
+    let rate = (number * multiplier) as u64;
+    if rate == 0 {
+        anyhow::bail!("invalid rate {:?}: must be above zero", s);
+    }
+    Ok(rate)
+}
+
+fn format_rate(rate: u64) -> String {
+    match rate {
+        r if r % (1024 * 1024) == 0 => format!("{}M", r / (1024 * 1024)),
+        r if r % 1024 == 0 => format!("{}K", r / 1024),
+        r => r.to_string(),
+    }
+}
+
+/// `01:00-07:00`; `24:00` is allowed as the end.
+fn parse_window(s: &str) -> Result<(u16, u16)> {
+    let invalid = || anyhow::anyhow!("invalid time window {:?}: expected HH:MM-HH:MM", s);
+    let time = |t: &str| -> Result<u16> {
+        let (h, m) = t.split_once(':').ok_or_else(invalid)?;
+        let (h, m): (u16, u16) = (h.parse().map_err(|_| invalid())?, m.parse().map_err(|_| invalid())?);
+        if h > 24 || m >= 60 || h * 60 + m > MINUTES_PER_DAY {
+            return Err(invalid());
+        }
+        Ok(h * 60 + m)
+    };
+    let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+    Ok((time(start)?, time(end)?))
+}
+
+/// `daily`, `weekdays`, `weekends`, or days and ranges like `mon-fri,sun`.
+fn parse_days(s: &str) -> Result<u8> {
+    let lower = s.to_ascii_lowercase();
+    match lower.as_str() {
+        "daily" => return Ok(ALL_DAYS),
+        "weekdays" => return Ok(WEEKDAYS),
+        "weekends" => return Ok(WEEKENDS),
+        _ => {}
+    }
+    let day = |name: &str| -> Result<usize> {
+        DAY_NAMES
+            .iter()
+            .position(|d| name.get(..3).is_some_and(|prefix| d.starts_with(prefix)) && Weekday::from_str(name).is_ok())
+            .ok_or_else(|| anyhow::anyhow!("invalid day {:?} in {:?}", name, s))
+    };
+    let mut days = 0u8;
+    for item in lower.split(',') {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (day(first)?, day(last)?);
+                let mut i = first;
+                loop {
+                    days |= 1 << i;
+                    if i == last {
+                        break;
+                    }
+                    i = (i + 1) % 7;
+                }
+            }
+            None => days |= 1 << day(item)?,
+        }
+    }
+    Ok(days)
+}
+
+/// What the rules in force say.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub run: bool,
+    /// Bytes per second.
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Schedule {
+    pub rules: Vec<ScheduleRule>,
+}
+
+impl Schedule {
+    /// Parses one rule per string.
+    pub fn parse<S: AsRef<str>>(rules: &[S]) -> Result<Self> {
+        let rules = rules.iter().map(|r| r.as_ref().parse()).collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn decide(&self, day: Weekday, minute: u16) -> Decision {
+        let active = |rule: &&ScheduleRule| rule.is_active(day, minute);
+        let mut windows = self.rules.iter().filter(|r| r.action == RuleAction::Only).peekable();
+        let in_window = windows.peek().is_none() || windows.any(|r| active(&r));
+        let paused = self.rules.iter().filter(active).any(|r| r.action == RuleAction::Pause);
+        let limit = self
+            .rules
+            .iter()
+            .filter(active)
+            .filter_map(|r| match r.action {
+                RuleAction::Limit(rate) => Some(rate),
+                _ => None,
+            })
+            .min();
+        Decision { run: in_window && !paused, limit }
+    }
+
+    /// Where the global schedule is kept, next to the session store.
+    pub fn default_path() -> PathBuf {
+        crate::utils::fs::get_state_dir().join("schedule.json")
+    }
+
+    /// Reads a schedule saved with [`save`](Self::save); a missing file is
+    /// an empty schedule.
+    pub async fn load(path: &Path) -> Result<Self> {
+        match tokio::fs::read_to_string(path).await {
+            Ok(json) => Ok(serde_json::from_str(&json)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec_pretty(self)?).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+}
+
+/// Token bucket that holds callers to a rate which can change at any time.
+/// A limiter with a parent also waits for the parent, so a global limit
+/// can sit above per-download ones.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// Bytes per second; 0 is unlimited.
+    rate: AtomicU64,
+    bucket: Mutex<Option<Bucket>>,
+    parent: Option<Arc<RateLimiter>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative while callers are in debt.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_parent(parent: Arc<RateLimiter>) -> Self {
+        Self { parent: Some(parent), ..Self::default() }
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.rate.store(rate.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        Some(self.rate.load(Ordering::Relaxed)).filter(|&r| r > 0)
+    }
+
+    /// Waits until `bytes` may pass this limiter and its parents.
+    pub async fn acquire(&self, bytes: u64) {
+        let mut limiter = Some(self);
+        while let Some(current) = limiter {
+            if let Some(wait) = current.reserve(bytes) {
+                tokio::time::sleep(wait).await;
+            }
+            limiter = current.parent.as_deref();
+        }
+    }
+
+    /// Takes `bytes` from the bucket and returns how long to wait for them.
+    fn reserve(&self, bytes: u64) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let Some(rate) = self.rate() else {
+            // Start from empty once a limit is set
+            *bucket = None;
+            return None;
+        };
+        let now = Instant::now();
+        let rate = rate as f64;
+        let bucket = bucket.get_or_insert(Bucket { tokens: 0.0, refilled: now });
+        // At most one second's worth of burst
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * rate).min(rate);
+        bucket.refilled = now;
+        bucket.tokens -= bytes as f64;
+        (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+    }
+}
+
+/// The global schedule, and the limiter its limits apply to, shared by
+/// every download run under it.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    global: RwLock<Schedule>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Scheduler {
+    pub fn new(global: Schedule) -> Self {
+        Self { global: RwLock::new(global), limiter: Arc::new(RateLimiter::new()) }
+    }
+
+    pub fn global(&self) -> Schedule {
+        self.global.read().unwrap().clone()
+    }
+
+    /// Replaces the global rules; running downloads pick them up within a
+    /// second.
+    pub fn set_global(&self, schedule: Schedule) {
+        *self.global.write().unwrap() = schedule;
+    }
+
+    /// Applies the rules in force now to the global limiter and to
+    /// `limiter`, and returns whether the download may run.
+    fn apply(&self, own: Option<&Schedule>, limiter: &RateLimiter) -> bool {
+        let now = chrono::Local::now();
+        let (day, minute) = (now.weekday(), (now.hour() * 60 + now.minute()) as u16);
+        let global = self.global.read().unwrap().decide(day, minute);
+        self.limiter.set_rate(global.limit);
+        let own = own.map_or(Decision { run: true, limit: None }, |s| s.decide(day, minute));
+        limiter.set_rate(own.limit);
+        global.run && own.run
+    }
+}
+
+impl Downloader {
+    /// Like [`run`](Self::run), under the rules of `scheduler` and
+    /// `session.schedule`.
+    ///
+    /// Outside the allowed times the session is checkpointed and waits in
+    /// [`DownloadState::Paused`]; it resumes by itself when the rules
+    /// allow it again, and speed limits follow the rules as they change.
+    /// Only `cancel_flag` stops it for good.
+    pub async fn run_scheduled(
+        &self,
+        session: &mut DownloadSession,
+        observer: Option<Arc<dyn DownloadObserver>>,
+        session_file: Option<PathBuf>,
+        cancel_flag: Option<Arc<AtomicBool>>,
+        scheduler: Arc<Scheduler>,
+    ) -> Result<()> {
+        let limiter = Arc::new(RateLimiter::with_parent(scheduler.limiter.clone()));
+        let downloader = self.clone().with_rate_limiter(limiter.clone());
+        let cancelled = move || cancel_flag.as_ref().is_some_and(|flag| flag.load(Ordering::Relaxed));
+
+        loop {
+            if cancelled() {
+                return Err(anyhow::anyhow!("cancelled"));
+            }
+            if !scheduler.apply(session.schedule.as_ref(), &limiter) {
+                if session.state != DownloadState::Paused {
+                    log::info!("Schedule pauses {:?}", session.output_path);
+                    Downloader::set_state(session, &observer, DownloadState::Paused);
+                    if let Some(path) = &session_file {
+                        let _ = session.checkpoint(path).await;
+                    }
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+                continue;
+            }
+            if session.state == DownloadState::Paused {
+                log::info!("Schedule resumes {:?}", session.output_path);
+                Downloader::set_state(session, &observer, DownloadState::Downloading);
+            }
+
+            // Stops the run when the user cancels or the rules stop allowing it
+            let stop = Arc::new(AtomicBool::new(false));
+            let monitor = {
+                let (stop, scheduler, limiter) = (stop.clone(), scheduler.clone(), limiter.clone());
+                let (own, cancelled) = (session.schedule.clone(), cancelled.clone());
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                        if cancelled() || !scheduler.apply(own.as_ref(), &limiter) {
+                            stop.store(true, Ordering::Relaxed);
+                            return;
+                        }
+                    }
+                })
+            };
+            let result = downloader.run(session, observer.clone(), session_file.clone(), Some(stop.clone())).await;
+            monitor.abort();
+
+            // Stopped by the schedule: wait for it to allow the download again
+            match result {
+                Err(_) if stop.load(Ordering::Relaxed) && !cancelled() => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parses_and_formats_rules() {
+        let rule: ScheduleRule = "01:00-07:00".parse().unwrap();
+        assert_eq!(rule, ScheduleRule { action: RuleAction::Only, days: ALL_DAYS, start: 60, end: 420 });
+        assert_eq!(rule.to_string(), "only 01:00-07:00");
+
+        let rule: ScheduleRule = "pause weekdays 09:00-17:30".parse().unwrap();
+        assert_eq!((rule.action, rule.days, rule.start, rule.end), (RuleAction::Pause, WEEKDAYS, 540, 1050));
+        let rule: ScheduleRule = "limit 2MB/s mon-wed,Sunday".parse().unwrap();
+        assert_eq!(rule.action, RuleAction::Limit(2 * 1024 * 1024));
+        assert_eq!(rule.to_string(), "limit 2M mon,tue,wed,sun");
+        assert_eq!("only fri-mon 22:00-06:00".parse::<ScheduleRule>().unwrap().days, 0b111_0001);
+
+        for rule in ["pause weekdays 09:00-17:00", "limit 500K weekends", "only sat 22:00-24:00"] {
+            assert_eq!(rule.parse::<ScheduleRule>().unwrap().to_string(), rule);
+        }
+        for bad in ["limit", "limit fast", "pause 25:00-26:00", "pause 1100:00-12:00", "only someday", "äää", "pause mon tue"] {
+            assert!(bad.parse::<ScheduleRule>().is_err(), "{:?} should not parse", bad);
+        }
+
+        let schedule = Schedule::parse(&["01:00-07:00", "limit 1M"]).unwrap();
+        let json = serde_json::to_string(&schedule).unwrap();
+        assert_eq!(json, r#"["only 01:00-07:00","limit 1M"]"#);
+        assert_eq!(serde_json::from_str::<Schedule>(&json).unwrap(), schedule);
+    }
+
+    #[test]
+    fn decides_by_day_and_time() {
+        let at = |h: u16, m: u16| h * 60 + m;
+        let night = Schedule::parse(&["22:00-06:00"]).unwrap();
+        assert!(night.decide(Weekday::Mon, at(23, 0)).run);
+        assert!(night.decide(Weekday::Tue, at(5, 59)).run);
+        assert!(!night.decide(Weekday::Tue, at(6, 0)).run);
+
+        let office = Schedule::parse(&["pause weekdays 09:00-17:00", "limit 2M weekdays", "limit 1M fri"]).unwrap();
+        assert_eq!(office.decide(Weekday::Mon, at(12, 0)), Decision { run: false, limit: Some(2 * 1024 * 1024) });
+        assert_eq!(office.decide(Weekday::Fri, at(18, 0)), Decision { run: true, limit: Some(1024 * 1024) });
+        assert_eq!(office.decide(Weekday::Sat, at(12, 0)), Decision { run: true, limit: None });
+
+        // A window past midnight belongs to the day it starts on
+        let friday_night = Schedule::parse(&["only fri 22:00-02:00"]).unwrap();
+        assert!(friday_night.decide(Weekday::Sat, at(1, 0)).run);
+        assert!(!friday_night.decide(Weekday::Fri, at(1, 0)).run);
+    }
+
+    #[tokio::test]
+    async fn limits_speed_and_waits_while_paused() {
+        let body = test_support::payload(192 * 1024);
+        let server = test_support::serve([("/f.bin".to_string(), body.clone())].into(), HashMap::new(), None).await;
+        let downloader = Downloader::new("test").unwrap();
+
+        let output = test_support::temp_path("scheduled");
+        let mut session = downloader.init_download(&server.url("/f.bin"), Some(output.clone()), 2).await.unwrap();
+        session.schedule = Some(Schedule::parse(&["limit 128K"]).unwrap());
+        let started = Instant::now();
+        downloader
+            .run_scheduled(&mut session, None, None, None, Arc::new(Scheduler::default()))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), body);
+        assert!(started.elapsed() >= Duration::from_millis(1200), "took {:?}", started.elapsed());
+
+        // Segmented streams are held to global limits too
+        let segment = test_support::payload(96 * 1024);
+        let playlist = "#EXTM3U\n#EXTINF:2.0,\ns0.ts\n#EXTINF:2.0,\ns1.ts\n#EXT-X-ENDLIST\n";
+        let files = [
+            ("/s0.ts".to_string(), segment.clone()),
+            ("/s1.ts".to_string(), segment.clone()),
+            ("/v.m3u8".to_string(), playlist.as_bytes().to_vec()),
+        ];
+        let hls_server = test_support::serve(files.into(), HashMap::new(), None).await;
+        let output = test_support::temp_path("scheduled-hls").with_extension("ts");
+        let mut session = downloader
+            .init_hls(&hls_server.url("/v.m3u8"), Some(output.clone()), 2, crate::VariantSelection::Best)
+            .await
+            .unwrap();
+        let scheduler = Arc::new(Scheduler::new(Schedule::parse(&["limit 128K"]).unwrap()));
+        let started = Instant::now();
+        downloader.run_scheduled(&mut session, None, None, None, scheduler).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), [segment.clone(), segment].concat());
+        assert!(started.elapsed() >= Duration::from_millis(1200), "took {:?}", started.elapsed());
+
+        // Paused around the clock: waits until cancelled
+        let output = test_support::temp_path("scheduled-paused");
+        let mut session = downloader.init_download(&server.url("/f.bin"), Some(output), 2).await.unwrap();
+        let scheduler = Arc::new(Scheduler::new(Schedule::parse(&["pause"]).unwrap()));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            flag.store(true, Ordering::Relaxed);
+        });
+        let err = downloader.run_scheduled(&mut session, None, None, Some(cancel), scheduler).await.unwrap_err();
+        assert_eq!(err.to_string(), "cancelled");
+        assert_eq!(session.state, DownloadState::Paused);
+        assert_eq!(session.completed_bytes(), 0);
+    }
+}
@@ -0,0 +1,270 @@
+//! Downloads made of many small resources joined into one file, as HLS
+//! and DASH tracks are.
+//!
+//! Segments are fetched by the regular [`Worker`]s into a scratch directory
+//! next to the output, decrypted if needed, and concatenated in order once
+//! all are present. Nothing is remuxed.
+
+use super::downloader::{DownloadObserver, Downloader};
+use super::session::{DownloadSession, DownloadState};
+use super::worker::{OPEN_END, Worker};
+use aes::cipher::{BlockDecryptMut, KeyIvInit, block_padding::Pkcs7};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Segment {
+    /// Absolute URL.
+    pub uri: String,
+    /// `(offset, length)` within `uri`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub byte_range: Option<(u64, u64)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<SegmentKey>,
+    /// An init section (`EXT-X-MAP`, DASH `Initialization`) rather than media.
+    #[serde(default)]
+    pub init: bool,
+    /// Downloaded and decrypted into the scratch directory.
+    #[serde(default)]
+    pub done: bool,
+}
+
+/// AES-128-CBC key for a segment (HLS `EXT-X-KEY` with `METHOD=AES-128`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SegmentKey {
+    pub uri: String,
+    /// Explicit IV, or the media sequence number as the spec requires.
+    #[serde(with = "hex_iv")]
+    pub iv: [u8; 16],
+}
+
+/// What the session needs to download and reassemble a segmented track.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SegmentJob {
+    /// Playlist or manifest the segments came from.
+    #[serde(alias = "playlist_url")]
+    pub manifest_url: String,
+    pub segments: Vec<Segment>,
+}
+
+impl SegmentJob {
+    pub fn completed_segments(&self) -> usize {
+        self.segments.iter().filter(|s| s.done).count()
+    }
+}
+
+mod hex_iv {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(iv: &[u8; 16], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(iv))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 16], D::Error> {
+        use serde::de::Error;
+        let bytes = hex::decode(String::deserialize(deserializer)?).map_err(D::Error::custom)?;
+        bytes.try_into().map_err(|_| D::Error::custom("IV must be 16 bytes"))
+    }
+}
+
+/// Scratch directory holding downloaded segments until they are joined.
+pub fn segments_dir(output_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.segments", output_path.to_string_lossy()))
+}
+
+/// Downloads the remaining segments of `session.stream` and joins them into
+/// `session.output_path`. Called by [`Downloader::run`].
+pub(crate) async fn run(
+    downloader: &Downloader,
+    session: &mut DownloadSession,
+    observer: Option<Arc<dyn DownloadObserver>>,
+    session_file: Option<PathBuf>,
+    cancel_flag: Option<Arc<AtomicBool>>,
+) -> Result<()> {
+    let dir = segments_dir(&session.output_path);
+    tokio::fs::create_dir_all(&dir).await?;
+    session.state = DownloadState::Downloading;
+
+    let mut queue: VecDeque<usize> = session
+        .stream
+        .as_ref()
+        .context("session has no segment list")?
+        .segments
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| !s.done)
+        .map(|(i, _)| i)
+        .collect();
+
+    let (tx, mut rx) = mpsc::channel::<(u32, u64, u8)>(100);
+    let slots = session.connections.max(1) as u32;
+    // Worker id -> segment index
+    let mut running: HashMap<u32, usize> = HashMap::new();
+    let mut keys: HashMap<String, [u8; 16]> = HashMap::new();
+    let mut last_save = Instant::now();
+
+    loop {
+        if let Some(flag) = &cancel_flag
+            && flag.load(Ordering::Relaxed)
+        {
+            if let Some(path) = &session_file {
+                let _ = session.save(path).await;
+            }
+            return Err(anyhow::anyhow!("cancelled"));
+        }
+
+        // Fill free worker slots with pending segments
+        for slot in 0..slots {
+            if running.contains_key(&slot) {
+                continue;
+            }
+            let Some(index) = queue.pop_front() else { break };
+            let segment = &session.stream.as_ref().expect("checked above").segments[index];
+            let part_path = dir.join(format!("{:05}.part", index));
+            tokio::fs::File::create(&part_path).await?;
+
+            let range = segment.byte_range.map_or((0, OPEN_END), |(offset, len)| (offset, offset + len - 1));
+            let transport = downloader.transport_for(&segment.uri)?;
+            let worker = Worker::new(
+                slot,
+                segment.uri.clone(),
+                range,
+                part_path,
+                transport,
+                tx.clone(),
+                None,
+            )
+            .writing_at(0)
+            .limited_by(downloader.rate_limiter());
+            running.insert(slot, index);
+            tokio::spawn(async move { worker.run().await });
+        }
+
+        if running.is_empty() {
+            break;
+        }
+
+        match timeout(Duration::from_secs(1), rx.recv()).await {
+            Ok(Some((slot, bytes, status))) => {
+                let Some(&index) = running.get(&slot) else { continue };
+                match status {
+                    0 => {
+                        if let Some(obs) = &observer {
+                            obs.on_progress(slot, bytes, running.len());
+                        }
+                    }
+                    1 => {
+                        running.remove(&slot);
+                        let job = session.stream.as_mut().expect("checked above");
+                        finish_segment(downloader, &dir, index, &job.segments[index], &mut keys).await?;
+                        job.segments[index].done = true;
+                    }
+                    _ => {
+                        if let Some(path) = &session_file {
+                            let _ = session.save(path).await;
+                        }
+                        anyhow::bail!("segment {} failed", index);
+                    }
+                }
+            }
+            Ok(None) => break,
+            Err(_) => {}
+        }
+
+        // Segments are renamed into place before being marked done, so a
+        // plain save is enough here
+        if let Some(path) = &session_file
+            && last_save.elapsed() >= Duration::from_secs(1)
+        {
+            if let Err(e) = session.save(path).await {
+                log::warn!("Failed to checkpoint session: {}", e);
+            }
+            last_save = Instant::now();
+        }
+    }
+
+    let job = session.stream.as_ref().expect("checked above");
+    let size = concatenate(&dir, job.segments.len(), &session.output_path).await?;
+    session.total_size = Some(size);
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    log::info!("Joined {} segments into {:?} ({} bytes)", job.segments.len(), session.output_path, size);
+
+    if let Some(obs) = &observer {
+        obs.on_state_change(&DownloadState::Completed);
+    }
+    session.state = DownloadState::Completed;
+    if let Some(path) = &session_file {
+        let _ = session.save(path).await;
+    }
+    Ok(())
+}
+
+/// Decrypts a downloaded segment if needed and moves it into place.
+async fn finish_segment(
+    downloader: &Downloader,
+    dir: &Path,
+    index: usize,
+    segment: &Segment,
+    keys: &mut HashMap<String, [u8; 16]>,
+) -> Result<()> {
+    let part_path = dir.join(format!("{:05}.part", index));
+    let final_path = dir.join(format!("{:05}.seg", index));
+
+    let Some(key) = &segment.key else {
+        tokio::fs::rename(&part_path, &final_path).await?;
+        return Ok(());
+    };
+
+    let key_bytes = match keys.get(&key.uri) {
+        Some(bytes) => *bytes,
+        None => {
+            let body = downloader.client().get(&key.uri).send().await?.error_for_status()?.bytes().await?;
+            let bytes: [u8; 16] = body.as_ref().try_into()
+                .map_err(|_| anyhow::anyhow!("HLS key {} is {} bytes, expected 16", key.uri, body.len()))?;
+            keys.insert(key.uri.clone(), bytes);
+            bytes
+        }
+    };
+
+    let mut data = tokio::fs::read(&part_path).await?;
+    let iv = key.iv;
+    let plain = tokio::task::spawn_blocking(move || {
+        let len = Aes128CbcDec::new(&key_bytes.into(), &iv.into())
+            .decrypt_padded_mut::<Pkcs7>(&mut data)
+            .map_err(|_| anyhow::anyhow!("segment {} failed to decrypt: bad key or padding", index))?
+            .len();
+        data.truncate(len);
+        anyhow::Ok(data)
+    })
+    .await??;
+
+    tokio::fs::write(&final_path, &plain).await?;
+    tokio::fs::remove_file(&part_path).await?;
+    Ok(())
+}
+
+/// Appends the finished segments in order. Returns the output size.
+async fn concatenate(dir: &Path, count: usize, output_path: &Path) -> Result<u64> {
+    let mut output = tokio::fs::File::create(output_path).await?;
+    let mut size = 0;
+    for index in 0..count {
+        let path = dir.join(format!("{:05}.seg", index));
+        let mut segment = tokio::fs::File::open(&path)
+            .await
+            .with_context(|| format!("segment {} is missing from {:?}", index, dir))?;
+        size += tokio::io::copy(&mut segment, &mut output).await?;
+    }
+    output.flush().await?;
+    output.sync_all().await?;
+    Ok(size)
+}
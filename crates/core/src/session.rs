@@ -1,10 +1,41 @@
+use super::delta::DeltaJob;
+use super::segments::SegmentJob;
+use super::integrity::{DigestSource, ExpectedDigest, MultipartEtag, Verification};
+use super::pieces::PieceHashes;
+use super::range::PartialRange;
+use super::schedule::Schedule;
+use super::torrent::TorrentJob;
+use super::zip::ZipEntryJob;
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// Version of the on-disk session format written by this build.
+///
+/// New optional fields with a serde default do not need a bump. Bump it when
+/// an existing field changes meaning or layout, and append a step to
+/// [`MIGRATIONS`] that upgrades the previous version.
+pub const SESSION_VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("session file version {found} is newer than supported version {supported}")]
+    UnsupportedVersion { found: u32, supported: u32 },
+    #[error("session migration from version {from} failed: {reason}")]
+    Migration { from: u32, reason: String },
+    #[error("session is invalid: {0}")]
+    Invalid(String),
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum DownloadState {
     Pending,
     Downloading,
+    Verifying,
+    /// A finished torrent still uploading towards its ratio limit.
+    Seeding,
     Paused,
     Completed,
     Error(String),
@@ -12,7 +43,7 @@ pub enum DownloadState {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DownloadPart {
-    pub id: u8,
+    pub id: u32,
     pub start_byte: u64,
     pub end_byte: u64,
     pub current_byte: u64,
@@ -21,35 +52,590 @@ pub struct DownloadPart {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DownloadSession {
+    #[serde(default)]
+    pub version: u32,
     pub url: String,
     pub output_path: PathBuf,
     pub total_size: Option<u64>,
     pub state: DownloadState,
     pub parts: Vec<DownloadPart>,
     pub connections: u8,
+    /// Digest the finished file must match, checked after the last part.
+    #[serde(default)]
+    pub expected_digest: Option<ExpectedDigest>,
+    #[serde(default)]
+    pub digest_source: Option<DigestSource>,
+    /// Result of the integrity check, once it has run.
+    #[serde(default)]
+    pub verification: Option<Verification>,
+    /// Per-piece hashes; pieces are checked as the parts covering them
+    /// complete and only failing ones are downloaded again.
+    #[serde(default)]
+    pub piece_hashes: Option<PieceHashes>,
+    /// S3 multipart ETag, checked like `expected_digest` when the object
+    /// has no plain digest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multipart_etag: Option<MultipartEtag>,
+    /// Alternative sources for the same file, best first. `url` is the one
+    /// currently in use.
+    #[serde(default)]
+    pub mirrors: Vec<Mirror>,
+    /// Segment list of an HLS or DASH track; such sessions have no byte parts.
+    #[serde(default, alias = "hls")]
+    pub stream: Option<SegmentJob>,
+    /// Pieces and peers of a BitTorrent download; such sessions have no
+    /// byte parts either.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub torrent: Option<TorrentJob>,
+    /// Set when the file was assembled from a local copy; `parts` then
+    /// only cover the ranges that changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta: Option<DeltaJob>,
+    /// Remote offset of the first byte of the downloaded data. Parts,
+    /// `total_size` and piece hashes count from the start of the data;
+    /// requests add this.
+    #[serde(default)]
+    pub remote_offset: u64,
+    /// Set when extracting one entry of a remote ZIP archive: the parts
+    /// fetch its compressed bytes into [`data_path`](Self::data_path),
+    /// which is then inflated into `output_path`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zip_entry: Option<ZipEntryJob>,
+    /// Set when only part of the remote file is wanted; the parts then
+    /// cover just that range.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<PartialRange>,
+    /// Fetch the file front to back in small parts so its head is usable
+    /// early, e.g. for previewing media.
+    #[serde(default)]
+    pub sequential: bool,
+    /// Rules for when this download may run and how fast, on top of the
+    /// global ones; see [`Downloader::run_scheduled`](crate::Downloader::run_scheduled).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+}
+
+/// Another URL serving identical bytes, e.g. from a Metalink document.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Mirror {
+    pub url: String,
+    /// Lower is preferred, as in Metalink v4.
+    pub priority: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+/// On-disk envelope for a session checkpoint. The checksum covers the
+/// canonical JSON encoding of `session`, so a torn or bit-rotted file is
+/// detected on load instead of being resumed from.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    checksum: String,
+    session: serde_json::Value,
+}
+
+fn checksum_of(value: &serde_json::Value) -> anyhow::Result<String> {
+    let canonical = serde_json::to_vec(value)?;
+    Ok(hex::encode(Sha256::digest(&canonical)))
+}
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Path of the previous good checkpoint kept next to `path`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    sibling_path(path, ".bak")
+}
+
+type Migration = fn(serde_json::Value) -> Result<serde_json::Value, String>;
+
+/// Upgrade steps indexed by the version they migrate *from*.
+const MIGRATIONS: &[Migration] = &[migrate_v0_to_v1];
+
+/// v0 sessions predate the `version` field; the layout is otherwise the same.
+fn migrate_v0_to_v1(mut value: serde_json::Value) -> Result<serde_json::Value, String> {
+    let obj = value.as_object_mut().ok_or("session is not a JSON object")?;
+    obj.insert("version".into(), 1.into());
+    Ok(value)
+}
+
+/// Runs the migration chain until `value` is at [`SESSION_VERSION`].
+fn migrate(mut value: serde_json::Value) -> Result<serde_json::Value, SessionError> {
+    let mut version = value
+        .get("version")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0) as u32;
+
+    if version > SESSION_VERSION {
+        return Err(SessionError::UnsupportedVersion { found: version, supported: SESSION_VERSION });
+    }
+
+    while version < SESSION_VERSION {
+        let step = MIGRATIONS[version as usize];
+        value = step(value).map_err(|reason| SessionError::Migration { from: version, reason })?;
+        version += 1;
+        log::info!("Migrated session to version {}", version);
+    }
+    Ok(value)
 }
 
 impl DownloadSession {
     pub fn new(url: String, output_path: PathBuf, connections: u8) -> Self {
         Self {
+            version: SESSION_VERSION,
             url,
             output_path,
             total_size: None,
             state: DownloadState::Pending,
             parts: Vec::new(),
             connections,
+            expected_digest: None,
+            digest_source: None,
+            verification: None,
+            piece_hashes: None,
+            multipart_etag: None,
+            mirrors: Vec::new(),
+            stream: None,
+            torrent: None,
+            delta: None,
+            remote_offset: 0,
+            zip_entry: None,
+            range: None,
+            sequential: false,
+            schedule: None,
+        }
+    }
+
+    pub fn set_expected_digest(&mut self, digest: ExpectedDigest, source: DigestSource) {
+        self.expected_digest = Some(digest);
+        self.digest_source = Some(source);
+    }
+
+    /// Bytes downloaded so far: verified pieces for a torrent, otherwise
+    /// the progress of every part plus any bytes reused from a local copy.
+    pub fn completed_bytes(&self) -> u64 {
+        match &self.torrent {
+            Some(job) => job.completed,
+            None => {
+                let reused = self.delta.as_ref().map_or(0, |job| job.reused_bytes);
+                reused + self.parts.iter().map(|p| p.current_byte - p.start_byte).sum::<u64>()
+            }
+        }
+    }
+
+    /// Bytes this session downloads in all: the length of the range for a
+    /// partial download, otherwise `total_size`.
+    pub fn target_size(&self) -> Option<u64> {
+        match &self.range {
+            Some(range) => Some(range.byte_count()),
+            None => self.total_size,
+        }
+    }
+
+    /// Size of the file at `url`, which differs from `total_size` for
+    /// partial downloads.
+    pub fn remote_size(&self) -> Option<u64> {
+        match &self.range {
+            Some(range) => range.remote_size,
+            None => self.total_size,
+        }
+    }
+
+    /// File the parts are written to: `output_path`, except while a ZIP
+    /// entry's compressed bytes are collected next to it.
+    pub fn data_path(&self) -> PathBuf {
+        match &self.zip_entry {
+            Some(_) => sibling_path(&self.output_path, ".zip-data"),
+            None => self.output_path.clone(),
+        }
+    }
+
+    /// Id for a new part, one past the highest in use.
+    pub fn next_part_id(&self) -> u32 {
+        self.parts.iter().map(|p| p.id).max().map_or(0, |id| id + 1)
+    }
+
+    /// Marks `start..=end` as not downloaded so it is fetched again.
+    ///
+    /// Completed parts overlapping the range are split around it and the
+    /// range itself becomes a fresh part; its id is returned. Only call
+    /// this for ranges covered by completed parts: a part still owned by a
+    /// running worker must not change under it.
+    pub fn reopen_range(&mut self, start: u64, end: u64) -> u32 {
+        let new_id = self.next_part_id();
+        let mut extra_id = new_id + 1;
+        let mut parts = Vec::with_capacity(self.parts.len() + 2);
+
+        for part in self.parts.drain(..) {
+            if part.end_byte < start || part.start_byte > end {
+                parts.push(part);
+                continue;
+            }
+            debug_assert!(part.completed, "reopening a range of an active part");
+            if part.start_byte < start {
+                parts.push(DownloadPart { end_byte: start - 1, current_byte: start, ..part.clone() });
+            }
+            if part.end_byte > end {
+                parts.push(DownloadPart {
+                    id: extra_id,
+                    start_byte: end + 1,
+                    end_byte: part.end_byte,
+                    current_byte: part.end_byte + 1,
+                    completed: true,
+                });
+                extra_id += 1;
+            }
         }
+
+        parts.push(DownloadPart { id: new_id, start_byte: start, end_byte: end, current_byte: start, completed: false });
+        parts.sort_by_key(|p| p.start_byte);
+        self.parts = parts;
+        new_id
     }
 
-    pub async fn save(&self, path: &std::path::Path) -> anyhow::Result<()> {
-        let json = serde_json::to_string_pretty(self)?;
-        tokio::fs::write(path, json).await?;
+    /// Atomically writes the session to `path`.
+    ///
+    /// The JSON goes to a temporary file which is fsynced and renamed over
+    /// `path`; the checkpoint it replaces is kept as `<path>.bak`.
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let session = serde_json::to_value(self)?;
+        let checkpoint = Checkpoint {
+            checksum: checksum_of(&session)?,
+            session,
+        };
+        let json = serde_json::to_string_pretty(&checkpoint)?;
+
+        let tmp_path = sibling_path(path, ".tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(json.as_bytes()).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        if tokio::fs::try_exists(path).await.unwrap_or(false) {
+            tokio::fs::rename(path, backup_path(path)).await?;
+        }
+        tokio::fs::rename(&tmp_path, path).await?;
+        sync_parent_dir(path).await;
         Ok(())
     }
 
-    pub async fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+    /// Flushes the output file's data to disk, then saves the session.
+    ///
+    /// Offsets recorded in the checkpoint therefore never run ahead of the
+    /// bytes that survive a power loss.
+    pub async fn checkpoint(&self, path: &Path) -> anyhow::Result<()> {
+        if let Ok(file) = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(self.data_path())
+            .await
+        {
+            file.sync_data().await?;
+        }
+        self.save(path).await
+    }
+
+    /// Loads a session, falling back to `<path>.bak` if the primary
+    /// checkpoint is missing, truncated or fails its checksum.
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        match Self::load_checked(path).await {
+            Ok(session) => Ok(session),
+            Err(primary_err) => {
+                let backup = backup_path(path);
+                if !tokio::fs::try_exists(&backup).await.unwrap_or(false) {
+                    return Err(primary_err);
+                }
+                log::warn!(
+                    "Session file {:?} is unusable ({}), falling back to {:?}",
+                    path, primary_err, backup
+                );
+                Self::load_checked(&backup)
+                    .await
+                    .with_context(|| format!("primary session file also unusable: {}", primary_err))
+            }
+        }
+    }
+
+    async fn load_checked(path: &Path) -> anyhow::Result<Self> {
         let json = tokio::fs::read_to_string(path).await?;
-        let session: Self = serde_json::from_str(&json)?;
+        let value: serde_json::Value = serde_json::from_str(&json)
+            .with_context(|| format!("session file {:?} is not valid JSON", path))?;
+
+        // Files written before checkpoints were introduced are a bare session.
+        let raw = if value.get("checksum").is_none() {
+            value
+        } else {
+            let checkpoint: Checkpoint = serde_json::from_value(value)?;
+            let actual = checksum_of(&checkpoint.session)?;
+            if actual != checkpoint.checksum {
+                anyhow::bail!("session file {:?} failed checksum verification", path);
+            }
+            checkpoint.session
+        };
+
+        let mut session: Self = serde_json::from_value(migrate(raw)?)?;
+        for repair in session.validate()? {
+            log::warn!("Repaired session {:?}: {}", path, repair);
+        }
         Ok(session)
     }
+
+    /// Checks the part layout invariants, fixing what can be fixed safely.
+    ///
+    /// Parts must lie inside `total_size`, be pairwise disjoint and have
+    /// `current_byte` within `start_byte..=end_byte + 1`. Returns a
+    /// description of every repair made; anything that cannot be repaired
+    /// without guessing is reported as [`SessionError::Invalid`].
+    pub fn validate(&mut self) -> Result<Vec<String>, SessionError> {
+        let mut repairs = Vec::new();
+
+        if let Some(job) = &self.stream {
+            if job.segments.is_empty() {
+                return Err(SessionError::Invalid("segmented session has no segments".into()));
+            }
+            return Ok(repairs);
+        }
+        if self.torrent.is_some() {
+            return Ok(repairs);
+        }
+
+        if self.parts.is_empty() {
+            // A delta update whose every block was found locally, or an
+            // empty ZIP entry, fetches nothing
+            if self.state == DownloadState::Completed || self.delta.is_some() || self.zip_entry.is_some() {
+                return Ok(repairs);
+            }
+            return Err(SessionError::Invalid("session has no parts".into()));
+        }
+
+        let Some(size) = self.total_size else {
+            // Unknown-size downloads stream through a single part.
+            if self.parts.len() != 1 {
+                return Err(SessionError::Invalid(format!(
+                    "{} parts for a download of unknown size", self.parts.len()
+                )));
+            }
+            return Ok(repairs);
+        };
+
+        if size == 0 {
+            for part in &mut self.parts {
+                if !part.completed {
+                    part.completed = true;
+                    repairs.push(format!("part {} marked complete for empty file", part.id));
+                }
+            }
+            return Ok(repairs);
+        }
+
+        let mut seen_ids = std::collections::HashSet::new();
+        for part in &self.parts {
+            if !seen_ids.insert(part.id) {
+                return Err(SessionError::Invalid(format!("duplicate part id {}", part.id)));
+            }
+            if part.start_byte >= size {
+                return Err(SessionError::Invalid(format!(
+                    "part {} starts at {} beyond file size {}", part.id, part.start_byte, size
+                )));
+            }
+            if part.start_byte > part.end_byte {
+                return Err(SessionError::Invalid(format!(
+                    "part {} has inverted range {}-{}", part.id, part.start_byte, part.end_byte
+                )));
+            }
+        }
+
+        self.parts.sort_by_key(|p| p.start_byte);
+
+        let mut i = 0;
+        while i < self.parts.len() {
+            if self.parts[i].end_byte >= size {
+                let part = &mut self.parts[i];
+                repairs.push(format!("part {} end clamped from {} to {}", part.id, part.end_byte, size - 1));
+                part.end_byte = size - 1;
+            }
+
+            if i > 0 {
+                let prev_end = self.parts[i - 1].end_byte;
+                let part = &mut self.parts[i];
+                if part.start_byte <= prev_end {
+                    if part.end_byte <= prev_end {
+                        // Fully covered by the previous part, which owns those bytes.
+                        repairs.push(format!("part {} dropped, range is covered by a previous part", part.id));
+                        self.parts.remove(i);
+                        continue;
+                    }
+                    repairs.push(format!("part {} start moved from {} to {} to remove overlap", part.id, part.start_byte, prev_end + 1));
+                    part.start_byte = prev_end + 1;
+                }
+            }
+
+            let part = &mut self.parts[i];
+            if part.current_byte < part.start_byte {
+                repairs.push(format!("part {} offset raised from {} to {}", part.id, part.current_byte, part.start_byte));
+                part.current_byte = part.start_byte;
+            }
+            if part.current_byte > part.end_byte + 1 {
+                repairs.push(format!("part {} offset lowered from {} to {}", part.id, part.current_byte, part.end_byte + 1));
+                part.current_byte = part.end_byte + 1;
+            }
+            if !part.completed && part.current_byte == part.end_byte + 1 {
+                repairs.push(format!("part {} marked complete", part.id));
+                part.completed = true;
+            }
+            i += 1;
+        }
+
+        Ok(repairs)
+    }
+
+    /// Removes the session file together with its backup and temp files.
+    pub async fn remove(path: &Path) {
+        for p in [path.to_path_buf(), backup_path(path), sibling_path(path, ".tmp")] {
+            let _ = tokio::fs::remove_file(p).await;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn sync_parent_dir(path: &Path) {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty())
+        && let Ok(dir) = tokio::fs::File::open(parent).await
+    {
+        let _ = dir.sync_all().await;
+    }
+}
+
+#[cfg(not(unix))]
+async fn sync_parent_dir(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_session_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kitsune-session-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("file.bin.kitsune")
+    }
+
+    fn sample_session() -> DownloadSession {
+        let mut session = DownloadSession::new("http://example.com/f".into(), "/tmp/f".into(), 1);
+        session.total_size = Some(100);
+        session.parts.push(DownloadPart { id: 0, start_byte: 0, end_byte: 99, current_byte: 10, completed: false });
+        session
+    }
+
+    #[tokio::test]
+    async fn corrupt_checkpoint_falls_back_to_backup() {
+        let path = temp_session_path("fallback");
+        let mut session = sample_session();
+        session.save(&path).await.unwrap();
+        session.parts[0].current_byte = 50;
+        session.save(&path).await.unwrap();
+
+        // Simulate a torn write of the newest checkpoint.
+        let json = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, &json[..json.len() / 2]).unwrap();
+
+        let loaded = DownloadSession::load(&path).await.unwrap();
+        assert_eq!(loaded.parts[0].current_byte, 10);
+        DownloadSession::remove(&path).await;
+    }
+
+    #[tokio::test]
+    async fn legacy_session_is_migrated() {
+        let path = temp_session_path("legacy");
+        let legacy = r#"{
+            "url": "http://example.com/f",
+            "output_path": "/tmp/f",
+            "total_size": 100,
+            "state": "Downloading",
+            "parts": [{"id": 0, "start_byte": 0, "end_byte": 99, "current_byte": 40, "completed": false}],
+            "connections": 1
+        }"#;
+        std::fs::write(&path, legacy).unwrap();
+
+        let loaded = DownloadSession::load(&path).await.unwrap();
+        assert_eq!(loaded.version, SESSION_VERSION);
+        assert_eq!(loaded.parts[0].current_byte, 40);
+        DownloadSession::remove(&path).await;
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let value = serde_json::json!({ "version": SESSION_VERSION + 1 });
+        assert!(matches!(migrate(value), Err(SessionError::UnsupportedVersion { .. })));
+    }
+
+    #[test]
+    fn validate_repairs_overlap_and_offsets() {
+        let mut session = sample_session();
+        session.parts = vec![
+            DownloadPart { id: 0, start_byte: 0, end_byte: 59, current_byte: 70, completed: false },
+            DownloadPart { id: 1, start_byte: 50, end_byte: 120, current_byte: 40, completed: false },
+            DownloadPart { id: 2, start_byte: 10, end_byte: 20, current_byte: 10, completed: false },
+        ];
+
+        let repairs = session.validate().unwrap();
+        assert!(!repairs.is_empty());
+        assert_eq!(session.parts.len(), 2);
+        assert_eq!((session.parts[0].current_byte, session.parts[0].completed), (60, true));
+        assert_eq!((session.parts[1].start_byte, session.parts[1].end_byte), (60, 99));
+        assert_eq!(session.parts[1].current_byte, 60);
+    }
+
+    #[test]
+    fn validate_rejects_unfixable_layout() {
+        let mut session = sample_session();
+        session.parts[0].start_byte = 150;
+        session.parts[0].end_byte = 160;
+        assert!(matches!(session.validate(), Err(SessionError::Invalid(_))));
+    }
+
+    #[test]
+    fn reopen_range_splits_completed_parts() {
+        let mut session = sample_session();
+        session.parts = vec![
+            DownloadPart { id: 0, start_byte: 0, end_byte: 49, current_byte: 50, completed: true },
+            DownloadPart { id: 1, start_byte: 50, end_byte: 99, current_byte: 100, completed: true },
+        ];
+
+        let id = session.reopen_range(40, 59);
+        let layout: Vec<_> = session.parts.iter().map(|p| (p.start_byte, p.end_byte, p.completed)).collect();
+        assert_eq!(layout, vec![(0, 39, true), (40, 59, false), (60, 99, true)]);
+        assert_eq!(session.parts[1].id, id);
+        assert!(session.validate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reopening_many_ranges_keeps_ids_unique() {
+        let mut session = sample_session();
+        session.total_size = Some(10_000);
+        session.parts = vec![DownloadPart { id: 0, start_byte: 0, end_byte: 9_999, current_byte: 10_000, completed: true }];
+
+        // Every range splits a completed part, using two ids
+        for i in 0..300 {
+            let id = session.reopen_range(i * 20 + 10, i * 20 + 14);
+            session.parts.iter_mut().find(|p| p.id == id).unwrap().completed = true;
+        }
+        assert_eq!(session.parts.len(), 601);
+        let ids: std::collections::HashSet<u32> = session.parts.iter().map(|p| p.id).collect();
+        assert_eq!(ids.len(), 601);
+        assert_eq!(session.next_part_id(), 601);
+        assert!(session.validate().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tampered_checkpoint_fails_checksum() {
+        let path = temp_session_path("tampered");
+        sample_session().save(&path).await.unwrap();
+        let json = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, json.replace("\"current_byte\": 10", "\"current_byte\": 90")).unwrap();
+
+        assert!(DownloadSession::load(&path).await.is_err());
+        DownloadSession::remove(&path).await;
+    }
 }
@@ -0,0 +1,268 @@
+use super::lock::{DownloadLock, LockError};
+use super::session::{DownloadSession, DownloadState};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const SESSION_EXT: &str = "kitsune";
+
+/// Directory of session checkpoints keyed by download id.
+///
+/// Sessions used to live next to the output as `<file>.kitsune`; the store
+/// keeps them under the state directory instead and still picks up those
+/// legacy files through [`SessionStore::find_by_output`].
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    root: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Opens the store under `$XDG_STATE_HOME/kitsune-dm/sessions`.
+    pub fn open_default() -> Result<Self> {
+        let root = crate::utils::fs::get_state_dir().join("sessions");
+        std::fs::create_dir_all(&root)?;
+        Ok(Self::new(root))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Stable id for downloads that are only known by their output path.
+    pub fn id_for_output(output_path: &Path) -> String {
+        let absolute = std::path::absolute(output_path).unwrap_or_else(|_| output_path.to_path_buf());
+        let digest = Sha256::digest(absolute.to_string_lossy().as_bytes());
+        hex::encode(&digest[..8])
+    }
+
+    /// Path of the checkpoint file for `id`, suitable for `Downloader::run`.
+    pub fn session_path(&self, id: &str) -> PathBuf {
+        let safe: String = id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        self.root.join(format!("{}.{}", safe, SESSION_EXT))
+    }
+
+    /// Takes the cross-process lock for `id` and its output file. Hold the
+    /// returned guard for as long as the download runs.
+    pub fn lock(&self, id: &str, output_path: &Path) -> Result<DownloadLock, LockError> {
+        DownloadLock::acquire(&self.root.join("locks"), id, output_path)
+    }
+
+    pub async fn save(&self, id: &str, session: &DownloadSession) -> Result<()> {
+        tokio::fs::create_dir_all(&self.root).await?;
+        session.save(&self.session_path(id)).await
+    }
+
+    pub async fn load(&self, id: &str) -> Result<Option<DownloadSession>> {
+        let path = self.session_path(id);
+        if !path.exists() && !super::session::backup_path(&path).exists() {
+            return Ok(None);
+        }
+        DownloadSession::load(&path).await.map(Some)
+    }
+
+    pub async fn remove(&self, id: &str) {
+        DownloadSession::remove(&self.session_path(id)).await;
+    }
+
+    /// Finds the session writing to `output_path`.
+    ///
+    /// A legacy side-by-side `<output>.kitsune` file is imported into the
+    /// store under `id` and deleted from the downloads folder.
+    pub async fn find_by_output(&self, id: &str, output_path: &Path) -> Result<Option<(String, DownloadSession)>> {
+        if let Some(session) = self.load(id).await? {
+            return Ok(Some((id.to_string(), session)));
+        }
+
+        for (other_id, session) in self.list().await? {
+            if session.output_path == output_path {
+                return Ok(Some((other_id, session)));
+            }
+        }
+
+        let legacy = PathBuf::from(format!("{}.{}", output_path.to_string_lossy(), SESSION_EXT));
+        if legacy.exists() {
+            log::info!("Importing legacy session file {:?} into the session store", legacy);
+            let session = DownloadSession::load(&legacy).await?;
+            self.save(id, &session).await?;
+            DownloadSession::remove(&legacy).await;
+            return Ok(Some((id.to_string(), session)));
+        }
+
+        Ok(None)
+    }
+
+    /// Lists every readable session in the store. Unreadable files are
+    /// skipped with a warning so one bad checkpoint does not hide the rest.
+    pub async fn list(&self) -> Result<Vec<(String, DownloadSession)>> {
+        let mut sessions = Vec::new();
+        for (id, path, _) in self.entries().await? {
+            match DownloadSession::load(&path).await {
+                Ok(session) => sessions.push((id, session)),
+                Err(e) => log::warn!("Skipping unreadable session {:?}: {}", path, e),
+            }
+        }
+        Ok(sessions)
+    }
+
+    /// Sessions that still have work left to do.
+    pub async fn list_unfinished(&self) -> Result<Vec<(String, DownloadSession)>> {
+        Ok(self
+            .list()
+            .await?
+            .into_iter()
+            .filter(|(_, s)| s.state != DownloadState::Completed)
+            .collect())
+    }
+
+    /// Removes sessions that have not been checkpointed for `max_age`.
+    pub async fn prune(&self, max_age: Duration) -> Result<Vec<String>> {
+        let now = SystemTime::now();
+        let mut removed = Vec::new();
+        for (id, path, modified) in self.entries().await? {
+            let age = now.duration_since(modified).unwrap_or_default();
+            if age > max_age {
+                DownloadSession::remove(&path).await;
+                removed.push(id);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes sessions that can never be resumed: completed ones, ones
+    /// whose partial output file is gone, and files that fail to load.
+    /// Sessions locked by a running download are left alone.
+    pub async fn gc(&self) -> Result<Vec<String>> {
+        let mut removed = Vec::new();
+        for (id, path, _) in self.entries().await? {
+            let mut _lock = None;
+            let stale = match DownloadSession::load(&path).await {
+                Ok(session) => {
+                    // Segmented output only appears once segments are joined,
+                    // ZIP entries once they are extracted; magnet links have
+                    // no files until metadata arrives
+                    let has_data = session.data_path().exists()
+                        || (session.stream.is_some() && crate::segments::segments_dir(&session.output_path).exists())
+                        || session.torrent.as_ref().is_some_and(|t| t.info.is_none());
+                    let stale = session.state == DownloadState::Completed || !has_data;
+                    if stale {
+                        match self.lock(&id, &session.output_path) {
+                            Ok(lock) => _lock = Some(lock),
+                            Err(e) => {
+                                log::info!("Keeping session {}: {}", id, e);
+                                continue;
+                            }
+                        }
+                    }
+                    stale
+                }
+                Err(_) => true,
+            };
+            if stale {
+                DownloadSession::remove(&path).await;
+                removed.push(id);
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn entries(&self) -> Result<Vec<(String, PathBuf, SystemTime)>> {
+        let mut entries = Vec::new();
+        let mut dir = match tokio::fs::read_dir(&self.root).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SESSION_EXT) {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()).map(str::to_string) else {
+                continue;
+            };
+            let modified = entry.metadata().await?.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            entries.push((id, path, modified));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::DownloadPart;
+    use crate::zip::{ZipEntry, ZipEntryJob};
+
+    fn temp_store(name: &str) -> (SessionStore, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("kitsune-store-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        (SessionStore::new(dir.join("sessions")), dir)
+    }
+
+    fn session_for(output: PathBuf) -> DownloadSession {
+        let mut session = DownloadSession::new("http://example.com/f".into(), output, 1);
+        session.total_size = Some(10);
+        session.parts.push(DownloadPart { id: 0, start_byte: 0, end_byte: 9, current_byte: 0, completed: false });
+        session
+    }
+
+    #[tokio::test]
+    async fn legacy_side_by_side_session_is_imported() {
+        let (store, dir) = temp_store("legacy");
+        let output = dir.join("file.bin");
+        let legacy = dir.join("file.bin.kitsune");
+        session_for(output.clone()).save(&legacy).await.unwrap();
+
+        let id = SessionStore::id_for_output(&output);
+        let (found_id, session) = store.find_by_output(&id, &output).await.unwrap().unwrap();
+        assert_eq!(found_id, id);
+        assert_eq!(session.output_path, output);
+        assert!(!legacy.exists());
+        assert!(store.session_path(&id).exists());
+    }
+
+    #[tokio::test]
+    async fn gc_removes_sessions_without_output() {
+        let (store, dir) = temp_store("gc");
+        let kept = dir.join("kept.bin");
+        std::fs::write(&kept, b"partial").unwrap();
+        store.save("kept", &session_for(kept)).await.unwrap();
+        store.save("orphan", &session_for(dir.join("gone.bin"))).await.unwrap();
+
+        // Only the compressed data exists until the entry is extracted
+        let mut zip = session_for(dir.join("entry.txt"));
+        zip.zip_entry = Some(ZipEntryJob {
+            entry: ZipEntry {
+                name: "dir/entry.txt".into(),
+                size: 20,
+                compressed_size: 10,
+                method: 8,
+                crc32: 0,
+                header_offset: 0,
+                encrypted: false,
+            },
+        });
+        std::fs::write(zip.data_path(), b"partial").unwrap();
+        store.save("zip", &zip).await.unwrap();
+
+        // Running elsewhere, with its output moved away for now
+        let busy = dir.join("busy.bin");
+        store.save("busy", &session_for(busy.clone())).await.unwrap();
+        let _lock = store.lock("busy", &busy).unwrap();
+
+        let removed = store.gc().await.unwrap();
+        assert_eq!(removed, vec!["orphan".to_string()]);
+        let remaining: Vec<_> = store.list_unfinished().await.unwrap().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(remaining, vec!["busy".to_string(), "kept".to_string(), "zip".to_string()]);
+    }
+}
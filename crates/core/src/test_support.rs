@@ -0,0 +1,294 @@
+//! Minimal HTTP/1.1 and FTP servers with range support, and a BitTorrent
+//! tracker, for end-to-end tests.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Rewrites the body of a response: `(request number, range start, bytes)`.
+pub type Tamper = Arc<dyn Fn(usize, u64, &mut [u8]) + Send + Sync>;
+
+/// Header name that makes the server fail every ranged GET except `0-0`.
+pub const FAIL_RANGED: &str = "x-test-fail-ranged";
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub requests: Arc<AtomicUsize>,
+    /// Addresses clients connected from.
+    pub clients: Arc<std::sync::Mutex<HashSet<IpAddr>>>,
+}
+
+impl TestServer {
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+}
+
+/// Serves `files` (path -> body) with extra response headers per path.
+pub async fn serve(
+    files: HashMap<String, Vec<u8>>,
+    headers: HashMap<String, Vec<(String, String)>>,
+    tamper: Option<Tamper>,
+) -> TestServer {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let files = Arc::new(files);
+    let headers = Arc::new(headers);
+
+    let clients = Arc::new(std::sync::Mutex::new(HashSet::new()));
+
+    let (counter, seen) = (requests.clone(), clients.clone());
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, remote)) = listener.accept().await else { return };
+            seen.lock().unwrap().insert(remote.ip());
+            let files = files.clone();
+            let headers = headers.clone();
+            let tamper = tamper.clone();
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 1024];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&buf).to_string();
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+
+                let Some(body) = files.get(&path) else {
+                    let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+                    return;
+                };
+
+                let range = request
+                    .lines()
+                    .find_map(|l| l.to_ascii_lowercase().strip_prefix("range: bytes=").map(str::to_string))
+                    .and_then(|r| {
+                        let (s, e) = r.split_once('-')?;
+                        let start: u64 = s.trim().parse().ok()?;
+                        let end: u64 = e.trim().parse().unwrap_or(body.len() as u64 - 1);
+                        Some((start, end.min(body.len() as u64 - 1)))
+                    });
+
+                // Simulates a mirror that answers probes but dies mid-download
+                let fails_ranged = headers.get(&path).into_iter().flatten().any(|(name, _)| name == FAIL_RANGED);
+                if fails_ranged && range.is_some_and(|r| r != (0, 0)) {
+                    let _ = stream.write_all(b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+                    return;
+                }
+
+                let mut head = String::new();
+                let (start, mut slice) = match range {
+                    Some((start, end)) => {
+                        head.push_str("HTTP/1.1 206 Partial Content\r\n");
+                        head.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n", start, end, body.len()));
+                        (start, body[start as usize..=end as usize].to_vec())
+                    }
+                    None => {
+                        head.push_str("HTTP/1.1 200 OK\r\n");
+                        (0, body.clone())
+                    }
+                };
+                if let Some(tamper) = &tamper {
+                    tamper(n, start, &mut slice);
+                }
+                head.push_str("Accept-Ranges: bytes\r\nConnection: close\r\n");
+                head.push_str(&format!("Content-Length: {}\r\n", slice.len()));
+                for (name, value) in headers.get(&path).into_iter().flatten() {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                head.push_str("\r\n");
+
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&slice).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+
+    TestServer { addr, requests, clients }
+}
+
+pub struct FtpTestServer {
+    pub addr: SocketAddr,
+    /// Successful logins, i.e. control connections in use.
+    pub logins: Arc<AtomicUsize>,
+}
+
+impl FtpTestServer {
+    pub fn url(&self, path: &str) -> String {
+        format!("ftp://{}{}", self.addr, path)
+    }
+}
+
+/// Paths without a leading `/` are looked up under this login directory.
+pub const FTP_HOME: &str = "/home/ftp";
+
+/// Passive-mode FTP server with `SIZE` and `REST`, anonymous login only.
+pub async fn serve_ftp(files: HashMap<String, Vec<u8>>) -> FtpTestServer {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let logins = Arc::new(AtomicUsize::new(0));
+    let files = Arc::new(files);
+
+    let counter = logins.clone();
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else { return };
+            let files = files.clone();
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = tokio::io::BufReader::new(read).lines();
+                let mut data: Option<TcpListener> = None;
+                let mut rest = 0usize;
+                let _ = write.write_all(b"220 test server\r\n").await;
+
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let (command, arg) = line.split_once(' ').unwrap_or((line.as_str(), ""));
+                    let path = if arg.starts_with('/') { arg.to_string() } else { format!("{}/{}", FTP_HOME, arg) };
+                    let reply = match command.to_ascii_uppercase().as_str() {
+                        "USER" => "331 password please".to_string(),
+                        "PASS" => {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                            "230 logged in".to_string()
+                        }
+                        "TYPE" => "200 binary".to_string(),
+                        "SIZE" => match files.get(&path) {
+                            Some(body) => format!("213 {}", body.len()),
+                            None => "550 no such file".to_string(),
+                        },
+                        "REST" => {
+                            rest = arg.parse().unwrap_or(0);
+                            format!("350 restarting at {}", rest)
+                        }
+                        "EPSV" => {
+                            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                            let port = listener.local_addr().unwrap().port();
+                            data = Some(listener);
+                            format!("229 Entering Extended Passive Mode (|||{}|)", port)
+                        }
+                        "RETR" => {
+                            let (Some(body), Some(listener)) = (files.get(&path), data.take()) else {
+                                let _ = write.write_all(b"550 no such file\r\n").await;
+                                continue;
+                            };
+                            let _ = write.write_all(b"150 opening data connection\r\n").await;
+                            let Ok((mut conn, _)) = listener.accept().await else { return };
+                            let _ = conn.write_all(&body[rest.min(body.len())..]).await;
+                            let _ = conn.shutdown().await;
+                            rest = 0;
+                            "226 transfer complete".to_string()
+                        }
+                        "QUIT" => {
+                            let _ = write.write_all(b"221 bye\r\n").await;
+                            return;
+                        }
+                        _ => "502 not implemented".to_string(),
+                    };
+                    if write.write_all(format!("{}\r\n", reply).as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+
+    FtpTestServer { addr, logins }
+}
+
+pub struct TrackerServer {
+    pub addr: SocketAddr,
+    announces: Arc<AtomicUsize>,
+}
+
+impl TrackerServer {
+    pub fn url(&self) -> String {
+        format!("http://{}/announce", self.addr)
+    }
+
+    pub fn announces(&self) -> usize {
+        self.announces.load(Ordering::SeqCst)
+    }
+}
+
+/// HTTP tracker that hands every announcer all peers announced so far,
+/// in compact form.
+pub async fn serve_tracker() -> TrackerServer {
+    use crate::torrent::bencode::Value;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peers = Arc::new(std::sync::Mutex::new(Vec::<SocketAddr>::new()));
+    let announces = Arc::new(AtomicUsize::new(0));
+
+    let counter = announces.clone();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, remote)) = listener.accept().await else { return };
+            let (known, counter) = (peers.clone(), counter.clone());
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut chunk = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&chunk[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let port = request
+                    .split(['?', '&', ' '])
+                    .find_map(|pair| pair.strip_prefix("port="))
+                    .and_then(|p| p.parse::<u16>().ok());
+
+                let compact: Vec<u8> = {
+                    let mut known = known.lock().unwrap();
+                    if let Some(port) = port {
+                        let peer = SocketAddr::new(remote.ip(), port);
+                        if !known.contains(&peer) {
+                            known.push(peer);
+                        }
+                    }
+                    known
+                        .iter()
+                        .filter_map(|p| match p {
+                            SocketAddr::V4(v4) => Some([v4.ip().octets().as_slice(), &v4.port().to_be_bytes()].concat()),
+                            SocketAddr::V6(_) => None,
+                        })
+                        .flatten()
+                        .collect()
+                };
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let body = Value::dict([("interval", Value::Int(60)), ("peers", Value::Bytes(compact))]).encode();
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+
+    TrackerServer { addr, announces }
+}
+
+/// Deterministic test payload.
+pub fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+pub fn temp_path(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("kitsune-e2e-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join("out.bin")
+}
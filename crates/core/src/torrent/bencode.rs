@@ -0,0 +1,229 @@
+//! Bencoding (BEP 3), the encoding of `.torrent` files, tracker replies,
+//! DHT messages and extension-protocol payloads.
+
+use anyhow::{Result, bail};
+use std::collections::BTreeMap;
+
+/// Nesting deeper than this is rejected rather than risking the stack on
+/// hostile input.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    /// Keys are raw byte strings, kept sorted as the encoding requires.
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    /// Builds a dictionary from `(key, value)` pairs.
+    pub fn dict<'a>(entries: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        Value::Dict(entries.into_iter().map(|(k, v)| (k.as_bytes().to_vec(), v)).collect())
+    }
+
+    pub fn bytes(data: impl Into<Vec<u8>>) -> Self {
+        Value::Bytes(data.into())
+    }
+
+    /// Looks up `key` if this is a dictionary.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// The byte string as UTF-8, if it is valid.
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(n) => out.extend_from_slice(format!("i{}e", n).as_bytes()),
+            Value::Bytes(b) => {
+                out.extend_from_slice(format!("{}:", b.len()).as_bytes());
+                out.extend_from_slice(b);
+            }
+            Value::List(items) => {
+                out.push(b'l');
+                for item in items {
+                    item.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    out.extend_from_slice(format!("{}:", key.len()).as_bytes());
+                    out.extend_from_slice(key);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+/// Decodes a single value that must span all of `data`.
+pub fn decode(data: &[u8]) -> Result<Value> {
+    let (value, used) = decode_prefix(data)?;
+    if used != data.len() {
+        bail!("{} trailing bytes after bencoded value", data.len() - used);
+    }
+    Ok(value)
+}
+
+/// Decodes the value at the start of `data`, returning it and its length.
+/// `ut_metadata` messages append raw data after the dictionary.
+pub fn decode_prefix(data: &[u8]) -> Result<(Value, usize)> {
+    let mut parser = Parser { data, pos: 0 };
+    let value = parser.value(0)?;
+    Ok((value, parser.pos))
+}
+
+/// Raw encoded bytes of `key` in the top-level dictionary of `data`. The
+/// info hash is taken over these bytes exactly as they appear in the file.
+pub fn raw_value<'a>(data: &'a [u8], key: &str) -> Result<Option<&'a [u8]>> {
+    let mut parser = Parser { data, pos: 0 };
+    parser.expect(b'd')?;
+    while parser.peek()? != b'e' {
+        let name = parser.string()?;
+        let start = parser.pos;
+        parser.value(1)?;
+        if name == key.as_bytes() {
+            return Ok(Some(&data[start..parser.pos]));
+        }
+    }
+    Ok(None)
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Result<u8> {
+        match self.data.get(self.pos) {
+            Some(&b) => Ok(b),
+            None => bail!("bencoded data ends early"),
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        if self.peek()? != byte {
+            bail!("expected '{}' at offset {}", byte as char, self.pos);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value> {
+        if depth > MAX_DEPTH {
+            bail!("bencoded data nested too deeply");
+        }
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let end = self.find(b'e')?;
+                let text = std::str::from_utf8(&self.data[self.pos..end])?;
+                if text.is_empty() || text == "-0" || (text.len() > 1 && text.trim_start_matches('-').starts_with('0')) {
+                    bail!("malformed integer {:?}", text);
+                }
+                self.pos = end + 1;
+                Ok(Value::Int(text.parse()?))
+            }
+            b'l' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                while self.peek()? != b'e' {
+                    items.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(items))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.string()?.to_vec();
+                    let value = self.value(depth + 1)?;
+                    dict.insert(key, value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.string()?.to_vec())),
+            other => bail!("unexpected byte 0x{:02x} at offset {}", other, self.pos),
+        }
+    }
+
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let colon = self.find(b':')?;
+        let len: usize = std::str::from_utf8(&self.data[self.pos..colon])?.parse()?;
+        let start = colon + 1;
+        let Some(end) = start.checked_add(len).filter(|&end| end <= self.data.len()) else {
+            bail!("string of {} bytes runs past the end of the data", len);
+        };
+        self.pos = end;
+        Ok(&self.data[start..end])
+    }
+
+    fn find(&self, byte: u8) -> Result<usize> {
+        match self.data[self.pos..].iter().position(|&b| b == byte) {
+            Some(i) => Ok(self.pos + i),
+            None => bail!("bencoded data ends early"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_finds_raw_values() {
+        let data = b"d8:announce12:http://t/ann4:infod6:lengthi12e4:name3:a.bee";
+        let value = decode(data).unwrap();
+        assert_eq!(value.get("announce").and_then(Value::as_str), Some("http://t/ann"));
+        assert_eq!(value.get("info").and_then(|i| i.get("length")).and_then(Value::as_int), Some(12));
+        assert_eq!(value.encode(), data);
+        assert_eq!(raw_value(data, "info").unwrap(), Some(&b"d6:lengthi12e4:name3:a.be"[..]));
+
+        assert!(decode(b"i03e").is_err());
+        assert!(decode(b"5:abc").is_err());
+        assert!(decode(&[b'l'; 200]).is_err());
+        let (_, used) = decode_prefix(b"d1:xi1eeRAW").unwrap();
+        assert_eq!(used, 8);
+    }
+}
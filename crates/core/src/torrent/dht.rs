@@ -0,0 +1,187 @@
+//! Peer lookups in the mainline DHT (BEP 5).
+//!
+//! This is a client only: it walks towards the info hash with iterative
+//! `get_peers` queries, collects the peers it is handed along the way and
+//! announces our port to the closest nodes. It keeps no routing table
+//! between lookups and does not answer queries.
+
+use super::bencode::{self, Value};
+use super::metainfo::InfoHash;
+use super::tracker::compact_v4;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout_at};
+
+/// Well-known routers for joining the DHT.
+pub const DEFAULT_BOOTSTRAP: &[&str] = &["router.bittorrent.com:6881", "dht.transmissionbt.com:6881", "router.utorrent.com:6881"];
+
+/// Nodes queried per round, and announced to at the end.
+const ALPHA: usize = 8;
+
+const MAX_ROUNDS: usize = 12;
+
+const ROUND_TIMEOUT: Duration = Duration::from_millis(1500);
+
+struct Node {
+    addr: SocketAddr,
+    /// Unknown for bootstrap routers until they answer.
+    id: Option<[u8; 20]>,
+}
+
+/// Looks up peers for `info_hash`, starting from the `bootstrap` nodes.
+/// With `announce_port`, the closest nodes are told we serve it there.
+pub(crate) async fn get_peers(bootstrap: &[String], info_hash: InfoHash, announce_port: Option<u16>) -> Result<Vec<SocketAddr>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let own_id: [u8; 20] = super::random_bytes();
+
+    let mut candidates = Vec::new();
+    for host in bootstrap {
+        match tokio::net::lookup_host(host.as_str()).await {
+            Ok(addrs) => candidates.extend(addrs.filter(SocketAddr::is_ipv4).map(|addr| Node { addr, id: None })),
+            Err(e) => log::debug!("DHT bootstrap node {} did not resolve: {}", host, e),
+        }
+    }
+
+    let mut queried = HashSet::new();
+    let mut tokens: Vec<([u8; 20], SocketAddr, Vec<u8>)> = Vec::new();
+    let mut peers = HashSet::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    for _ in 0..MAX_ROUNDS {
+        // Closest unqueried nodes first; bootstrap routers before anything
+        candidates.retain(|n| !queried.contains(&n.addr));
+        candidates.sort_by_key(|n| n.id.map(|id| distance(&id, &info_hash)));
+        let round: Vec<SocketAddr> = candidates.iter().take(ALPHA).map(|n| n.addr).collect();
+        if round.is_empty() {
+            break;
+        }
+
+        let mut pending: HashMap<[u8; 2], SocketAddr> = HashMap::new();
+        for (i, addr) in round.iter().enumerate() {
+            queried.insert(*addr);
+            let transaction = [b'g', i as u8];
+            let query = Value::dict([
+                ("t", Value::bytes(transaction)),
+                ("y", Value::bytes("q")),
+                ("q", Value::bytes("get_peers")),
+                ("a", Value::dict([("id", Value::bytes(own_id)), ("info_hash", Value::bytes(info_hash))])),
+            ]);
+            if socket.send_to(&query.encode(), addr).await.is_ok() {
+                pending.insert(transaction, *addr);
+            }
+        }
+
+        let deadline = Instant::now() + ROUND_TIMEOUT;
+        while !pending.is_empty() {
+            let Ok(Ok((n, from))) = timeout_at(deadline, socket.recv_from(&mut buf)).await else { break };
+            let Ok(reply) = bencode::decode(&buf[..n]) else { continue };
+            let Some(transaction) = reply.get("t").and_then(Value::as_bytes).and_then(|t| <[u8; 2]>::try_from(t).ok()) else { continue };
+            if pending.get(&transaction) != Some(&from) {
+                continue;
+            }
+            pending.remove(&transaction);
+            let Some(body) = reply.get("r") else { continue };
+
+            if let Some(values) = body.get("values").and_then(Value::as_list) {
+                peers.extend(values.iter().filter_map(Value::as_bytes).flat_map(compact_v4));
+            }
+            if let Some(nodes) = body.get("nodes").and_then(Value::as_bytes) {
+                for entry in nodes.chunks_exact(26) {
+                    let id: [u8; 20] = entry[..20].try_into().expect("20 bytes");
+                    let addr = compact_v4(&entry[20..])[0];
+                    if !queried.contains(&addr) && addr.port() != 0 {
+                        candidates.push(Node { addr, id: Some(id) });
+                    }
+                }
+            }
+            if let (Some(id), Some(token)) = (
+                body.get("id").and_then(Value::as_bytes).and_then(|id| <[u8; 20]>::try_from(id).ok()),
+                body.get("token").and_then(Value::as_bytes),
+            ) {
+                tokens.push((id, from, token.to_vec()));
+            }
+        }
+    }
+
+    if let Some(port) = announce_port {
+        tokens.sort_by_key(|(id, _, _)| distance(id, &info_hash));
+        for (_, addr, token) in tokens.iter().take(ALPHA) {
+            let query = Value::dict([
+                ("t", Value::bytes("ap")),
+                ("y", Value::bytes("q")),
+                ("q", Value::bytes("announce_peer")),
+                (
+                    "a",
+                    Value::dict([
+                        ("id", Value::bytes(own_id)),
+                        ("info_hash", Value::bytes(info_hash)),
+                        ("port", Value::Int(port as i64)),
+                        ("token", Value::bytes(token.clone())),
+                    ]),
+                ),
+            ]);
+            let _ = socket.send_to(&query.encode(), addr).await;
+        }
+    }
+
+    Ok(peers.into_iter().collect())
+}
+
+fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A node that knows one peer and records announces.
+    #[tokio::test]
+    async fn finds_peers_and_announces() {
+        let node = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let bootstrap = vec![node.local_addr().unwrap().to_string()];
+        let (announced_tx, announced_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let mut announced_tx = Some(announced_tx);
+            loop {
+                let (n, from) = node.recv_from(&mut buf).await.unwrap();
+                let query = bencode::decode(&buf[..n]).unwrap();
+                let args = query.get("a").unwrap();
+                match query.get("q").and_then(Value::as_str) {
+                    Some("get_peers") => {
+                        assert_eq!(args.get("info_hash").and_then(Value::as_bytes), Some(&[7u8; 20][..]));
+                        let reply = Value::dict([
+                            ("t", query.get("t").unwrap().clone()),
+                            ("y", Value::bytes("r")),
+                            (
+                                "r",
+                                Value::dict([
+                                    ("id", Value::bytes([7u8; 20])),
+                                    ("token", Value::bytes("tok")),
+                                    ("values", Value::List(vec![Value::bytes([127, 0, 0, 1, 0x1f, 0x90])])),
+                                ]),
+                            ),
+                        ]);
+                        node.send_to(&reply.encode(), from).await.unwrap();
+                    }
+                    Some("announce_peer") => {
+                        let port = args.get("port").and_then(Value::as_int);
+                        assert_eq!(args.get("token").and_then(Value::as_str), Some("tok"));
+                        if let Some(tx) = announced_tx.take() {
+                            let _ = tx.send(port);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let peers = get_peers(&bootstrap, [7; 20], Some(6881)).await.unwrap();
+        assert_eq!(peers, vec!["127.0.0.1:8080".parse().unwrap()]);
+        assert_eq!(announced_rx.await.unwrap(), Some(6881));
+    }
+}
@@ -0,0 +1,295 @@
+//! `.torrent` files and magnet links.
+
+use super::bencode::{self, Value};
+use anyhow::{Context, Result, bail};
+use percent_encoding::percent_decode_str;
+use sha1::{Digest, Sha1};
+
+/// SHA-1 of a bencoded info dictionary, identifying a (v1) torrent.
+pub type InfoHash = [u8; 20];
+
+/// One file of a torrent, laid out back to back with the others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Path components below the torrent's directory; empty for a
+    /// single-file torrent, whose only file is the torrent itself.
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Offset of the file in the concatenated torrent data.
+    pub offset: u64,
+}
+
+/// The info dictionary: everything covered by the info hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Info {
+    pub name: String,
+    pub piece_length: u64,
+    pub pieces: Vec<[u8; 20]>,
+    pub files: Vec<FileEntry>,
+    /// BEP 27: peers may only come from the torrent's trackers.
+    pub private: bool,
+}
+
+impl Info {
+    /// Parses a bencoded info dictionary.
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        let info = bencode::decode(raw).context("invalid info dictionary")?;
+        let name = info.get("name").and_then(Value::as_str).context("info dictionary has no name")?;
+        let name = safe_component(name).with_context(|| format!("unsafe torrent name {:?}", name))?;
+        let piece_length = info
+            .get("piece length")
+            .and_then(Value::as_int)
+            .filter(|&n| n > 0)
+            .context("info dictionary has no valid piece length")? as u64;
+        let Some(hashes) = info.get("pieces").and_then(Value::as_bytes) else {
+            bail!("info dictionary has no v1 piece hashes; BitTorrent v2-only torrents are not supported");
+        };
+        if hashes.len() % 20 != 0 {
+            bail!("piece hash string is {} bytes, not a multiple of 20", hashes.len());
+        }
+        let pieces = hashes.chunks_exact(20).map(|c| c.try_into().expect("20-byte chunk")).collect::<Vec<_>>();
+
+        let mut files = Vec::new();
+        if let Some(length) = info.get("length").and_then(Value::as_int) {
+            files.push(FileEntry { path: Vec::new(), length: non_negative(length)?, offset: 0 });
+        } else {
+            let list = info.get("files").and_then(Value::as_list).context("info dictionary has neither length nor files")?;
+            let mut offset = 0u64;
+            for entry in list {
+                let length = non_negative(entry.get("length").and_then(Value::as_int).context("file entry has no length")?)?;
+                let path = entry
+                    .get("path")
+                    .and_then(Value::as_list)
+                    .context("file entry has no path")?
+                    .iter()
+                    .map(|c| {
+                        let c = c.as_str().context("path component is not UTF-8")?;
+                        safe_component(c).with_context(|| format!("unsafe path component {:?}", c))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if path.is_empty() {
+                    bail!("file entry has an empty path");
+                }
+                files.push(FileEntry { path, length, offset });
+                offset = offset.checked_add(length).context("torrent size overflows")?;
+            }
+            if files.is_empty() {
+                bail!("torrent lists no files");
+            }
+        }
+
+        let info = Self {
+            name,
+            piece_length,
+            pieces,
+            files,
+            private: info.get("private").and_then(Value::as_int) == Some(1),
+        };
+        let expected = info.total_size().div_ceil(piece_length);
+        if expected != info.pieces.len() as u64 {
+            bail!("{} piece hashes for {} pieces", info.pieces.len(), expected);
+        }
+        Ok(info)
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.files.last().map_or(0, |f| f.offset + f.length)
+    }
+
+    /// Length of piece `index`; only the last one may be short.
+    pub fn piece_size(&self, index: usize) -> u64 {
+        let start = index as u64 * self.piece_length;
+        (self.total_size() - start).min(self.piece_length)
+    }
+
+    pub fn is_single_file(&self) -> bool {
+        self.files.len() == 1 && self.files[0].path.is_empty()
+    }
+}
+
+/// A parsed `.torrent` file.
+#[derive(Debug, Clone)]
+pub struct Metainfo {
+    pub info_hash: InfoHash,
+    pub info: Info,
+    /// The raw info dictionary, served to peers fetching metadata.
+    pub info_bytes: Vec<u8>,
+    /// `announce-list` tiers flattened in order, then `announce`.
+    pub trackers: Vec<String>,
+}
+
+impl Metainfo {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let root = bencode::decode(data).context("not a valid .torrent file")?;
+        let info_bytes = bencode::raw_value(data, "info")?.context(".torrent file has no info dictionary")?.to_vec();
+        let info = Info::parse(&info_bytes)?;
+
+        let mut trackers = Vec::new();
+        for tier in root.get("announce-list").and_then(Value::as_list).unwrap_or_default() {
+            trackers.extend(tier.as_list().unwrap_or_default().iter().filter_map(Value::as_str).map(str::to_string));
+        }
+        if let Some(announce) = root.get("announce").and_then(Value::as_str) {
+            trackers.push(announce.to_string());
+        }
+        dedup(&mut trackers);
+
+        Ok(Self { info_hash: Sha1::digest(&info_bytes).into(), info, info_bytes, trackers })
+    }
+}
+
+/// A `magnet:?xt=urn:btih:...` link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: InfoHash,
+    /// `dn`, the display name.
+    pub name: Option<String>,
+    /// `tr` parameters.
+    pub trackers: Vec<String>,
+    /// `x.pe` parameters: peers to contact directly, as `host:port`.
+    pub peers: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .with_context(|| format!("not a magnet link: {}", uri))?;
+        let mut info_hash = None;
+        let mut magnet = Self { info_hash: [0; 20], name: None, trackers: Vec::new(), peers: Vec::new() };
+
+        for pair in query.split('&') {
+            let Some((key, value)) = pair.split_once('=') else { continue };
+            let value = percent_decode_str(&value.replace('+', " ")).decode_utf8_lossy().into_owned();
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => magnet.name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                "x.pe" => magnet.peers.push(value),
+                _ => {}
+            }
+        }
+        magnet.info_hash = info_hash.context("magnet link has no urn:btih info hash")?;
+        dedup(&mut magnet.trackers);
+        Ok(magnet)
+    }
+}
+
+/// 40 hex digits or 32 base32 characters.
+fn parse_btih(hash: &str) -> Result<InfoHash> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("invalid hex info hash")?,
+        32 => base32_decode(hash).context("invalid base32 info hash")?,
+        n => bail!("info hash has {} characters, expected 40 hex or 32 base32", n),
+    };
+    Ok(bytes.try_into().expect("decoded to 20 bytes"))
+}
+
+/// RFC 4648 base32 without padding.
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut buffer, mut bits) = (0u64, 0);
+    for c in text.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Rejects names that would escape the download directory.
+fn safe_component(name: &str) -> Option<String> {
+    let unsafe_name = name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\\', '\0'])
+        || (cfg!(windows) && name.contains(':'));
+    (!unsafe_name).then(|| name.to_string())
+}
+
+fn non_negative(n: i64) -> Result<u64> {
+    u64::try_from(n).map_err(|_| anyhow::anyhow!("negative file length {}", n))
+}
+
+fn dedup(list: &mut Vec<String>) {
+    let mut seen = std::collections::HashSet::new();
+    list.retain(|s| seen.insert(s.clone()));
+}
+
+/// Builds `.torrent` files for tests.
+#[cfg(test)]
+pub(crate) fn build_torrent(name: &str, piece_length: u64, files: &[(&str, &[u8])], announce: Option<&str>) -> Vec<u8> {
+    let data: Vec<u8> = files.iter().flat_map(|(_, body)| body.iter().copied()).collect();
+    let pieces: Vec<u8> = data.chunks(piece_length as usize).flat_map(|c| Sha1::digest(c).to_vec()).collect();
+    let mut info = vec![
+        ("name", Value::bytes(name)),
+        ("piece length", Value::Int(piece_length as i64)),
+        ("pieces", Value::Bytes(pieces)),
+    ];
+    if files.len() == 1 && files[0].0.is_empty() {
+        info.push(("length", Value::Int(data.len() as i64)));
+    } else {
+        let list = files
+            .iter()
+            .map(|(path, body)| {
+                Value::dict([
+                    ("length", Value::Int(body.len() as i64)),
+                    ("path", Value::List(path.split('/').map(Value::bytes).collect())),
+                ])
+            })
+            .collect();
+        info.push(("files", Value::List(list)));
+    }
+    let mut root = vec![("info", Value::dict(info))];
+    if let Some(url) = announce {
+        root.push(("announce", Value::bytes(url)));
+    }
+    Value::dict(root).encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_multi_file_torrents() {
+        let data = build_torrent("album", 4, &[("cd1/a.flac", b"abcdef"), ("b.txt", b"ghi")], Some("http://t/announce"));
+        let meta = Metainfo::parse(&data).unwrap();
+        assert_eq!(meta.info.name, "album");
+        assert_eq!(meta.info.pieces.len(), 3);
+        assert_eq!(meta.info.files[1], FileEntry { path: vec!["b.txt".into()], length: 3, offset: 6 });
+        assert_eq!(meta.info.piece_size(2), 1);
+        assert_eq!(meta.trackers, vec!["http://t/announce"]);
+        assert_eq!(meta.info_hash, <[u8; 20]>::from(Sha1::digest(&meta.info_bytes)));
+
+        let hostile = build_torrent("x", 4, &[("../../.bashrc", b"pwned"), ("y", b"z")], None);
+        assert!(Metainfo::parse(&hostile).is_err());
+    }
+
+    #[test]
+    fn parses_magnet_links() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:C12FE1C06BBA254A9DC9F519B335AA7C1367A88A&dn=Some+File&tr=udp%3A%2F%2Ft.example%3A80&x.pe=10.0.0.1:6881",
+        )
+        .unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), "c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+        assert_eq!(magnet.name.as_deref(), Some("Some File"));
+        assert_eq!(magnet.trackers, vec!["udp://t.example:80"]);
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881"]);
+
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(base32.info_hash, magnet.info_hash);
+        assert!(Magnet::parse("magnet:?dn=nothing").is_err());
+    }
+}
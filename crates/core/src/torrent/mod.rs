@@ -0,0 +1,1022 @@
+//! BitTorrent downloads from `.torrent` files and magnet links.
+//!
+//! A torrent is a session like any other: [`Downloader::init_torrent`]
+//! creates it, [`Downloader::run`] hands it to [`run`] here and progress
+//! goes to the usual [`DownloadObserver`]. Instead of byte parts the
+//! session carries a [`TorrentJob`] recording which pieces are verified.
+//!
+//! Peers come from trackers, the DHT and the magnet link itself, and
+//! incoming peers are accepted on [`TorrentConfig::listen_port`]. Pieces
+//! are picked rarest first, requested in 16 KiB blocks and checked against
+//! their SHA-1 before they are written. Once the selected files are
+//! complete the session keeps seeding until its ratio limit is reached.
+
+pub mod bencode;
+mod dht;
+pub mod metainfo;
+mod peer;
+mod storage;
+mod tracker;
+
+pub use metainfo::{FileEntry, Info, Magnet, Metainfo};
+
+use crate::downloader::{DownloadObserver, Downloader};
+use crate::pieces::Bitfield;
+use crate::schedule::RateLimiter;
+use crate::session::{DownloadSession, DownloadState};
+use anyhow::{Context, Result};
+use metainfo::InfoHash;
+use peer::{BLOCK_SIZE, Message, MetadataMessage, UT_METADATA_ID};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use storage::Storage;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, broadcast, mpsc};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::timeout;
+
+/// Block requests kept in flight per peer.
+const PIPELINE: usize = 16;
+
+/// Peers we upload to at the same time.
+const MAX_UPLOAD_SLOTS: usize = 8;
+
+/// Largest block we serve; BEP 3 clients ask for 16 KiB.
+const MAX_REQUEST: u32 = 128 * 1024;
+
+/// A peer that sends nothing for a requested piece this long is dropped.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Before trying a peer again after it disconnected or failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Magnet metadata is fetched from this many peers at once.
+const METADATA_ATTEMPTS: usize = 8;
+
+/// Tracker intervals are clamped to this range.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// The job carries the info dictionary, so checkpoints are written less
+/// often than for byte-range downloads.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// True for magnet links and paths or URLs ending in `.torrent`.
+pub fn is_torrent_source(source: &str) -> bool {
+    let path = source.split(['?', '#']).next().unwrap_or(source).to_ascii_lowercase();
+    source.starts_with("magnet:") || path.ends_with(".torrent")
+}
+
+/// Peer discovery and listening settings shared by the torrents of a
+/// [`Downloader`].
+#[derive(Debug, Clone)]
+pub struct TorrentConfig {
+    /// Port to accept peers on; `0` picks a free one. Falls back to a free
+    /// port if this one is taken.
+    pub listen_port: u16,
+    /// Look up peers in the DHT (never for private torrents).
+    pub dht: bool,
+    /// `host:port` of DHT nodes to start lookups from.
+    pub dht_bootstrap: Vec<String>,
+    /// Connected peers per torrent.
+    pub max_peers: usize,
+}
+
+impl Default for TorrentConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: 6881,
+            dht: true,
+            dht_bootstrap: dht::DEFAULT_BOOTSTRAP.iter().map(|s| s.to_string()).collect(),
+            max_peers: 40,
+        }
+    }
+}
+
+/// Torrent state of a session, in [`DownloadSession::torrent`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TorrentJob {
+    /// Hex SHA-1 of the info dictionary.
+    pub info_hash: String,
+    #[serde(default)]
+    pub trackers: Vec<String>,
+    /// `host:port` peers to try besides tracker and DHT results, from a
+    /// magnet link's `x.pe`.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Bencoded info dictionary; for magnet links it is fetched from peers
+    /// when the download first starts.
+    #[serde(default, with = "base64_bytes", skip_serializing_if = "Option::is_none")]
+    pub info: Option<Vec<u8>>,
+    /// Indices of the files to download; `None` downloads all of them.
+    #[serde(default)]
+    pub selected_files: Option<Vec<usize>>,
+    /// Pieces on disk that passed their hash check.
+    #[serde(default)]
+    pub have: Bitfield,
+    /// Set once data already on disk has been hashed, on the first start.
+    #[serde(default)]
+    pub checked: bool,
+    /// Verified bytes of the selected files.
+    #[serde(default)]
+    pub completed: u64,
+    #[serde(default)]
+    pub uploaded: u64,
+    #[serde(default)]
+    pub downloaded: u64,
+    /// Seed until `uploaded` reaches this multiple of the selected size;
+    /// `0` stops as soon as the download is complete.
+    #[serde(default)]
+    pub seed_ratio: f64,
+}
+
+impl TorrentJob {
+    /// The parsed info dictionary, once known.
+    pub fn parsed_info(&self) -> Result<Option<Info>> {
+        self.info.as_deref().map(Info::parse).transpose()
+    }
+}
+
+mod base64_bytes {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_some(&STANDARD.encode(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        use serde::de::Error;
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| STANDARD.decode(s).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+impl Downloader {
+    /// Creates a session for a magnet link or a `.torrent` path or URL,
+    /// saving under `output_dir`. `selected_files` limits the download to
+    /// those file indices.
+    pub async fn init_torrent(
+        &self,
+        source: &str,
+        output_dir: &Path,
+        selected_files: Option<Vec<usize>>,
+        seed_ratio: f64,
+    ) -> Result<DownloadSession> {
+        let (info_hash, name, trackers, peers, info) = if source.starts_with("magnet:") {
+            let magnet = Magnet::parse(source)?;
+            let name = magnet
+                .name
+                .as_deref()
+                .map(|n| n.replace(['/', '\\', '\0'], "_"))
+                .filter(|n| !n.is_empty() && n != "." && n != "..")
+                .unwrap_or_else(|| hex::encode(magnet.info_hash));
+            (magnet.info_hash, name, magnet.trackers, magnet.peers, None)
+        } else {
+            let data = match crate::transport::scheme(source).as_deref() {
+                Some("http" | "https") => self.client().get(source).send().await?.error_for_status()?.bytes().await?.to_vec(),
+                _ => tokio::fs::read(source).await.with_context(|| format!("cannot read {}", source))?,
+            };
+            let meta = Metainfo::parse(&data)?;
+            (meta.info_hash, meta.info.name, meta.trackers, Vec::new(), Some(meta.info_bytes))
+        };
+
+        let mut session = DownloadSession::new(source.to_string(), output_dir.join(name), 1);
+        if let Some(info) = &info {
+            let info = Info::parse(info)?;
+            if let Some(bad) = selected_files.iter().flatten().find(|&&i| i >= info.files.len()) {
+                anyhow::bail!("file index {} is out of range; the torrent has {} files", bad, info.files.len());
+            }
+            let storage = Storage::new(info, session.output_path.clone(), selected_files.as_deref());
+            session.total_size = Some(storage.selected_size());
+        }
+        session.torrent = Some(TorrentJob {
+            info_hash: hex::encode(info_hash),
+            trackers,
+            peers,
+            info,
+            selected_files,
+            have: Bitfield::default(),
+            checked: false,
+            completed: 0,
+            uploaded: 0,
+            downloaded: 0,
+            seed_ratio,
+        });
+        Ok(session)
+    }
+}
+
+/// Random bytes for peer ids, node ids and transaction ids. Only needs to
+/// be unpredictable enough not to collide.
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    use std::hash::{BuildHasher, Hasher};
+    let mut out = [0u8; N];
+    for chunk in out.chunks_mut(8) {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos());
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+    }
+    out
+}
+
+/// Azureus-style id: client code and version, then random bytes.
+fn new_peer_id() -> [u8; 20] {
+    let mut id = [0u8; 20];
+    id[..8].copy_from_slice(b"-KT0100-");
+    id[8..].copy_from_slice(&random_bytes::<12>());
+    id
+}
+
+/// Transfer totals shared with the announce task.
+#[derive(Default)]
+struct Stats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+    completed: Notify,
+}
+
+/// Everything peer connections share.
+struct Shared {
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    port: u16,
+    storage: Arc<Storage>,
+    /// Raw info dictionary, served to peers that fetch metadata.
+    metadata: Vec<u8>,
+    /// Announces newly verified pieces to every connection.
+    haves: broadcast::Sender<u32>,
+    swarm: Mutex<Swarm>,
+    /// Holds back reading from peers to the download's speed limit.
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+struct Swarm {
+    have: Bitfield,
+    wanted: Vec<bool>,
+    /// Connected peers having each piece.
+    availability: Vec<u32>,
+    /// Pieces a connection is downloading.
+    assigned: HashSet<usize>,
+    /// Peers connected or being connected to.
+    connected: HashSet<SocketAddr>,
+    unchoked: usize,
+    uploaded: u64,
+    downloaded: u64,
+    /// Selected bytes verified since the last progress report.
+    progress: u64,
+    /// A failed disk write; ends the download.
+    disk_error: Option<String>,
+}
+
+impl Swarm {
+    fn missing(&self, piece: usize) -> bool {
+        self.wanted[piece] && !self.have.get(piece)
+    }
+
+    fn complete(&self) -> bool {
+        (0..self.wanted.len()).all(|p| !self.missing(p))
+    }
+
+    /// Whether a peer having `has` has anything we still need.
+    fn wants_from(&self, has: &Bitfield) -> bool {
+        (0..self.wanted.len()).any(|p| self.missing(p) && has.get(p))
+    }
+
+    /// Rarest missing piece the peer has and nobody is downloading.
+    fn pick(&mut self, has: &Bitfield) -> Option<usize> {
+        let piece = (0..self.wanted.len())
+            .filter(|&p| self.missing(p) && has.get(p) && !self.assigned.contains(&p))
+            .min_by_key(|&p| self.availability[p])?;
+        self.assigned.insert(piece);
+        Some(piece)
+    }
+}
+
+/// Downloads and seeds `session.torrent`. Called by [`Downloader::run`].
+pub(crate) async fn run(
+    downloader: &Downloader,
+    session: &mut DownloadSession,
+    observer: Option<Arc<dyn DownloadObserver>>,
+    session_file: Option<PathBuf>,
+    cancel_flag: Option<Arc<AtomicBool>>,
+) -> Result<()> {
+    let config = downloader.torrent_config().clone();
+    let job = session.torrent.as_ref().context("session has no torrent")?;
+    let info_hash: InfoHash = hex::decode(&job.info_hash)
+        .ok()
+        .and_then(|h| h.try_into().ok())
+        .context("session has an invalid info hash")?;
+    let private = job.parsed_info()?.is_some_and(|info| info.private);
+    let peer_id = new_peer_id();
+
+    let listener = match TcpListener::bind((Ipv4Addr::UNSPECIFIED, config.listen_port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::warn!("Cannot listen on port {} ({}); using a free port", config.listen_port, e);
+            TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?
+        }
+    };
+    let port = listener.local_addr()?.port();
+
+    // Unknown until metadata arrives, but never zero: trackers hand
+    // seeders only leechers
+    let stats = Arc::new(Stats::default());
+    stats.left.store(session.total_size.map_or(1, |size| size.saturating_sub(job.completed)).max(1), Ordering::Relaxed);
+    stats.uploaded.store(job.uploaded, Ordering::Relaxed);
+    stats.downloaded.store(job.downloaded, Ordering::Relaxed);
+
+    let (found_tx, mut found_rx) = mpsc::unbounded_channel();
+    for peer in &job.peers {
+        match tokio::net::lookup_host(peer.as_str()).await {
+            Ok(addrs) => addrs.for_each(|addr| {
+                let _ = found_tx.send(addr);
+            }),
+            Err(e) => log::debug!("Magnet peer {} did not resolve: {}", peer, e),
+        }
+    }
+    let discovery = Discovery {
+        client: downloader.client().clone(),
+        trackers: job.trackers.clone(),
+        dht_bootstrap: if config.dht && !private { config.dht_bootstrap.clone() } else { Vec::new() },
+        info_hash,
+        peer_id,
+        port,
+        stats: stats.clone(),
+    };
+    let discovery = AbortOnDrop(tokio::spawn(discovery.run(found_tx)));
+
+    // Peer address -> when we last connected to it
+    let mut known: HashMap<SocketAddr, Option<Instant>> = HashMap::new();
+    if job.info.is_none() {
+        let metadata = fetch_metadata(session, &mut found_rx, &mut known, info_hash, peer_id, &session_file, &cancel_flag).await?;
+        let job = session.torrent.as_mut().expect("checked above");
+        let info = Info::parse(&metadata)?;
+        log::info!("Fetched metadata for {} ({} files, {} bytes)", info.name, info.files.len(), info.total_size());
+        if let Some(bad) = job.selected_files.iter().flatten().find(|&&i| i >= info.files.len()) {
+            anyhow::bail!("file index {} is out of range; the torrent has {} files", bad, info.files.len());
+        }
+        job.info = Some(metadata);
+    }
+
+    let job = session.torrent.as_mut().expect("checked above");
+    let metadata = job.info.clone().expect("metadata is known");
+    let storage = Arc::new(Storage::new(Info::parse(&metadata)?, session.output_path.clone(), job.selected_files.as_deref()));
+    let wanted = storage.wanted();
+    let selected_size = storage.selected_size();
+    session.total_size = Some(selected_size);
+
+    if !job.checked || job.have.len() != storage.piece_count() {
+        if let Some(obs) = &observer {
+            obs.on_state_change(&DownloadState::Verifying);
+        }
+        session.state = DownloadState::Verifying;
+        let checker = storage.clone();
+        let wanted = wanted.clone();
+        let have = tokio::task::spawn_blocking(move || checker.recheck(&wanted)).await?;
+        let job = session.torrent.as_mut().expect("checked above");
+        job.completed = (0..have.len()).filter(|&p| have.get(p)).map(|p| storage.selected_bytes(p)).sum();
+        job.have = have;
+        job.checked = true;
+        log::info!("{} of {} selected bytes already on disk", job.completed, selected_size);
+        if let Some(path) = &session_file {
+            session.save(path).await?;
+        }
+    }
+
+    let job = session.torrent.as_ref().expect("checked above");
+    stats.left.store(selected_size - job.completed, Ordering::Relaxed);
+    let shared = Arc::new(Shared {
+        info_hash,
+        peer_id,
+        port,
+        storage: storage.clone(),
+        metadata,
+        haves: broadcast::channel(256).0,
+        limiter: downloader.rate_limiter(),
+        swarm: Mutex::new(Swarm {
+            have: job.have.clone(),
+            availability: vec![0; wanted.len()],
+            wanted,
+            assigned: HashSet::new(),
+            connected: HashSet::new(),
+            unchoked: 0,
+            uploaded: job.uploaded,
+            downloaded: job.downloaded,
+            progress: 0,
+            disk_error: None,
+        }),
+    });
+
+    let seeding_target = (job.seed_ratio.max(0.0) * selected_size as f64) as u64;
+    let initial = if shared.swarm.lock().unwrap().complete() { DownloadState::Seeding } else { DownloadState::Downloading };
+    if let Some(obs) = &observer {
+        obs.on_state_change(&initial);
+    }
+    session.state = initial;
+
+    let mut connections = JoinSet::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    let mut last_save = Instant::now();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                if let Ok((stream, addr)) = accepted {
+                    let mut swarm = shared.swarm.lock().unwrap();
+                    if swarm.connected.len() < config.max_peers && swarm.connected.insert(addr) {
+                        connections.spawn(connect(shared.clone(), addr, Some(stream)));
+                    }
+                }
+            }
+            Some(addr) = found_rx.recv() => {
+                known.entry(addr).or_insert(None);
+            }
+            _ = ticker.tick() => {
+                while connections.try_join_next().is_some() {}
+
+                let (progress, active, complete, have, uploaded, downloaded, disk_error) = {
+                    let mut swarm = shared.swarm.lock().unwrap();
+                    (
+                        std::mem::take(&mut swarm.progress),
+                        swarm.connected.len(),
+                        swarm.complete(),
+                        swarm.have.clone(),
+                        swarm.uploaded,
+                        swarm.downloaded,
+                        swarm.disk_error.take(),
+                    )
+                };
+                let job = session.torrent.as_mut().expect("checked above");
+                job.completed += progress;
+                job.have = have;
+                job.uploaded = uploaded;
+                job.downloaded = downloaded;
+                stats.uploaded.store(uploaded, Ordering::Relaxed);
+                stats.downloaded.store(downloaded, Ordering::Relaxed);
+                stats.left.store(selected_size.saturating_sub(job.completed), Ordering::Relaxed);
+
+                if let Some(e) = disk_error {
+                    if let Some(path) = &session_file {
+                        let _ = session.save(path).await;
+                    }
+                    anyhow::bail!("cannot write torrent data: {}", e);
+                }
+                if let Some(flag) = &cancel_flag
+                    && flag.load(Ordering::Relaxed)
+                {
+                    if let Some(path) = &session_file {
+                        let _ = session.save(path).await;
+                    }
+                    return Err(anyhow::anyhow!("cancelled"));
+                }
+                if progress > 0
+                    && let Some(obs) = &observer
+                {
+                    obs.on_progress(0, progress, active);
+                }
+
+                if complete {
+                    if uploaded >= seeding_target {
+                        break;
+                    }
+                    if session.state != DownloadState::Seeding {
+                        log::info!("Download complete; seeding until {} bytes are uploaded", seeding_target);
+                        stats.completed.notify_one();
+                        if let Some(obs) = &observer {
+                            obs.on_state_change(&DownloadState::Seeding);
+                        }
+                        session.state = DownloadState::Seeding;
+                    }
+                }
+
+                // Fill free connection slots with peers not tried recently
+                let now = Instant::now();
+                {
+                    let mut swarm = shared.swarm.lock().unwrap();
+                    for (addr, last) in known.iter_mut() {
+                        if swarm.connected.len() >= config.max_peers {
+                            break;
+                        }
+                        if last.is_some_and(|t| now - t < RECONNECT_DELAY) || !swarm.connected.insert(*addr) {
+                            continue;
+                        }
+                        *last = Some(now);
+                        connections.spawn(connect(shared.clone(), *addr, None));
+                    }
+                }
+
+                if let Some(path) = &session_file
+                    && last_save.elapsed() >= CHECKPOINT_INTERVAL
+                {
+                    if let Err(e) = session.save(path).await {
+                        log::warn!("Failed to checkpoint session: {}", e);
+                    }
+                    last_save = Instant::now();
+                }
+            }
+        }
+    }
+
+    drop(connections);
+    drop(discovery);
+    let _ = tokio::fs::remove_dir_all(storage::parts_dir(&session.output_path)).await;
+    log::info!("Torrent {:?} finished", session.output_path);
+
+    if let Some(obs) = &observer {
+        obs.on_state_change(&DownloadState::Completed);
+    }
+    session.state = DownloadState::Completed;
+    if let Some(path) = &session_file {
+        let _ = session.save(path).await;
+    }
+    Ok(())
+}
+
+/// Waits for a magnet link's info dictionary from the peers discovery
+/// turns up, trying several at once.
+async fn fetch_metadata(
+    session: &DownloadSession,
+    found_rx: &mut mpsc::UnboundedReceiver<SocketAddr>,
+    known: &mut HashMap<SocketAddr, Option<Instant>>,
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    session_file: &Option<PathBuf>,
+    cancel_flag: &Option<Arc<AtomicBool>>,
+) -> Result<Vec<u8>> {
+    let mut queue = VecDeque::new();
+    let mut attempts = JoinSet::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        while attempts.len() < METADATA_ATTEMPTS
+            && let Some(addr) = queue.pop_front()
+        {
+            attempts.spawn(peer::fetch_metadata(addr, info_hash, peer_id));
+        }
+
+        tokio::select! {
+            Some(addr) = found_rx.recv() => {
+                if let std::collections::hash_map::Entry::Vacant(entry) = known.entry(addr) {
+                    entry.insert(None);
+                    queue.push_back(addr);
+                }
+            }
+            Some(result) = attempts.join_next() => {
+                match result {
+                    Ok(Ok(metadata)) => return Ok(metadata),
+                    Ok(Err(e)) => log::debug!("Metadata fetch failed: {:#}", e),
+                    Err(e) => log::debug!("Metadata fetch task failed: {}", e),
+                }
+            }
+            _ = ticker.tick() => {
+                if let Some(flag) = cancel_flag
+                    && flag.load(Ordering::Relaxed)
+                {
+                    if let Some(path) = session_file {
+                        let _ = session.save(path).await;
+                    }
+                    return Err(anyhow::anyhow!("cancelled"));
+                }
+            }
+        }
+    }
+}
+
+/// Announces to trackers and looks up the DHT, feeding every peer found
+/// to the download until it is dropped.
+struct Discovery {
+    client: reqwest::Client,
+    trackers: Vec<String>,
+    /// Empty when the DHT is not used.
+    dht_bootstrap: Vec<String>,
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    port: u16,
+    stats: Arc<Stats>,
+}
+
+impl Discovery {
+    async fn run(self, found: mpsc::UnboundedSender<SocketAddr>) {
+        let mut event = tracker::Event::Started;
+        loop {
+            let request = tracker::Announce {
+                info_hash: self.info_hash,
+                peer_id: self.peer_id,
+                port: self.port,
+                uploaded: self.stats.uploaded.load(Ordering::Relaxed),
+                downloaded: self.stats.downloaded.load(Ordering::Relaxed),
+                left: self.stats.left.load(Ordering::Relaxed),
+                event,
+            };
+            let announces = self.trackers.iter().map(|url| tracker::announce(&self.client, url, &request));
+            let mut interval = MAX_ANNOUNCE_INTERVAL;
+            for (url, result) in self.trackers.iter().zip(futures::future::join_all(announces).await) {
+                match result {
+                    Ok(response) => {
+                        log::debug!("Tracker {} returned {} peers", url, response.peers.len());
+                        interval = interval.min(response.interval);
+                        response.peers.into_iter().for_each(|addr| {
+                            let _ = found.send(addr);
+                        });
+                    }
+                    Err(e) => log::debug!("Announce to {} failed: {:#}", url, e),
+                }
+            }
+            if !self.dht_bootstrap.is_empty() {
+                match dht::get_peers(&self.dht_bootstrap, self.info_hash, Some(self.port)).await {
+                    Ok(peers) => {
+                        log::debug!("DHT returned {} peers", peers.len());
+                        peers.into_iter().for_each(|addr| {
+                            let _ = found.send(addr);
+                        });
+                    }
+                    Err(e) => log::debug!("DHT lookup failed: {:#}", e),
+                }
+            }
+
+            event = tracker::Event::None;
+            tokio::select! {
+                _ = tokio::time::sleep(interval.max(MIN_ANNOUNCE_INTERVAL)) => {}
+                _ = self.stats.completed.notified() => event = tracker::Event::Completed,
+            }
+        }
+    }
+}
+
+/// Aborts the task when dropped.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs one peer connection, outgoing unless `stream` is given.
+async fn connect(shared: Arc<Shared>, addr: SocketAddr, stream: Option<TcpStream>) {
+    let mut peer = Peer::new(shared, addr);
+    if let Err(e) = peer.run(stream).await {
+        log::debug!("Peer {} disconnected: {:#}", addr, e);
+    }
+}
+
+/// A piece being downloaded from one peer.
+struct PieceDownload {
+    index: usize,
+    data: Vec<u8>,
+    received: Vec<bool>,
+    /// Next block to request.
+    next: usize,
+    outstanding: usize,
+}
+
+/// One peer connection. Dropping it releases its piece, slots and
+/// availability counts.
+struct Peer {
+    shared: Arc<Shared>,
+    addr: SocketAddr,
+    has: Bitfield,
+    am_interested: bool,
+    /// The peer is not sending us blocks.
+    choked: bool,
+    /// We are not sending the peer blocks.
+    choking: bool,
+    interested: bool,
+    /// The peer's id for `ut_metadata` messages.
+    metadata_id: Option<u8>,
+    current: Option<PieceDownload>,
+    last_block: Instant,
+    reader: Option<AbortOnDrop>,
+}
+
+impl Peer {
+    fn new(shared: Arc<Shared>, addr: SocketAddr) -> Self {
+        let pieces = shared.storage.piece_count();
+        Self {
+            shared,
+            addr,
+            has: Bitfield::new(pieces),
+            am_interested: false,
+            choked: true,
+            choking: true,
+            interested: false,
+            metadata_id: None,
+            current: None,
+            last_block: Instant::now(),
+            reader: None,
+        }
+    }
+
+    async fn run(&mut self, stream: Option<TcpStream>) -> Result<()> {
+        let mut stream = match stream {
+            Some(stream) => stream,
+            None => timeout(peer::CONNECT_TIMEOUT, TcpStream::connect(self.addr)).await??,
+        };
+        let remote = peer::handshake(&mut stream, &self.shared.info_hash, &self.shared.peer_id).await?;
+        if remote.peer_id == self.shared.peer_id {
+            anyhow::bail!("connected to ourselves");
+        }
+
+        let (mut reader, mut writer) = stream.into_split();
+        let (message_tx, mut messages) = mpsc::channel(64);
+        self.reader = Some(AbortOnDrop(tokio::spawn(async move {
+            while let Ok(message) = peer::read_message(&mut reader).await {
+                if message_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        })));
+        let mut haves = self.shared.haves.subscribe();
+
+        if remote.extensions {
+            let handshake = peer::extension_handshake(Some(self.shared.metadata.len()), self.shared.port);
+            peer::write_message(&mut writer, &handshake).await?;
+        }
+        let have = self.shared.swarm.lock().unwrap().have.clone();
+        if have.count_ones() > 0 {
+            peer::write_message(&mut writer, &Message::Bitfield(have.as_bytes().to_vec())).await?;
+        }
+
+        let mut keepalive = tokio::time::interval_at(tokio::time::Instant::now() + Duration::from_secs(10), Duration::from_secs(10));
+        loop {
+            tokio::select! {
+                message = messages.recv() => {
+                    let message = message.context("connection closed")?;
+                    self.handle(message, &mut writer).await?;
+                }
+                have = haves.recv() => match have {
+                    Ok(piece) if !self.has.get(piece as usize) => peer::write_message(&mut writer, &Message::Have(piece)).await?,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = keepalive.tick() => {
+                    if self.current.is_some() && self.last_block.elapsed() > STALL_TIMEOUT {
+                        anyhow::bail!("no data for {:?}", STALL_TIMEOUT);
+                    }
+                    peer::write_message(&mut writer, &Message::KeepAlive).await?;
+                }
+            }
+            if self.update(&mut writer).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn handle(&mut self, message: Message, writer: &mut OwnedWriteHalf) -> Result<()> {
+        let pieces = self.has.len();
+        match message {
+            Message::Bitfield(bits) => {
+                let has = Bitfield::from_bytes(bits, pieces).context("peer sent a bitfield of the wrong size")?;
+                let mut swarm = self.shared.swarm.lock().unwrap();
+                for piece in (0..pieces).filter(|&p| has.get(p) && !self.has.get(p)) {
+                    swarm.availability[piece] += 1;
+                }
+                self.has = has;
+            }
+            Message::Have(piece) => {
+                let piece = piece as usize;
+                if piece < pieces && !self.has.get(piece) {
+                    self.has.set(piece, true);
+                    self.shared.swarm.lock().unwrap().availability[piece] += 1;
+                }
+            }
+            Message::Choke => {
+                self.choked = true;
+                // Outstanding requests are discarded by the peer
+                self.release_piece();
+            }
+            Message::Unchoke => self.choked = false,
+            Message::Interested => self.interested = true,
+            Message::NotInterested => {
+                self.interested = false;
+                if !self.choking {
+                    self.choking = true;
+                    self.shared.swarm.lock().unwrap().unchoked -= 1;
+                    peer::write_message(writer, &Message::Choke).await?;
+                }
+            }
+            Message::Request { index, begin, length } => {
+                let piece = index as usize;
+                let servable = !self.choking
+                    && length <= MAX_REQUEST
+                    && piece < pieces
+                    && begin as u64 + length as u64 <= self.shared.storage.info().piece_size(piece)
+                    && self.shared.swarm.lock().unwrap().have.get(piece);
+                if servable {
+                    let storage = self.shared.storage.clone();
+                    let data = tokio::task::spawn_blocking(move || storage.read(piece, begin as u64, length as u64)).await??;
+                    peer::write_message(writer, &Message::Piece { index, begin, data }).await?;
+                    self.shared.swarm.lock().unwrap().uploaded += length as u64;
+                }
+            }
+            Message::Piece { index, begin, data } => self.receive_block(index as usize, begin, data).await?,
+            Message::Extended { id: 0, payload } => self.metadata_id = peer::parse_extension_handshake(&payload).0,
+            Message::Extended { id: UT_METADATA_ID, payload } => {
+                if let (Some(id), Ok(MetadataMessage::Request(piece))) = (self.metadata_id, MetadataMessage::decode(&payload)) {
+                    let answer = MetadataMessage::answer(piece, Some(&self.shared.metadata));
+                    peer::write_message(writer, &Message::Extended { id, payload: answer.encode() }).await?;
+                }
+            }
+            Message::KeepAlive | Message::Cancel { .. } | Message::Extended { .. } | Message::Unknown(_) => {}
+        }
+        Ok(())
+    }
+
+    async fn receive_block(&mut self, index: usize, begin: u32, data: Vec<u8>) -> Result<()> {
+        let Some(current) = self.current.as_mut().filter(|c| c.index == index) else {
+            return Ok(());
+        };
+        let block = (begin / BLOCK_SIZE) as usize;
+        let start = begin as usize;
+        let expected = (current.data.len() - start.min(current.data.len())).min(BLOCK_SIZE as usize);
+        if !begin.is_multiple_of(BLOCK_SIZE) || block >= current.received.len() || current.received[block] || data.len() != expected {
+            return Ok(());
+        }
+        current.data[start..start + data.len()].copy_from_slice(&data);
+        current.received[block] = true;
+        if let Some(limiter) = &self.shared.limiter {
+            limiter.acquire(data.len() as u64).await;
+        }
+        current.outstanding = current.outstanding.saturating_sub(1);
+        self.last_block = Instant::now();
+        self.shared.swarm.lock().unwrap().downloaded += data.len() as u64;
+        if !current.received.iter().all(|r| *r) {
+            return Ok(());
+        }
+
+        let piece = self.current.take().expect("checked above");
+        let storage = self.shared.storage.clone();
+        let stored = tokio::task::spawn_blocking(move || storage.store_piece(piece.index, &piece.data)).await?;
+        let mut swarm = self.shared.swarm.lock().unwrap();
+        swarm.assigned.remove(&index);
+        match stored {
+            Ok(true) => {
+                swarm.have.set(index, true);
+                swarm.progress += self.shared.storage.selected_bytes(index);
+                drop(swarm);
+                let _ = self.shared.haves.send(index as u32);
+                Ok(())
+            }
+            Ok(false) => anyhow::bail!("piece {} failed its hash check", index),
+            Err(e) => {
+                swarm.disk_error = Some(e.to_string());
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Updates interest and choking, keeps requests in flight and returns
+    /// true once the connection is of no further use.
+    async fn update(&mut self, writer: &mut OwnedWriteHalf) -> Result<bool> {
+        let (wants, complete, can_unchoke) = {
+            let swarm = self.shared.swarm.lock().unwrap();
+            let wants = self.current.is_some() || swarm.wants_from(&self.has);
+            (wants, swarm.complete(), swarm.unchoked < MAX_UPLOAD_SLOTS)
+        };
+        // Two seeds have nothing to exchange
+        if complete && self.has.count_ones() == self.has.len() {
+            return Ok(true);
+        }
+        if wants != self.am_interested {
+            self.am_interested = wants;
+            let message = if wants { Message::Interested } else { Message::NotInterested };
+            peer::write_message(writer, &message).await?;
+        }
+        if self.interested && self.choking && can_unchoke {
+            self.choking = false;
+            self.shared.swarm.lock().unwrap().unchoked += 1;
+            peer::write_message(writer, &Message::Unchoke).await?;
+        }
+
+        if self.choked || !self.am_interested {
+            return Ok(false);
+        }
+        if self.current.is_none() {
+            let Some(index) = self.shared.swarm.lock().unwrap().pick(&self.has) else {
+                return Ok(false);
+            };
+            let size = self.shared.storage.info().piece_size(index) as usize;
+            self.current = Some(PieceDownload {
+                index,
+                data: vec![0; size],
+                received: vec![false; size.div_ceil(BLOCK_SIZE as usize)],
+                next: 0,
+                outstanding: 0,
+            });
+            self.last_block = Instant::now();
+        }
+        let current = self.current.as_mut().expect("set above");
+        while current.outstanding < PIPELINE && current.next < current.received.len() {
+            let begin = current.next as u32 * BLOCK_SIZE;
+            let length = (current.data.len() as u32 - begin).min(BLOCK_SIZE);
+            peer::write_message(writer, &Message::Request { index: current.index as u32, begin, length }).await?;
+            current.next += 1;
+            current.outstanding += 1;
+        }
+        Ok(false)
+    }
+
+    fn release_piece(&mut self) {
+        if let Some(piece) = self.current.take() {
+            self.shared.swarm.lock().unwrap().assigned.remove(&piece.index);
+        }
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.release_piece();
+        let mut swarm = self.shared.swarm.lock().unwrap();
+        for piece in (0..self.has.len()).filter(|&p| self.has.get(p)) {
+            swarm.availability[piece] = swarm.availability[piece].saturating_sub(1);
+        }
+        if !self.choking {
+            swarm.unchoked -= 1;
+        }
+        swarm.connected.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::ChannelObserver;
+    use crate::test_support::{payload, serve_tracker, temp_path};
+
+    #[tokio::test]
+    async fn magnet_download_from_local_seeder() {
+        let tracker = serve_tracker().await;
+        let a = payload(100_000);
+        let c: Vec<u8> = payload(50_000).into_iter().rev().collect();
+        let d: Vec<u8> = payload(40_000).iter().map(|b| b ^ 0x5a).collect();
+        let torrent = metainfo::build_torrent("set", 32 * 1024, &[("a.bin", &a), ("sub/c.bin", &c), ("d.bin", &d)], Some(&tracker.url()));
+        let info_hash = Metainfo::parse(&torrent).unwrap().info_hash;
+
+        let seed_dir = temp_path("torrent-seed").parent().unwrap().to_path_buf();
+        std::fs::create_dir_all(seed_dir.join("set/sub")).unwrap();
+        std::fs::write(seed_dir.join("set/a.bin"), &a).unwrap();
+        std::fs::write(seed_dir.join("set/sub/c.bin"), &c).unwrap();
+        std::fs::write(seed_dir.join("set/d.bin"), &d).unwrap();
+        std::fs::write(seed_dir.join("set.torrent"), &torrent).unwrap();
+
+        let config = TorrentConfig { listen_port: 0, dht: false, ..Default::default() };
+        let downloader = Downloader::new("Kitsune-Test/1.0").unwrap().with_torrent_config(config);
+        let mut seeder = downloader
+            .init_torrent(seed_dir.join("set.torrent").to_str().unwrap(), &seed_dir, None, 10.0)
+            .await
+            .unwrap();
+        let stop_seeding = Arc::new(AtomicBool::new(false));
+        let seeding = {
+            let (downloader, stop) = (downloader.clone(), stop_seeding.clone());
+            tokio::spawn(async move {
+                let result = downloader.run(&mut seeder, None, None, Some(stop)).await;
+                (result, seeder)
+            })
+        };
+        while tracker.announces() == 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // Only a.bin and d.bin; the pieces they share with c.bin go to .parts
+        let leech_dir = temp_path("torrent-leech").parent().unwrap().to_path_buf();
+        let magnet = format!("magnet:?xt=urn:btih:{}&dn=set&tr={}", hex::encode(info_hash), tracker.url());
+        let mut leecher = downloader.init_torrent(&magnet, &leech_dir, Some(vec![0, 2]), 0.0).await.unwrap();
+        let (tx, mut rx) = mpsc::channel(1024);
+        let observer = Arc::new(ChannelObserver::new(tx));
+        timeout(Duration::from_secs(30), downloader.run(&mut leecher, Some(observer), None, None))
+            .await
+            .expect("download timed out")
+            .unwrap();
+
+        assert_eq!(std::fs::read(leech_dir.join("set/a.bin")).unwrap(), a);
+        assert_eq!(std::fs::read(leech_dir.join("set/d.bin")).unwrap(), d);
+        assert!(!leech_dir.join("set/sub/c.bin").exists());
+        assert!(!storage::parts_dir(&leech_dir.join("set")).exists());
+        assert_eq!(leecher.state, DownloadState::Completed);
+        assert_eq!(leecher.total_size, Some(140_000));
+        let mut reported = 0;
+        while let Ok((_, bytes, _)) = rx.try_recv() {
+            reported += bytes;
+        }
+        assert_eq!(reported, 140_000);
+
+        stop_seeding.store(true, Ordering::Relaxed);
+        let (result, seeder) = seeding.await.unwrap();
+        assert_eq!(result.unwrap_err().to_string(), "cancelled");
+        let job = seeder.torrent.unwrap();
+        assert_eq!(job.completed, 190_000);
+        assert!(job.uploaded >= 140_000);
+    }
+}
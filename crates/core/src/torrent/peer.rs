@@ -0,0 +1,322 @@
+//! The peer wire protocol (BEP 3) with the extension protocol (BEP 10)
+//! and metadata exchange (BEP 9) for magnet links.
+
+use super::bencode::{self, Value};
+use super::metainfo::InfoHash;
+use anyhow::{Context, Result, bail};
+use sha1::{Digest, Sha1};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// Size of the blocks pieces are requested in; the largest most clients serve.
+pub(crate) const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// Id we ask peers to use for `ut_metadata` messages sent to us.
+pub(crate) const UT_METADATA_ID: u8 = 1;
+
+/// Metadata is exchanged in pieces of this size.
+const METADATA_PIECE: usize = 16 * 1024;
+
+/// Largest message accepted: a block plus headers, or a bitfield for a
+/// very large torrent.
+const MAX_MESSAGE: usize = 1024 * 1024;
+
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, data: Vec<u8> },
+    Cancel { index: u32, begin: u32, length: u32 },
+    Extended { id: u8, payload: Vec<u8> },
+    /// Messages of extensions we do not speak, e.g. the fast extension.
+    Unknown(u8),
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            Message::KeepAlive => {}
+            Message::Choke => body.push(0),
+            Message::Unchoke => body.push(1),
+            Message::Interested => body.push(2),
+            Message::NotInterested => body.push(3),
+            Message::Have(index) => {
+                body.push(4);
+                body.extend_from_slice(&index.to_be_bytes());
+            }
+            Message::Bitfield(bits) => {
+                body.push(5);
+                body.extend_from_slice(bits);
+            }
+            Message::Request { index, begin, length } | Message::Cancel { index, begin, length } => {
+                body.push(if matches!(self, Message::Request { .. }) { 6 } else { 8 });
+                for n in [index, begin, length] {
+                    body.extend_from_slice(&n.to_be_bytes());
+                }
+            }
+            Message::Piece { index, begin, data } => {
+                body.push(7);
+                body.extend_from_slice(&index.to_be_bytes());
+                body.extend_from_slice(&begin.to_be_bytes());
+                body.extend_from_slice(data);
+            }
+            Message::Extended { id, payload } => {
+                body.push(20);
+                body.push(*id);
+                body.extend_from_slice(payload);
+            }
+            Message::Unknown(id) => body.push(*id),
+        }
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.extend(body);
+        frame
+    }
+
+    fn decode(body: &[u8]) -> Result<Self> {
+        let Some((&id, rest)) = body.split_first() else {
+            return Ok(Message::KeepAlive);
+        };
+        let int = |i: usize| -> Result<u32> {
+            let bytes = rest.get(i * 4..i * 4 + 4).context("peer message too short")?;
+            Ok(u32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+        };
+        Ok(match id {
+            0 => Message::Choke,
+            1 => Message::Unchoke,
+            2 => Message::Interested,
+            3 => Message::NotInterested,
+            4 => Message::Have(int(0)?),
+            5 => Message::Bitfield(rest.to_vec()),
+            6 => Message::Request { index: int(0)?, begin: int(1)?, length: int(2)? },
+            7 => Message::Piece { index: int(0)?, begin: int(1)?, data: rest.get(8..).unwrap_or_default().to_vec() },
+            8 => Message::Cancel { index: int(0)?, begin: int(1)?, length: int(2)? },
+            20 => {
+                let (&ext, payload) = rest.split_first().context("empty extended message")?;
+                Message::Extended { id: ext, payload: payload.to_vec() }
+            }
+            other => Message::Unknown(other),
+        })
+    }
+}
+
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Message> {
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_MESSAGE {
+        bail!("peer sent a {} byte message", len);
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+    Message::decode(&body)
+}
+
+pub(crate) async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> Result<()> {
+    writer.write_all(&message.encode()).await?;
+    Ok(())
+}
+
+/// What the remote side sent in its handshake.
+pub(crate) struct Handshake {
+    pub peer_id: [u8; 20],
+    /// Supports the extension protocol.
+    pub extensions: bool,
+}
+
+/// Exchanges handshakes, refusing peers for another torrent.
+pub(crate) async fn handshake(stream: &mut TcpStream, info_hash: &InfoHash, peer_id: &[u8; 20]) -> Result<Handshake> {
+    let mut ours = vec![PROTOCOL.len() as u8];
+    ours.extend_from_slice(PROTOCOL);
+    let mut reserved = [0u8; 8];
+    reserved[5] |= 0x10;
+    ours.extend_from_slice(&reserved);
+    ours.extend_from_slice(info_hash);
+    ours.extend_from_slice(peer_id);
+
+    timeout(CONNECT_TIMEOUT, async {
+        stream.write_all(&ours).await?;
+        let mut theirs = [0u8; 68];
+        stream.read_exact(&mut theirs).await?;
+        if theirs[0] as usize != PROTOCOL.len() || &theirs[1..20] != PROTOCOL {
+            bail!("peer does not speak the BitTorrent protocol");
+        }
+        if &theirs[28..48] != info_hash {
+            bail!("peer is serving a different torrent");
+        }
+        Ok(Handshake {
+            peer_id: theirs[48..68].try_into().expect("20 bytes"),
+            extensions: theirs[25] & 0x10 != 0,
+        })
+    })
+    .await
+    .context("handshake timed out")?
+}
+
+/// Our BEP 10 handshake, advertising `ut_metadata` and, once known, the
+/// size of the info dictionary.
+pub(crate) fn extension_handshake(metadata_size: Option<usize>, port: u16) -> Message {
+    let mut dict = vec![
+        ("m", Value::dict([("ut_metadata", Value::Int(UT_METADATA_ID as i64))])),
+        ("p", Value::Int(port as i64)),
+        ("v", Value::bytes(concat!("Kitsune-DM ", env!("CARGO_PKG_VERSION")))),
+    ];
+    if let Some(size) = metadata_size {
+        dict.push(("metadata_size", Value::Int(size as i64)));
+    }
+    Message::Extended { id: 0, payload: Value::dict(dict).encode() }
+}
+
+/// The peer's `ut_metadata` id and metadata size from its BEP 10 handshake.
+pub(crate) fn parse_extension_handshake(payload: &[u8]) -> (Option<u8>, Option<usize>) {
+    let Ok(dict) = bencode::decode(payload) else {
+        return (None, None);
+    };
+    let id = dict
+        .get("m")
+        .and_then(|m| m.get("ut_metadata"))
+        .and_then(Value::as_int)
+        .filter(|&id| (1..=255).contains(&id))
+        .map(|id| id as u8);
+    let size = dict.get("metadata_size").and_then(Value::as_int).and_then(|n| usize::try_from(n).ok());
+    (id, size)
+}
+
+/// A `ut_metadata` message.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum MetadataMessage {
+    Request(usize),
+    Data { piece: usize, data: Vec<u8> },
+    Reject(usize),
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, piece) = match self {
+            MetadataMessage::Request(piece) => (0, piece),
+            MetadataMessage::Data { piece, .. } => (1, piece),
+            MetadataMessage::Reject(piece) => (2, piece),
+        };
+        let mut dict = vec![("msg_type", Value::Int(kind)), ("piece", Value::Int(*piece as i64))];
+        if let MetadataMessage::Data { data, .. } = self {
+            dict.push(("total_size", Value::Int(data.len() as i64)));
+        }
+        let mut payload = Value::dict(dict).encode();
+        if let MetadataMessage::Data { data, .. } = self {
+            payload.extend_from_slice(data);
+        }
+        payload
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let (dict, used) = bencode::decode_prefix(payload)?;
+        let piece = dict.get("piece").and_then(Value::as_int).context("ut_metadata message has no piece")? as usize;
+        Ok(match dict.get("msg_type").and_then(Value::as_int) {
+            Some(0) => MetadataMessage::Request(piece),
+            Some(1) => MetadataMessage::Data { piece, data: payload[used..].to_vec() },
+            _ => MetadataMessage::Reject(piece),
+        })
+    }
+
+    /// The answer to a request for `piece` of `metadata`.
+    pub fn answer(piece: usize, metadata: Option<&[u8]>) -> Self {
+        match metadata.and_then(|m| m.chunks(METADATA_PIECE).nth(piece)) {
+            Some(chunk) => MetadataMessage::Data { piece, data: chunk.to_vec() },
+            None => MetadataMessage::Reject(piece),
+        }
+    }
+}
+
+/// Downloads the info dictionary of `info_hash` from the peer at `addr`
+/// and checks it against the hash.
+pub(crate) async fn fetch_metadata(addr: SocketAddr, info_hash: InfoHash, peer_id: [u8; 20]) -> Result<Vec<u8>> {
+    let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await??;
+    let remote = handshake(&mut stream, &info_hash, &peer_id).await?;
+    if remote.peer_id == peer_id {
+        bail!("{} is ourselves", addr);
+    }
+    if !remote.extensions {
+        bail!("{} does not support the extension protocol", addr);
+    }
+    write_message(&mut stream, &extension_handshake(None, 0)).await?;
+
+    timeout(Duration::from_secs(30), async {
+        let mut their_id = None;
+        let mut metadata = Vec::new();
+        let mut pieces = 0;
+        loop {
+            match read_message(&mut stream).await? {
+                Message::Extended { id: 0, payload } => {
+                    let (id, size) = parse_extension_handshake(&payload);
+                    let (Some(id), Some(size)) = (id, size) else {
+                        bail!("{} cannot send metadata", addr);
+                    };
+                    if size == 0 || size > 16 * 1024 * 1024 {
+                        bail!("{} claims metadata of {} bytes", addr, size);
+                    }
+                    their_id = Some(id);
+                    pieces = size.div_ceil(METADATA_PIECE);
+                    metadata.reserve(size);
+                    for piece in 0..pieces {
+                        let request = MetadataMessage::Request(piece).encode();
+                        write_message(&mut stream, &Message::Extended { id, payload: request }).await?;
+                    }
+                }
+                Message::Extended { id: UT_METADATA_ID, payload } if their_id.is_some() => {
+                    match MetadataMessage::decode(&payload)? {
+                        MetadataMessage::Data { piece, data } if piece == metadata.len() / METADATA_PIECE => {
+                            metadata.extend_from_slice(&data);
+                            if piece + 1 == pieces {
+                                break;
+                            }
+                        }
+                        MetadataMessage::Reject(_) => bail!("{} refused to send metadata", addr),
+                        _ => bail!("{} sent metadata out of order", addr),
+                    }
+                }
+                _ => {}
+            }
+        }
+        if <[u8; 20]>::from(Sha1::digest(&metadata)) != info_hash {
+            bail!("metadata from {} does not match the info hash", addr);
+        }
+        Ok(metadata)
+    })
+    .await
+    .with_context(|| format!("timed out fetching metadata from {}", addr))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        for message in [
+            Message::Have(7),
+            Message::Request { index: 1, begin: 16384, length: 16384 },
+            Message::Piece { index: 2, begin: 0, data: b"block".to_vec() },
+            Message::Extended { id: 3, payload: MetadataMessage::Data { piece: 0, data: b"d4:name1:xe".to_vec() }.encode() },
+        ] {
+            let frame = message.encode();
+            assert_eq!(u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize, frame.len() - 4);
+            assert_eq!(Message::decode(&frame[4..]).unwrap(), message);
+        }
+
+        let Message::Extended { payload, .. } = extension_handshake(Some(1234), 6881) else { unreachable!() };
+        assert_eq!(parse_extension_handshake(&payload), (Some(UT_METADATA_ID), Some(1234)));
+        let data = MetadataMessage::decode(&MetadataMessage::Data { piece: 1, data: b"xyz".to_vec() }.encode()).unwrap();
+        assert_eq!(data, MetadataMessage::Data { piece: 1, data: b"xyz".to_vec() });
+    }
+}
@@ -0,0 +1,191 @@
+//! Maps pieces onto the torrent's files.
+//!
+//! Only selected files are created. A piece that also covers part of an
+//! unselected file still has to be downloaded whole to check its hash, so
+//! such pieces are kept complete in a `.parts` directory next to the
+//! output, from where they can be seeded and rechecked.
+
+use super::metainfo::Info;
+use crate::pieces::Bitfield;
+use sha1::{Digest, Sha1};
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub(crate) struct Storage {
+    info: Info,
+    root: PathBuf,
+    selected: Vec<bool>,
+}
+
+/// Part of a piece that lies in one file.
+struct Span {
+    file: usize,
+    /// Offset within the file.
+    file_offset: u64,
+    /// Offset within the piece.
+    piece_offset: u64,
+    len: u64,
+}
+
+impl Storage {
+    /// `root` is the file of a single-file torrent or the directory of a
+    /// multi-file one. `selected` holds file indices; `None` selects all.
+    pub fn new(info: Info, root: PathBuf, selected: Option<&[usize]>) -> Self {
+        let selected = (0..info.files.len())
+            .map(|i| selected.is_none_or(|s| s.contains(&i)))
+            .collect();
+        Self { info, root, selected }
+    }
+
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.info.pieces.len()
+    }
+
+    pub fn file_path(&self, file: usize) -> PathBuf {
+        self.info.files[file].path.iter().fold(self.root.clone(), |path, c| path.join(c))
+    }
+
+    /// Total size of the selected files.
+    pub fn selected_size(&self) -> u64 {
+        self.info.files.iter().zip(&self.selected).filter(|(_, s)| **s).map(|(f, _)| f.length).sum()
+    }
+
+    /// Pieces that overlap a selected file.
+    pub fn wanted(&self) -> Vec<bool> {
+        (0..self.piece_count())
+            .map(|piece| self.spans(piece).any(|s| self.selected[s.file]))
+            .collect()
+    }
+
+    /// Bytes of `piece` that belong to selected files.
+    pub fn selected_bytes(&self, piece: usize) -> u64 {
+        self.spans(piece).filter(|s| self.selected[s.file]).map(|s| s.len).sum()
+    }
+
+    fn spans(&self, piece: usize) -> impl Iterator<Item = Span> + '_ {
+        let start = piece as u64 * self.info.piece_length;
+        let end = start + self.info.piece_size(piece);
+        self.info
+            .files
+            .iter()
+            .enumerate()
+            .filter(move |(_, f)| f.length > 0 && f.offset < end && f.offset + f.length > start)
+            .map(move |(i, f)| {
+                let from = start.max(f.offset);
+                let to = end.min(f.offset + f.length);
+                Span { file: i, file_offset: from - f.offset, piece_offset: from - start, len: to - from }
+            })
+    }
+
+    /// Whether `piece` is kept in the parts directory.
+    fn partial(&self, piece: usize) -> bool {
+        self.spans(piece).any(|s| !self.selected[s.file])
+    }
+
+    fn parts_dir(&self) -> PathBuf {
+        parts_dir(&self.root)
+    }
+
+    /// Checks `data` against the piece hash and writes it if it matches.
+    /// Returns whether it matched.
+    pub fn store_piece(&self, piece: usize, data: &[u8]) -> io::Result<bool> {
+        if <[u8; 20]>::from(Sha1::digest(data)) != self.info.pieces[piece] {
+            return Ok(false);
+        }
+        for span in self.spans(piece).filter(|s| self.selected[s.file]) {
+            let path = self.file_path(span.file);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(&path)?;
+            file.seek(SeekFrom::Start(span.file_offset))?;
+            let from = span.piece_offset as usize;
+            file.write_all(&data[from..from + span.len as usize])?;
+        }
+        if self.partial(piece) {
+            std::fs::create_dir_all(self.parts_dir())?;
+            std::fs::write(self.parts_dir().join(piece.to_string()), data)?;
+        }
+        Ok(true)
+    }
+
+    /// Reads `len` bytes at `offset` within a piece that is on disk.
+    pub fn read(&self, piece: usize, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        if self.partial(piece) {
+            let mut file = std::fs::File::open(self.parts_dir().join(piece.to_string()))?;
+            return read_exact_at(&mut file, offset, len);
+        }
+        let mut out = Vec::with_capacity(len as usize);
+        for span in self.spans(piece) {
+            let from = span.piece_offset.max(offset);
+            let to = (span.piece_offset + span.len).min(offset + len);
+            if from >= to {
+                continue;
+            }
+            let mut file = std::fs::File::open(self.file_path(span.file))?;
+            out.extend(read_exact_at(&mut file, span.file_offset + (from - span.piece_offset), to - from)?);
+        }
+        if out.len() as u64 != len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("piece {} is shorter than requested", piece)));
+        }
+        Ok(out)
+    }
+
+    /// Hashes the wanted pieces already on disk, e.g. from an earlier
+    /// download or another client.
+    pub fn recheck(&self, wanted: &[bool]) -> Bitfield {
+        let mut have = Bitfield::new(self.piece_count());
+        for piece in (0..self.piece_count()).filter(|&p| wanted[p]) {
+            let Ok(data) = self.read(piece, 0, self.info.piece_size(piece)) else { continue };
+            if <[u8; 20]>::from(Sha1::digest(&data)) == self.info.pieces[piece] {
+                have.set(piece, true);
+            }
+        }
+        have
+    }
+}
+
+/// Scratch directory for pieces shared with unselected files.
+pub(crate) fn parts_dir(root: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.parts", root.to_string_lossy()))
+}
+
+fn read_exact_at(file: &mut std::fs::File, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; len as usize];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::metainfo::{Metainfo, build_torrent};
+
+    #[test]
+    fn skips_unselected_files_and_rechecks() {
+        let dir = crate::test_support::temp_path("torrent-storage").parent().unwrap().to_path_buf();
+        let torrent = build_torrent("set", 4, &[("a.bin", b"aaaaaa"), ("b.bin", b"bbbbbb")], None);
+        let meta = Metainfo::parse(&torrent).unwrap();
+        let storage = Storage::new(meta.info, dir.join("set"), Some(&[0]));
+
+        assert_eq!(storage.wanted(), vec![true, true, false]);
+        assert_eq!(storage.selected_bytes(1), 2);
+        assert!(!storage.store_piece(0, b"aaaX").unwrap());
+        assert!(storage.store_piece(0, b"aaaa").unwrap());
+        assert!(storage.store_piece(1, b"aabb").unwrap());
+
+        assert_eq!(std::fs::read(dir.join("set/a.bin")).unwrap(), b"aaaaaa");
+        assert!(!dir.join("set/b.bin").exists());
+        assert_eq!(storage.read(1, 1, 3).unwrap(), b"abb");
+
+        std::fs::write(dir.join("set/a.bin"), b"aaXaaa").unwrap();
+        let have = storage.recheck(&storage.wanted());
+        assert!(!have.get(0) && have.get(1));
+    }
+}
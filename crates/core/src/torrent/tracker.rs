@@ -0,0 +1,229 @@
+//! Tracker announces over HTTP (BEP 3, compact peers per BEP 23) and UDP
+//! (BEP 15).
+
+use super::bencode::{self, Value};
+use super::metainfo::InfoHash;
+use anyhow::{Context, Result, bail};
+use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
+use reqwest::Client;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+/// Used when a tracker does not say how often to announce.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+const UDP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// BEP 15 magic for connect requests.
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Event {
+    Started,
+    Completed,
+    /// Regular re-announce.
+    None,
+}
+
+pub(crate) struct Announce {
+    pub info_hash: InfoHash,
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Event,
+}
+
+#[derive(Debug)]
+pub(crate) struct AnnounceResponse {
+    pub peers: Vec<SocketAddr>,
+    pub interval: Duration,
+}
+
+pub(crate) async fn announce(client: &Client, tracker: &str, request: &Announce) -> Result<AnnounceResponse> {
+    match crate::transport::scheme(tracker).as_deref() {
+        Some("http" | "https") => announce_http(client, tracker, request).await,
+        Some("udp") => announce_udp(tracker, request).await,
+        _ => bail!("unsupported tracker {}", tracker),
+    }
+}
+
+async fn announce_http(client: &Client, tracker: &str, request: &Announce) -> Result<AnnounceResponse> {
+    let mut url = format!(
+        "{}{}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+        tracker,
+        if tracker.contains('?') { '&' } else { '?' },
+        percent_encode(&request.info_hash, NON_ALPHANUMERIC),
+        percent_encode(&request.peer_id, NON_ALPHANUMERIC),
+        request.port,
+        request.uploaded,
+        request.downloaded,
+        request.left,
+    );
+    match request.event {
+        Event::Started => url.push_str("&event=started"),
+        Event::Completed => url.push_str("&event=completed"),
+        Event::None => {}
+    }
+
+    let body = client.get(&url).send().await?.error_for_status()?.bytes().await?;
+    let reply = bencode::decode(&body).with_context(|| format!("tracker {} sent an invalid reply", tracker))?;
+    if let Some(reason) = reply.get("failure reason").and_then(Value::as_str) {
+        bail!("tracker {} refused the announce: {}", tracker, reason);
+    }
+
+    let mut peers = Vec::new();
+    match reply.get("peers") {
+        Some(Value::Bytes(compact)) => peers.extend(compact_v4(compact)),
+        Some(Value::List(list)) => {
+            for peer in list {
+                let ip = peer.get("ip").and_then(Value::as_str).and_then(|ip| ip.parse::<IpAddr>().ok());
+                let port = peer.get("port").and_then(Value::as_int).and_then(|p| u16::try_from(p).ok());
+                if let (Some(ip), Some(port)) = (ip, port) {
+                    peers.push(SocketAddr::new(ip, port));
+                }
+            }
+        }
+        _ => {}
+    }
+    if let Some(compact) = reply.get("peers6").and_then(Value::as_bytes) {
+        peers.extend(compact_v6(compact));
+    }
+
+    let interval = reply
+        .get("interval")
+        .and_then(Value::as_int)
+        .and_then(|s| u64::try_from(s).ok())
+        .map_or(DEFAULT_INTERVAL, Duration::from_secs);
+    Ok(AnnounceResponse { peers, interval })
+}
+
+async fn announce_udp(tracker: &str, request: &Announce) -> Result<AnnounceResponse> {
+    let host = tracker
+        .strip_prefix("udp://")
+        .and_then(|rest| rest.split('/').next())
+        .context("invalid UDP tracker URL")?;
+    let addr = tokio::net::lookup_host(host)
+        .await?
+        .next()
+        .with_context(|| format!("tracker {} did not resolve", host))?;
+    let local: SocketAddr = if addr.is_ipv4() { (Ipv4Addr::UNSPECIFIED, 0).into() } else { (Ipv6Addr::UNSPECIFIED, 0).into() };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+
+    let transaction = u32::from_be_bytes(super::random_bytes());
+    let mut connect = UDP_PROTOCOL_ID.to_be_bytes().to_vec();
+    connect.extend_from_slice(&0u32.to_be_bytes());
+    connect.extend_from_slice(&transaction.to_be_bytes());
+    let reply = udp_exchange(&socket, &connect, 0, transaction).await?;
+    let connection_id = reply.get(8..16).context("short UDP connect reply")?;
+
+    let event: u32 = match request.event {
+        Event::None => 0,
+        Event::Completed => 1,
+        Event::Started => 2,
+    };
+    let mut packet = connection_id.to_vec();
+    packet.extend_from_slice(&1u32.to_be_bytes());
+    packet.extend_from_slice(&transaction.to_be_bytes());
+    packet.extend_from_slice(&request.info_hash);
+    packet.extend_from_slice(&request.peer_id);
+    for n in [request.downloaded, request.left, request.uploaded] {
+        packet.extend_from_slice(&n.to_be_bytes());
+    }
+    packet.extend_from_slice(&event.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes()); // IP: use the sender's
+    packet.extend_from_slice(&super::random_bytes::<4>());
+    packet.extend_from_slice(&(-1i32).to_be_bytes()); // as many peers as the tracker likes
+    packet.extend_from_slice(&request.port.to_be_bytes());
+
+    let reply = udp_exchange(&socket, &packet, 1, transaction).await?;
+    let interval = u32::from_be_bytes(reply.get(8..12).context("short UDP announce reply")?.try_into()?);
+    let peers = if addr.is_ipv4() { compact_v4(&reply[20.min(reply.len())..]) } else { compact_v6(&reply[20.min(reply.len())..]) };
+    Ok(AnnounceResponse { peers, interval: Duration::from_secs(interval as u64) })
+}
+
+/// Sends `packet` and waits for the reply to `action`, surfacing tracker
+/// errors (action 3).
+async fn udp_exchange(socket: &UdpSocket, packet: &[u8], action: u32, transaction: u32) -> Result<Vec<u8>> {
+    socket.send(packet).await?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = timeout(UDP_TIMEOUT, socket.recv(&mut buf)).await.context("UDP tracker timed out")??;
+        let reply = &buf[..n];
+        if reply.len() < 8 || reply[4..8] != transaction.to_be_bytes() {
+            continue;
+        }
+        let kind = u32::from_be_bytes(reply[..4].try_into()?);
+        if kind == 3 {
+            bail!("UDP tracker error: {}", String::from_utf8_lossy(&reply[8..]));
+        }
+        if kind == action {
+            return Ok(reply.to_vec());
+        }
+    }
+}
+
+/// 6-byte IPv4 address and port entries.
+pub(crate) fn compact_v4(data: &[u8]) -> Vec<SocketAddr> {
+    data.chunks_exact(6)
+        .map(|c| SocketAddr::new(Ipv4Addr::new(c[0], c[1], c[2], c[3]).into(), u16::from_be_bytes([c[4], c[5]])))
+        .collect()
+}
+
+fn compact_v6(data: &[u8]) -> Vec<SocketAddr> {
+    data.chunks_exact(18)
+        .map(|c| {
+            let ip: [u8; 16] = c[..16].try_into().expect("16 bytes");
+            SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([c[16], c[17]]))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers one connect and one announce with a single peer.
+    #[tokio::test]
+    async fn announces_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker = format!("udp://{}/announce", server.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (_, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(buf[..8], UDP_PROTOCOL_ID.to_be_bytes());
+            let mut reply = 0u32.to_be_bytes().to_vec();
+            reply.extend_from_slice(&buf[12..16]);
+            reply.extend_from_slice(&42u64.to_be_bytes());
+            server.send_to(&reply, from).await.unwrap();
+
+            let (n, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 98);
+            assert_eq!(buf[..8], 42u64.to_be_bytes());
+            assert_eq!(buf[96..98], 6881u16.to_be_bytes());
+            let mut reply = 1u32.to_be_bytes().to_vec();
+            reply.extend_from_slice(&buf[12..16]);
+            reply.extend_from_slice(&900u32.to_be_bytes());
+            reply.extend_from_slice(&[0; 8]);
+            reply.extend_from_slice(&[10, 0, 0, 7, 0x1a, 0xe1]);
+            server.send_to(&reply, from).await.unwrap();
+        });
+
+        let request = Announce {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: Event::Started,
+        };
+        let response = announce(&Client::new(), &tracker, &request).await.unwrap();
+        assert_eq!(response.peers, vec!["10.0.0.7:6881".parse().unwrap()]);
+        assert_eq!(response.interval, Duration::from_secs(900));
+    }
+}
@@ -0,0 +1,360 @@
+use super::{ByteStream, Transport, TransportError};
+use crate::downloader::RemoteMetadata;
+use crate::worker::OPEN_END;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use crate::network::{Lease, Network, Route, SourceAddress};
+use reqwest::{Client, ClientBuilder, StatusCode, Url, header};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How requests to a host are spread over connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HttpStrategy {
+    /// HTTP/1.1 with a new connection for every request, so each part has
+    /// its own. Suits CDNs that throttle per connection.
+    #[default]
+    PerConnection,
+    /// HTTP/2 where the server negotiates it over TLS, kept-alive HTTP/1.1
+    /// otherwise. Parts and retries share a few pooled connections.
+    Multiplexed,
+    /// HTTP/3 over QUIC. Experimental: needs the `http3` feature built with
+    /// `RUSTFLAGS="--cfg reqwest_unstable"`.
+    Http3,
+}
+
+impl HttpStrategy {
+    /// Whether this build can use the strategy.
+    pub fn is_available(self) -> bool {
+        self != HttpStrategy::Http3 || cfg!(all(feature = "http3", reqwest_unstable))
+    }
+}
+
+impl FromStr for HttpStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "h1" | "http1" | "per-connection" => Ok(HttpStrategy::PerConnection),
+            "h2" | "http2" | "multiplexed" => Ok(HttpStrategy::Multiplexed),
+            "h3" | "http3" | "quic" => Ok(HttpStrategy::Http3),
+            other => anyhow::bail!("invalid HTTP strategy {:?}: expected h1, h2 or h3", other),
+        }
+    }
+}
+
+impl fmt::Display for HttpStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HttpStrategy::PerConnection => "h1",
+            HttpStrategy::Multiplexed => "h2",
+            HttpStrategy::Http3 => "h3",
+        })
+    }
+}
+
+/// Default [`HttpStrategy`] and per-host overrides.
+#[derive(Debug, Clone, Default)]
+pub struct HttpConfig {
+    pub default: HttpStrategy,
+    /// Keyed by lowercase host name; `*.example.com` also covers every
+    /// subdomain. Exact names win over wildcards.
+    pub hosts: HashMap<String, HttpStrategy>,
+}
+
+impl HttpConfig {
+    /// Adds a `host=strategy` rule, e.g. `*.cdn.example=h2`.
+    pub fn add_rule(&mut self, rule: &str) -> Result<()> {
+        let (host, strategy) = rule
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("invalid host rule {:?}: expected host=strategy", rule))?;
+        self.hosts.insert(host.trim().to_ascii_lowercase(), strategy.parse()?);
+        Ok(())
+    }
+
+    pub fn strategy_for(&self, url: &str) -> HttpStrategy {
+        let Some(host) = Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_ascii_lowercase)) else {
+            return self.default;
+        };
+        if let Some(&strategy) = self.hosts.get(&host) {
+            return strategy;
+        }
+        // Most specific wildcard first
+        let mut suffix = host.as_str();
+        while let Some((_, parent)) = suffix.split_once('.') {
+            if let Some(&strategy) = self.hosts.get(&format!("*.{}", parent)) {
+                return strategy;
+            }
+            suffix = parent;
+        }
+        self.default
+    }
+
+    fn uses(&self, strategy: HttpStrategy) -> bool {
+        self.default == strategy || self.hosts.values().any(|&s| s == strategy)
+    }
+}
+
+/// HTTP(S) through shared `reqwest` clients, one per [`HttpStrategy`] and
+/// network route.
+#[derive(Clone)]
+pub struct HttpTransport {
+    user_agent: String,
+    config: HttpConfig,
+    network: Network,
+    /// Parallel to `network.routes()`.
+    routes: Vec<Clients>,
+}
+
+#[derive(Clone)]
+struct Clients {
+    per_connection: Client,
+    multiplexed: Client,
+    /// Only built when the config asks for HTTP/3.
+    http3: Option<Client>,
+}
+
+impl HttpTransport {
+    pub fn new(user_agent: &str, config: HttpConfig) -> Result<Self> {
+        Self::build(user_agent, config, Network::default())
+    }
+
+    fn build(user_agent: &str, config: HttpConfig, network: Network) -> Result<Self> {
+        let routes = network
+            .routes()
+            .iter()
+            .map(|route| -> Result<Clients> {
+                let builder = || bind(Client::builder().user_agent(user_agent).timeout(REQUEST_TIMEOUT), route);
+                Ok(Clients {
+                    per_connection: builder()?.http1_only().pool_max_idle_per_host(0).build()?,
+                    multiplexed: builder()?.http2_adaptive_window(true).build()?,
+                    http3: if config.uses(HttpStrategy::Http3) { Some(http3(builder()?)?) } else { None },
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { user_agent: user_agent.to_string(), config, network, routes })
+    }
+
+    /// Same network under a different config.
+    pub fn with_config(self, config: HttpConfig) -> Result<Self> {
+        Self::build(&self.user_agent, config, self.network)
+    }
+
+    pub(crate) fn with_network(self, network: Network) -> Result<Self> {
+        Self::build(&self.user_agent, self.config, network)
+    }
+
+    /// The HTTP/1.1 client without pooling on the first route, for one-off
+    /// requests.
+    pub fn client(&self) -> &Client {
+        &self.routes[0].per_connection
+    }
+
+    pub fn config(&self) -> &HttpConfig {
+        &self.config
+    }
+
+    /// A client for `url` on the least busy route, which stays counted as
+    /// busy until the lease is dropped.
+    fn client_for(&self, url: &str) -> (&Client, Lease) {
+        let lease = self.network.lease();
+        let clients = &self.routes[lease.index];
+        let client = match self.config.strategy_for(url) {
+            HttpStrategy::PerConnection => &clients.per_connection,
+            HttpStrategy::Multiplexed => &clients.multiplexed,
+            HttpStrategy::Http3 => clients.http3.as_ref().expect("built with the config"),
+        };
+        (client, lease)
+    }
+}
+
+/// Applies a route's source address and resolver to `builder`.
+fn bind(builder: ClientBuilder, route: &Route) -> Result<ClientBuilder> {
+    let builder = builder.dns_resolver(Arc::new(route.resolver.clone()));
+    Ok(match &route.source {
+        None => builder,
+        Some(SourceAddress::Ip(ip)) => builder.local_address(*ip),
+        #[cfg(any(
+            target_os = "android",
+            target_os = "fuchsia",
+            target_os = "illumos",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "solaris",
+            target_os = "tvos",
+            target_os = "visionos",
+            target_os = "watchos",
+        ))]
+        Some(SourceAddress::Interface(name)) => builder.interface(name),
+        #[cfg(not(any(
+            target_os = "android",
+            target_os = "fuchsia",
+            target_os = "illumos",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "solaris",
+            target_os = "tvos",
+            target_os = "visionos",
+            target_os = "watchos",
+        )))]
+        Some(SourceAddress::Interface(name)) => anyhow::bail!("binding to interface {} is not supported on this platform", name),
+    })
+}
+
+#[cfg(all(feature = "http3", reqwest_unstable))]
+fn http3(builder: ClientBuilder) -> Result<Client> {
+    Ok(builder.tls_backend_rustls().http3_prior_knowledge().build()?)
+}
+
+#[cfg(not(all(feature = "http3", reqwest_unstable)))]
+fn http3(_builder: ClientBuilder) -> Result<Client> {
+    anyhow::bail!("HTTP/3 is not compiled in; build with --features http3 and RUSTFLAGS=\"--cfg reqwest_unstable\"")
+}
+
+#[async_trait]
+impl Transport for HttpTransport {
+    async fn probe(&self, url: &str) -> Result<RemoteMetadata> {
+        let (client, _lease) = self.client_for(url);
+        let response = client
+            .get(url)
+            .header(header::RANGE, "bytes=0-0")
+            .send()
+            .await?
+            .error_for_status()?;
+        let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+        let headers = response.headers();
+
+        let total_size = headers
+            .get(header::CONTENT_RANGE)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.split('/').next_back())
+            .and_then(|val| val.parse::<u64>().ok())
+            .or_else(|| {
+                headers
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|val| val.to_str().ok())
+                    .and_then(|val| val.parse::<u64>().ok())
+            });
+
+        let accept_ranges = headers.get(header::CONTENT_RANGE).is_some()
+            || headers
+                .get(header::ACCEPT_RANGES)
+                .and_then(|val| val.to_str().ok())
+                .map(|val| val == "bytes")
+                .unwrap_or(false);
+
+        let content_disposition = headers
+            .get(header::CONTENT_DISPOSITION)
+            .and_then(|val| val.to_str().ok());
+
+        let filename_from_header = content_disposition
+            .and_then(|cd| {
+                if let Some(idx) = cd.find("filename=") {
+                    let remaining = &cd[idx + 9..];
+                    let end_idx = remaining.find(';').unwrap_or(remaining.len());
+                    let raw_name = &remaining[..end_idx];
+                    Some(raw_name.trim_matches('"').to_string())
+                } else {
+                    None
+                }
+            });
+
+        let filename = filename_from_header.unwrap_or_else(|| super::filename_from_url(url));
+
+        let etag = headers
+            .get(header::ETAG)
+            .and_then(|val| val.to_str().ok())
+            .map(|val| val.trim_start_matches("W/").trim_matches('"').to_string());
+
+        Ok(RemoteMetadata {
+            filename,
+            total_size,
+            accept_ranges,
+            digests: crate::integrity::advertised_digests(headers, partial),
+            etag,
+            mirrors: crate::mirrors::duplicate_links(headers, response.url().as_str()),
+            multipart_etag: None,
+        })
+    }
+
+    async fn read_range(&self, url: &str, start: u64, end: u64) -> Result<ByteStream, TransportError> {
+        let range = if end == OPEN_END {
+            format!("bytes={}-", start)
+        } else {
+            format!("bytes={}-{}", start, end)
+        };
+
+        let (client, lease) = self.client_for(url);
+        let response = client
+            .get(url)
+            .header(header::RANGE, range)
+            .send()
+            .await
+            .map_err(|e| TransportError::Transient(e.into()))?;
+
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+            return Err(TransportError::Transient(anyhow::anyhow!("server answered {}", status)));
+        }
+        let response = response.error_for_status().map_err(|e| TransportError::Fatal(e.into()))?;
+        // A full body is only usable if we asked for it from the start
+        if response.status() != StatusCode::PARTIAL_CONTENT && start > 0 {
+            return Err(TransportError::RangeIgnored);
+        }
+
+        // The route counts as busy until the worker drops the body
+        let body = response
+            .bytes_stream()
+            .map_err(std::io::Error::other)
+            .map(move |chunk| {
+                let _ = &lease;
+                chunk
+            })
+            .boxed();
+        Ok(super::limit(body, start, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_strategy_by_host() {
+        let mut config = HttpConfig::default();
+        config.add_rule("*.cdn.example=h2").unwrap();
+        config.add_rule("slow.cdn.example=h1").unwrap();
+        config.add_rule("Fast.Example=multiplexed").unwrap();
+        assert!(config.add_rule("cdn.example").is_err());
+        assert!(config.add_rule("cdn.example=spdy").is_err());
+
+        assert_eq!(config.strategy_for("https://a.b.cdn.example/f"), HttpStrategy::Multiplexed);
+        assert_eq!(config.strategy_for("https://slow.cdn.example/f"), HttpStrategy::PerConnection);
+        assert_eq!(config.strategy_for("https://cdn.example/f"), HttpStrategy::PerConnection);
+        assert_eq!(config.strategy_for("http://FAST.example:8080/f"), HttpStrategy::Multiplexed);
+        assert_eq!(config.strategy_for("not a url"), HttpStrategy::PerConnection);
+    }
+
+    #[tokio::test]
+    async fn downloads_with_a_multiplexed_client() {
+        let data = crate::test_support::payload(300_000);
+        let server = crate::test_support::serve([("/f".to_string(), data.clone())].into(), HashMap::new(), None).await;
+        let mut config = HttpConfig::default();
+        config.add_rule("127.0.0.1=h2").unwrap();
+        let downloader = crate::Downloader::new("test").unwrap().with_http_config(config).unwrap();
+        let output = crate::test_support::temp_path("multiplexed.bin");
+
+        let mut session = downloader.init_download(&server.url("/f"), Some(output.clone()), 4).await.unwrap();
+        downloader.run(&mut session, None, None, None).await.unwrap();
+        assert_eq!(tokio::fs::read(&output).await.unwrap(), data);
+        let _ = tokio::fs::remove_file(&output).await;
+    }
+}
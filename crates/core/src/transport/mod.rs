@@ -0,0 +1,130 @@
+//! Protocol backends behind a common interface: probing a resource and
+//! streaming byte ranges of it.
+
+pub mod ftp;
+pub mod http;
+pub mod s3;
+pub mod sftp;
+
+use crate::downloader::RemoteMetadata;
+use crate::worker::OPEN_END;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+
+pub use ftp::FtpTransport;
+pub use http::{HttpConfig, HttpStrategy, HttpTransport};
+pub use s3::{S3Config, S3Transport};
+pub use sftp::SftpTransport;
+
+/// Body of a ranged read.
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    /// Worth retrying after a backoff: dropped connections, HTTP 429/503,
+    /// FTP 4xx replies.
+    #[error(transparent)]
+    Transient(anyhow::Error),
+    /// Retrying will not help, e.g. a missing file or rejected login.
+    #[error(transparent)]
+    Fatal(anyhow::Error),
+    #[error("server does not support resuming at an offset")]
+    RangeIgnored,
+}
+
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Size, name and range support of the resource at `url`.
+    async fn probe(&self, url: &str) -> anyhow::Result<RemoteMetadata>;
+
+    /// Streams bytes `start..=end` of `url`. `end` may be [`OPEN_END`] to
+    /// read until the server closes the body.
+    async fn read_range(&self, url: &str, start: u64, end: u64) -> Result<ByteStream, TransportError>;
+}
+
+/// URL schemes that have a transport.
+pub const SUPPORTED_SCHEMES: &[&str] = &["http", "https", "ftp", "ftps", "ftpes", "sftp", "s3"];
+
+/// Lowercased scheme of `url`, if it has one.
+pub fn scheme(url: &str) -> Option<String> {
+    let (scheme, _) = url.split_once("://")?;
+    Some(scheme.to_ascii_lowercase())
+}
+
+/// True if `url` uses a scheme some transport can download.
+pub fn is_supported(url: &str) -> bool {
+    scheme(url).is_some_and(|s| SUPPORTED_SCHEMES.contains(&s.as_str()))
+}
+
+/// Last path segment of `url`, or `download.bin`.
+pub(crate) fn filename_from_url(url: &str) -> String {
+    url.split(['?', '#'])
+        .next()
+        .unwrap_or(url)
+        .split('/')
+        .next_back()
+        .filter(|s| !s.is_empty())
+        .unwrap_or("download.bin")
+        .to_string()
+}
+
+/// Cuts `stream` off after the byte at `end`, for servers that can only
+/// be told where to start.
+pub(crate) fn limit(stream: ByteStream, start: u64, end: u64) -> ByteStream {
+    if end == OPEN_END {
+        return stream;
+    }
+    let len = end.saturating_sub(start) + 1;
+    stream
+        .scan(len, |remaining, chunk| {
+            let item = match chunk {
+                Ok(_) if *remaining == 0 => None,
+                Ok(mut bytes) => {
+                    if bytes.len() as u64 > *remaining {
+                        bytes.truncate(*remaining as usize);
+                    }
+                    *remaining -= bytes.len() as u64;
+                    Some(Ok(bytes))
+                }
+                Err(e) => Some(Err(e)),
+            };
+            futures::future::ready(item)
+        })
+        .boxed()
+}
+
+/// Reads a whole stream into memory, failing once it exceeds `max` bytes.
+pub(crate) async fn collect(mut stream: ByteStream, max: usize) -> anyhow::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > max {
+            anyhow::bail!("response larger than {} bytes", max);
+        }
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn limit_stops_at_the_range_end() {
+        let chunks = vec![Ok(Bytes::from_static(b"abcd")), Ok(Bytes::from_static(b"efgh")), Ok(Bytes::from_static(b"ij"))];
+        let stream = futures::stream::iter(chunks).boxed();
+        let body = collect(limit(stream, 10, 15), 1024).await.unwrap();
+        assert_eq!(body, b"abcdef");
+    }
+
+    #[test]
+    fn recognises_schemes() {
+        assert!(is_supported("FTP://mirror.example/pub/x.iso"));
+        assert!(is_supported("ftpes://vendor.example/drop.zip"));
+        assert!(is_supported("sftp://ci@build.example/out/app.tar.gz"));
+        assert!(!is_supported("rsync://mirror.example/x.iso"));
+        assert_eq!(filename_from_url("ftp://h/pub/x.iso?type=i"), "x.iso");
+    }
+}
@@ -12,3 +12,22 @@ pub fn get_downloads_dir() -> PathBuf {
     // Fallback to current directory if Downloads doesn't exist or on other OS
     std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."))
 }
+
+pub fn get_state_dir() -> PathBuf {
+    // Follow the XDG base directory spec on Linux and friends
+    if let Ok(state) = std::env::var("XDG_STATE_HOME")
+        && !state.is_empty()
+    {
+        return PathBuf::from(state).join("kitsune-dm");
+    }
+
+    if let Ok(local) = std::env::var("LOCALAPPDATA") {
+        return PathBuf::from(local).join("kitsune-dm").join("state");
+    }
+
+    if let Ok(home) = std::env::var("HOME") {
+        return PathBuf::from(home).join(".local").join("state").join("kitsune-dm");
+    }
+
+    std::env::temp_dir().join("kitsune-dm")
+}
@@ -1,6 +1,7 @@
+use crate::schedule::RateLimiter;
+use crate::transport::{Transport, TransportError};
 use anyhow::Result;
-use reqwest::header::RANGE;
-use reqwest::Client;
+use futures::StreamExt;
 use std::path::PathBuf;
 use tokio::fs::OpenOptions;
 use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
@@ -9,23 +10,29 @@ use std::sync::Arc;
 use tokio::sync::mpsc;
 
 pub struct Worker {
-    pub id: u8,
+    pub id: u32,
     url: String,
     range: (u64, u64),
     output_path: PathBuf,
-    client: Client,
-    progress_tx: mpsc::Sender<(u8, u64, u8)>, // (worker_id, bytes_written, status: 0=progress 1=complete 2=error)
+    transport: Arc<dyn Transport>,
+    progress_tx: mpsc::Sender<(u32, u64, u8)>, // (worker_id, bytes_written, status: 0=progress 1=complete 2=error)
     end_byte_atomic: Option<Arc<AtomicU64>>,
+    /// File offset that `range.0` is written to.
+    file_start: u64,
+    limiter: Option<Arc<RateLimiter>>,
 }
 
+/// Range end for a worker that reads until the server closes the body.
+pub const OPEN_END: u64 = u64::MAX;
+
 impl Worker {
     pub fn new(
-        id: u8,
+        id: u32,
         url: String,
         range: (u64, u64),
         output_path: PathBuf,
-        client: Client,
-        progress_tx: mpsc::Sender<(u8, u64, u8)>,
+        transport: Arc<dyn Transport>,
+        progress_tx: mpsc::Sender<(u32, u64, u8)>,
         end_byte_atomic: Option<Arc<AtomicU64>>,
     ) -> Self {
         Self {
@@ -33,12 +40,27 @@ impl Worker {
             url,
             range,
             output_path,
-            client,
+            transport,
             progress_tx,
             end_byte_atomic,
+            file_start: range.0,
+            limiter: None,
         }
     }
 
+    /// Writes the range starting at `offset` in the output file instead of
+    /// at the same offset as in the remote resource.
+    pub fn writing_at(mut self, offset: u64) -> Self {
+        self.file_start = offset;
+        self
+    }
+
+    /// Waits for `limiter` before writing each chunk.
+    pub fn limited_by(mut self, limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
     pub async fn run(self) -> Result<()> {
         let tx = self.progress_tx.clone();
         let id = self.id;
@@ -72,76 +94,71 @@ impl Worker {
                 return Err(anyhow::anyhow!("Worker {} failed after {} retries", self.id, retries));
             }
 
-            let range_header = format!("bytes={}-{}", current_pos, self.range.1);
-
-            let response_result = self.client
-                .get(&self.url)
-                .header(RANGE, range_header.clone())
-                .send()
-                .await;
-
-            match response_result {
-                Ok(response) => {
-                    let status = response.status();
-                    if status.is_success() {
-                        let mut run_response = response;
-                        let mut file = OpenOptions::new()
-                            .write(true)
-                            .create(false) 
-                            .open(&self.output_path)
-                            .await?;
+            match self.transport.read_range(&self.url, current_pos, self.range.1).await {
+                Ok(mut body) => {
+                    let mut file = OpenOptions::new()
+                        .write(true)
+                        .create(false) 
+                        .open(&self.output_path)
+                        .await?;
                 
-                        file.seek(SeekFrom::Start(current_pos)).await?;
+                    file.seek(SeekFrom::Start(current_pos - self.range.0 + self.file_start)).await?;
                 
-                        let mut stream_error: Option<anyhow::Error> = None;
-                        
-                        loop {
-                            match run_response.chunk().await {
-                                Ok(Some(chunk)) => {
-                                    let len = chunk.len() as u64;
-                                    
-                                    if let Some(atomic_end) = &self.end_byte_atomic {
-                                        let actual_end = atomic_end.load(Ordering::Relaxed);
-                                        if current_pos >= actual_end {
-                                            break; // Done (split or completed range)
-                                        }
-                                    }
+                    let mut stream_error: Option<anyhow::Error> = None;
+                    
+                    loop {
+                        match body.next().await {
+                            Some(Ok(chunk)) => {
+                                let len = chunk.len() as u64;
                                 
-                                    if let Err(e) = file.write_all(&chunk).await {
-                                        stream_error = Some(e.into());
-                                        break;
-                                    }
-                                    
-                                    current_pos += len;
-                                    
-                                    if self.progress_tx.send((self.id, len, 0)).await.is_err() {
-                                        return Ok(()); // Receiver dropped
+                                if let Some(atomic_end) = &self.end_byte_atomic {
+                                    let actual_end = atomic_end.load(Ordering::Relaxed);
+                                    if current_pos >= actual_end {
+                                        break; // Done (split or completed range)
                                     }
                                 }
-                                Ok(None) => break, // EOF, success
-                                Err(e) => {
+
+                                if let Some(limiter) = &self.limiter {
+                                    limiter.acquire(len).await;
+                                }
+                            
+                                // Flush so the bytes are handed to the OS before they are
+                                // reported; checkpoints rely on reported offsets being on file.
+                                if let Err(e) = file.write_all(&chunk).await {
                                     stream_error = Some(e.into());
                                     break;
                                 }
+                                if let Err(e) = file.flush().await {
+                                    stream_error = Some(e.into());
+                                    break;
+                                }
+                                
+                                current_pos += len;
+                                
+                                if self.progress_tx.send((self.id, len, 0)).await.is_err() {
+                                    return Ok(()); // Receiver dropped
+                                }
+                            }
+                            None => break, // EOF, success
+                            Some(Err(e)) => {
+                                stream_error = Some(e.into());
+                                break;
                             }
                         }
-                        
-                        if let Some(e) = stream_error {
-                            log::warn!("Worker {} stream error: {}. Retrying...", self.id, e);
-                        } else {
-                            // Success!
-                            let _ = self.progress_tx.send((self.id, 0, 1)).await;
-                            return Ok(());
-                        }
-                    } else if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status == reqwest::StatusCode::SERVICE_UNAVAILABLE {
-                        log::warn!("Worker {} received {}. Retrying...", self.id, status);
+                    }
+                    
+                    if let Some(e) = stream_error {
+                        log::warn!("Worker {} stream error: {}. Retrying...", self.id, e);
                     } else {
-                        response.error_for_status()?;
+                        // Success!
+                        let _ = self.progress_tx.send((self.id, 0, 1)).await;
+                        return Ok(());
                     }
                 },
-                Err(e) => {
-                    log::warn!("Worker {} connection failed: {}. Retrying...", self.id, e);
+                Err(TransportError::Transient(e)) => {
+                    log::warn!("Worker {} request failed: {}. Retrying...", self.id, e);
                 },
+                Err(e) => return Err(e.into()),
             }
 
             log::info!("Worker {} sleeping for {:?} before retry {}", self.id, backoff, retries + 1);
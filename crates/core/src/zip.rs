@@ -0,0 +1,562 @@
+//! Remote ZIP archives: list the entries of an archive and extract a
+//! single one without downloading the rest.
+//!
+//! The end-of-central-directory record and the central directory are read
+//! with range requests. Extracting an entry fetches only its compressed
+//! bytes, through the usual parts and workers, into `<output>.zip-data`
+//! and inflates that into the output. ZIP64 archives are supported;
+//! split archives and encrypted entries are not.
+
+use super::downloader::Downloader;
+use super::session::{DownloadSession, DownloadState};
+use anyhow::{Context, Result};
+use flate2::write::DeflateDecoder;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const EOCD_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const ZIP64_EOCD_SIGNATURE: u32 = 0x0606_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+
+const EOCD_LEN: usize = 22;
+const ZIP64_LOCATOR_LEN: usize = 20;
+const ZIP64_EOCD_LEN: usize = 56;
+const CENTRAL_HEADER_LEN: usize = 46;
+const LOCAL_HEADER_LEN: usize = 30;
+
+/// The EOCD record sits within the last 64 KiB comment plus the record
+/// itself; the ZIP64 locator comes right before it.
+const TAIL_LEN: u64 = (EOCD_LEN + u16::MAX as usize + ZIP64_LOCATOR_LEN) as u64;
+
+/// Refuse central directories larger than this rather than buffer them.
+const MAX_CENTRAL_DIRECTORY: u64 = 256 * 1024 * 1024;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// True if `url` looks like a ZIP archive.
+pub fn is_zip_source(url: &str) -> bool {
+    url.split(['?', '#']).next().unwrap_or(url).to_ascii_lowercase().ends_with(".zip")
+}
+
+/// One file or directory in an archive's central directory.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ZipEntry {
+    /// Path inside the archive, `/`-separated; directories end in `/`.
+    pub name: String,
+    pub size: u64,
+    pub compressed_size: u64,
+    /// 0 is stored, 8 is deflate; others cannot be extracted.
+    pub method: u16,
+    pub crc32: u32,
+    /// Offset of the entry's local header in the archive.
+    pub header_offset: u64,
+    pub encrypted: bool,
+}
+
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    /// Last component of `name`, used as the default output file name.
+    pub fn file_name(&self) -> &str {
+        self.name.trim_end_matches('/').rsplit('/').next().unwrap_or(&self.name)
+    }
+
+    fn check_extractable(&self) -> Result<()> {
+        if self.is_dir() {
+            anyhow::bail!("{} is a directory", self.name);
+        }
+        if self.encrypted {
+            anyhow::bail!("{} is encrypted", self.name);
+        }
+        if !matches!(self.method, METHOD_STORED | METHOD_DEFLATED) {
+            anyhow::bail!("{} uses compression method {}; only stored and deflate are supported", self.name, self.method);
+        }
+        Ok(())
+    }
+}
+
+/// The entry a session extracts.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ZipEntryJob {
+    pub entry: ZipEntry,
+}
+
+/// Little-endian reader over a byte slice.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len).context("ZIP record is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().expect("two bytes")))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().expect("four bytes")))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().expect("eight bytes")))
+    }
+}
+
+/// Where the central directory is, from the (ZIP64) end records.
+#[derive(Debug, PartialEq, Eq)]
+struct Directory {
+    offset: u64,
+    size: u64,
+    entries: u64,
+}
+
+/// Finds the EOCD record in `tail`, the last bytes of the archive. Returns
+/// its offset in `tail` and the directory it describes.
+fn find_eocd(tail: &[u8]) -> Result<(usize, Directory, bool)> {
+    let position = (0..=tail.len().saturating_sub(EOCD_LEN))
+        .rev()
+        .find(|&i| {
+            // The comment length must reach exactly to the end of the file,
+            // so a signature inside the comment is not mistaken for the record
+            tail[i..i + 4] == EOCD_SIGNATURE.to_le_bytes()
+                && i + EOCD_LEN + u16::from_le_bytes([tail[i + 20], tail[i + 21]]) as usize == tail.len()
+        })
+        .context("no end of central directory record; not a ZIP archive")?;
+
+    let mut r = Reader::new(&tail[position + 4..]);
+    let disk = r.u16()?;
+    let directory_disk = r.u16()?;
+    let _entries_on_disk = r.u16()?;
+    let entries = r.u16()?;
+    let size = r.u32()?;
+    let offset = r.u32()?;
+    if disk != 0 && disk != u16::MAX || directory_disk != 0 && directory_disk != u16::MAX {
+        anyhow::bail!("split ZIP archives are not supported");
+    }
+    // Any saturated field means the real value is in the ZIP64 record
+    let zip64 = entries == u16::MAX || size == u32::MAX || offset == u32::MAX;
+    Ok((position, Directory { offset: offset as u64, size: size as u64, entries: entries as u64 }, zip64))
+}
+
+/// Reads the ZIP64 locator just before the EOCD record; returns the offset
+/// of the ZIP64 EOCD record if there is one.
+fn zip64_locator(tail: &[u8], eocd: usize) -> Result<Option<u64>> {
+    let Some(start) = eocd.checked_sub(ZIP64_LOCATOR_LEN) else {
+        return Ok(None);
+    };
+    let mut r = Reader::new(&tail[start..eocd]);
+    if r.u32()? != ZIP64_LOCATOR_SIGNATURE {
+        return Ok(None);
+    }
+    let _disk = r.u32()?;
+    let offset = r.u64()?;
+    if r.u32()? > 1 {
+        anyhow::bail!("split ZIP archives are not supported");
+    }
+    Ok(Some(offset))
+}
+
+fn parse_zip64_eocd(record: &[u8]) -> Result<Directory> {
+    let mut r = Reader::new(record);
+    if r.u32()? != ZIP64_EOCD_SIGNATURE {
+        anyhow::bail!("ZIP64 end of central directory record not found where the locator points");
+    }
+    let _record_size = r.u64()?;
+    let _version_made = r.u16()?;
+    let _version_needed = r.u16()?;
+    let _disk = r.u32()?;
+    let _directory_disk = r.u32()?;
+    let _entries_on_disk = r.u64()?;
+    let entries = r.u64()?;
+    let size = r.u64()?;
+    let offset = r.u64()?;
+    Ok(Directory { offset, size, entries })
+}
+
+/// Parses `count` central directory headers.
+fn parse_central_directory(data: &[u8], count: u64) -> Result<Vec<ZipEntry>> {
+    let mut r = Reader::new(data);
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        if r.u32()? != CENTRAL_HEADER_SIGNATURE {
+            anyhow::bail!("corrupt central directory at entry {}", entries.len());
+        }
+        let mut header = Reader::new(r.bytes(CENTRAL_HEADER_LEN - 4)?);
+        let _version_made = header.u16()?;
+        let _version_needed = header.u16()?;
+        let flags = header.u16()?;
+        let method = header.u16()?;
+        let _time = header.u16()?;
+        let _date = header.u16()?;
+        let crc32 = header.u32()?;
+        let mut compressed_size = header.u32()? as u64;
+        let mut size = header.u32()? as u64;
+        let name_len = header.u16()? as usize;
+        let extra_len = header.u16()? as usize;
+        let comment_len = header.u16()? as usize;
+        let _disk = header.u16()?;
+        let _internal = header.u16()?;
+        let _external = header.u32()?;
+        let mut header_offset = header.u32()? as u64;
+
+        // Bit 11 marks UTF-8 names; older archives use CP437, which is
+        // ASCII-compatible for the names that matter in practice
+        let name = String::from_utf8_lossy(r.bytes(name_len)?).into_owned();
+        let mut extra = Reader::new(r.bytes(extra_len)?);
+        r.bytes(comment_len)?;
+
+        // The ZIP64 extra field holds, in order, only the saturated values
+        while extra.pos + 4 <= extra.data.len() {
+            let id = extra.u16()?;
+            let len = extra.u16()? as usize;
+            let field = extra.bytes(len)?;
+            if id != 0x0001 {
+                continue;
+            }
+            let mut values = Reader::new(field);
+            if size == u32::MAX as u64 {
+                size = values.u64()?;
+            }
+            if compressed_size == u32::MAX as u64 {
+                compressed_size = values.u64()?;
+            }
+            if header_offset == u32::MAX as u64 {
+                header_offset = values.u64()?;
+            }
+        }
+
+        entries.push(ZipEntry { name, size, compressed_size, method, crc32, header_offset, encrypted: flags & 1 != 0 });
+    }
+    Ok(entries)
+}
+
+impl Downloader {
+    /// Lists the entries of the ZIP archive at `url` using range requests
+    /// for its end records and central directory.
+    pub async fn list_zip(&self, url: &str) -> Result<Vec<ZipEntry>> {
+        let meta = self.get_remote_metadata(url).await?;
+        let size = meta.total_size.with_context(|| format!("{} does not report its size", url))?;
+        if !meta.accept_ranges {
+            anyhow::bail!("{} does not serve byte ranges, which browsing an archive needs", url);
+        }
+        if size < EOCD_LEN as u64 {
+            anyhow::bail!("{} is too small to be a ZIP archive", url);
+        }
+
+        let tail_start = size.saturating_sub(TAIL_LEN);
+        let tail = self.read_exact(url, tail_start, size - 1).await?;
+        let (eocd, mut directory, zip64) = find_eocd(&tail)?;
+        if let Some(record) = zip64_locator(&tail, eocd)? {
+            let end = record + ZIP64_EOCD_LEN as u64 - 1;
+            directory = if record >= tail_start {
+                let start = (record - tail_start) as usize;
+                parse_zip64_eocd(tail.get(start..).context("ZIP64 record offset is out of range")?)?
+            } else {
+                parse_zip64_eocd(&self.read_exact(url, record, end).await?)?
+            };
+        } else if zip64 {
+            anyhow::bail!("archive needs ZIP64 but has no ZIP64 locator");
+        }
+
+        if directory.size > MAX_CENTRAL_DIRECTORY {
+            anyhow::bail!("central directory of {} bytes is too large", directory.size);
+        }
+        if directory.offset + directory.size > size {
+            anyhow::bail!("central directory lies beyond the end of the archive");
+        }
+        if directory.entries == 0 {
+            return Ok(Vec::new());
+        }
+        let data = if directory.offset >= tail_start {
+            let start = (directory.offset - tail_start) as usize;
+            tail[start..start + directory.size as usize].to_vec()
+        } else {
+            self.read_exact(url, directory.offset, directory.offset + directory.size - 1).await?
+        };
+        parse_central_directory(&data, directory.entries)
+    }
+
+    /// Creates a session that downloads only `entry`'s compressed bytes
+    /// from the archive at `url` and inflates them into `output_path`
+    /// (default: the entry's file name in the downloads directory).
+    pub async fn init_zip_entry(
+        &self,
+        url: &str,
+        entry: &ZipEntry,
+        output_path: Option<PathBuf>,
+        connections: u8,
+    ) -> Result<DownloadSession> {
+        entry.check_extractable()?;
+
+        // The local header's extra field may differ from the central one
+        let header = self.read_exact(url, entry.header_offset, entry.header_offset + LOCAL_HEADER_LEN as u64 - 1).await?;
+        let mut r = Reader::new(&header);
+        if r.u32()? != LOCAL_HEADER_SIGNATURE {
+            anyhow::bail!("no local header for {} at offset {}", entry.name, entry.header_offset);
+        }
+        r.bytes(22)?;
+        let name_len = r.u16()? as u64;
+        let extra_len = r.u16()? as u64;
+        let data_start = entry.header_offset + LOCAL_HEADER_LEN as u64 + name_len + extra_len;
+
+        let output_path = output_path.unwrap_or_else(|| crate::utils::fs::get_downloads_dir().join(entry.file_name()));
+        // Tiny entries get no more parts than bytes
+        let connections = connections.clamp(1, entry.compressed_size.clamp(1, u8::MAX as u64) as u8);
+        let mut session = DownloadSession::new(url.to_string(), output_path, connections);
+        session.remote_offset = data_start;
+        session.total_size = Some(entry.compressed_size);
+        session.zip_entry = Some(ZipEntryJob { entry: entry.clone() });
+        if entry.compressed_size > 0 {
+            Self::plan_parts(&mut session, true);
+        }
+        session.state = DownloadState::Downloading;
+        Ok(session)
+    }
+
+    async fn read_exact(&self, url: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+        let data = self
+            .fetch_range(url, start, end)
+            .await?
+            .with_context(|| format!("{} ignored a range request", url))?;
+        if data.len() as u64 != end - start + 1 {
+            anyhow::bail!("short read from {}: {} of {} bytes", url, data.len(), end - start + 1);
+        }
+        Ok(data)
+    }
+}
+
+/// Checksums everything written through it.
+struct CrcWriter<W> {
+    inner: W,
+    crc: crc32fast::Hasher,
+    written: u64,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Inflates `data` into `output`; returns the CRC-32 and length written.
+fn inflate(data: &Path, output: &Path, method: u16) -> io::Result<(u32, u64)> {
+    let mut source = File::open(data)?;
+    let sink = CrcWriter { inner: BufWriter::new(File::create(output)?), crc: crc32fast::Hasher::new(), written: 0 };
+    let mut sink = match method {
+        METHOD_DEFLATED => {
+            let mut decoder = DeflateDecoder::new(sink);
+            io::copy(&mut source, &mut decoder)?;
+            decoder.finish()?
+        }
+        _ => {
+            let mut sink = sink;
+            io::copy(&mut source, &mut sink)?;
+            sink
+        }
+    };
+    sink.flush()?;
+    sink.inner.get_ref().sync_all()?;
+    Ok((sink.crc.finalize(), sink.written))
+}
+
+/// Inflates a finished ZIP entry session's data file into its output and
+/// checks the entry's CRC. On a mismatch every part is reset so the next
+/// run downloads the entry again.
+pub(crate) async fn extract(session: &mut DownloadSession) -> Result<()> {
+    let entry = session.zip_entry.as_ref().context("session has no ZIP entry")?.entry.clone();
+    let (data, output) = (session.data_path(), session.output_path.clone());
+    let method = entry.method;
+    let (crc, written) = {
+        let data = data.clone();
+        tokio::task::spawn_blocking(move || inflate(&data, &output, method)).await?
+    }
+    .with_context(|| format!("cannot inflate {}", entry.name))?;
+
+    if crc != entry.crc32 || written != entry.size {
+        for part in &mut session.parts {
+            part.current_byte = part.start_byte;
+            part.completed = false;
+        }
+        anyhow::bail!(
+            "{} failed its CRC check (crc {:08x}, {} bytes; expected {:08x}, {} bytes)",
+            entry.name, crc, written, entry.crc32, entry.size
+        );
+    }
+    let _ = tokio::fs::remove_file(&data).await;
+    log::info!("Extracted {} ({} bytes) to {:?}", entry.name, written, session.output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Writes a ZIP archive; `zip64` forces ZIP64 end records and extra fields.
+    fn build_zip(files: &[(&str, &[u8], bool)], zip64: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for &(name, data, deflate) in files {
+            let mut crc = crc32fast::Hasher::new();
+            crc.update(data);
+            let crc = crc.finalize();
+            let (method, body) = if deflate {
+                let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                (METHOD_DEFLATED, encoder.finish().unwrap())
+            } else {
+                (METHOD_STORED, data.to_vec())
+            };
+            let offset = out.len() as u64;
+
+            // Local header with a little extra field the central one lacks
+            out.extend_from_slice(&LOCAL_HEADER_SIGNATURE.to_le_bytes());
+            out.extend_from_slice(&[20, 0, 0, 0]);
+            out.extend_from_slice(&method.to_le_bytes());
+            out.extend_from_slice(&[0; 4]);
+            out.extend_from_slice(&crc.to_le_bytes());
+            out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&8u16.to_le_bytes());
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&[0xfe, 0xca, 4, 0, 1, 2, 3, 4]);
+            out.extend_from_slice(&body);
+
+            let saturated = |value: u64| if zip64 { u32::MAX } else { value as u32 };
+            central.extend_from_slice(&CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+            central.extend_from_slice(&[45, 3, 45, 0, 0, 8]);
+            central.extend_from_slice(&method.to_le_bytes());
+            central.extend_from_slice(&[0; 4]);
+            central.extend_from_slice(&crc.to_le_bytes());
+            central.extend_from_slice(&saturated(body.len() as u64).to_le_bytes());
+            central.extend_from_slice(&saturated(data.len() as u64).to_le_bytes());
+            central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            central.extend_from_slice(&(if zip64 { 28u16 } else { 0 }).to_le_bytes());
+            central.extend_from_slice(&[0; 10]);
+            central.extend_from_slice(&saturated(offset).to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+            if zip64 {
+                central.extend_from_slice(&[1, 0, 24, 0]);
+                central.extend_from_slice(&(data.len() as u64).to_le_bytes());
+                central.extend_from_slice(&(body.len() as u64).to_le_bytes());
+                central.extend_from_slice(&offset.to_le_bytes());
+            }
+        }
+
+        let directory_offset = out.len() as u64;
+        out.extend_from_slice(&central);
+        let count = files.len() as u64;
+        if zip64 {
+            let record = out.len() as u64;
+            out.extend_from_slice(&ZIP64_EOCD_SIGNATURE.to_le_bytes());
+            out.extend_from_slice(&44u64.to_le_bytes());
+            out.extend_from_slice(&[45, 3, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            out.extend_from_slice(&count.to_le_bytes());
+            out.extend_from_slice(&count.to_le_bytes());
+            out.extend_from_slice(&(central.len() as u64).to_le_bytes());
+            out.extend_from_slice(&directory_offset.to_le_bytes());
+            out.extend_from_slice(&ZIP64_LOCATOR_SIGNATURE.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&record.to_le_bytes());
+            out.extend_from_slice(&1u32.to_le_bytes());
+        }
+        let (count16, size32, offset32) = if zip64 {
+            (u16::MAX, u32::MAX, u32::MAX)
+        } else {
+            (count as u16, central.len() as u32, directory_offset as u32)
+        };
+        out.extend_from_slice(&EOCD_SIGNATURE.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&count16.to_le_bytes());
+        out.extend_from_slice(&count16.to_le_bytes());
+        out.extend_from_slice(&size32.to_le_bytes());
+        out.extend_from_slice(&offset32.to_le_bytes());
+        // A comment that contains a fake EOCD signature
+        let comment = [b"PK\x05\x06".as_slice(), b" built for tests"].concat();
+        out.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+        out.extend_from_slice(&comment);
+        out
+    }
+
+    #[tokio::test]
+    async fn lists_and_extracts_entries_by_range() {
+        let big = test_support::payload(300 * 1024);
+        let small = b"hello from inside the archive".to_vec();
+        let padding = vec![0x5a; 512 * 1024];
+
+        for zip64 in [false, true] {
+            let archive = build_zip(
+                &[("docs/", b"", false), ("padding.bin", &padding, false), ("data/big.bin", &big, true), ("note.txt", &small, false)],
+                zip64,
+            );
+            let served = Arc::new(AtomicU64::new(0));
+            let counter = served.clone();
+            let tamper: test_support::Tamper = Arc::new(move |_, _, bytes: &mut [u8]| {
+                counter.fetch_add(bytes.len() as u64, Ordering::SeqCst);
+            });
+            let server = test_support::serve([("/a.zip".to_string(), archive.clone())].into(), HashMap::new(), Some(tamper)).await;
+            let url = server.url("/a.zip");
+
+            let downloader = Downloader::new("test").unwrap();
+            let entries = downloader.list_zip(&url).await.unwrap();
+            let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+            assert_eq!(names, ["docs/", "padding.bin", "data/big.bin", "note.txt"]);
+            assert!(entries[0].is_dir());
+            assert_eq!(entries[2].size, big.len() as u64);
+            assert_eq!(entries[2].method, METHOD_DEFLATED);
+
+            for (entry, expected) in [(&entries[2], &big), (&entries[3], &small)] {
+                let output = test_support::temp_path(&format!("zip-{}", zip64)).with_file_name(entry.file_name());
+                let mut session = downloader.init_zip_entry(&url, entry, Some(output.clone()), 3).await.unwrap();
+                downloader.run(&mut session, None, None, None).await.unwrap();
+                assert_eq!(&std::fs::read(&output).unwrap(), expected);
+                assert!(!session.data_path().exists());
+            }
+            // Never close to the whole archive
+            assert!(served.load(Ordering::SeqCst) < archive.len() as u64 - padding.len() as u64 / 2);
+        }
+    }
+
+    #[test]
+    fn rejects_entries_it_cannot_extract() {
+        let entry = ZipEntry {
+            name: "a.xz".into(),
+            size: 10,
+            compressed_size: 8,
+            method: 95,
+            crc32: 0,
+            header_offset: 0,
+            encrypted: false,
+        };
+        assert!(entry.check_extractable().is_err());
+        assert!(ZipEntry { method: METHOD_STORED, encrypted: true, ..entry.clone() }.check_extractable().is_err());
+        assert!(ZipEntry { method: METHOD_DEFLATED, ..entry }.check_extractable().is_ok());
+    }
+}
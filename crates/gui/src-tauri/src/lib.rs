@@ -4,9 +4,12 @@ use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
 use kitsune_core::downloader::DownloadObserver;
+use kitsune_core::session::DownloadState;
 
 struct AppState {
     cancel_flags: Mutex<HashMap<String, Arc<AtomicBool>>>,
+    /// Global schedule rules, shared by every running download
+    scheduler: Arc<kitsune_core::Scheduler>,
 }
 
 #[derive(Serialize, Clone)]
@@ -22,19 +25,37 @@ struct TauriProgressObserver {
 }
 
 impl DownloadObserver for TauriProgressObserver {
-    fn on_progress(&self, _worker_id: u8, bytes_downloaded: u64, active_workers: usize) {
+    fn on_progress(&self, _worker_id: u32, bytes_downloaded: u64, active_workers: usize) {
         let _ = self.app_handle.emit("download-progress", ProgressPayload {
             download_id: self.download_id.clone(),
             bytes_downloaded,
             active_workers,
         });
     }
+
+    fn on_state_change(&self, state: &DownloadState) {
+        let event = match state {
+            DownloadState::Verifying => "download-verifying",
+            DownloadState::Seeding => "download-seeding",
+            // Outside the times the schedule allows, and back inside them
+            DownloadState::Paused => "download-scheduled",
+            DownloadState::Downloading => "download-resumed",
+            _ => return,
+        };
+        let _ = self.app_handle.emit(event, ProgressPayload {
+            download_id: self.download_id.clone(),
+            bytes_downloaded: 0,
+            active_workers: 0,
+        });
+    }
 }
 
 #[derive(Serialize, Clone)]
 struct CompletedPayload {
     download_id: String,
     url: String,
+    /// e.g. "sha256 (Repr-Digest header)" when the file was verified
+    verified_with: Option<String>,
 }
 
 #[derive(Serialize, Clone)]
@@ -48,6 +69,9 @@ pub struct DownloadMetadata {
     pub filename: String,
     pub size: u64,
     pub url: String,
+    pub advertised_checksum: Option<String>,
+    /// Mirrors advertised via `Link: rel=duplicate`
+    pub mirrors: usize,
 }
 
 fn log_to_file(msg: &str) {
@@ -76,15 +100,35 @@ fn remove_ipc_port_file() {
     }
 }
 
-fn extract_url_from_args(args: &[String]) -> Option<String> {
+/// A download requested through `kitsune://download?url=...&checksum=...`.
+#[derive(Serialize, Clone, Debug)]
+struct DeepLinkPayload {
+    url: String,
+    checksum: Option<String>,
+}
+
+fn query_param(link: &str, name: &str) -> Option<String> {
+    let query = link.split_once('?').map(|(_, q)| q)?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| urlencoding::decode(value).ok())
+        .map(|s| s.into_owned())
+        .filter(|s| !s.is_empty())
+}
+
+fn extract_url_from_args(args: &[String]) -> Option<DeepLinkPayload> {
     log_to_file(&format!("Extracting from args: {:?}", args));
     for arg in args {
         // Strip quotes that might be added by the shell or desktop environment
         let clean_arg = arg.trim_matches(|c| c == '"' || c == '\'');
         if clean_arg.starts_with("kitsune://") {
-            if let Some(encoded) = clean_arg.split("url=").nth(1) {
-                let encoded = encoded.split('&').next().unwrap_or(encoded);
-                return urlencoding::decode(encoded).ok().map(|s| s.into_owned());
+            if let Some(url) = query_param(clean_arg, "url") {
+                return Some(DeepLinkPayload {
+                    url,
+                    checksum: query_param(clean_arg, "checksum"),
+                });
             }
         }
     }
@@ -95,12 +139,44 @@ fn extract_url_from_args(args: &[String]) -> Option<String> {
 async fn get_metadata(url: String) -> Result<DownloadMetadata, String> {
     let downloader = kitsune_core::Downloader::new("Kitsune-DM/1.0")
         .map_err(|e| e.to_string())?;
-    let (filename, size, _) = downloader.get_remote_metadata(&url)
+    if kitsune_core::hls::is_hls_source(&url) {
+        // The playlist itself is tiny; name the file after the joined stream
+        let session = downloader.init_hls(&url, None, 1, kitsune_core::VariantSelection::Best)
+            .await
+            .map_err(|e| e.to_string())?;
+        return Ok(DownloadMetadata {
+            filename: session.output_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+            size: 0,
+            url,
+            advertised_checksum: None,
+            mirrors: 0,
+        });
+    }
+    let meta = downloader.get_remote_metadata(&url)
         .await
         .map_err(|e| e.to_string())?;
-    Ok(DownloadMetadata { filename, size: size.unwrap_or(0), url })
+    Ok(DownloadMetadata {
+        filename: meta.filename,
+        size: meta.total_size.unwrap_or(0),
+        url,
+        advertised_checksum: meta.digests.first().map(|(digest, _)| digest.to_string()),
+        mirrors: meta.mirrors.len(),
+    })
+}
+
+/// Lists a remote ZIP archive's entries without downloading it.
+#[tauri::command]
+async fn list_zip_entries(url: String) -> Result<Vec<kitsune_core::ZipEntry>, String> {
+    let downloader = kitsune_core::Downloader::new("Kitsune-DM/1.0")
+        .map_err(|e| e.to_string())?;
+    downloader.list_zip(&url).await.map_err(|e| e.to_string())
 }
 
+/// Starts or resumes a download. With `zip_entry`, `url` is a ZIP archive
+/// and only that entry is downloaded and decompressed to `path`. `schedule`
+/// holds rules like "pause weekdays 09:00-17:00" for this download alone;
+/// while they or the global schedule hold it back it waits in the
+/// "scheduled" state instead of finishing the command.
 #[tauri::command]
 async fn start_download(
     app_handle: tauri::AppHandle,
@@ -109,22 +185,59 @@ async fn start_download(
     url: String,
     path: String,
     connections: u8,
+    checksum: Option<String>,
+    zip_entry: Option<String>,
+    schedule: Option<Vec<String>>,
 ) -> Result<(), String> {
+    let schedule = schedule
+        .map(|rules| kitsune_core::Schedule::parse(&rules))
+        .transpose()
+        .map_err(|e| e.to_string())?
+        .filter(|s| !s.is_empty());
+    let expected_digest = checksum
+        .filter(|c| !c.trim().is_empty())
+        .map(|c| c.parse::<kitsune_core::ExpectedDigest>())
+        .transpose()
+        .map_err(|e| e.to_string())?;
+
     let downloader = kitsune_core::Downloader::new("Kitsune-DM/1.0")
         .map_err(|e| e.to_string())?;
     let output_path = std::path::PathBuf::from(&path);
-    // Session file is side-by-side with the output file
-    let session_file = std::path::PathBuf::from(format!("{}.kitsune", path));
+    // Sessions live in the central store, keyed by the frontend's download id
+    let store = kitsune_core::SessionStore::open_default().map_err(|e| e.to_string())?;
 
-    let mut session = if session_file.exists() {
-        kitsune_core::DownloadSession::load(&session_file)
-            .await
-            .map_err(|e| e.to_string())?
-    } else {
-        downloader.init_download(&url, Some(output_path), connections)
-            .await
-            .map_err(|e| e.to_string())?
+    let existing = store.find_by_output(&download_id, &output_path)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (session_id, mut session) = match existing {
+        Some(found) => found,
+        None => {
+            let session = if let Some(name) = &zip_entry {
+                let entries = downloader.list_zip(&url).await.map_err(|e| e.to_string())?;
+                let entry = entries
+                    .iter()
+                    .find(|e| e.name == *name)
+                    .ok_or_else(|| format!("archive has no entry {}", name))?;
+                downloader.init_zip_entry(&url, entry, Some(output_path), connections).await
+            } else if kitsune_core::hls::is_hls_source(&url) {
+                downloader.init_hls(&url, Some(output_path), connections, kitsune_core::VariantSelection::Best).await
+            } else if kitsune_core::torrent::is_torrent_source(&url) {
+                let directory = output_path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+                downloader.init_torrent(&url, &directory, None, 0.0).await
+            } else {
+                downloader.init_download(&url, Some(output_path), connections).await
+            }
+            .map_err(|e| e.to_string())?;
+            (download_id.clone(), session)
+        }
     };
+    let session_file = store.session_path(&session_id);
+    if let Some(digest) = expected_digest {
+        session.set_expected_digest(digest, kitsune_core::DigestSource::User);
+    }
+    session.schedule = schedule;
+    // Refuse to start if the CLI or another GUI instance already owns this download
+    let download_lock = store.lock(&session_id, &session.output_path).map_err(|e| e.to_string())?;
 
     let observer = Arc::new(TauriProgressObserver {
         app_handle: app_handle.clone(),
@@ -139,10 +252,14 @@ async fn start_download(
     let download_id_clone = download_id.clone();
     let app_handle_clone = app_handle.clone();
     let session_file_clone = session_file.clone();
+    let scheduler = state.scheduler.clone();
 
     tokio::spawn(async move {
         // Pass the session_file so the core saves progress
-        let result = downloader.run(&mut session, Some(observer), Some(session_file_clone.clone()), Some(cancel_flag)).await;
+        let result = downloader
+            .run_scheduled(&mut session, Some(observer), Some(session_file_clone.clone()), Some(cancel_flag), scheduler)
+            .await;
+        drop(download_lock);
         
         let app_state = app_handle_clone.state::<AppState>();
         if let Ok(mut flags) = app_state.cancel_flags.lock() {
@@ -164,19 +281,154 @@ async fn start_download(
                 });
             }
         } else {
+            let verified_with = session.verification
+                .as_ref()
+                .filter(|v| v.verified)
+                .map(|v| format!("{} ({})", v.digest.algorithm, v.source));
             let _ = app_handle_clone.emit("download-completed", CompletedPayload {
                 download_id: download_id_clone,
                 url: session.url,
+                verified_with,
             });
             // Clean up session file on success
-            if session_file_clone.exists() {
-                let _ = std::fs::remove_file(session_file_clone);
-            }
+            kitsune_core::DownloadSession::remove(&session_file_clone).await;
         }
     });
     Ok(())
 }
 
+/// A download created from a Metalink file, DASH manifest or torrent,
+/// already saved in the session store.
+#[derive(Serialize)]
+pub struct ImportedDownload {
+    pub download_id: String,
+    pub url: String,
+    pub filename: String,
+    pub path: String,
+    pub total_size: u64,
+    pub mirrors: usize,
+}
+
+/// Parses a Metalink file and stores one ready-to-run session per entry;
+/// the frontend then starts each with `start_download`.
+#[tauri::command]
+async fn import_metalink(source: String, directory: String, connections: u8) -> Result<Vec<ImportedDownload>, String> {
+    let downloader = kitsune_core::Downloader::new("Kitsune-DM/1.0")
+        .map_err(|e| e.to_string())?;
+    let store = kitsune_core::SessionStore::open_default().map_err(|e| e.to_string())?;
+    let files = downloader.fetch_metalink(&source).await.map_err(|e| e.to_string())?;
+    let directory = std::path::PathBuf::from(directory);
+
+    let mut entries = Vec::new();
+    for file in files {
+        let path = directory.join(&file.name);
+        let download_id = kitsune_core::SessionStore::id_for_output(&path);
+        let existing = store.find_by_output(&download_id, &path).await.map_err(|e| e.to_string())?;
+        let (download_id, session) = match existing {
+            Some(found) => found,
+            None => {
+                let session = downloader.init_from_metalink(&file, &directory, connections)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                store.save(&download_id, &session).await.map_err(|e| e.to_string())?;
+                (download_id, session)
+            }
+        };
+        entries.push(ImportedDownload {
+            download_id,
+            url: session.url.clone(),
+            filename: file.name.to_string_lossy().to_string(),
+            path: session.output_path.to_string_lossy().to_string(),
+            total_size: session.total_size.unwrap_or(0),
+            mirrors: session.mirrors.len(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Stores one session per selected track (best video and audio) of a DASH
+/// manifest; the frontend starts each with `start_download`.
+#[tauri::command]
+async fn import_dash(url: String, directory: String, connections: u8) -> Result<Vec<ImportedDownload>, String> {
+    let downloader = kitsune_core::Downloader::new("Kitsune-DM/1.0")
+        .map_err(|e| e.to_string())?;
+    let store = kitsune_core::SessionStore::open_default().map_err(|e| e.to_string())?;
+    let sessions = downloader
+        .init_dash(&url, std::path::Path::new(&directory), connections, &kitsune_core::TrackSelection::default())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut entries = Vec::new();
+    for session in sessions {
+        let download_id = kitsune_core::SessionStore::id_for_output(&session.output_path);
+        let existing = store.find_by_output(&download_id, &session.output_path).await.map_err(|e| e.to_string())?;
+        let (download_id, session) = match existing {
+            Some(found) => found,
+            None => {
+                store.save(&download_id, &session).await.map_err(|e| e.to_string())?;
+                (download_id, session)
+            }
+        };
+        entries.push(ImportedDownload {
+            download_id,
+            url: session.url.clone(),
+            filename: session.output_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+            path: session.output_path.to_string_lossy().to_string(),
+            total_size: 0,
+            mirrors: 0,
+        });
+    }
+    Ok(entries)
+}
+
+/// Stores a session for a magnet link or `.torrent` file or URL, saving
+/// into `directory`; the frontend starts it with `start_download`. Magnet
+/// links report no size until their metadata has been fetched.
+#[tauri::command]
+async fn import_torrent(source: String, directory: String, seed_ratio: f64) -> Result<Vec<ImportedDownload>, String> {
+    let downloader = kitsune_core::Downloader::new("Kitsune-DM/1.0")
+        .map_err(|e| e.to_string())?;
+    let store = kitsune_core::SessionStore::open_default().map_err(|e| e.to_string())?;
+    let session = downloader
+        .init_torrent(&source, std::path::Path::new(&directory), None, seed_ratio)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let download_id = kitsune_core::SessionStore::id_for_output(&session.output_path);
+    let existing = store.find_by_output(&download_id, &session.output_path).await.map_err(|e| e.to_string())?;
+    let (download_id, session) = match existing {
+        Some(found) => found,
+        None => {
+            store.save(&download_id, &session).await.map_err(|e| e.to_string())?;
+            (download_id, session)
+        }
+    };
+    Ok(vec![ImportedDownload {
+        download_id,
+        url: session.url.clone(),
+        filename: session.output_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+        path: session.output_path.to_string_lossy().to_string(),
+        total_size: session.total_size.unwrap_or(0),
+        mirrors: 0,
+    }])
+}
+
+#[tauri::command]
+fn get_global_schedule(state: tauri::State<'_, AppState>) -> Vec<String> {
+    state.scheduler.global().rules.iter().map(|rule| rule.to_string()).collect()
+}
+
+/// Replaces the global schedule; running downloads follow it within a
+/// second, and the CLI daemon picks it up from the same file.
+#[tauri::command]
+async fn set_global_schedule(state: tauri::State<'_, AppState>, rules: Vec<String>) -> Result<Vec<String>, String> {
+    let schedule = kitsune_core::Schedule::parse(&rules).map_err(|e| e.to_string())?;
+    schedule.save(&kitsune_core::Schedule::default_path()).await.map_err(|e| e.to_string())?;
+    let normalized = schedule.rules.iter().map(|rule| rule.to_string()).collect();
+    state.scheduler.set_global(schedule);
+    Ok(normalized)
+}
+
 #[tauri::command]
 fn cancel_download(state: tauri::State<'_, AppState>, download_id: String) {
     if let Ok(flags) = state.cancel_flags.lock() {
@@ -186,6 +438,15 @@ fn cancel_download(state: tauri::State<'_, AppState>, download_id: String) {
     }
 }
 
+#[tauri::command]
+async fn delete_session(download_id: String, path: String) -> Result<(), String> {
+    let store = kitsune_core::SessionStore::open_default().map_err(|e| e.to_string())?;
+    store.remove(&download_id).await;
+    // Older versions kept the session next to the output file
+    kitsune_core::DownloadSession::remove(&std::path::PathBuf::from(format!("{}.kitsune", path))).await;
+    Ok(())
+}
+
 #[tauri::command]
 fn show_in_folder(path: String) {
     #[cfg(target_os = "windows")]
@@ -209,7 +470,11 @@ fn show_in_folder(path: String) {
 
 #[tauri::command]
 fn delete_file(path: String) -> Result<(), String> {
-    if std::path::Path::new(&path).exists() {
+    let path = std::path::Path::new(&path);
+    if path.is_dir() {
+        // Multi-file torrents download into a directory
+        std::fs::remove_dir_all(path).map_err(|e| e.to_string())?;
+    } else if path.exists() {
         std::fs::remove_file(path).map_err(|e| e.to_string())?;
     }
     Ok(())
@@ -248,6 +513,13 @@ pub struct PersistedDownload {
     pub status: String,
     pub connections: u8,
     pub started_at: u64,
+    #[serde(default)]
+    pub checksum: Option<String>,
+    #[serde(default)]
+    pub verified_with: Option<String>,
+    /// Schedule rules of this download alone
+    #[serde(default)]
+    pub schedule: Vec<String>,
 }
 
 fn state_file_path() -> std::path::PathBuf {
@@ -278,8 +550,15 @@ fn load_state() -> Vec<PersistedDownload> {
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    let global_schedule = std::fs::read_to_string(kitsune_core::Schedule::default_path())
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
     tauri::Builder::default()
-        .manage(AppState { cancel_flags: Mutex::new(HashMap::new()) })
+        .manage(AppState {
+            cancel_flags: Mutex::new(HashMap::new()),
+            scheduler: Arc::new(kitsune_core::Scheduler::new(global_schedule)),
+        })
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_single_instance::init(|app, args, _cwd| {
@@ -288,9 +567,9 @@ pub fn run() {
                 let _ = w.show();
                 let _ = w.set_focus();
             }
-            if let Some(url) = extract_url_from_args(&args) {
-                log_to_file(&format!("Emitting deep-link-received from single-instance: {}", url));
-                if let Err(e) = app.emit("deep-link-received", url) {
+            if let Some(payload) = extract_url_from_args(&args) {
+                log_to_file(&format!("Emitting deep-link-received from single-instance: {:?}", payload));
+                if let Err(e) = app.emit("deep-link-received", payload) {
                     log_to_file(&format!("Failed to emit deep-link-received: {}", e));
                 }
             } else {
@@ -312,9 +591,9 @@ pub fn run() {
                 // tauri-plugin-deep-link sends Vec<String> as JSON
                 if let Ok(urls) = serde_json::from_str::<Vec<String>>(raw) {
                     for link in urls {
-                        if let Some(url) = extract_url_from_args(&[link]) {
-                             log_to_file(&format!("Emitting deep-link-received from payload listener: {}", url));
-                             let _ = handle.emit("deep-link-received", url);
+                        if let Some(payload) = extract_url_from_args(&[link]) {
+                             log_to_file(&format!("Emitting deep-link-received from payload listener: {:?}", payload));
+                             let _ = handle.emit("deep-link-received", payload);
                         }
                     }
                 }
@@ -360,11 +639,11 @@ pub fn run() {
             });
 
             let startup_args: Vec<String> = std::env::args().collect();
-            if let Some(url) = extract_url_from_args(&startup_args) {
+            if let Some(payload) = extract_url_from_args(&startup_args) {
                 let handle3 = app.handle().clone();
                 tauri::async_runtime::spawn(async move {
                     tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                    let _ = handle3.emit("deep-link-received", url);
+                    let _ = handle3.emit("deep-link-received", payload);
                 });
             }
 
@@ -401,7 +680,10 @@ pub fn run() {
                                             let _ = w.show();
                                             let _ = w.set_focus();
                                         }
-                                        let _ = handle.emit("deep-link-received", url);
+                                        // The shim sends either a bare URL or a full kitsune:// link
+                                        let payload = extract_url_from_args(&[url.clone()])
+                                            .unwrap_or(DeepLinkPayload { url, checksum: None });
+                                        let _ = handle.emit("deep-link-received", payload);
                                     }
                                 }
                             });
@@ -423,11 +705,18 @@ pub fn run() {
         })
         .invoke_handler(tauri::generate_handler![
             get_metadata,
+            list_zip_entries,
             start_download,
+            import_metalink,
+            import_dash,
+            import_torrent,
             get_downloads_dir,
             save_state,
             load_state,
             cancel_download,
+            get_global_schedule,
+            set_global_schedule,
+            delete_session,
             show_in_folder,
             delete_file
         ])
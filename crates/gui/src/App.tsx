@@ -1,11 +1,43 @@
 import { useState, useEffect, useCallback } from "react";
+import { invoke } from "@tauri-apps/api/core";
 import { listen } from "@tauri-apps/api/event";
-import { Plus } from "lucide-react";
+import { getCurrentWebview } from "@tauri-apps/api/webview";
+import { open as openDialog } from "@tauri-apps/plugin-dialog";
+import { CalendarClock, FileDown, Plus } from "lucide-react";
 import { AddDownloadModal } from "./components/AddDownloadModal";
 import { DownloadCard } from "./components/DownloadCard";
+import { ScheduleModal } from "./components/ScheduleModal";
 import { ToastContainer, ToastMessage } from "./components/Toast";
 import { useDownloads } from "./hooks/useDownloads";
 
+interface DeepLinkPayload {
+  url: string;
+  checksum?: string | null;
+}
+
+interface ImportedDownload {
+  download_id: string;
+  url: string;
+  filename: string;
+  path: string;
+  total_size: number;
+  mirrors: number;
+}
+
+const IMPORT_CONNECTIONS = 8;
+
+function isMetalinkPath(path: string): boolean {
+  return /\.(meta4|metalink)$/i.test(path);
+}
+
+function isDashUrl(url: string): boolean {
+  return /\.mpd([?#]|$)/i.test(url);
+}
+
+function isTorrentSource(source: string): boolean {
+  return source.startsWith("magnet:") || /\.torrent([?#]|$)/i.test(source);
+}
+
 function extractUrlFromDeepLink(raw: string): string {
   const trimmed = raw.trim().replace(/^"|"$/g, "");
   return trimmed;
@@ -13,7 +45,9 @@ function extractUrlFromDeepLink(raw: string): string {
 
 function App() {
   const [showModal, setShowModal] = useState(false);
+  const [showSchedule, setShowSchedule] = useState(false);
   const [pendingUrl, setPendingUrl] = useState("");
+  const [pendingChecksum, setPendingChecksum] = useState("");
   const [toasts, setToasts] = useState<ToastMessage[]>([]);
   const { 
     downloads, 
@@ -30,21 +64,96 @@ function App() {
   }, []);
 
   useEffect(() => {
-    const unlisten = listen<string>("deep-link-received", (event) => {
-      const url = extractUrlFromDeepLink(event.payload);
+    const unlisten = listen<{ download_id: string; error: string }>("download-error", (event) => {
+      const { error } = event.payload;
+      setToasts(prev => [...prev, { id: `${Date.now()}`, message: `Download failed: ${error}` }]);
+    });
+    return () => { unlisten.then(fn => fn()); };
+  }, []);
+
+  // Metalink files, DASH manifests and torrents become downloads that the
+  // backend has already planned; add and start each of them
+  const importDownloads = useCallback(async (
+    command: "import_metalink" | "import_dash" | "import_torrent",
+    args: Record<string, string | number>
+  ) => {
+    try {
+      const directory = await invoke<string>("get_downloads_dir");
+      const entries = await invoke<ImportedDownload[]>(command, {
+        ...args,
+        directory,
+        connections: IMPORT_CONNECTIONS,
+      });
+      for (const entry of entries) {
+        addDownload({
+          id: entry.download_id,
+          url: entry.url,
+          filename: entry.filename,
+          path: entry.path,
+          totalSize: entry.total_size,
+          connections: IMPORT_CONNECTIONS,
+        });
+        invoke("start_download", {
+          downloadId: entry.download_id,
+          url: entry.url,
+          path: entry.path,
+          connections: IMPORT_CONNECTIONS,
+          checksum: null,
+        }).catch(err => {
+          setToasts(prev => [...prev, { id: `${Date.now()}`, message: `Download failed: ${err}` }]);
+        });
+      }
+    } catch (err) {
+      setToasts(prev => [...prev, { id: `${Date.now()}`, message: `Import failed: ${err}` }]);
+    }
+  }, [addDownload]);
+
+  const importMetalink = useCallback(
+    (source: string) => importDownloads("import_metalink", { source }),
+    [importDownloads]
+  );
+
+  const importTorrent = useCallback(
+    (source: string) => importDownloads("import_torrent", { source, seedRatio: 0 }),
+    [importDownloads]
+  );
+
+  useEffect(() => {
+    const unlisten = listen<DeepLinkPayload>("deep-link-received", (event) => {
+      const url = extractUrlFromDeepLink(event.payload.url);
+      if (isDashUrl(url)) {
+        importDownloads("import_dash", { url });
+        return;
+      }
+      if (isTorrentSource(url)) {
+        importTorrent(url);
+        return;
+      }
       setPendingUrl(url);
+      setPendingChecksum(event.payload.checksum ?? "");
       setShowModal(true);
     });
     return () => { unlisten.then(fn => fn()); };
-  }, []);
+  }, [importDownloads, importTorrent]);
+
+  const handleOpenMetalink = async () => {
+    const selected = await openDialog({
+      title: "Open Metalink file",
+      filters: [{ name: "Metalink", extensions: ["meta4", "metalink"] }],
+    });
+    if (selected && typeof selected === "string") {
+      importMetalink(selected);
+    }
+  };
 
   useEffect(() => {
-    const unlisten = listen<{ download_id: string; error: string }>("download-error", (event) => {
-      const { error } = event.payload;
-      setToasts(prev => [...prev, { id: `${Date.now()}`, message: `Download failed: ${error}` }]);
+    const unlisten = getCurrentWebview().onDragDropEvent((event) => {
+      if (event.payload.type !== "drop") return;
+      event.payload.paths.filter(isMetalinkPath).forEach(importMetalink);
+      event.payload.paths.filter(isTorrentSource).forEach(importTorrent);
     });
     return () => { unlisten.then(fn => fn()); };
-  }, []);
+  }, [importMetalink, importTorrent]);
 
   const handleStarted = (
     id: string,
@@ -52,9 +161,11 @@ function App() {
     filename: string,
     path: string,
     totalSize: number,
-    connections: number
+    connections: number,
+    checksum?: string,
+    schedule?: string[]
   ) => {
-    addDownload({ id, url, filename, path, totalSize, connections });
+    addDownload({ id, url, filename, path, totalSize, connections, checksum, schedule });
   };
 
   const activeCount = downloads.filter(d => d.status === "downloading").length;
@@ -78,7 +189,23 @@ function App() {
             </span>
           )}
           <button
-            onClick={() => { setPendingUrl(""); setShowModal(true); }}
+            onClick={() => setShowSchedule(true)}
+            title="When downloads may run and how fast"
+            className="flex items-center gap-2 px-4 py-2 bg-zinc-800 hover:bg-zinc-700 text-zinc-200 text-sm font-medium rounded-lg transition-colors"
+          >
+            <CalendarClock className="w-4 h-4" />
+            Schedule
+          </button>
+          <button
+            onClick={handleOpenMetalink}
+            title="Open a .meta4 or .metalink file (or drop one on the window)"
+            className="flex items-center gap-2 px-4 py-2 bg-zinc-800 hover:bg-zinc-700 text-zinc-200 text-sm font-medium rounded-lg transition-colors"
+          >
+            <FileDown className="w-4 h-4" />
+            Metalink
+          </button>
+          <button
+            onClick={() => { setPendingUrl(""); setPendingChecksum(""); setShowModal(true); }}
             className="flex items-center gap-2 px-4 py-2 bg-blue-600 hover:bg-blue-500 text-white text-sm font-medium rounded-lg transition-colors"
           >
             <Plus className="w-4 h-4" />
@@ -94,7 +221,7 @@ function App() {
               <img src="/logo.png" alt="Kitsune Logo" className="w-full h-full object-contain opacity-20 grayscale" />
             </div>
             <p className="text-zinc-400 font-medium">No downloads yet</p>
-            <p className="text-zinc-600 text-sm mt-1">Click "Add Download" or use the browser extension</p>
+            <p className="text-zinc-600 text-sm mt-1">Click "Add Download", drop a Metalink or torrent file here, or use the browser extension</p>
           </div>
         ) : (
           <div className="space-y-3 max-w-2xl mx-auto">
@@ -116,11 +243,14 @@ function App() {
       {showModal && (
         <AddDownloadModal
           initialUrl={pendingUrl}
-          onClose={() => { setShowModal(false); setPendingUrl(""); }}
+          initialChecksum={pendingChecksum}
+          onClose={() => { setShowModal(false); setPendingUrl(""); setPendingChecksum(""); }}
           onStarted={handleStarted}
         />
       )}
 
+      {showSchedule && <ScheduleModal onClose={() => setShowSchedule(false)} />}
+
       <ToastContainer toasts={toasts} onDismiss={dismissToast} />
     </div>
   );
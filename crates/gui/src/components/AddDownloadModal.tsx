@@ -1,12 +1,30 @@
 import { useState, useEffect } from "react";
 import { invoke } from "@tauri-apps/api/core";
 import { open as openDialog } from "@tauri-apps/plugin-dialog";
-import { X, FolderOpen, Loader2, Download } from "lucide-react";
+import { X, FolderOpen, Loader2, Download, Archive } from "lucide-react";
 
 interface DownloadMetadata {
   filename: string;
   size: number;
   url: string;
+  advertised_checksum: string | null;
+  mirrors: number;
+}
+
+interface ZipEntry {
+  name: string;
+  size: number;
+  compressed_size: number;
+  method: number;
+  encrypted: boolean;
+}
+
+function isZipName(name: string): boolean {
+  return /\.zip([?#]|$)/i.test(name);
+}
+
+function baseName(path: string): string {
+  return path.replace(/\/+$/, "").split("/").pop() ?? path;
 }
 
 function formatBytes(bytes: number): string {
@@ -18,12 +36,16 @@ function formatBytes(bytes: number): string {
 
 interface AddDownloadModalProps {
   initialUrl?: string;
+  initialChecksum?: string;
   onClose: () => void;
-  onStarted: (id: string, url: string, filename: string, path: string, totalSize: number, connections: number) => void;
+  onStarted: (id: string, url: string, filename: string, path: string, totalSize: number, connections: number, checksum?: string, schedule?: string[]) => void;
 }
 
-export function AddDownloadModal({ initialUrl = "", onClose, onStarted }: AddDownloadModalProps) {
+export function AddDownloadModal({ initialUrl = "", initialChecksum = "", onClose, onStarted }: AddDownloadModalProps) {
   const [url, setUrl] = useState(initialUrl);
+  const [checksum, setChecksum] = useState(initialChecksum);
+  // One rule per line, e.g. "01:00-07:00" or "limit 2M weekdays"
+  const [schedule, setSchedule] = useState("");
   const [filename, setFilename] = useState("");
   const [savePath, setSavePath] = useState("");
   const [connections, setConnections] = useState(8);
@@ -31,6 +53,11 @@ export function AddDownloadModal({ initialUrl = "", onClose, onStarted }: AddDow
   const [loading, setLoading] = useState(false);
   const [starting, setStarting] = useState(false);
   const [error, setError] = useState("");
+  // Set once the user browses a ZIP archive; picking an entry downloads
+  // only that entry
+  const [zipEntries, setZipEntries] = useState<ZipEntry[] | null>(null);
+  const [zipEntry, setZipEntry] = useState<ZipEntry | null>(null);
+  const [listing, setListing] = useState(false);
 
   useEffect(() => {
     invoke<string>("get_downloads_dir").then(dir => {
@@ -48,6 +75,8 @@ export function AddDownloadModal({ initialUrl = "", onClose, onStarted }: AddDow
     if (!targetUrl) return;
     setLoading(true);
     setError("");
+    setZipEntries(null);
+    setZipEntry(null);
     try {
       const meta = await invoke<DownloadMetadata>("get_metadata", { url: targetUrl });
       setMetadata(meta);
@@ -61,6 +90,28 @@ export function AddDownloadModal({ initialUrl = "", onClose, onStarted }: AddDow
     }
   };
 
+  const handleBrowseArchive = async () => {
+    setListing(true);
+    setError("");
+    try {
+      const entries = await invoke<ZipEntry[]>("list_zip_entries", { url });
+      setZipEntries(entries.filter(e => !e.name.endsWith("/")));
+    } catch (e) {
+      setError(String(e));
+    } finally {
+      setListing(false);
+    }
+  };
+
+  const selectZipEntry = (entry: ZipEntry | null) => {
+    if (!metadata) return;
+    const name = entry ? baseName(entry.name) : metadata.filename;
+    const dir = savePath.includes("/") ? savePath.slice(0, savePath.lastIndexOf("/")) : savePath;
+    setZipEntry(entry);
+    setFilename(name);
+    setSavePath(`${dir}/${name}`);
+  };
+
   const handleBrowse = async () => {
     const selected = await openDialog({ directory: true, title: "Choose save location" });
     if (selected && typeof selected === "string") {
@@ -75,7 +126,11 @@ export function AddDownloadModal({ initialUrl = "", onClose, onStarted }: AddDow
 
     const downloadId = `${Date.now()}-${Math.random().toString(36).slice(2)}`;
 
-    onStarted(downloadId, url, filename, savePath, metadata.size, connections);
+    const trimmedChecksum = checksum.trim() || undefined;
+    const rules = schedule.split("\n").map(r => r.trim()).filter(Boolean);
+    // An archive entry's progress counts its compressed bytes
+    const totalSize = zipEntry ? zipEntry.compressed_size : metadata.size;
+    onStarted(downloadId, url, filename, savePath, totalSize, connections, trimmedChecksum, rules.length ? rules : undefined);
     onClose();
 
     try {
@@ -84,6 +139,9 @@ export function AddDownloadModal({ initialUrl = "", onClose, onStarted }: AddDow
         url,
         path: savePath,
         connections,
+        checksum: trimmedChecksum ?? null,
+        zipEntry: zipEntry?.name ?? null,
+        schedule: rules.length ? rules : null,
       });
     } catch (e) {
       console.error("start_download failed:", e);
@@ -134,8 +192,60 @@ export function AddDownloadModal({ initialUrl = "", onClose, onStarted }: AddDow
               <div className="flex items-center gap-3 px-3 py-2.5 bg-zinc-800/50 border border-zinc-700/50 rounded-lg">
                 <div className="w-2 h-2 rounded-full bg-emerald-400 shrink-0" />
                 <span className="text-sm text-zinc-300">{formatBytes(metadata.size)}</span>
+                {metadata.mirrors > 0 && (
+                  <span className="text-xs text-blue-400">
+                    +{metadata.mirrors} mirror{metadata.mirrors === 1 ? "" : "s"}
+                  </span>
+                )}
+                {metadata.advertised_checksum && (
+                  <span className="ml-auto text-xs text-emerald-400" title={metadata.advertised_checksum}>
+                    Server checksum: {metadata.advertised_checksum.split(":")[0]}
+                  </span>
+                )}
               </div>
 
+              {(isZipName(url) || isZipName(metadata.filename)) && (
+                <div className="space-y-1.5">
+                  {zipEntries === null ? (
+                    <button
+                      onClick={handleBrowseArchive}
+                      disabled={listing}
+                      className="w-full py-2 bg-zinc-800 hover:bg-zinc-700 disabled:opacity-50 text-zinc-300 text-sm rounded-lg transition-colors flex items-center justify-center gap-2"
+                    >
+                      {listing ? <Loader2 className="w-4 h-4 animate-spin" /> : <Archive className="w-4 h-4" />}
+                      Browse archive to download a single file
+                    </button>
+                  ) : (
+                    <>
+                      <label className="block text-sm font-medium text-zinc-300">Archive entry</label>
+                      <div className="max-h-48 overflow-y-auto bg-zinc-800/50 border border-zinc-700/50 rounded-lg divide-y divide-zinc-800">
+                        <button
+                          onClick={() => selectZipEntry(null)}
+                          className={`w-full px-3 py-1.5 text-left text-sm transition-colors ${
+                            zipEntry === null ? "bg-blue-600/20 text-blue-300" : "text-zinc-400 hover:bg-zinc-700/50"
+                          }`}
+                        >
+                          Whole archive
+                        </button>
+                        {zipEntries.map(entry => (
+                          <button
+                            key={entry.name}
+                            onClick={() => selectZipEntry(entry)}
+                            disabled={entry.encrypted || (entry.method !== 0 && entry.method !== 8)}
+                            className={`w-full px-3 py-1.5 flex items-center gap-3 text-left text-sm transition-colors disabled:opacity-40 ${
+                              zipEntry?.name === entry.name ? "bg-blue-600/20 text-blue-300" : "text-zinc-300 hover:bg-zinc-700/50"
+                            }`}
+                          >
+                            <span className="flex-1 truncate font-mono" title={entry.name}>{entry.name}</span>
+                            <span className="text-xs text-zinc-500 shrink-0">{formatBytes(entry.size)}</span>
+                          </button>
+                        ))}
+                      </div>
+                    </>
+                  )}
+                </div>
+              )}
+
               <div className="space-y-1.5">
                 <label className="block text-sm font-medium text-zinc-300">Filename</label>
                 <input
@@ -165,6 +275,28 @@ export function AddDownloadModal({ initialUrl = "", onClose, onStarted }: AddDow
                 </div>
               </div>
 
+              <div className="space-y-1.5">
+                <label className="block text-sm font-medium text-zinc-300">Checksum (optional)</label>
+                <input
+                  type="text"
+                  value={checksum}
+                  onChange={(e) => setChecksum(e.target.value)}
+                  placeholder="sha256:9f86d081..."
+                  className="w-full px-3 py-2.5 bg-zinc-800 border border-zinc-700 rounded-lg text-sm text-white placeholder-zinc-500 font-mono focus:outline-none focus:border-blue-500 transition-colors"
+                />
+              </div>
+
+              <div className="space-y-1.5">
+                <label className="block text-sm font-medium text-zinc-300">Schedule (optional)</label>
+                <textarea
+                  value={schedule}
+                  onChange={(e) => setSchedule(e.target.value)}
+                  rows={2}
+                  placeholder={"01:00-07:00\nlimit 2M weekdays"}
+                  className="w-full px-3 py-2.5 bg-zinc-800 border border-zinc-700 rounded-lg text-sm text-white placeholder-zinc-500 font-mono focus:outline-none focus:border-blue-500 transition-colors resize-none"
+                />
+              </div>
+
               <div className="space-y-1.5">
                 <label className="block text-sm font-medium text-zinc-300">Connections</label>
                 <div className="flex gap-2">
@@ -1,5 +1,5 @@
 import { Download } from "../hooks/useDownloads";
-import { CheckCircle, XCircle, Download as DownloadIcon, Zap, Clock, Layers, Play, Pause, Folder, Trash2, X } from "lucide-react";
+import { CalendarClock, CheckCircle, ShieldCheck, XCircle, Download as DownloadIcon, Zap, Clock, Layers, Play, Pause, Folder, Trash2, Upload, X } from "lucide-react";
 
 function formatBytes(bytes: number): string {
   if (bytes === 0) return "0 B";
@@ -34,11 +34,14 @@ interface DownloadCardProps {
 }
 
 export function DownloadCard({ download, onPause, onResume, onRemove, onDismiss, onOpenFolder }: DownloadCardProps) {
-  const { filename, url, totalSize, downloadedBytes, speed, eta, status, connections, error, path } = download;
+  const { filename, url, totalSize, downloadedBytes, speed, eta, status, connections, error, path, verifiedWith, schedule } = download;
   const progress = totalSize > 0 ? Math.min((downloadedBytes / totalSize) * 100, 100) : 0;
 
   const statusIcon = {
     downloading: <DownloadIcon className="w-4 h-4 text-blue-400 animate-pulse" />,
+    verifying: <ShieldCheck className="w-4 h-4 text-amber-400 animate-pulse" />,
+    seeding: <Upload className="w-4 h-4 text-teal-400 animate-pulse" />,
+    scheduled: <CalendarClock className="w-4 h-4 text-violet-400" />,
     completed: <CheckCircle className="w-4 h-4 text-emerald-400" />,
     error: <XCircle className="w-4 h-4 text-red-400" />,
     paused: <Pause className="w-4 h-4 text-zinc-400" />,
@@ -46,6 +49,9 @@ export function DownloadCard({ download, onPause, onResume, onRemove, onDismiss,
 
   const statusColor = {
     downloading: "text-blue-400",
+    verifying: "text-amber-400",
+    seeding: "text-teal-400",
+    scheduled: "text-violet-400",
     completed: "text-emerald-400",
     error: "text-red-400",
     paused: "text-zinc-400",
@@ -53,6 +59,9 @@ export function DownloadCard({ download, onPause, onResume, onRemove, onDismiss,
 
   const progressBarColor = {
     downloading: "bg-blue-500",
+    verifying: "bg-amber-500",
+    seeding: "bg-teal-500",
+    scheduled: "bg-violet-500",
     completed: "bg-emerald-500",
     error: "bg-red-500",
     paused: "bg-zinc-500",
@@ -70,10 +79,10 @@ export function DownloadCard({ download, onPause, onResume, onRemove, onDismiss,
         </div>
         <div className="flex items-center gap-1 shrink-0">
           <span className={`text-xs font-medium px-2 py-1 rounded-full bg-zinc-800 ${statusColor}`}>
-            {status === "downloading" ? "Downloading" : status === "completed" ? "Done" : status === "paused" ? "Paused" : "Error"}
+            {status === "downloading" ? "Downloading" : status === "verifying" ? "Verifying" : status === "seeding" ? "Seeding" : status === "scheduled" ? "Scheduled" : status === "completed" ? "Done" : status === "paused" ? "Paused" : "Error"}
           </span>
           
-          {status === "downloading" && (
+          {(status === "downloading" || status === "seeding" || status === "scheduled") && (
             <button
               onClick={() => onPause(download.id)}
               className="p-1.5 rounded-lg text-zinc-500 hover:text-blue-400 hover:bg-blue-950/30 transition-colors"
@@ -151,6 +160,20 @@ export function DownloadCard({ download, onPause, onResume, onRemove, onDismiss,
         </div>
       )}
 
+      {schedule && status !== "completed" && (
+        <p className="flex items-center gap-1.5 text-xs text-zinc-400">
+          <CalendarClock className="w-3.5 h-3.5 text-violet-400" />
+          {schedule.join(" · ")}
+        </p>
+      )}
+
+      {status === "completed" && verifiedWith && (
+        <p className="flex items-center gap-1.5 text-xs text-emerald-400">
+          <ShieldCheck className="w-3.5 h-3.5" />
+          Verified {verifiedWith}
+        </p>
+      )}
+
       {status === "error" && error && (
         <p className="text-xs text-red-400 bg-red-950/30 border border-red-900/50 rounded-lg px-3 py-2">{error}</p>
       )}
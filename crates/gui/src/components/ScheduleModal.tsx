@@ -0,0 +1,91 @@
+import { useState, useEffect } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { CalendarClock, Loader2, X } from "lucide-react";
+
+interface ScheduleModalProps {
+  onClose: () => void;
+}
+
+/** Edits the global schedule, which applies to every download. */
+export function ScheduleModal({ onClose }: ScheduleModalProps) {
+  const [rules, setRules] = useState("");
+  const [saving, setSaving] = useState(false);
+  const [error, setError] = useState("");
+
+  useEffect(() => {
+    invoke<string[]>("get_global_schedule").then(current => setRules(current.join("\n")));
+  }, []);
+
+  const handleSave = async () => {
+    setSaving(true);
+    setError("");
+    try {
+      await invoke<string[]>("set_global_schedule", {
+        rules: rules.split("\n").map(r => r.trim()).filter(Boolean),
+      });
+      onClose();
+    } catch (e) {
+      setError(String(e));
+    } finally {
+      setSaving(false);
+    }
+  };
+
+  return (
+    <div className="fixed inset-0 z-50 flex items-center justify-center">
+      <div className="absolute inset-0 bg-black/70 backdrop-blur-sm" onClick={onClose} />
+      <div className="relative w-full max-w-lg mx-4 bg-zinc-900 border border-zinc-800 rounded-2xl shadow-2xl overflow-hidden">
+        <div className="flex items-center justify-between px-6 py-4 border-b border-zinc-800">
+          <div className="flex items-center gap-2">
+            <CalendarClock className="w-5 h-5 text-violet-400" />
+            <h2 className="text-lg font-semibold text-white">Schedule</h2>
+          </div>
+          <button
+            onClick={onClose}
+            className="text-zinc-500 hover:text-white transition-colors rounded-lg p-1 hover:bg-zinc-800"
+          >
+            <X className="w-5 h-5" />
+          </button>
+        </div>
+
+        <div className="px-6 py-5 space-y-3">
+          <p className="text-sm text-zinc-400">
+            One rule per line, in local time. <span className="font-mono text-zinc-300">01:00-07:00</span> only
+            runs downloads then, <span className="font-mono text-zinc-300">pause weekdays 09:00-17:00</span> stops
+            them during working hours and <span className="font-mono text-zinc-300">limit 2M weekdays</span> caps
+            the total speed.
+          </p>
+          <textarea
+            value={rules}
+            onChange={(e) => setRules(e.target.value)}
+            rows={6}
+            placeholder={"01:00-07:00\npause weekdays 09:00-17:00\nlimit 2M weekdays"}
+            className="w-full px-3 py-2.5 bg-zinc-800 border border-zinc-700 rounded-lg text-sm text-white placeholder-zinc-500 font-mono focus:outline-none focus:border-blue-500 transition-colors resize-none"
+          />
+          {error && (
+            <div className="px-3 py-2.5 bg-red-950/40 border border-red-900/50 rounded-lg text-sm text-red-400">
+              {error}
+            </div>
+          )}
+        </div>
+
+        <div className="flex gap-3 px-6 py-4 border-t border-zinc-800">
+          <button
+            onClick={onClose}
+            className="flex-1 py-2.5 bg-zinc-800 hover:bg-zinc-700 text-zinc-300 text-sm font-medium rounded-lg transition-colors"
+          >
+            Cancel
+          </button>
+          <button
+            onClick={handleSave}
+            disabled={saving}
+            className="flex-1 py-2.5 bg-blue-600 hover:bg-blue-500 disabled:opacity-50 disabled:cursor-not-allowed text-white text-sm font-semibold rounded-lg transition-colors flex items-center justify-center gap-2"
+          >
+            {saving && <Loader2 className="w-4 h-4 animate-spin" />}
+            Save
+          </button>
+        </div>
+      </div>
+    </div>
+  );
+}
@@ -2,7 +2,7 @@ import { useState, useEffect, useRef, useCallback } from "react";
 import { listen } from "@tauri-apps/api/event";
 import { invoke } from "@tauri-apps/api/core";
 
-export type DownloadStatus = "downloading" | "completed" | "error" | "paused";
+export type DownloadStatus = "downloading" | "verifying" | "seeding" | "scheduled" | "completed" | "error" | "paused";
 
 export interface Download {
   id: string;
@@ -17,6 +17,10 @@ export interface Download {
   connections: number;
   error?: string;
   startedAt: number;
+  checksum?: string;
+  verifiedWith?: string;
+  /** Schedule rules of this download alone, e.g. "pause weekdays 09:00-17:00" */
+  schedule?: string[];
 }
 
 interface ProgressEvent {
@@ -28,6 +32,7 @@ interface ProgressEvent {
 interface CompletedEvent {
   download_id: string;
   url: string;
+  verified_with: string | null;
 }
 
 interface ErrorEvent {
@@ -45,6 +50,9 @@ interface PersistedDownload {
   status: string;
   connections: number;
   started_at: number;
+  checksum?: string | null;
+  verified_with?: string | null;
+  schedule?: string[];
 }
 
 function toPersistedDownload(d: Download): PersistedDownload {
@@ -58,6 +66,9 @@ function toPersistedDownload(d: Download): PersistedDownload {
     status: d.status,
     connections: d.connections,
     started_at: d.startedAt,
+    checksum: d.checksum ?? null,
+    verified_with: d.verifiedWith ?? null,
+    schedule: d.schedule ?? [],
   };
 }
 
@@ -71,9 +82,12 @@ function fromPersistedDownload(p: PersistedDownload): Download {
     downloadedBytes: p.downloaded_bytes,
     speed: 0,
     eta: 0,
-    status: p.status === "downloading" ? "paused" : (p.status as DownloadStatus),
+    status: ["downloading", "verifying", "seeding", "scheduled"].includes(p.status) ? "paused" : (p.status as DownloadStatus),
     connections: p.connections,
     startedAt: p.started_at,
+    checksum: p.checksum ?? undefined,
+    verifiedWith: p.verified_with ?? undefined,
+    schedule: p.schedule?.length ? p.schedule : undefined,
   };
 }
 
@@ -83,7 +97,7 @@ export function useDownloads() {
   const initializedRef = useRef(false);
 
   const addDownload = useCallback((
-    download: Omit<Download, "downloadedBytes" | "speed" | "eta" | "status" | "startedAt">
+    download: Omit<Download, "downloadedBytes" | "speed" | "eta" | "status" | "startedAt" | "verifiedWith">
   ) => {
     const newDownload: Download = {
       ...download,
@@ -114,7 +128,9 @@ export function useDownloads() {
       downloadId: target.id,
       url: target.url,
       path: target.path,
-      connections: target.connections
+      connections: target.connections,
+      checksum: target.checksum ?? null,
+      schedule: target.schedule ?? null
     });
   }, [downloads]);
 
@@ -122,12 +138,12 @@ export function useDownloads() {
     const target = downloads.find(d => d.id === id);
     if (!target) return;
 
-    if (target.status === "downloading") {
+    if (target.status === "downloading" || target.status === "seeding" || target.status === "scheduled") {
       invoke("cancel_download", { downloadId: id });
     }
 
     // Delete session file
-    invoke("delete_file", { path: `${target.path}.kitsune` });
+    invoke("delete_session", { downloadId: id, path: target.path });
     // Delete actual file (optional, but requested as "Remove the download when its done or paused/stopped")
     // If it's done, maybe we don't want to delete the file? 
     // Usually "Remove" in DMs means remove from list and optionally delete files.
@@ -142,7 +158,7 @@ export function useDownloads() {
     const target = downloads.find(d => d.id === id);
     if (!target) return;
 
-    if (target.status === "downloading") {
+    if (target.status === "downloading" || target.status === "seeding" || target.status === "scheduled") {
       invoke("cancel_download", { downloadId: id });
     }
 
@@ -204,11 +220,11 @@ export function useDownloads() {
     });
 
     const unlistenCompleted = listen<CompletedEvent>("download-completed", (event) => {
-      const { download_id } = event.payload;
+      const { download_id, verified_with } = event.payload;
       setDownloads(prev =>
         prev.map(d =>
           d.id === download_id
-            ? { ...d, status: "completed" as DownloadStatus, speed: 0, eta: 0 }
+            ? { ...d, status: "completed" as DownloadStatus, speed: 0, eta: 0, verifiedWith: verified_with ?? undefined }
             : d
         )
       );
@@ -225,6 +241,51 @@ export function useDownloads() {
       );
     });
 
+    const unlistenVerifying = listen<ProgressEvent>("download-verifying", (event) => {
+      const { download_id } = event.payload;
+      setDownloads(prev =>
+        prev.map(d =>
+          d.id === download_id
+            ? { ...d, status: "verifying" as DownloadStatus, speed: 0, eta: 0 }
+            : d
+        )
+      );
+    });
+
+    const unlistenSeeding = listen<ProgressEvent>("download-seeding", (event) => {
+      const { download_id } = event.payload;
+      setDownloads(prev =>
+        prev.map(d =>
+          d.id === download_id
+            ? { ...d, status: "seeding" as DownloadStatus, speed: 0, eta: 0 }
+            : d
+        )
+      );
+    });
+
+    // The schedule holds a download back, or lets it go on
+    const unlistenScheduled = listen<ProgressEvent>("download-scheduled", (event) => {
+      const { download_id } = event.payload;
+      setDownloads(prev =>
+        prev.map(d =>
+          d.id === download_id
+            ? { ...d, status: "scheduled" as DownloadStatus, speed: 0, eta: 0 }
+            : d
+        )
+      );
+    });
+
+    const unlistenResumed = listen<ProgressEvent>("download-resumed", (event) => {
+      const { download_id } = event.payload;
+      setDownloads(prev =>
+        prev.map(d =>
+          d.id === download_id && d.status === "scheduled"
+            ? { ...d, status: "downloading" as DownloadStatus }
+            : d
+        )
+      );
+    });
+
     const unlistenPaused = listen<ProgressEvent>("download-paused", (event) => {
       const { download_id } = event.payload;
       setDownloads(prev =>
@@ -240,6 +301,10 @@ export function useDownloads() {
       unlistenProgress.then(fn => fn());
       unlistenCompleted.then(fn => fn());
       unlistenError.then(fn => fn());
+      unlistenVerifying.then(fn => fn());
+      unlistenSeeding.then(fn => fn());
+      unlistenScheduled.then(fn => fn());
+      unlistenResumed.then(fn => fn());
       unlistenPaused.then(fn => fn());
     };
   }, []);